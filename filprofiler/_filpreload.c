@@ -6,6 +6,8 @@
 #endif
 #include "frameobject.h"
 #include <dlfcn.h>
+#include <pthread.h>
+#include <stdarg.h>
 #include <stdint.h>
 #include <stdio.h>
 #include <sys/mman.h>
@@ -31,6 +33,10 @@
 static void *(*underlying_real_mmap)(void *addr, size_t length, int prot,
                                      int flags, int fd, off_t offset) = 0;
 static int (*underlying_real_munmap)(void *addr, size_t length) = 0;
+#ifdef __linux__
+static void *(*underlying_real_mremap)(void *old_address, size_t old_size,
+                                       size_t new_size, int flags, ...) = 0;
+#endif
 
 // Used on Linux to implement these APIs:
 extern void *_rjem_malloc(size_t length);
@@ -40,6 +46,8 @@ extern void _rjem_free(void *addr);
 extern void *_rjem_aligned_alloc(size_t alignment, size_t size);
 extern size_t _rjem_malloc_usable_size(void *ptr);
 extern int _rjem_posix_memalign(void **memptr, size_t alignment, size_t size);
+extern void *_rjem_memalign(size_t alignment, size_t size);
+extern void *_rjem_valloc(size_t size);
 
 // Note whether we've been initialized yet or not:
 static int initialized = 0;
@@ -49,6 +57,15 @@ static int initialized = 0;
 // this on from start until finish.
 static int tracking_allocations = 0;
 
+// Note whether we're tracking Python callstacks. If not, allocations are only
+// attributed to the native code that called malloc() and friends, which is much
+// cheaper since no Python tracer is needed.
+static int tracking_python_stacks = 1;
+
+// Whether sbrk() heap growth is tracked; off by default, since few programs
+// call it directly.
+static int tracking_sbrk = 0;
+
 // ID of Python code object extra data:
 static Py_ssize_t extra_code_index = -1;
 
@@ -87,6 +104,14 @@ static inline void set_will_i_be_reentrant(int i) { will_i_be_reentrant = i; }
 // 3. This isn't a reentrant call: we don't want to track memory allocations
 //    triggered by the Rust tracking code, as that will result in infinite
 //    recursion.
+//
+// A single per-thread guard is sufficient: it is only set while our own
+// tracking code runs, never while the real malloc()/mmap() runs, so the only
+// allocations it skips are the ones done by the tracking code itself. An
+// mmap() done internally by the underlying malloc() doesn't reach our mmap()
+// at all, since e.g. glibc calls it directly rather than via the dynamic
+// linker, so that memory is recorded exactly once, for the malloc(); see
+// test_mmap_inside_malloc_is_recorded_once in tests/test_endtoend.py.
 static inline int should_track_memory() {
   return (likely(initialized) && tracking_allocations && !am_i_reentrant());
 }
@@ -102,6 +127,13 @@ struct FunctionLocation {
   Py_ssize_t function_name_length;
 };
 
+// Statistics about interned callstacks.
+struct InternerStats {
+  size_t callstacks;
+  size_t max_id;
+  size_t estimated_bytes;
+};
+
 static void __attribute__((constructor)) constructor() {
   if (initialized) {
     return;
@@ -129,6 +161,13 @@ static void __attribute__((constructor)) constructor() {
     fprintf(stderr, "Couldn't load munmap(): %s\n", dlerror());
     exit(1);
   }
+#ifdef __linux__
+  underlying_real_mremap = dlsym(RTLD_NEXT, "mremap");
+  if (!underlying_real_mremap) {
+    fprintf(stderr, "Couldn't load mremap(): %s\n", dlerror());
+    exit(1);
+  }
+#endif
 
   initialized = 1;
   unsetenv("LD_PRELOAD");
@@ -143,13 +182,182 @@ extern void pymemprofile_start_call(uint16_t parent_line_number,
 extern void pymemprofile_finish_call();
 extern void pymemprofile_new_line_number(uint16_t line_number);
 extern void pymemprofile_reset();
-extern void pymemprofile_dump_peak_to_flamegraph(const char *path);
+extern int pymemprofile_reset_and_dump(const char *path,
+                                       const char *default_path);
+extern void pymemprofile_reset_keeping_interner(const char *default_path);
+extern int pymemprofile_dump_peak_to_flamegraph(const char *path,
+                                                const char *title,
+                                                const char *subtitle);
+extern int pymemprofile_dump_peak_by_thread_to_flamegraph(const char *path);
+extern int pymemprofile_dump_combined_report(const char *path);
+extern int pymemprofile_dump_peak_collapsed(const char *path);
+extern int pymemprofile_dump_peak_by_file(const char *path);
+extern int pymemprofile_dump_peak_by_label(const char *path);
+extern int pymemprofile_dump_peak_to_pprof(const char *path);
+extern int pymemprofile_dump_peak_to_csv(const char *path);
+extern size_t pymemprofile_largest_allocation(char *buffer,
+                                              size_t buffer_length);
+extern size_t pymemprofile_top_allocators(size_t n, int peak, char *buffer,
+                                          size_t buffer_length);
+extern int pymemprofile_dump_peak_binary(const char *path);
+extern int pymemprofile_binary_to_collapsed(const char *binary_path,
+                                            const char *collapsed_path);
+extern int pymemprofile_render_binary_to_flamegraph(const char *binary_path,
+                                                    const char *output_directory);
+extern int pymemprofile_merge_profiles(const char **paths, size_t count,
+                                       const char *output_directory);
+extern int pymemprofile_enable_event_log(const char *path);
+extern int pymemprofile_render_event_log(const char *log_path,
+                                         const char *output_directory);
+extern void pymemprofile_snapshot_baseline(const char *name);
+extern void pymemprofile_new_generation();
+extern int pymemprofile_dump_old_allocations_flamegraph(uint16_t min_age,
+                                                        const char *path);
+extern int pymemprofile_dump_byte_seconds_flamegraph(const char *path);
+extern void pymemprofile_dump_on_exit(const char *path);
+extern void pymemprofile_for_each_peak_callstack(
+    void (*callback)(const char *callstack, size_t bytes, void *userdata),
+    void *userdata);
+extern int pymemprofile_dump_delta_flamegraph(const char *name,
+                                              const char *path);
+extern void pymemprofile_enable_leak_detection();
+extern void pymemprofile_install_signal_dumper();
+extern int pymemprofile_dump_leak_suspects(const char *path);
+extern void pymemprofile_enable_call_peaks();
+extern int pymemprofile_dump_function_lines(const char *function_name,
+                                            const char *path);
+extern int pymemprofile_dump_call_peaks(const char *path);
+extern int pymemprofile_write_diff_summary(const char *before_path,
+                                           const char *after_path,
+                                           const char *output_path,
+                                           size_t limit);
 extern void pymemprofile_add_allocation(size_t address, size_t length,
                                         uint16_t line_number);
-extern void pymemprofile_free_allocation(size_t address);
+extern void pymemprofile_add_allocation_from_caller(size_t address,
+                                                    size_t length,
+                                                    uint16_t line_number,
+                                                    size_t caller);
+extern size_t pymemprofile_free_allocation(size_t address);
+extern size_t pymemprofile_forget_allocation(size_t address);
+extern void pymemprofile_realloc_allocation(size_t old_address,
+                                            size_t new_address,
+                                            size_t new_size,
+                                            uint16_t line_number);
 extern void pymemprofile_add_anon_mmap(size_t address, size_t length,
                                        uint16_t line_number);
+extern void pymemprofile_add_native_allocation(size_t address, size_t length,
+                                               size_t caller);
+extern void pymemprofile_add_native_anon_mmap(size_t address, size_t length,
+                                              size_t caller);
+extern void pymemprofile_add_huge_page_mmap(size_t address, size_t length,
+                                            uint16_t line_number);
+extern void pymemprofile_add_native_huge_page_mmap(size_t address,
+                                                   size_t length,
+                                                   size_t caller);
 extern void pymemprofile_free_anon_mmap(size_t address, size_t length);
+extern void pymemprofile_change_heap_break(size_t previous_break,
+                                           intptr_t increment,
+                                           uint16_t line_number);
+extern void pymemprofile_change_native_heap_break(size_t previous_break,
+                                                  intptr_t increment,
+                                                  size_t caller);
+extern void pymemprofile_remap_anon_mmap(size_t old_address, size_t old_size,
+                                         size_t new_address, size_t new_size,
+                                         uint16_t line_number);
+extern void pymemprofile_remap_native_anon_mmap(size_t old_address,
+                                                size_t old_size,
+                                                size_t new_address,
+                                                size_t new_size, size_t caller);
+extern void pymemprofile_dump_recent_events(int fd);
+extern int pymemprofile_dump_peak_collapsed_to_fd(int fd);
+extern int pymemprofile_dump_peak_svg_to_fd(int fd);
+extern size_t pymemprofile_get_untracked_free_count();
+extern size_t pymemprofile_get_double_free_count();
+extern size_t pymemprofile_get_tracked_allocation_count();
+extern struct InternerStats pymemprofile_get_interner_stats();
+extern size_t pymemprofile_get_current_allocated_bytes();
+extern size_t pymemprofile_get_peak_allocated_bytes();
+extern size_t pymemprofile_get_total_allocated_bytes();
+extern size_t pymemprofile_get_memory_usage();
+extern void pymemprofile_set_page_size(size_t bytes);
+extern size_t pymemprofile_get_allocation_size(size_t address);
+extern int pymemprofile_run_self_test();
+extern void pymemprofile_reset_peak();
+extern size_t pymemprofile_compact_interner();
+extern void pymemprofile_begin_region(const char *name);
+extern void pymemprofile_end_region();
+extern void pymemprofile_push_label(const char *name);
+extern void pymemprofile_pop_label();
+extern void pymemprofile_set_regions_only(int enabled);
+extern int pymemprofile_peak_exceeds(size_t max_bytes);
+extern void pymemprofile_set_oom_free_threshold(size_t threshold);
+extern void pymemprofile_set_flamegraph_width(size_t width);
+extern void pymemprofile_set_inactive_callstacks(int enabled);
+extern void pymemprofile_set_flamegraph_style(size_t font_size,
+                                              size_t frame_height,
+                                              const char *font_type);
+extern void pymemprofile_set_min_flamegraph_fraction(double fraction);
+extern void pymemprofile_set_max_flamegraph_lines(size_t max_lines);
+extern void pymemprofile_set_flamegraph_colors_by_module(int by_module);
+extern void pymemprofile_set_flamegraph_dark_mode(int enabled);
+extern int pymemprofile_set_allocator_mode(int mode);
+extern void pymemprofile_set_thread_root_frames(int enabled);
+extern void pymemprofile_set_tracked_thread(int enabled);
+extern void pymemprofile_set_capture_native_stacks(int enabled);
+extern void pymemprofile_set_record_native_callers(int enabled);
+extern void pymemprofile_set_sampling_rate(size_t rate);
+extern void pymemprofile_set_watchpoint(size_t size, size_t tolerance);
+extern void pymemprofile_set_max_in_memory_allocations(size_t max);
+extern void pymemprofile_set_max_allocations(size_t max);
+extern void pymemprofile_set_timeline_delta(size_t delta);
+extern void pymemprofile_set_snapshot_interval_seconds(uint64_t seconds);
+extern void pymemprofile_set_resident_mmaps_interval_ms(uint64_t milliseconds);
+extern int pymemprofile_dump_memory_timeline_to_csv(const char *path);
+extern void pymemprofile_set_max_stack_depth(size_t depth);
+extern void pymemprofile_set_verbosity(size_t verbosity);
+extern void pymemprofile_add_hidden_frame_pattern(const char *pattern);
+extern void pymemprofile_add_hidden_prefix(const char *prefix);
+extern void pymemprofile_set_path_prefix(const char *prefix);
+extern void pymemprofile_set_collapse_site_packages(int enabled);
+extern void pymemprofile_set_spare_memory_mb(size_t megabytes);
+extern void pymemprofile_prepare_for_fork();
+extern void pymemprofile_after_fork_in_parent();
+extern void pymemprofile_after_fork_in_child();
+
+// fork() handlers. The tracker stays locked while the fork happens, so the
+// child gets a coherent copy, which it then resets since its allocations are
+// its own from now on.
+//
+// A fork() from inside the tracking code, i.e. the out-of-memory dump, already
+// holds the tracker's lock, and its child wants the parent's allocations, so
+// there the Rust handlers are skipped. Either way the reentrancy state from
+// before the fork is restored afterwards.
+static _Thread_local int reentrant_before_fork = 0;
+
+static void prepare_for_fork() {
+  reentrant_before_fork = am_i_reentrant();
+  if (reentrant_before_fork) {
+    return;
+  }
+  set_will_i_be_reentrant(1);
+  pymemprofile_prepare_for_fork();
+}
+
+static void after_fork_in_parent() {
+  if (reentrant_before_fork) {
+    return;
+  }
+  pymemprofile_after_fork_in_parent();
+  set_will_i_be_reentrant(reentrant_before_fork);
+}
+
+static void after_fork_in_child() {
+  if (reentrant_before_fork) {
+    return;
+  }
+  pymemprofile_after_fork_in_child();
+  set_will_i_be_reentrant(reentrant_before_fork);
+}
 
 static void start_call(struct FunctionLocation *loc, uint16_t line_number) {
   if (should_track_memory()) {
@@ -234,17 +442,49 @@ fil_tracer(PyObject *obj, PyFrameObject *frame, int what, PyObject *arg) {
 /// APIs.
 __attribute__((visibility("default"))) void fil_initialize_from_python() {
   extra_code_index = _PyEval_RequestCodeExtraIndex(NULL);
+  pthread_atfork(prepare_for_fork, after_fork_in_parent, after_fork_in_child);
 }
 
 /// Start memory tracing.
 __attribute__((visibility("default"))) void
 fil_reset(const char *default_path) {
   tracking_allocations = 1;
+  tracking_python_stacks = 1;
   set_will_i_be_reentrant(1);
   pymemprofile_reset(default_path);
   set_will_i_be_reentrant(0);
 }
 
+/// Dump the peak memory usage of the current run to the given directory, then
+/// start memory tracing anew.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_reset_and_dump(const char *path, const char *default_path) {
+  tracking_allocations = 1;
+  tracking_python_stacks = 1;
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_reset_and_dump(path, default_path);
+  set_will_i_be_reentrant(0);
+  return result;
+}
+
+/// Start memory tracing, keeping previously seen callstacks around so repeated
+/// profiling of the same code is faster.
+__attribute__((visibility("default"))) void
+fil_reset_keeping_interner(const char *default_path) {
+  tracking_allocations = 1;
+  tracking_python_stacks = 1;
+  set_will_i_be_reentrant(1);
+  pymemprofile_reset_keeping_interner(default_path);
+  set_will_i_be_reentrant(0);
+}
+
+/// Don't track Python callstacks, only the native code calling malloc() and
+/// friends. Python won't register the tracer in this mode.
+__attribute__((visibility("default"))) void fil_disable_python_stacks() {
+  tracking_python_stacks = 0;
+}
+
 /// End memory tracing.
 __attribute__((visibility("default"))) void fil_shutting_down() {
   tracking_allocations = 0;
@@ -256,155 +496,1128 @@ __attribute__((visibility("default"))) void register_fil_tracer() {
   PyEval_SetProfile(fil_tracer, PyLong_FromLong(123));
 }
 
-/// Dump the current peak memory usage to disk.
-__attribute__((visibility("default"))) void
-fil_dump_peak_to_flamegraph(const char *path) {
+/// Dump the current peak memory usage to disk. The title and subtitle may be
+/// NULL to use the defaults.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_dump_peak_to_flamegraph(const char *path, const char *title,
+                            const char *subtitle) {
   // This maybe called after we're done, when will_i_be_reentrant is permanently
   // set to 1, or might be called mid-way through code run. Either way we want
   // to prevent reentrant malloc() calls, but we want to run regardless.
   int current_reentrant_status = am_i_reentrant();
   set_will_i_be_reentrant(1);
-  pymemprofile_dump_peak_to_flamegraph(path);
+  int result = pymemprofile_dump_peak_to_flamegraph(path, title, subtitle);
   set_will_i_be_reentrant(current_reentrant_status);
+  return result;
 }
 
-// *** End APIs called by Python ***
+/// Collapse calls deeper than depth into a single frame; 0 means no limit.
+__attribute__((visibility("default"))) void
+fil_set_max_stack_depth(size_t depth) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_max_stack_depth(depth);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
 
-static void add_allocation(size_t address, size_t size) {
-  uint16_t line_number = 0;
-  PyFrameObject *f = current_frame;
-  if (f != NULL) {
-    line_number = PyCode_Addr2Line(f->f_code, f->f_lasti);
-  }
-  pymemprofile_add_allocation(address, size, line_number);
+/// Set how much gets written to stderr: 0 for only warnings and errors, 1 (the
+/// default) to also mention written reports, 2 to add debugging messages.
+__attribute__((visibility("default"))) void fil_set_verbosity(size_t verbosity) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_verbosity(verbosity);
+  set_will_i_be_reentrant(current_reentrant_status);
 }
 
-static void add_anon_mmap(size_t address, size_t size) {
-  uint16_t line_number = 0;
-  PyFrameObject *f = current_frame;
-  if (f != NULL) {
-    line_number = PyCode_Addr2Line(f->f_code, f->f_lasti);
-  }
-  pymemprofile_add_anon_mmap(address, size, line_number);
+/// Only record 1 in rate allocations, to reduce profiling overhead.
+__attribute__((visibility("default"))) void fil_set_sampling_rate(size_t rate) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_sampling_rate(rate);
+  set_will_i_be_reentrant(current_reentrant_status);
 }
 
-// Override memory-allocation functions:
-__attribute__((visibility("default"))) void *
-SYMBOL_PREFIX(malloc)(size_t size) {
-  void *result = REAL_IMPL(malloc)(size);
-  if (should_track_memory()) {
-    set_will_i_be_reentrant(1);
-    add_allocation((size_t)result, size);
-    set_will_i_be_reentrant(0);
-  }
-  return result;
+/// Report allocations within tolerance bytes of size on stderr, with their
+/// callstack; a size of 0 turns this off.
+__attribute__((visibility("default"))) void fil_set_watchpoint(size_t size,
+                                                               size_t tolerance) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_watchpoint(size, tolerance);
+  set_will_i_be_reentrant(current_reentrant_status);
 }
 
-__attribute__((visibility("default"))) void *
-SYMBOL_PREFIX(calloc)(size_t nmemb, size_t size) {
-  void *result = REAL_IMPL(calloc)(nmemb, size);
-  size_t allocated = nmemb * size;
-  if (should_track_memory()) {
-    set_will_i_be_reentrant(1);
-    add_allocation((size_t)result, allocated);
-    set_will_i_be_reentrant(0);
-  }
-  return result;
+/// Keep at most max allocations' details in memory, spilling the rest to
+/// disk; 0 means no limit.
+__attribute__((visibility("default"))) void
+fil_set_max_in_memory_allocations(size_t max) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_max_in_memory_allocations(max);
+  set_will_i_be_reentrant(current_reentrant_status);
 }
 
-__attribute__((visibility("default"))) void *
-SYMBOL_PREFIX(realloc)(void *addr, size_t size) {
-  void *result = REAL_IMPL(realloc)(addr, size);
-  if (should_track_memory()) {
-    set_will_i_be_reentrant(1);
-    // Sometimes you'll get same address, so if we did add first and then
-    // removed, it would remove the entry erroneously.
-    pymemprofile_free_allocation((size_t)addr);
-    add_allocation((size_t)result, size);
-    set_will_i_be_reentrant(0);
-  }
-  return result;
+/// Store at most max allocations, estimating free()s of the rest; 0 means no
+/// limit. Reports are marked as approximate once the limit is hit.
+__attribute__((visibility("default"))) void
+fil_set_max_allocations(size_t max) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_max_allocations(max);
+  set_will_i_be_reentrant(current_reentrant_status);
 }
 
-__attribute__((visibility("default"))) int
-SYMBOL_PREFIX(posix_memalign)(void **memptr, size_t alignment, size_t size) {
-  int result = REAL_IMPL(posix_memalign)(memptr, alignment, size);
-  if (!result && should_track_memory()) {
-    set_will_i_be_reentrant(1);
-    add_allocation((size_t)*memptr, size);
-    set_will_i_be_reentrant(0);
-  }
-  return result;
+/// Hide frames whose filename or function name contains the pattern from
+/// rendered callstacks.
+__attribute__((visibility("default"))) void
+fil_add_hidden_frame_pattern(const char *pattern) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_add_hidden_frame_pattern(pattern);
+  set_will_i_be_reentrant(current_reentrant_status);
 }
 
-__attribute__((visibility("default"))) void SYMBOL_PREFIX(free)(void *addr) {
-  REAL_IMPL(free)(addr);
-  if (should_track_memory()) {
-    set_will_i_be_reentrant(1);
-    pymemprofile_free_allocation((size_t)addr);
-    set_will_i_be_reentrant(0);
-  }
+/// Hide frames whose filename starts with the prefix from rendered callstacks.
+__attribute__((visibility("default"))) void
+fil_add_hidden_prefix(const char *prefix) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_add_hidden_prefix(prefix);
+  set_will_i_be_reentrant(current_reentrant_status);
 }
 
-__attribute__((visibility("default"))) void *
-SYMBOL_PREFIX(mmap)(void *addr, size_t length, int prot, int flags, int fd,
-                    off_t offset) {
-  if (unlikely(!initialized)) {
-#ifdef __APPLE__
-    return mmap(addr, length, prot, flags, fd, offset);
-#else
-    return (void *)syscall(SYS_mmap, addr, length, prot, flags, fd, offset);
-#endif
-  }
+/// Strip these project roots, separated by ':', from the start of filenames
+/// in rendered callstacks.
+__attribute__((visibility("default"))) void
+fil_set_path_prefix(const char *prefix) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_path_prefix(prefix);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
 
-  void *result = underlying_real_mmap(addr, length, prot, flags, fd, offset);
+/// If enabled, show filenames inside site-packages as "<venv>/...".
+__attribute__((visibility("default"))) void
+fil_set_collapse_site_packages(int enabled) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_collapse_site_packages(enabled);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
 
-  // For now we only track anonymous mmap()s:
-  if (result != MAP_FAILED && (flags & MAP_ANONYMOUS) && should_track_memory()) {
-    set_will_i_be_reentrant(1);
-    add_anon_mmap((size_t)result, length);
-    set_will_i_be_reentrant(0);
-  }
-  return result;
+/// Set the width of flamegraph SVGs in pixels; 0 means the default width.
+__attribute__((visibility("default"))) void
+fil_set_flamegraph_width(size_t width) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_flamegraph_width(width);
+  set_will_i_be_reentrant(current_reentrant_status);
 }
 
-__attribute__((visibility("default"))) int
-SYMBOL_PREFIX(munmap)(void *addr, size_t length) {
-  if (unlikely(!initialized)) {
-#ifdef __APPLE__
-    return munmap(addr, length);
-#else
-    return syscall(SYS_munmap, addr, length);
-#endif
-  }
+/// Include every callstack that ever allocated in text reports, with 0 bytes if
+/// it's using nothing, if non-zero. Flamegraphs leave them out.
+__attribute__((visibility("default"))) void
+fil_set_inactive_callstacks(int enabled) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_inactive_callstacks(enabled);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
 
-  int result = underlying_real_munmap(addr, length);
-  if (result != -1 && should_track_memory()) {
-    set_will_i_be_reentrant(1);
-    pymemprofile_free_anon_mmap(result, length);
-    set_will_i_be_reentrant(0);
-  }
-  return result;
+/// Set the font size and frame height of flamegraphs in pixels, and the font
+/// name; 0 or NULL leave the default.
+__attribute__((visibility("default"))) void
+fil_set_flamegraph_style(size_t font_size, size_t frame_height,
+                         const char *font_type) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_flamegraph_style(font_size, frame_height, font_type);
+  set_will_i_be_reentrant(current_reentrant_status);
 }
 
-__attribute__((visibility("default"))) void *
-SYMBOL_PREFIX(aligned_alloc)(size_t alignment, size_t size) {
-  void *result = REAL_IMPL(aligned_alloc)(alignment, size);
+/// Color flamegraph frames by the module they're in if non-zero, otherwise by
+/// size.
+__attribute__((visibility("default"))) void
+fil_set_flamegraph_colors_by_module(int by_module) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_flamegraph_colors_by_module(by_module);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
 
-  // For now we only track anonymous mmap()s:
-  if (should_track_memory()) {
-    set_will_i_be_reentrant(1);
-    add_allocation((size_t)result, size);
-    set_will_i_be_reentrant(0);
-  }
+/// Render flamegraphs with a dark background if non-zero, otherwise with the
+/// usual light theme.
+__attribute__((visibility("default"))) void
+fil_set_flamegraph_dark_mode(int enabled) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_flamegraph_dark_mode(enabled);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Capture native backtraces for allocations with no Python callstack if
+/// non-zero, so it's possible to tell which C code allocated. This is
+/// expensive.
+__attribute__((visibility("default"))) void
+fil_set_capture_native_stacks(int enabled) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_capture_native_stacks(enabled);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Attribute allocations with no Python callstack to the symbol of the native
+/// code that called malloc() if non-zero. Much cheaper than capturing native
+/// backtraces.
+__attribute__((visibility("default"))) void
+fil_set_record_native_callers(int enabled) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_record_native_callers(enabled);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Track heap growth from direct sbrk() calls if non-zero. Linux-only.
+__attribute__((visibility("default"))) void fil_set_track_sbrk(int enabled) {
+  tracking_sbrk = enabled;
+}
+
+/// Only record malloc()s made by the calling thread if non-zero, otherwise
+/// those from all threads. Call it from the thread to be profiled.
+__attribute__((visibility("default"))) void fil_set_tracked_thread(int enabled) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_tracked_thread(enabled);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Start flamegraph callstacks with a frame naming their thread if non-zero.
+__attribute__((visibility("default"))) void
+fil_set_thread_root_frames(int enabled) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_thread_root_frames(enabled);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Choose which hooks get recorded: 0 for malloc() and friends only, 1 for
+/// mmap() only, 2 for both. Use 1 for programs that call a custom allocator
+/// like jemalloc or tcmalloc directly. Returns 0 on success, -1 on an unknown
+/// mode.
+__attribute__((visibility("default"))) int fil_set_allocator_mode(int mode) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_set_allocator_mode(mode);
+  set_will_i_be_reentrant(current_reentrant_status);
   return result;
 }
 
-#ifdef __linux__
-// Make sure we expose jemalloc variant of malloc_usable_size(), in case someone
-// actually uses it.
-size_t SYMBOL_PREFIX(malloc_usable_size)(void *ptr) {
-  return REAL_IMPL(malloc_usable_size)(ptr);
+/// Merge flamegraph callstacks using less than this fraction of total memory
+/// into one; 0 keeps everything.
+__attribute__((visibility("default"))) void
+fil_set_min_flamegraph_fraction(double fraction) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_min_flamegraph_fraction(fraction);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Only render the largest this many callstacks into flamegraphs, since huge
+/// profiles are very slow to render; 0 means no limit.
+__attribute__((visibility("default"))) void
+fil_set_max_flamegraph_lines(size_t max_lines) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_max_flamegraph_lines(max_lines);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Set the size above which Python allocations get free()d on out-of-memory,
+/// to make room for writing out the report.
+__attribute__((visibility("default"))) void
+fil_set_oom_free_threshold(size_t threshold) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_oom_free_threshold(threshold);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Set how many MiB of memory are reserved for writing the report if we run
+/// out of memory.
+__attribute__((visibility("default"))) void
+fil_set_spare_memory_mb(size_t megabytes) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_spare_memory_mb(megabytes);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Write the most recent allocation events to a file descriptor, typically to
+/// figure out what happened right before a crash.
+__attribute__((visibility("default"))) void fil_dump_recent_events(int fd) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_dump_recent_events(fd);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Write peak memory usage in collapsed-stack format to an already open file
+/// descriptor, for environments where we can't create files ourselves.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_dump_peak_collapsed_to_fd(int fd) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_dump_peak_collapsed_to_fd(fd);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Write a peak memory usage flamegraph SVG to an already open file
+/// descriptor.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int fil_dump_peak_svg_to_fd(int fd) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_dump_peak_svg_to_fd(fd);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Dump the current peak memory usage to disk, both combined and for each
+/// thread separately.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_dump_peak_by_thread_to_flamegraph(const char *path) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_dump_peak_by_thread_to_flamegraph(path);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Dump current and peak memory usage to disk, with an HTML report that
+/// toggles between them.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_dump_combined_report(const char *path) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_dump_combined_report(path);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Write the n callstacks using the most memory into the buffer as lines of
+/// "<bytes> <callstack>". Returns the buffer size needed for the full result.
+__attribute__((visibility("default"))) size_t
+fil_top_allocators(size_t n, int peak, char *buffer, size_t buffer_length) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  size_t result = pymemprofile_top_allocators(n, peak, buffer, buffer_length);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Write the largest live allocation into the buffer as "<address> <bytes>
+/// <callstack>". Returns the buffer size needed for the full result.
+__attribute__((visibility("default"))) size_t
+fil_largest_allocation(char *buffer, size_t buffer_length) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  size_t result = pymemprofile_largest_allocation(buffer, buffer_length);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Write peak memory usage to a file in collapsed-stack format.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_dump_peak_collapsed(const char *path) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_dump_peak_collapsed(path);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Write peak memory usage summed by file to a file.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_dump_peak_by_file(const char *path) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_dump_peak_by_file(path);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Write peak memory usage summed by label to a file.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_dump_peak_by_label(const char *path) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_dump_peak_by_label(path);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Write peak memory usage to a file as CSV, one row per callstack.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_dump_peak_to_csv(const char *path) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_dump_peak_to_csv(path);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Write peak memory usage to a file in pprof's protobuf format.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_dump_peak_to_pprof(const char *path) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_dump_peak_to_pprof(path);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Write peak memory usage to a file in the compact binary format.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_dump_peak_binary(const char *path) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_dump_peak_binary(path);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Convert a binary profile file to the text format. Returns 0 on success,
+/// otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_binary_to_collapsed(const char *binary_path, const char *collapsed_path) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_binary_to_collapsed(binary_path, collapsed_path);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Dump current allocations to the output path whenever SIGUSR2 is received.
+__attribute__((visibility("default"))) void fil_install_signal_dumper() {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_install_signal_dumper();
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Render a binary profile file to flamegraphs in the output directory.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_render_binary_to_flamegraph(const char *binary_path,
+                                const char *output_directory) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result =
+      pymemprofile_render_binary_to_flamegraph(binary_path, output_directory);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Write every allocation event to a log file from now on, so the history
+/// survives crashes. Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_enable_event_log(const char *path) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_enable_event_log(path);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Replay an event log to current and peak flamegraphs in the output
+/// directory. Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_render_event_log(const char *log_path, const char *output_directory) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_render_event_log(log_path, output_directory);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Merge collapsed-stack profile files into one set of flamegraphs. Returns 0
+/// on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_merge_profiles(const char **paths, size_t count,
+                   const char *output_directory) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_merge_profiles(paths, count, output_directory);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Start a new generation; allocations made so far get one generation older.
+__attribute__((visibility("default"))) void fil_new_generation() {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_new_generation();
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Write flamegraphs of current allocations made at least min_age generations
+/// ago to the given directory.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_dump_old_allocations_flamegraph(uint16_t min_age, const char *path) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_dump_old_allocations_flamegraph(min_age, path);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Write flamegraphs of byte-seconds, i.e. allocation size times how long the
+/// allocation was live, to the given directory.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_dump_byte_seconds_flamegraph(const char *path) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_dump_byte_seconds_flamegraph(path);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Dump peak memory usage to the given directory when the process exits
+/// normally, or to the default path if it's NULL or empty.
+__attribute__((visibility("default"))) void fil_dump_on_exit(const char *path) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_dump_on_exit(path);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Call callback with each callstack's rendered string, its memory usage at the
+/// peak in bytes, and userdata, largest first. Allocations made by the
+/// callback, e.g. to build Python objects, aren't tracked.
+__attribute__((visibility("default"))) void fil_for_each_peak_callstack(
+    void (*callback)(const char *callstack, size_t bytes, void *userdata),
+    void *userdata) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_for_each_peak_callstack(callback, userdata);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Remember current memory usage under the given name.
+__attribute__((visibility("default"))) void
+fil_snapshot_baseline(const char *name) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_snapshot_baseline(name);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Write flamegraphs of memory growth since the named baseline.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_dump_delta_flamegraph(const char *name, const char *path) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_dump_delta_flamegraph(name, path);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Start looking for Python calls that leak memory every time they're called.
+__attribute__((visibility("default"))) void fil_enable_leak_detection() {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_enable_leak_detection();
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Write out the Python calls that appear to leak memory.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_dump_leak_suspects(const char *path) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_dump_leak_suspects(path);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Write peak memory usage by line within the named function, largest first.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_dump_function_lines(const char *function_name, const char *path) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_dump_function_lines(function_name, path);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Start tracking the peak memory of each function call, callees included.
+__attribute__((visibility("default"))) void fil_enable_call_peaks() {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_enable_call_peaks();
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Write out the most memory any single call to each function added.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_dump_call_peaks(const char *path) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_dump_call_peaks(path);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Write a summary of the differences between two .prof files. Returns 0 on
+/// success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_write_diff_summary(const char *before_path, const char *after_path,
+                       const char *output_path, size_t limit) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_write_diff_summary(before_path, after_path,
+                                               output_path, limit);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// How many free()s were of untracked addresses, for diagnostics.
+__attribute__((visibility("default"))) size_t fil_get_untracked_free_count() {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  size_t result = pymemprofile_get_untracked_free_count();
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// How many free()s were of recently free()d addresses, i.e. probably double
+/// free()s.
+__attribute__((visibility("default"))) size_t fil_get_double_free_count() {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  size_t result = pymemprofile_get_double_free_count();
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Check that malloc() and free() are being tracked, writing discrepancies to
+/// stderr. Returns 0 if they are, otherwise 1. Unlike the other wrappers this
+/// doesn't set the reentrancy flag, since the point is for the self-test's
+/// allocations to go through the hooks.
+__attribute__((visibility("default"))) int fil_run_self_test() {
+  return pymemprofile_run_self_test();
+}
+
+/// How many allocations and mmap()ed ranges are being tracked.
+__attribute__((visibility("default"))) size_t
+fil_get_tracked_allocation_count() {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  size_t result = pymemprofile_get_tracked_allocation_count();
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// How many distinct callstacks have been seen, and roughly how much memory
+/// they use.
+__attribute__((visibility("default"))) struct InternerStats
+fil_get_interner_stats() {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  struct InternerStats result = pymemprofile_get_interner_stats();
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// How many bytes are currently allocated.
+__attribute__((visibility("default"))) size_t
+fil_get_current_allocated_bytes() {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  size_t result = pymemprofile_get_current_allocated_bytes();
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// How many bytes have been malloc()ed since tracking started, ignoring
+/// free()s. Sampling this over time gives the allocation rate.
+__attribute__((visibility("default"))) size_t fil_get_total_allocated_bytes() {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  size_t result = pymemprofile_get_total_allocated_bytes();
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// The largest number of bytes allocated at any one time.
+__attribute__((visibility("default"))) size_t fil_get_peak_allocated_bytes() {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  size_t result = pymemprofile_get_peak_allocated_bytes();
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// The process's resident memory in bytes, or 0 if unknown.
+__attribute__((visibility("default"))) size_t fil_get_memory_usage() {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  size_t result = pymemprofile_get_memory_usage();
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Compute resident memory with this page size in bytes, e.g. to match
+/// another machine; 0 uses the system's page size.
+__attribute__((visibility("default"))) void fil_set_page_size(size_t bytes) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_page_size(bytes);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Returns 1 if peak memory usage so far is more than the given number of
+/// bytes, otherwise 0.
+__attribute__((visibility("default"))) int fil_peak_exceeds(size_t max_bytes) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_peak_exceeds(max_bytes);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Start a named region of the current thread's callstack.
+__attribute__((visibility("default"))) void fil_begin_region(const char *name) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_begin_region(name);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// End the region started by the matching fil_begin_region().
+__attribute__((visibility("default"))) void fil_end_region() {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_end_region();
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Label the current thread's allocations until the matching fil_pop_label().
+__attribute__((visibility("default"))) void fil_push_label(const char *name) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_push_label(name);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Go back to the label in effect before the matching fil_push_label().
+__attribute__((visibility("default"))) void fil_pop_label() {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_pop_label();
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Only include allocations made inside a region in flamegraphs if non-zero.
+__attribute__((visibility("default"))) void fil_set_regions_only(int enabled) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_regions_only(enabled);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// The tracked size of the allocation at the given address, or 0 if it isn't
+/// tracked.
+__attribute__((visibility("default"))) size_t
+fil_get_allocation_size(size_t address) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  size_t result = pymemprofile_get_allocation_size(address);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Stop tracking the allocation at the given address, e.g. because it was
+/// handed off to code whose free()s aren't tracked, without it counting as a
+/// free(). Returns its tracked size, or 0 if it isn't tracked.
+__attribute__((visibility("default"))) size_t
+fil_forget_allocation(size_t address) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  size_t result = pymemprofile_forget_allocation(address);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Forget the peak so far, keeping track of current allocations, e.g. at the
+/// start of a new phase of the program.
+__attribute__((visibility("default"))) void fil_reset_peak() {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_reset_peak();
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Forget callstacks that are no longer used, to cap the profiler's own memory
+/// usage in long-running processes. Returns how many were forgotten.
+__attribute__((visibility("default"))) size_t fil_compact_interner() {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  size_t result = pymemprofile_compact_interner();
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+/// Record memory usage over time, sampling whenever it changes by at least
+/// delta bytes; 0 disables recording.
+__attribute__((visibility("default"))) void
+fil_set_timeline_delta(size_t delta) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_timeline_delta(delta);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Every n milliseconds, count only the resident part of anonymous mmap()s,
+/// read from /proc/self/smaps (Linux only, and slow); 0 counts all mapped
+/// bytes.
+__attribute__((visibility("default"))) void
+fil_set_resident_mmaps_interval_ms(uint64_t milliseconds) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_resident_mmaps_interval_ms(milliseconds);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Dump current allocations to the output path every n seconds, rotating
+/// between a few sets of files; 0 disables snapshots.
+__attribute__((visibility("default"))) void
+fil_set_snapshot_interval_seconds(uint64_t seconds) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  pymemprofile_set_snapshot_interval_seconds(seconds);
+  set_will_i_be_reentrant(current_reentrant_status);
+}
+
+/// Write memory usage over time to a CSV file.
+/// Returns 0 on success, otherwise an errno-style error code.
+__attribute__((visibility("default"))) int
+fil_dump_memory_timeline_to_csv(const char *path) {
+  int current_reentrant_status = am_i_reentrant();
+  set_will_i_be_reentrant(1);
+  int result = pymemprofile_dump_memory_timeline_to_csv(path);
+  set_will_i_be_reentrant(current_reentrant_status);
+  return result;
+}
+
+// *** End APIs called by Python ***
+
+static void add_allocation(size_t address, size_t size, void *caller) {
+  if (!tracking_python_stacks) {
+    pymemprofile_add_native_allocation(address, size, (size_t)caller);
+    return;
+  }
+  uint16_t line_number = 0;
+  PyFrameObject *f = current_frame;
+  if (f != NULL) {
+    line_number = PyCode_Addr2Line(f->f_code, f->f_lasti);
+  }
+  pymemprofile_add_allocation_from_caller(address, size, line_number,
+                                          (size_t)caller);
+}
+
+static void add_anon_mmap(size_t address, size_t size, int huge_pages,
+                          void *caller) {
+  if (!tracking_python_stacks) {
+    if (huge_pages) {
+      pymemprofile_add_native_huge_page_mmap(address, size, (size_t)caller);
+    } else {
+      pymemprofile_add_native_anon_mmap(address, size, (size_t)caller);
+    }
+    return;
+  }
+  uint16_t line_number = 0;
+  PyFrameObject *f = current_frame;
+  if (f != NULL) {
+    line_number = PyCode_Addr2Line(f->f_code, f->f_lasti);
+  }
+  if (huge_pages) {
+    pymemprofile_add_huge_page_mmap(address, size, line_number);
+  } else {
+    pymemprofile_add_anon_mmap(address, size, line_number);
+  }
+}
+
+static void remap_anon_mmap(size_t old_address, size_t old_size,
+                            size_t new_address, size_t new_size,
+                            void *caller) {
+  if (!tracking_python_stacks) {
+    pymemprofile_remap_native_anon_mmap(old_address, old_size, new_address,
+                                        new_size, (size_t)caller);
+    return;
+  }
+  uint16_t line_number = 0;
+  PyFrameObject *f = current_frame;
+  if (f != NULL) {
+    line_number = PyCode_Addr2Line(f->f_code, f->f_lasti);
+  }
+  pymemprofile_remap_anon_mmap(old_address, old_size, new_address, new_size,
+                               line_number);
+}
+
+static void change_heap_break(size_t previous_break, intptr_t increment,
+                              void *caller) {
+  if (!tracking_python_stacks) {
+    pymemprofile_change_native_heap_break(previous_break, increment,
+                                          (size_t)caller);
+    return;
+  }
+  uint16_t line_number = 0;
+  PyFrameObject *f = current_frame;
+  if (f != NULL) {
+    line_number = PyCode_Addr2Line(f->f_code, f->f_lasti);
+  }
+  pymemprofile_change_heap_break(previous_break, increment, line_number);
+}
+
+static void realloc_allocation(size_t old_address, size_t new_address,
+                               size_t new_size, void *caller) {
+  if (!tracking_python_stacks) {
+    // Sometimes you'll get same address, so if we did add first and then
+    // removed, it would remove the entry erroneously.
+    pymemprofile_free_allocation(old_address);
+    pymemprofile_add_native_allocation(new_address, new_size, (size_t)caller);
+    return;
+  }
+  uint16_t line_number = 0;
+  PyFrameObject *f = current_frame;
+  if (f != NULL) {
+    line_number = PyCode_Addr2Line(f->f_code, f->f_lasti);
+  }
+  pymemprofile_realloc_allocation(old_address, new_address, new_size,
+                                  line_number);
+}
+
+// Override memory-allocation functions:
+__attribute__((visibility("default"))) void *
+SYMBOL_PREFIX(malloc)(size_t size) {
+  void *result = REAL_IMPL(malloc)(size);
+  if (should_track_memory()) {
+    set_will_i_be_reentrant(1);
+    add_allocation((size_t)result, size, __builtin_return_address(0));
+    set_will_i_be_reentrant(0);
+  }
+  return result;
+}
+
+__attribute__((visibility("default"))) void *
+SYMBOL_PREFIX(calloc)(size_t nmemb, size_t size) {
+  void *result = REAL_IMPL(calloc)(nmemb, size);
+  size_t allocated = nmemb * size;
+  if (should_track_memory()) {
+    set_will_i_be_reentrant(1);
+    add_allocation((size_t)result, allocated, __builtin_return_address(0));
+    set_will_i_be_reentrant(0);
+  }
+  return result;
+}
+
+__attribute__((visibility("default"))) void *
+SYMBOL_PREFIX(realloc)(void *addr, size_t size) {
+  void *result = REAL_IMPL(realloc)(addr, size);
+  if (should_track_memory()) {
+    set_will_i_be_reentrant(1);
+    realloc_allocation((size_t)addr, (size_t)result, size,
+                       __builtin_return_address(0));
+    set_will_i_be_reentrant(0);
+  }
+  return result;
+}
+
+__attribute__((visibility("default"))) int
+SYMBOL_PREFIX(posix_memalign)(void **memptr, size_t alignment, size_t size) {
+  int result = REAL_IMPL(posix_memalign)(memptr, alignment, size);
+  if (!result && should_track_memory()) {
+    set_will_i_be_reentrant(1);
+    add_allocation((size_t)*memptr, size, __builtin_return_address(0));
+    set_will_i_be_reentrant(0);
+  }
+  return result;
+}
+
+__attribute__((visibility("default"))) void SYMBOL_PREFIX(free)(void *addr) {
+  REAL_IMPL(free)(addr);
+  if (should_track_memory()) {
+    set_will_i_be_reentrant(1);
+    pymemprofile_free_allocation((size_t)addr);
+    set_will_i_be_reentrant(0);
+  }
+}
+
+__attribute__((visibility("default"))) void *
+SYMBOL_PREFIX(mmap)(void *addr, size_t length, int prot, int flags, int fd,
+                    off_t offset) {
+  if (unlikely(!initialized)) {
+#ifdef __APPLE__
+    return mmap(addr, length, prot, flags, fd, offset);
+#else
+    return (void *)syscall(SYS_mmap, addr, length, prot, flags, fd, offset);
+#endif
+  }
+
+  void *result = underlying_real_mmap(addr, length, prot, flags, fd, offset);
+
+  // For now we only track anonymous mmap()s:
+  if (result != MAP_FAILED && (flags & MAP_ANONYMOUS) && should_track_memory()) {
+    set_will_i_be_reentrant(1);
+#ifdef MAP_HUGETLB
+    int huge_pages = (flags & MAP_HUGETLB) != 0;
+#else
+    int huge_pages = 0;
+#endif
+    add_anon_mmap((size_t)result, length, huge_pages,
+                  __builtin_return_address(0));
+    set_will_i_be_reentrant(0);
+  }
+  return result;
+}
+
+__attribute__((visibility("default"))) int
+SYMBOL_PREFIX(munmap)(void *addr, size_t length) {
+  if (unlikely(!initialized)) {
+#ifdef __APPLE__
+    return munmap(addr, length);
+#else
+    return syscall(SYS_munmap, addr, length);
+#endif
+  }
+
+  int result = underlying_real_munmap(addr, length);
+  if (result != -1 && should_track_memory()) {
+    set_will_i_be_reentrant(1);
+    pymemprofile_free_anon_mmap(result, length);
+    set_will_i_be_reentrant(0);
+  }
+  return result;
+}
+
+__attribute__((visibility("default"))) void *
+SYMBOL_PREFIX(aligned_alloc)(size_t alignment, size_t size) {
+  void *result = REAL_IMPL(aligned_alloc)(alignment, size);
+
+  // For now we only track anonymous mmap()s:
+  if (should_track_memory()) {
+    set_will_i_be_reentrant(1);
+    add_allocation((size_t)result, size, __builtin_return_address(0));
+    set_will_i_be_reentrant(0);
+  }
+  return result;
+}
+
+// Legacy API, but still used by some libraries. Allocates page-aligned memory.
+__attribute__((visibility("default"))) void *SYMBOL_PREFIX(valloc)(size_t size) {
+  void *result = REAL_IMPL(valloc)(size);
+  if (should_track_memory()) {
+    set_will_i_be_reentrant(1);
+    add_allocation((size_t)result, size, __builtin_return_address(0));
+    set_will_i_be_reentrant(0);
+  }
+  return result;
+}
+
+#ifdef __linux__
+// Legacy glibc API, but still used by some libraries.
+__attribute__((visibility("default"))) void *
+SYMBOL_PREFIX(memalign)(size_t alignment, size_t size) {
+  void *result = REAL_IMPL(memalign)(alignment, size);
+  if (should_track_memory()) {
+    set_will_i_be_reentrant(1);
+    add_allocation((size_t)result, size, __builtin_return_address(0));
+    set_will_i_be_reentrant(0);
+  }
+  return result;
+}
+
+// Make sure we expose jemalloc variant of malloc_usable_size(), in case someone
+// actually uses it.
+size_t SYMBOL_PREFIX(malloc_usable_size)(void *ptr) {
+  return REAL_IMPL(malloc_usable_size)(ptr);
+}
+
+// mremap() is Linux-only. Only anonymous mmap()s we're already tracking are
+// affected, so there's no need to check the flags it was mmap()ed with.
+__attribute__((visibility("default"))) void *
+SYMBOL_PREFIX(mremap)(void *old_address, size_t old_size, size_t new_size,
+                      int flags, ...) {
+  void *new_address = NULL;
+  if (flags & MREMAP_FIXED) {
+    va_list args;
+    va_start(args, flags);
+    new_address = va_arg(args, void *);
+    va_end(args);
+  }
+  if (unlikely(!initialized)) {
+    return (void *)syscall(SYS_mremap, old_address, old_size, new_size, flags,
+                           new_address);
+  }
+
+  void *result = underlying_real_mremap(old_address, old_size, new_size, flags,
+                                        new_address);
+  if (result != MAP_FAILED && should_track_memory()) {
+    set_will_i_be_reentrant(1);
+    remap_anon_mmap((size_t)old_address, old_size, (size_t)result, new_size,
+                    __builtin_return_address(0));
+    set_will_i_be_reentrant(0);
+  }
+  return result;
+}
+
+// glibc's own sbrk(), which our version wraps:
+extern void *__sbrk(intptr_t increment);
+
+// Whether the code address is in this library, e.g. jemalloc, whose heap is
+// already tracked via malloc() and friends.
+static int is_in_this_library(void *address) {
+  Dl_info ours, theirs;
+  if (!dladdr((void *)is_in_this_library, &ours) ||
+      !dladdr(address, &theirs)) {
+    return 0;
+  }
+  return ours.dli_fbase == theirs.dli_fbase;
+}
+
+// sbrk() is Linux-only in practice; on macOS it's emulated and long
+// deprecated. glibc's malloc() calls its internal __sbrk() directly, so only
+// direct callers end up here, but our bundled jemalloc could too, and its
+// memory is already tracked, so it's skipped.
+__attribute__((visibility("default"))) void *
+SYMBOL_PREFIX(sbrk)(intptr_t increment) {
+  void *result = __sbrk(increment);
+  if (tracking_sbrk && increment != 0 && result != (void *)-1 &&
+      should_track_memory() &&
+      !is_in_this_library(__builtin_return_address(0))) {
+    set_will_i_be_reentrant(1);
+    change_heap_break((size_t)result, increment, __builtin_return_address(0));
+    set_will_i_be_reentrant(0);
+  }
+  return result;
 }
 #endif
 
@@ -417,4 +1630,5 @@ DYLD_INTERPOSE(SYMBOL_PREFIX(mmap), mmap)
 DYLD_INTERPOSE(SYMBOL_PREFIX(munmap), munmap)
 DYLD_INTERPOSE(SYMBOL_PREFIX(aligned_alloc), aligned_alloc)
 DYLD_INTERPOSE(SYMBOL_PREFIX(posix_memalign), posix_memalign)
+DYLD_INTERPOSE(SYMBOL_PREFIX(valloc), valloc)
 #endif
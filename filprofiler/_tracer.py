@@ -1,13 +1,14 @@
 """Trace code, so that libpymemprofile_api know's where we are."""
 
 import atexit
-from ctypes import PyDLL
+from ctypes import PyDLL, c_size_t
 from datetime import datetime
 import os
 import sys
 import threading
 import webbrowser
 from contextlib import contextmanager
+from typing import Optional
 
 from ._utils import timestamp_now, library_path
 from ._report import render_report
@@ -16,10 +17,26 @@ preload = PyDLL(library_path("_filpreload"))
 preload.fil_initialize_from_python()
 
 
-def start_tracing(output_path: str):
-    """Start tracing allocations."""
+def start_tracing(
+    output_path: str, python_stacks: bool = True, keep_interner: bool = False
+):
+    """Start tracing allocations.
+
+    If ``python_stacks`` is false, Python callstacks aren't tracked at all, and
+    allocations are attributed only to the native code that made them. This
+    has much lower overhead.
+
+    If ``keep_interner`` is true, callstacks seen by previous tracing runs are
+    remembered, which speeds up profiling the same code repeatedly.
+    """
     path = os.path.join(output_path, timestamp_now()).encode("utf-8")
-    preload.fil_reset(path)
+    if keep_interner:
+        preload.fil_reset_keeping_interner(path)
+    else:
+        preload.fil_reset(path)
+    if not python_stacks:
+        preload.fil_disable_python_stacks()
+        return
     threading.setprofile(_start_thread_trace)
     preload.register_fil_tracer()
 
@@ -48,13 +65,51 @@ def stop_tracing(output_path: str) -> str:
     return create_report(output_path)
 
 
-def create_report(output_path: str) -> str:
+def create_report(
+    output_path: str, title: Optional[str] = None, subtitle: Optional[str] = None
+) -> str:
+    """Dump peak memory usage and write the HTML report.
+
+    The flamegraphs get the given title and subtitle, or defaults if they're
+    not given.
+    """
+
+    def encode(text: Optional[str]) -> Optional[bytes]:
+        # None is passed as a NULL pointer:
+        return None if text is None else text.encode("utf-8")
+
     now = datetime.now()
     output_path = os.path.join(output_path, now.isoformat(timespec="milliseconds"))
-    preload.fil_dump_peak_to_flamegraph(output_path.encode("utf-8"))
+    error = preload.fil_dump_peak_to_flamegraph(
+        output_path.encode("utf-8"), encode(title), encode(subtitle)
+    )
+    if error != 0:
+        # -1 means the error didn't come from the operating system:
+        if error > 0:
+            raise OSError(error, os.strerror(error), output_path)
+        raise OSError("Failed to write the memory profile to " + output_path)
     return render_report(output_path, now)
 
 
+class MemoryBudgetExceeded(Exception):
+    """Peak memory usage was more than the budget."""
+
+
+@contextmanager
+def fil_budget(max_mib: float):
+    """Raise MemoryBudgetExceeded at the end of the block if peak memory usage
+    so far, including within the block, was more than ``max_mib`` MiB.
+
+    Useful for memory regression tests in CI.
+    """
+    yield
+    max_bytes = int(max_mib * 1024 * 1024)
+    if preload.fil_peak_exceeds(c_size_t(max_bytes)):
+        raise MemoryBudgetExceeded(
+            "Peak memory usage was more than {} MiB".format(max_mib)
+        )
+
+
 def trace_until_exit(code, globals_, output_path: str):
     """
     Given code (Python or code object), run it under the tracer until the
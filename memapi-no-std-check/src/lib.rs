@@ -0,0 +1,6 @@
+//! Builds the accounting core of pymemprofile_api without std; if it starts
+//! depending on std, this crate stops compiling.
+#![no_std]
+
+#[path = "../../memapi/src/accounting.rs"]
+pub mod accounting;
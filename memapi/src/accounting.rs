@@ -0,0 +1,418 @@
+//! The core of allocation accounting: callstacks, how they're interned, how
+//! allocations are stored and sized, and memory usage per callstack. This
+//! only uses `core` and `alloc`, so it can be reused outside a std
+//! environment, e.g. in embedded runtimes; the memapi-no-std-check crate
+//! builds it with `#![no_std]` to make sure it stays that way. Keep I/O,
+//! locking and rendering out of here; memorytracking.rs layers those on top,
+//! using std's faster collections where the core is generic over them.
+
+extern crate alloc;
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::hash::{Hash, Hasher};
+use core::iter::FromIterator;
+use core::ops::{Index, IndexMut};
+
+/// Identifies an interned callstack.
+pub type CallstackId = u32;
+
+/// Identifies threads. These are assigned in the order threads first allocate,
+/// starting from 1.
+pub type ThreadId = u32;
+
+/// Identifies a label set by push_label(); 0 means no label.
+pub type LabelId = u32;
+
+pub const MIB: usize = 1024 * 1024;
+pub const HIGH_32BIT: u32 = 1 << 31;
+
+/// Large allocation sizes are stored as multiples of this many bytes. With the
+/// kib-resolution feature sizes are accurate to within 512 bytes rather than
+/// 512KiB, but the largest size that can be stored drops from 2PiB to 2TiB.
+/// Either way tracking costs the same memory per allocation; the tradeoff is
+/// range, not overhead.
+#[cfg(not(feature = "kib-resolution"))]
+pub const COMPRESSION_UNIT: usize = MIB;
+#[cfg(feature = "kib-resolution")]
+pub const COMPRESSION_UNIT: usize = 1024;
+
+/// The largest number of units a compressed size can store.
+pub const MAX_COMPRESSED_UNITS: usize = (HIGH_32BIT - 1) as usize;
+
+/// A specific call to malloc()/calloc().
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Allocation {
+    pub callstack_id: CallstackId,
+    // If high bit is set, this is COMPRESSION_UNITs, by default MiBs (without
+    // the high bit being meaningful). Otherwise, it's bytes. We only store
+    // units for allocations larger than 2 ** 31 bytes (2GB), which means the
+    // loss of resolution isn't meaningful.
+    // This compression allows us to reduce memory overhead from tracking
+    // allocations.
+    compressed_size: u32,
+    /// The tracker's generation when this was allocated.
+    pub generation: Generation,
+    /// When this was allocated.
+    pub created: Timestamp,
+}
+
+/// Counts dumps and snapshots, so allocations that survive several of them can
+/// be told apart from short-lived ones. A u16 keeps Allocation small; it stops
+/// counting once it reaches the maximum.
+pub type Generation = u16;
+
+/// Milliseconds since tracking started. A u32 lasts 49 days, after which
+/// timestamps stop increasing.
+pub type Timestamp = u32;
+
+impl Allocation {
+    pub fn new(
+        callstack_id: CallstackId,
+        size: usize,
+        generation: Generation,
+        created: Timestamp,
+    ) -> Self {
+        let compressed_size = if size >= HIGH_32BIT as usize {
+            // Rounding division by the unit, plus the high bit. Adding unit / 2
+            // before dividing could overflow on 32-bit platforms, where size
+            // can be close to usize::MAX, so round using the remainder instead.
+            // Absurdly large sizes on 64-bit platforms are clamped rather than
+            // having their high bits silently dropped.
+            let units = size / COMPRESSION_UNIT
+                + (size % COMPRESSION_UNIT >= COMPRESSION_UNIT / 2) as usize;
+            (units.min(MAX_COMPRESSED_UNITS) as u32) | HIGH_32BIT
+        } else {
+            size as u32
+        };
+        Allocation {
+            callstack_id,
+            compressed_size,
+            generation,
+            created,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        if self.compressed_size >= HIGH_32BIT {
+            // On 32-bit platforms sizes just under 4GiB round up to 4096 MiB,
+            // which doesn't fit in a usize:
+            ((self.compressed_size - HIGH_32BIT) as usize).saturating_mul(COMPRESSION_UNIT)
+        } else {
+            self.compressed_size as usize
+        }
+    }
+
+    /// The size as stored, e.g. for ordering allocations cheaply.
+    pub fn compressed_size(&self) -> u32 {
+        self.compressed_size
+    }
+}
+
+/// A frame in a callstack. Its line number changes as its function runs.
+pub trait Frame: Clone {
+    fn set_line_number(&mut self, line_number: u16);
+}
+
+/// The current callstack, outermost call first. We use ids instead of the
+/// frames' details for performance reasons.
+#[derive(Clone, Debug)]
+pub struct Callstack<F> {
+    pub calls: Vec<F>,
+    // Address of the native code that did the allocation, or 0 if unknown.
+    // Only used when we're not tracking Python callstacks.
+    pub native_caller: usize,
+    // The thread that did the allocation, or 0 if unknown:
+    pub thread_id: ThreadId,
+    // The innermost label when the allocation happened, or 0 if none:
+    pub label: LabelId,
+    // How many calls beyond the maximum depth are represented by the final
+    // truncation frame. Deliberately not part of equality or hashing, so all
+    // truncated callstacks with the same prefix are the same.
+    pub truncated_calls: usize,
+    // Return addresses of the native backtrace, innermost first, if there
+    // were no Python calls and native stacks are being captured. They're only
+    // symbolicated when dumping.
+    pub native_frames: Vec<usize>,
+    // Whether there were no Python calls because profiling hadn't started
+    // yet, as opposed to e.g. a thread that isn't running Python code:
+    pub before_profiling: bool,
+}
+
+impl<F: PartialEq> PartialEq for Callstack<F> {
+    fn eq(&self, other: &Self) -> bool {
+        self.calls == other.calls
+            && self.native_caller == other.native_caller
+            && self.thread_id == other.thread_id
+            && self.label == other.label
+            && self.native_frames == other.native_frames
+            && self.before_profiling == other.before_profiling
+    }
+}
+
+impl<F: Eq> Eq for Callstack<F> {}
+
+impl<F: Hash> Hash for Callstack<F> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.calls.hash(state);
+        self.native_caller.hash(state);
+        self.thread_id.hash(state);
+        self.label.hash(state);
+        self.native_frames.hash(state);
+        self.before_profiling.hash(state);
+    }
+}
+
+impl<F> Default for Callstack<F> {
+    fn default() -> Self {
+        Callstack::from_native_caller(0)
+    }
+}
+
+impl<F> Callstack<F> {
+    pub fn new() -> Self {
+        Callstack::default()
+    }
+
+    /// A callstack that is attributed purely to the native code that called
+    /// malloc() and friends.
+    pub fn from_native_caller(native_caller: usize) -> Self {
+        Callstack {
+            calls: Vec::new(),
+            native_caller,
+            thread_id: 0,
+            label: 0,
+            truncated_calls: 0,
+            native_frames: Vec::new(),
+            before_profiling: false,
+        }
+    }
+
+    /// Is this a Python call?
+    pub fn in_python(&self) -> bool {
+        !self.calls.is_empty()
+    }
+}
+
+impl<F: Frame> Callstack<F> {
+    /// Add a call. Once there are max_depth calls (if non-zero), deeper calls
+    /// are all represented by a single truncation frame.
+    pub fn start_call_with_max_depth(
+        &mut self,
+        parent_line_number: u16,
+        frame: F,
+        max_depth: usize,
+        truncation_frame: F,
+    ) {
+        if self.truncated_calls > 0 {
+            self.truncated_calls += 1;
+            return;
+        }
+        if parent_line_number != 0 {
+            if let Some(call) = self.calls.last_mut() {
+                call.set_line_number(parent_line_number);
+            }
+        }
+        if max_depth > 0 && self.calls.len() >= max_depth {
+            self.truncated_calls = 1;
+            self.calls.push(truncation_frame);
+        } else {
+            self.calls.push(frame);
+        }
+    }
+
+    pub fn finish_call(&mut self) {
+        if self.truncated_calls > 0 {
+            self.truncated_calls -= 1;
+            if self.truncated_calls > 0 {
+                return;
+            }
+        }
+        self.calls.pop();
+    }
+
+    pub fn new_line_number(&mut self, line_number: u16) {
+        if self.truncated_calls > 0 {
+            return;
+        }
+        if let Some(frame) = self.calls.last_mut() {
+            frame.set_line_number(line_number);
+        }
+    }
+}
+
+/// The map a CallstackInterner uses to find callstacks' ids. Without std
+/// that's a BTreeMap; hash maps are faster where they're available.
+pub trait IdMap:
+    Default + FromIterator<(Self::Key, CallstackId)> + IntoIterator<Item = (Self::Key, CallstackId)>
+{
+    type Key;
+
+    fn get_id(&self, key: &Self::Key) -> Option<CallstackId>;
+    fn insert_id(&mut self, key: Self::Key, id: CallstackId);
+}
+
+impl<K: Ord> IdMap for BTreeMap<K, CallstackId> {
+    type Key = K;
+
+    fn get_id(&self, key: &K) -> Option<CallstackId> {
+        self.get(key).copied()
+    }
+
+    fn insert_id(&mut self, key: K, id: CallstackId) {
+        self.insert(key, id);
+    }
+}
+
+/// Maps callstacks to consecutive integer ids, so allocations and memory
+/// usage can refer to them cheaply.
+pub struct CallstackInterner<M: IdMap> {
+    pub max_id: CallstackId,
+    pub callstack_to_id: M,
+    // Callstacks' rendered strings, keyed by id and whether they were
+    // rendered to be post-processed. An id's callstack never changes, so
+    // entries only go stale if the way callstacks get rendered changes.
+    pub rendered: RefCell<BTreeMap<(CallstackId, bool), String>>,
+}
+
+impl<M: IdMap> Default for CallstackInterner<M> {
+    fn default() -> Self {
+        CallstackInterner {
+            max_id: 0,
+            callstack_to_id: M::default(),
+            rendered: RefCell::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl<M: IdMap> CallstackInterner<M>
+where
+    M::Key: Clone,
+{
+    pub fn new() -> Self {
+        CallstackInterner::default()
+    }
+
+    /// Add a (possibly) new callstack, returning its id. call_on_new is only
+    /// called if the callstack wasn't interned yet.
+    pub fn get_or_insert_id<F: FnOnce()>(
+        &mut self,
+        callstack: &M::Key,
+        call_on_new: F,
+    ) -> CallstackId {
+        if let Some(result) = self.callstack_to_id.get_id(callstack) {
+            result
+        } else {
+            let new_id = self.max_id;
+            self.max_id += 1;
+            self.callstack_to_id.insert_id(callstack.clone(), new_id);
+            call_on_new();
+            new_id
+        }
+    }
+
+    /// The rendered string for the callstack with the given id, rendering
+    /// it with render() only the first time.
+    pub fn cached_rendering<G: FnOnce() -> String>(
+        &self,
+        callstack_id: CallstackId,
+        to_be_post_processed: bool,
+        render: G,
+    ) -> String {
+        self.rendered
+            .borrow_mut()
+            .entry((callstack_id, to_be_post_processed))
+            .or_insert_with(render)
+            .clone()
+    }
+
+    /// Forget rendered callstacks, e.g. because they'd now render differently.
+    pub fn clear_rendered(&mut self) {
+        self.rendered.get_mut().clear();
+    }
+
+    /// Forget callstacks whose new id is None, and give the rest their new
+    /// ids. New ids must be 0..n for the n callstacks that are kept.
+    pub fn remap_ids(&mut self, new_ids: &[Option<CallstackId>]) {
+        let callstack_to_id = core::mem::take(&mut self.callstack_to_id);
+        self.callstack_to_id = callstack_to_id
+            .into_iter()
+            .filter_map(|(callstack, id)| new_ids[id as usize].map(|new_id| (callstack, new_id)))
+            .collect();
+        self.max_id = new_ids.iter().filter(|id| id.is_some()).count() as CallstackId;
+        self.clear_rendered();
+    }
+}
+
+/// Per-callstack memory usage, indexed by CallstackId. Without std that's a
+/// Vec; a persistent vector makes snapshotting the peak much cheaper.
+pub trait UsageVector: Clone + Index<usize, Output = usize> + IndexMut<usize> {
+    /// Usage of n callstacks, all 0.
+    fn zeroed(n: usize) -> Self;
+    /// Add a callstack with no usage.
+    fn push_callstack(&mut self);
+}
+
+impl UsageVector for Vec<usize> {
+    fn zeroed(n: usize) -> Self {
+        alloc::vec![0; n]
+    }
+
+    fn push_callstack(&mut self) {
+        self.push(0);
+    }
+}
+
+/// Memory usage, in total and per callstack, now and at the peak.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryUsage<V> {
+    pub current: V,
+    pub peak: V,
+    pub current_bytes: usize,
+    pub peak_bytes: usize,
+}
+
+impl<V: UsageVector> MemoryUsage<V> {
+    /// No usage, by the given number of callstacks.
+    pub fn new(callstacks: usize) -> Self {
+        let current = V::zeroed(callstacks);
+        MemoryUsage {
+            peak: current.clone(),
+            current,
+            current_bytes: 0,
+            peak_bytes: 0,
+        }
+    }
+
+    pub fn add(&mut self, callstack_id: CallstackId, bytes: usize) {
+        self.current_bytes += bytes;
+        self.current[callstack_id as usize] += bytes;
+    }
+
+    pub fn remove(&mut self, callstack_id: CallstackId, bytes: usize) {
+        self.current_bytes -= bytes;
+        // TODO what if goes below zero? add a check I guess, in case of bugs.
+        self.current[callstack_id as usize] -= bytes;
+    }
+
+    /// Record the current usage as the peak if it's a new peak, returning
+    /// whether it was.
+    pub fn check_if_new_peak(&mut self) -> bool {
+        if self.current_bytes > self.peak_bytes {
+            self.peak_bytes = self.current_bytes;
+            self.peak.clone_from(&self.current);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forget the peak so far, so the next peak is measured from current
+    /// usage.
+    pub fn reset_peak(&mut self) {
+        self.peak_bytes = self.current_bytes;
+        self.peak.clone_from(&self.current);
+    }
+}
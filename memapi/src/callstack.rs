@@ -1,6 +1,12 @@
-/*
+//! Per-function-call peak memory: how much memory each call, including the
+//! calls it made, added at its peak on top of what was in use when it started.
+//! This only looks at the total tracked memory as it changes, so it's
+//! independent of which callstack allocations are attributed to.
+
 use std::cell::RefCell;
 use std::cmp;
+use std::collections::HashMap;
+use std::sync::Mutex;
 
 /// A function call in Python (or other languages wrapping this library).
 /// Memory usage is in bytes.
@@ -21,12 +27,8 @@ impl Call {
     }
 
     fn allocated_memory(&self) -> usize {
-        if self.starting_memory > self.peak_memory {
-            // Technically should never happen, but just in case:
-            0
-        } else {
-            self.peak_memory - self.starting_memory
-        }
+        // The peak never drops below the start, but just in case:
+        self.peak_memory.saturating_sub(self.starting_memory)
     }
 
     fn update_memory_usage(&mut self, currently_used_memory: usize) {
@@ -36,106 +38,58 @@ impl Call {
     }
 }
 
-#[test]
-fn call_no_allocated_memory() {
-    let mut call = Call::new("mycall".to_string(), 123);
-    assert_eq!(
-        call,
-        Call {
-            name: "mycall".to_string(),
-            starting_memory: 123,
-            peak_memory: 123
-        }
-    );
-    assert_eq!(call.allocated_memory(), 0);
-}
-
-#[test]
-fn call_updates_peak_if_higher_than_previous_peak() {
-    let mut call = Call::new("mycall".to_string(), 123);
-    call.update_memory_usage(200);
-    assert_eq!(
-        call,
-        Call {
-            name: "mycall".to_string(),
-            starting_memory: 123,
-            peak_memory: 200
-        }
-    );
-    call.update_memory_usage(200);
-    assert_eq!(
-        call,
-        Call {
-            name: "mycall".to_string(),
-            starting_memory: 123,
-            peak_memory: 200
-        }
-    );
-    call.update_memory_usage(201);
-    assert_eq!(
-        call,
-        Call {
-            name: "mycall".to_string(),
-            starting_memory: 123,
-            peak_memory: 201
-        }
-    );
-}
-
-#[test]
-fn call_allocated_memory() {
-    let mut call = Call::new("mycall".to_string(), 123);
-    call.update_memory_usage(137);
-    assert_eq!(call.allocated_memory(), 14);
-    call.update_memory_usage(139);
-    assert_eq!(call.allocated_memory(), 16);
-}
-
 /// A finished call.
 struct FinishedCall {
-    // TODO: switch to immutable datastructure to reduce copying?
-    callstack: Vec<String>,
+    name: String,
     allocated_by_call: usize,
 }
 
 /// Record finished calls.
 trait RecordFinishedCall {
-    fn record(&mut self, finished_call: FinishedCall) {}
+    fn record(&mut self, finished_call: FinishedCall);
 }
 
+#[cfg(test)]
 struct RecordToMemory {
-    finished_calls: Vec<FinishedCall>,
+    finished_calls: std::rc::Rc<RefCell<Vec<FinishedCall>>>,
 }
 
+#[cfg(test)]
 impl RecordToMemory {
     fn new() -> RecordToMemory {
         RecordToMemory {
-            finished_calls: Vec::new(),
+            finished_calls: std::rc::Rc::new(RefCell::new(Vec::new())),
         }
     }
 }
 
+#[cfg(test)]
 impl RecordFinishedCall for RecordToMemory {
     fn record(&mut self, finished_call: FinishedCall) {
-        self.finished_calls.push(finished_call);
+        self.finished_calls.borrow_mut().push(finished_call);
     }
 }
 
-struct RecordToFile {
-    // TODO
+lazy_static! {
+    // The largest allocated_memory() of any call to each function so far,
+    // across all threads:
+    static ref CALL_PEAKS: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::default());
 }
 
-impl RecordFinishedCall for RecordToFile {
+/// Records finished calls into CALL_PEAKS.
+struct RecordToCallPeaks {}
+
+impl RecordFinishedCall for RecordToCallPeaks {
     fn record(&mut self, finished_call: FinishedCall) {
-        if finished_call.allocated_by_call > 0 {
-            println!(
-                "{} {}",
-finished_call.callstack.join(";"),
-                finished_call.allocated_by_call
-            );
+        if finished_call.allocated_by_call == 0 {
+            return;
         }
+        let mut call_peaks = CALL_PEAKS.lock().unwrap();
+        let peak = call_peaks.entry(finished_call.name).or_insert(0);
+        *peak = cmp::max(*peak, finished_call.allocated_by_call);
     }
 }
+
 /// A callstack.
 struct Callstack {
     calls: Vec<Call>,
@@ -151,87 +105,54 @@ impl Callstack {
     }
 
     fn start_call(&mut self, name: String, currently_used_memory: usize) {
-        // TODO maybe update_memory_usage() with new value?
-        let num_calls = self.calls.len();
-        let baseline_memory = if num_calls > 0 {
-            cmp::max(currently_used_memory, self.calls[num_calls - 1].peak_memory)
-        } else {
-            currently_used_memory
+        // A call can't start below its parent's peak, or the parent's own
+        // allocations would count towards the child:
+        let baseline_memory = match self.calls.last() {
+            Some(parent) => cmp::max(currently_used_memory, parent.peak_memory),
+            None => currently_used_memory,
         };
         self.calls.push(Call::new(name, baseline_memory));
     }
 
+    #[cfg(test)]
     fn current_calls(&self) -> Vec<String> {
         self.calls.iter().map(|c| c.name.clone()).collect()
     }
 
-    // For testing.
+    #[cfg(test)]
     fn current_allocated(&self) -> Vec<usize> {
         self.calls.iter().map(|c| c.allocated_memory()).collect()
     }
 
     fn finish_call(&mut self) {
-        let callstack: Vec<String> = self.current_calls();
-        let call = self.calls.pop();
-        match call {
-            None => {
-                panic!("I was asked to finish a call, but the callstack is empty!");
-            }
-            Some(call) => {
-                let allocated_by_call = call.allocated_memory();
-                let finished_call = FinishedCall {
-                    callstack,
-                    allocated_by_call,
-                };
-                self.recorder.record(finished_call);
+        // The stack may be empty if tracking started mid-call:
+        if let Some(call) = self.calls.pop() {
+            let allocated_by_call = call.allocated_memory();
+            // Whatever the call used at its peak, its caller was using too:
+            if let Some(parent) = self.calls.last_mut() {
+                parent.update_memory_usage(call.peak_memory);
             }
+            self.recorder.record(FinishedCall {
+                name: call.name,
+                allocated_by_call,
+            });
         }
     }
 
+    /// A new peak for a call is a new peak for all its parents too, so the
+    /// whole stack gets updated.
     fn update_memory_usage(&mut self, currently_used_memory: usize) {
         for call in self.calls.iter_mut() {
-            &call.update_memory_usage(currently_used_memory);
+            call.update_memory_usage(currently_used_memory);
         }
     }
 }
 
-#[test]
-fn callstack_update_memory_usage_updates_full_stack() {
-    let mut callstack = Callstack::new(Box::new(RecordToMemory::new()));
-    callstack.start_call("a".to_string(), 2);
-    callstack.start_call("b".to_string(), 2);
-    callstack.update_memory_usage(10);
-    assert_eq!(callstack.current_calls(), ["a", "b"]);
-    assert_eq!(callstack.current_allocated(), [8, 8]);
-    // Memory baseline that is less than current peak of 10:
-    callstack.start_call("c".to_string(), 10);
-    assert_eq!(callstack.current_calls(), ["a", "b", "c"]);
-    assert_eq!(callstack.current_allocated(), [8, 8, 0]);
-    callstack.start_call("d".to_string(), 10);
-    callstack.update_memory_usage(15);
-    assert_eq!(callstack.current_calls(), ["a", "b", "c", "d"]);
-    assert_eq!(callstack.current_allocated(), [13, 13, 5, 5]);
-}
-
-#[test]
-fn callstack_start_call_starting_memory_at_least_previous_peak() {
-    let mut callstack = Callstack::new(Box::new(RecordToMemory::new()));
-    callstack.start_call("a".to_string(), 2);
-    callstack.start_call("b".to_string(), 1);
-    assert_eq!(callstack.calls[1].starting_memory, 2);
-    callstack.start_call("c".to_string(), 3);
-    assert_eq!(callstack.calls[1].starting_memory, 2);
-    assert_eq!(callstack.calls[2].starting_memory, 3);
-}
-
-// fn callstack_finish_call...
-
-thread_local!(static CALLSTACK: RefCell<Callstack> = RefCell::new(Callstack::new(Box::new(RecordToFile{}))));
+thread_local!(static CALLSTACK: RefCell<Callstack> = RefCell::new(Callstack::new(Box::new(RecordToCallPeaks {}))));
 
 /// Add to per-thread function stack:
 pub fn start_call(name: String, currently_used_memory: usize) {
     CALLSTACK.with(|cs| {
-        //println!("start call {} {}", name, currently_used_memory / 1000000);
         cs.borrow_mut().start_call(name, currently_used_memory);
     });
 }
@@ -240,7 +161,6 @@ pub fn start_call(name: String, currently_used_memory: usize) {
 /// stack.
 pub fn finish_call() {
     CALLSTACK.with(|cs| {
-        //println!("finish call");
         cs.borrow_mut().finish_call();
     });
 }
@@ -251,6 +171,144 @@ pub fn update_memory_usage(currently_used_memory: usize) {
         cs.borrow_mut().update_memory_usage(currently_used_memory);
     });
 }
-/// Create flamegraph SVG from function stack:
-pub fn dump_functions_to_flamegraph_svg(path: String) {}
-*/
+
+/// The most memory any single call to each function added, including its
+/// callees, as "function bytes" lines sorted by function.
+pub fn call_peak_lines() -> Vec<String> {
+    let call_peaks = CALL_PEAKS.lock().unwrap();
+    let mut lines: Vec<String> = call_peaks
+        .iter()
+        .map(|(name, bytes)| format!("{} {}", name, bytes))
+        .collect();
+    lines.sort_unstable();
+    lines
+}
+
+/// Forget the call peaks recorded so far.
+pub fn reset_call_peaks() {
+    CALL_PEAKS.lock().unwrap().clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Call, Callstack, FinishedCall, RecordToMemory};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn recording_callstack() -> (Callstack, Rc<RefCell<Vec<FinishedCall>>>) {
+        let finished_calls = Rc::new(RefCell::new(Vec::new()));
+        let callstack = Callstack::new(Box::new(RecordToMemory {
+            finished_calls: finished_calls.clone(),
+        }));
+        (callstack, finished_calls)
+    }
+
+    #[test]
+    fn call_no_allocated_memory() {
+        let call = Call::new("mycall".to_string(), 123);
+        assert_eq!(
+            call,
+            Call {
+                name: "mycall".to_string(),
+                starting_memory: 123,
+                peak_memory: 123
+            }
+        );
+        assert_eq!(call.allocated_memory(), 0);
+    }
+
+    #[test]
+    fn call_updates_peak_if_higher_than_previous_peak() {
+        let mut call = Call::new("mycall".to_string(), 123);
+        call.update_memory_usage(200);
+        assert_eq!(
+            call,
+            Call {
+                name: "mycall".to_string(),
+                starting_memory: 123,
+                peak_memory: 200
+            }
+        );
+        call.update_memory_usage(200);
+        assert_eq!(
+            call,
+            Call {
+                name: "mycall".to_string(),
+                starting_memory: 123,
+                peak_memory: 200
+            }
+        );
+        call.update_memory_usage(201);
+        assert_eq!(
+            call,
+            Call {
+                name: "mycall".to_string(),
+                starting_memory: 123,
+                peak_memory: 201
+            }
+        );
+    }
+
+    #[test]
+    fn call_allocated_memory() {
+        let mut call = Call::new("mycall".to_string(), 123);
+        call.update_memory_usage(137);
+        assert_eq!(call.allocated_memory(), 14);
+        call.update_memory_usage(139);
+        assert_eq!(call.allocated_memory(), 16);
+    }
+
+    #[test]
+    fn callstack_update_memory_usage_updates_full_stack() {
+        let mut callstack = Callstack::new(Box::new(RecordToMemory::new()));
+        callstack.start_call("a".to_string(), 2);
+        callstack.start_call("b".to_string(), 2);
+        callstack.update_memory_usage(10);
+        assert_eq!(callstack.current_calls(), ["a", "b"]);
+        assert_eq!(callstack.current_allocated(), [8, 8]);
+        // Memory baseline that is less than current peak of 10:
+        callstack.start_call("c".to_string(), 10);
+        assert_eq!(callstack.current_calls(), ["a", "b", "c"]);
+        assert_eq!(callstack.current_allocated(), [8, 8, 0]);
+        callstack.start_call("d".to_string(), 10);
+        callstack.update_memory_usage(15);
+        assert_eq!(callstack.current_calls(), ["a", "b", "c", "d"]);
+        assert_eq!(callstack.current_allocated(), [13, 13, 5, 5]);
+    }
+
+    #[test]
+    fn callstack_start_call_starting_memory_at_least_previous_peak() {
+        let mut callstack = Callstack::new(Box::new(RecordToMemory::new()));
+        callstack.start_call("a".to_string(), 2);
+        callstack.start_call("b".to_string(), 1);
+        assert_eq!(callstack.calls[1].starting_memory, 2);
+        callstack.start_call("c".to_string(), 3);
+        assert_eq!(callstack.calls[1].starting_memory, 2);
+        assert_eq!(callstack.calls[2].starting_memory, 3);
+    }
+
+    #[test]
+    fn parent_allocated_memory_includes_child() {
+        let (mut callstack, finished_calls) = recording_callstack();
+        callstack.start_call("parent".to_string(), 100);
+        callstack.update_memory_usage(150);
+        callstack.start_call("child".to_string(), 150);
+        callstack.update_memory_usage(400);
+        // The child frees some memory before returning:
+        callstack.update_memory_usage(200);
+        callstack.finish_call();
+        callstack.update_memory_usage(250);
+        callstack.finish_call();
+        // Finishing with an empty stack is ignored:
+        callstack.finish_call();
+        let finished: Vec<(String, usize)> = finished_calls
+            .borrow()
+            .iter()
+            .map(|call| (call.name.clone(), call.allocated_by_call))
+            .collect();
+        assert_eq!(
+            finished,
+            [("child".to_string(), 250), ("parent".to_string(), 300)]
+        );
+    }
+}
@@ -0,0 +1,316 @@
+//! Utilities for working with collapsed-stack ("folded") profiles, i.e. the
+//! `.prof` files we write out: one line per callstack, with the callstack and
+//! its size in bytes separated by a space.
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Parse a collapsed-stack profile into a mapping from callstack to bytes.
+/// Repeated callstacks are summed, and malformed lines are skipped.
+pub fn parse_collapsed(text: &str) -> HashMap<&str, usize> {
+    let mut result = HashMap::default();
+    for line in text.lines() {
+        if let Some((callstack, size)) = line.trim_end().rsplit_once(' ') {
+            if let Ok(size) = size.parse::<usize>() {
+                *result.entry(callstack).or_insert(0) += size;
+            }
+        }
+    }
+    result
+}
+
+/// Sum several collapsed-stack profiles, adding up the bytes of identical
+/// callstacks. Returns the merged lines, sorted, and the combined total.
+pub fn merge_collapsed<S: AsRef<str>>(texts: &[S]) -> (Vec<String>, usize) {
+    let mut sizes: HashMap<&str, usize> = HashMap::default();
+    for text in texts {
+        for (callstack, size) in parse_collapsed(text.as_ref()) {
+            *sizes.entry(callstack).or_insert(0) += size;
+        }
+    }
+    let total = sizes.values().sum();
+    let mut lines: Vec<String> = sizes
+        .into_iter()
+        .map(|(callstack, size)| format!("{} {}", callstack, size))
+        .collect();
+    lines.sort();
+    (lines, total)
+}
+
+/// Merge collapsed-stack profile files into a single output file. Returns the
+/// combined total bytes.
+pub fn merge_collapsed_files(inputs: &[PathBuf], output: &Path) -> std::io::Result<usize> {
+    let texts = inputs
+        .iter()
+        .map(fs::read_to_string)
+        .collect::<std::io::Result<Vec<String>>>()?;
+    let (lines, total) = merge_collapsed(&texts);
+    let mut file = std::io::BufWriter::new(fs::File::create(output)?);
+    for line in lines {
+        writeln!(file, "{}", line)?;
+    }
+    file.flush()?;
+    Ok(total)
+}
+
+/// Summary of the differences between two profiles.
+#[derive(Debug, PartialEq)]
+pub struct DiffSummary {
+    /// Callstacks whose memory usage grew the most, largest growth first.
+    pub grew: Vec<(String, i64)>,
+    /// Callstacks whose memory usage shrank the most, largest shrinkage first.
+    pub shrank: Vec<(String, i64)>,
+    /// Change in total memory usage.
+    pub net: i64,
+}
+
+/// Compare two collapsed-stack profiles, keeping the top `limit` callstacks
+/// that grew and shrank.
+pub fn diff_collapsed(before: &str, after: &str, limit: usize) -> DiffSummary {
+    let before = parse_collapsed(before);
+    let after = parse_collapsed(after);
+    let mut deltas: HashMap<&str, i64> = HashMap::default();
+    for (callstack, size) in after.iter() {
+        *deltas.entry(callstack).or_insert(0) += *size as i64;
+    }
+    for (callstack, size) in before.iter() {
+        *deltas.entry(callstack).or_insert(0) -= *size as i64;
+    }
+    let net = deltas.values().sum();
+    // Sort by callstack too, so ties come out in a consistent order:
+    let mut grew: Vec<(String, i64)> = deltas
+        .iter()
+        .filter(|(_, delta)| **delta > 0)
+        .map(|(callstack, delta)| (callstack.to_string(), *delta))
+        .collect();
+    grew.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    grew.truncate(limit);
+    let mut shrank: Vec<(String, i64)> = deltas
+        .iter()
+        .filter(|(_, delta)| **delta < 0)
+        .map(|(callstack, delta)| (callstack.to_string(), *delta))
+        .collect();
+    shrank.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+    shrank.truncate(limit);
+    DiffSummary { grew, shrank, net }
+}
+
+/// Compare two collapsed-stack profile files, and write a textual summary of
+/// the differences to the output path.
+pub fn write_diff_summary(
+    before_path: &str,
+    after_path: &str,
+    output_path: &str,
+    limit: usize,
+) -> std::io::Result<()> {
+    let before = fs::read_to_string(before_path)?;
+    let after = fs::read_to_string(after_path)?;
+    let summary = diff_collapsed(&before, &after, limit);
+    let mut file = fs::File::create(output_path)?;
+    writeln!(file, "Net change: {:+} bytes", summary.net)?;
+    writeln!(file, "\nGrew:")?;
+    for (callstack, delta) in summary.grew.iter() {
+        writeln!(file, "{:+} {}", delta, callstack)?;
+    }
+    writeln!(file, "\nShrank:")?;
+    for (callstack, delta) in summary.shrank.iter() {
+        writeln!(file, "{:+} {}", delta, callstack)?;
+    }
+    file.flush()
+}
+
+/// Identifies the compact binary version of collapsed-stack data.
+const BINARY_MAGIC: &[u8; 8] = b"FILPROF1";
+
+/// Write collapsed-stack data in a compact binary format: instead of repeating
+/// each callstack's text for every row, there's a table mapping ids to
+/// callstacks, followed by (id, size) rows. All integers are little-endian.
+pub fn write_binary<W: Write>(
+    out: &mut W,
+    callstacks: &HashMap<u32, String>,
+    rows: &[(u32, usize)],
+) -> std::io::Result<()> {
+    out.write_all(BINARY_MAGIC)?;
+    out.write_all(&(callstacks.len() as u32).to_le_bytes())?;
+    for (id, callstack) in callstacks.iter() {
+        out.write_all(&id.to_le_bytes())?;
+        out.write_all(&(callstack.len() as u32).to_le_bytes())?;
+        out.write_all(callstack.as_bytes())?;
+    }
+    out.write_all(&(rows.len() as u64).to_le_bytes())?;
+    for (id, size) in rows {
+        out.write_all(&id.to_le_bytes())?;
+        out.write_all(&(*size as u64).to_le_bytes())?;
+    }
+    out.flush()
+}
+
+fn read_u32<R: Read>(input: &mut R) -> std::io::Result<u32> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> std::io::Result<u64> {
+    let mut buf = [0; 8];
+    input.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Read `length` bytes. The length comes from the file, so a truncated or
+/// corrupt file could claim far more than it has; the buffer only grows as
+/// data actually arrives, and running out is an UnexpectedEof error.
+pub fn read_bytes<R: Read>(input: &mut R, length: usize) -> std::io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    input.take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < length {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "data ended early",
+        ));
+    }
+    Ok(bytes)
+}
+
+/// Read data written by write_binary(), reconstructing collapsed-stack lines.
+/// Rows with the same callstack are merged, and lines are sorted.
+pub fn read_binary<R: Read>(input: &mut R) -> std::io::Result<Vec<String>> {
+    let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != BINARY_MAGIC {
+        return Err(invalid("not a binary collapsed-stack file"));
+    }
+    let mut callstacks: HashMap<u32, String> = HashMap::default();
+    for _ in 0..read_u32(input)? {
+        let id = read_u32(input)?;
+        let length = read_u32(input)? as usize;
+        let callstack = read_bytes(input, length)?;
+        let callstack =
+            String::from_utf8(callstack).map_err(|_| invalid("callstack wasn't UTF-8"))?;
+        callstacks.insert(id, callstack);
+    }
+    let mut sizes: HashMap<&str, usize> = HashMap::default();
+    for _ in 0..read_u64(input)? {
+        let id = read_u32(input)?;
+        let size = read_u64(input)? as usize;
+        let callstack = callstacks
+            .get(&id)
+            .ok_or_else(|| invalid("unknown callstack id"))?;
+        *sizes.entry(callstack).or_insert(0) += size;
+    }
+    let mut lines: Vec<String> = sizes
+        .into_iter()
+        .map(|(callstack, size)| format!("{} {}", callstack, size))
+        .collect();
+    lines.sort();
+    Ok(lines)
+}
+
+/// Convert a binary collapsed-stack file into the text format, e.g. for
+/// rendering or merging.
+pub fn binary_to_collapsed(binary_path: &str, collapsed_path: &str) -> std::io::Result<()> {
+    let mut input = std::io::BufReader::new(fs::File::open(binary_path)?);
+    let lines = read_binary(&mut input)?;
+    let mut file = std::io::BufWriter::new(fs::File::create(collapsed_path)?);
+    for line in lines {
+        writeln!(file, "{}", line)?;
+    }
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        diff_collapsed, merge_collapsed_files, parse_collapsed, read_binary, write_binary,
+        write_diff_summary, BINARY_MAGIC,
+    };
+    use std::collections::HashMap;
+
+    #[test]
+    fn parse_sums_and_skips_malformed() {
+        let parsed = parse_collapsed("a;b 10\na;b 5\nc:1 (f) 7\ngarbage\nd x\n");
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed["a;b"], 15);
+        assert_eq!(parsed["c:1 (f)"], 7);
+    }
+
+    #[test]
+    fn merge_sums_shared_callstacks() {
+        let directory = tempfile::tempdir().unwrap();
+        let first = directory.path().join("first.prof");
+        let second = directory.path().join("second.prof");
+        let output = directory.path().join("merged.prof");
+        std::fs::write(&first, "a;b 100\nc 5\n").unwrap();
+        std::fs::write(&second, "a;b 50\nd 20\n").unwrap();
+        let total = merge_collapsed_files(&[first, second], &output).unwrap();
+        assert_eq!(total, 175);
+        assert_eq!(
+            std::fs::read_to_string(output).unwrap(),
+            "a;b 150\nc 5\nd 20\n"
+        );
+    }
+
+    #[test]
+    fn diff_ranks_growth_and_shrinkage() {
+        let before = "a 100\nb 200\nc 300\nd 50\n";
+        let after = "a 1100\nb 250\nc 0\ne 30\n";
+        let summary = diff_collapsed(before, after, 2);
+        assert_eq!(
+            summary.grew,
+            vec![("a".to_string(), 1000), ("b".to_string(), 50)]
+        );
+        assert_eq!(
+            summary.shrank,
+            vec![("c".to_string(), -300), ("d".to_string(), -50)]
+        );
+        assert_eq!(summary.net, 1000 + 50 - 300 - 50 + 30);
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let mut callstacks = HashMap::new();
+        callstacks.insert(0, "a:1 (f);b:2 (g)".to_string());
+        callstacks.insert(7, "[No Python stack]".to_string());
+        let rows = vec![(0, 100), (7, 5), (0, 20)];
+        let mut binary = vec![];
+        write_binary(&mut binary, &callstacks, &rows).unwrap();
+        let mut lines = read_binary(&mut &binary[..]).unwrap();
+        lines.sort();
+        assert_eq!(lines, vec!["[No Python stack] 5", "a:1 (f);b:2 (g) 120"]);
+
+        // Truncated or bogus data is an error:
+        assert!(read_binary(&mut &binary[..binary.len() - 1]).is_err());
+        assert!(read_binary(&mut &b"NOTAPROFILE"[..]).is_err());
+        // Including a callstack length far beyond the end of the data:
+        let mut bogus = BINARY_MAGIC.to_vec();
+        for value in &[1, 0, u32::MAX] {
+            bogus.extend_from_slice(&u32::to_le_bytes(*value));
+        }
+        bogus.extend_from_slice(b"a:1 (f)");
+        let error = read_binary(&mut &bogus[..]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn diff_summary_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let before = directory.path().join("before.prof");
+        let after = directory.path().join("after.prof");
+        let output = directory.path().join("diff.txt");
+        std::fs::write(&before, "a 100\nb 10\n").unwrap();
+        std::fs::write(&after, "a 150\n").unwrap();
+        write_diff_summary(
+            before.to_str().unwrap(),
+            after.to_str().unwrap(),
+            output.to_str().unwrap(),
+            10,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(output).unwrap(),
+            "Net change: +40 bytes\n\nGrew:\n+50 a\n\nShrank:\n-10 b\n"
+        );
+    }
+}
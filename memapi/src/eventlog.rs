@@ -0,0 +1,285 @@
+//! A log of every allocation event, written as it happens, so the history
+//! survives crashes and the out-of-memory exit. The log can be replayed
+//! offline to reconstruct current and peak memory usage.
+//!
+//! The format is a magic header followed by records, each starting with a
+//! one byte tag. A callstack record, written the first time an id is used,
+//! maps the id to its rendered callstack; event records give the operation,
+//! address, size and callstack id. All integers are little-endian. The same
+//! id may be defined again later, e.g. after ids were compacted, in which case
+//! the new callstack applies from then on.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::collapsed::read_bytes;
+
+/// Identifies an event log file.
+const EVENT_LOG_MAGIC: &[u8; 8] = b"FILEVLG1";
+
+/// The tag of callstack records; event records are tagged with their Op.
+const CALLSTACK_TAG: u8 = 0xff;
+
+/// A logged operation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Allocate = 0,
+    Free = 1,
+    Mmap = 2,
+    Munmap = 3,
+}
+
+impl Op {
+    fn from_tag(tag: u8) -> Option<Op> {
+        match tag {
+            0 => Some(Op::Allocate),
+            1 => Some(Op::Free),
+            2 => Some(Op::Mmap),
+            3 => Some(Op::Munmap),
+            _ => None,
+        }
+    }
+
+    fn adds_memory(self) -> bool {
+        self == Op::Allocate || self == Op::Mmap
+    }
+}
+
+/// Appends events to the log file. Each record is a single unbuffered write,
+/// so everything logged so far is in the file even if the process dies
+/// without warning; the price is a system call per event.
+pub struct EventLog {
+    file: fs::File,
+    // Which callstack ids the log has a current definition for:
+    defined: Vec<bool>,
+}
+
+impl EventLog {
+    pub fn create(path: &Path) -> std::io::Result<EventLog> {
+        let mut file = fs::File::create(path)?;
+        file.write_all(EVENT_LOG_MAGIC)?;
+        Ok(EventLog {
+            file,
+            defined: Vec::new(),
+        })
+    }
+
+    /// Whether the callstack with this id needs to be written before it's
+    /// used by an event.
+    pub fn needs_callstack(&self, callstack_id: u32) -> bool {
+        !self
+            .defined
+            .get(callstack_id as usize)
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// Forget which callstacks were written out, because their ids now refer
+    /// to different callstacks.
+    pub fn forget_callstacks(&mut self) {
+        self.defined.clear();
+    }
+
+    pub fn write_callstack(&mut self, callstack_id: u32, callstack: &str) -> std::io::Result<()> {
+        let mut record = Vec::with_capacity(9 + callstack.len());
+        record.push(CALLSTACK_TAG);
+        record.extend_from_slice(&callstack_id.to_le_bytes());
+        record.extend_from_slice(&(callstack.len() as u32).to_le_bytes());
+        record.extend_from_slice(callstack.as_bytes());
+        self.file.write_all(&record)?;
+        let index = callstack_id as usize;
+        if index >= self.defined.len() {
+            self.defined.resize(index + 1, false);
+        }
+        self.defined[index] = true;
+        Ok(())
+    }
+
+    pub fn write_event(
+        &mut self,
+        op: Op,
+        address: usize,
+        size: usize,
+        callstack_id: u32,
+    ) -> std::io::Result<()> {
+        let mut record = [0u8; 21];
+        record[0] = op as u8;
+        record[1..9].copy_from_slice(&(address as u64).to_le_bytes());
+        record[9..17].copy_from_slice(&(size as u64).to_le_bytes());
+        record[17..21].copy_from_slice(&callstack_id.to_le_bytes());
+        self.file.write_all(&record)
+    }
+}
+
+/// Memory usage reconstructed from an event log.
+#[derive(Debug, Default, PartialEq)]
+pub struct Replayed {
+    pub current_bytes: usize,
+    pub peak_bytes: usize,
+    /// Current memory usage by rendered callstack, leaving out those using
+    /// nothing.
+    pub current: HashMap<String, usize>,
+    /// Memory usage by rendered callstack at the peak.
+    pub peak: HashMap<String, usize>,
+}
+
+fn read_array<R: Read, const N: usize>(input: &mut R) -> std::io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    input.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Replay events from an event log. An event record cut short at the end, as
+/// happens if the process died mid-write, is ignored; a callstack record whose
+/// length runs past the end of the log is an error.
+pub fn replay<R: Read>(input: &mut R) -> std::io::Result<Replayed> {
+    let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    if &read_array::<_, 8>(input)? != EVENT_LOG_MAGIC {
+        return Err(invalid("not an event log"));
+    }
+    let mut callstacks: HashMap<u32, String> = HashMap::new();
+    // im makes snapshotting the peak cheap:
+    let mut current: im::HashMap<String, usize> = im::HashMap::new();
+    let mut peak = current.clone();
+    let mut current_bytes: usize = 0;
+    let mut peak_bytes: usize = 0;
+    loop {
+        let mut tag = [0u8; 1];
+        if input.read(&mut tag)? == 0 {
+            break;
+        }
+        let record = if tag[0] == CALLSTACK_TAG {
+            read_callstack(input).map(|(id, callstack)| {
+                callstacks.insert(id, callstack);
+            })
+        } else {
+            let op = Op::from_tag(tag[0]).ok_or_else(|| invalid("unknown record"))?;
+            read_array::<_, 20>(input).and_then(|event| {
+                let size = u64::from_le_bytes(event[8..16].try_into().unwrap()) as usize;
+                let id = u32::from_le_bytes(event[16..20].try_into().unwrap());
+                let callstack = callstacks
+                    .get(&id)
+                    .ok_or_else(|| invalid("unknown callstack id"))?;
+                let usage = current.entry(callstack.clone()).or_insert(0);
+                if op.adds_memory() {
+                    *usage += size;
+                    current_bytes += size;
+                } else {
+                    *usage = usage.saturating_sub(size);
+                    current_bytes = current_bytes.saturating_sub(size);
+                }
+                if current_bytes > peak_bytes {
+                    peak_bytes = current_bytes;
+                    peak = current.clone();
+                }
+                Ok(())
+            })
+        };
+        match record {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+    let nonzero = |usage: im::HashMap<String, usize>| {
+        usage
+            .into_iter()
+            .filter(|(_, size)| *size > 0)
+            .collect::<HashMap<String, usize>>()
+    };
+    Ok(Replayed {
+        current_bytes,
+        peak_bytes,
+        current: nonzero(current),
+        peak: nonzero(peak),
+    })
+}
+
+fn read_callstack<R: Read>(input: &mut R) -> std::io::Result<(u32, String)> {
+    let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    let id = u32::from_le_bytes(read_array(input)?);
+    let length = u32::from_le_bytes(read_array(input)?) as usize;
+    // A length running past the end of the log can't be told apart from a
+    // corrupt one, so unlike a cut short event it's an error:
+    let callstack = read_bytes(input, length).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            invalid("callstack record runs past the end of the log")
+        } else {
+            e
+        }
+    })?;
+    let callstack = String::from_utf8(callstack).map_err(|_| invalid("callstack wasn't UTF-8"))?;
+    Ok((id, callstack))
+}
+
+/// Replay the event log at the given path.
+pub fn replay_file(path: &Path) -> std::io::Result<Replayed> {
+    replay(&mut std::io::BufReader::new(fs::File::open(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{replay_file, EventLog, Op};
+
+    #[test]
+    fn replay_reconstructs_usage() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("events.log");
+        let mut log = EventLog::create(&path).unwrap();
+        assert!(log.needs_callstack(0));
+        log.write_callstack(0, "a:1 (f)").unwrap();
+        assert!(!log.needs_callstack(0));
+        log.write_event(Op::Allocate, 1, 100, 0).unwrap();
+        log.write_callstack(1, "b:2 (g)").unwrap();
+        log.write_event(Op::Mmap, 4096, 4096, 1).unwrap();
+        log.write_event(Op::Free, 1, 100, 0).unwrap();
+        // Id 0 gets reused for a different callstack:
+        log.forget_callstacks();
+        assert!(log.needs_callstack(1));
+        log.write_callstack(0, "c:3 (h)").unwrap();
+        log.write_event(Op::Allocate, 2, 50, 0).unwrap();
+        drop(log);
+        // A record cut short by a crash is ignored:
+        let mut data = std::fs::read(&path).unwrap();
+        data.extend_from_slice(&[Op::Allocate as u8, 1, 2, 3]);
+        std::fs::write(&path, data).unwrap();
+
+        let replayed = replay_file(&path).unwrap();
+        assert_eq!(replayed.current_bytes, 4146);
+        assert_eq!(replayed.peak_bytes, 4196);
+        assert_eq!(replayed.current.len(), 2);
+        assert_eq!(replayed.current["b:2 (g)"], 4096);
+        assert_eq!(replayed.current["c:3 (h)"], 50);
+        assert_eq!(replayed.peak.len(), 2);
+        assert_eq!(replayed.peak["a:1 (f)"], 100);
+        assert_eq!(replayed.peak["b:2 (g)"], 4096);
+    }
+
+    #[test]
+    fn replaying_truncated_callstack_is_an_error() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("events.log");
+        let mut log = EventLog::create(&path).unwrap();
+        log.write_callstack(0, "a:1 (f)").unwrap();
+        log.write_event(Op::Allocate, 1, 100, 0).unwrap();
+        log.write_callstack(1, "b:2 (g)").unwrap();
+        drop(log);
+        let mut data = std::fs::read(&path).unwrap();
+        data.truncate(data.len() - 3);
+        std::fs::write(&path, &data).unwrap();
+        let error = replay_file(&path).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        // Same for a length that claims gigabytes, which isn't allocated:
+        let length_offset = data.len() + 3 - "b:2 (g)".len() - 4;
+        data.truncate(length_offset);
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        data.extend_from_slice(b"b:2");
+        std::fs::write(&path, &data).unwrap();
+        let error = replay_file(&path).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// How memory still allocated after a repeatedly-invoked call has changed over
+/// its invocations.
+#[derive(Clone, Debug, PartialEq)]
+struct Growth {
+    invocations: usize,
+    first_bytes: usize,
+    last_bytes: usize,
+    // Did memory go up after every invocation?
+    monotonic: bool,
+}
+
+/// Find calls that leak: every time they return, more memory is still
+/// allocated by them than the previous time they returned.
+pub struct LeakDetector<K: Hash + Eq> {
+    growth: HashMap<K, Growth>,
+}
+
+impl<K: Hash + Eq> LeakDetector<K> {
+    pub fn new() -> Self {
+        LeakDetector {
+            growth: HashMap::default(),
+        }
+    }
+
+    /// A call identified by `key` just returned, and `bytes` allocated by it
+    /// (in this and previous invocations) are still not freed.
+    pub fn finished_call(&mut self, key: K, bytes: usize) {
+        let growth = self.growth.entry(key).or_insert(Growth {
+            invocations: 0,
+            first_bytes: bytes,
+            last_bytes: bytes,
+            monotonic: true,
+        });
+        if growth.invocations > 0 && bytes <= growth.last_bytes {
+            growth.monotonic = false;
+        }
+        growth.invocations += 1;
+        growth.last_bytes = bytes;
+    }
+
+    /// Calls that were invoked at least `min_invocations` times and grew memory
+    /// after every invocation, with how much memory they grew by, largest
+    /// growth first. At most `limit` are returned.
+    pub fn suspects(&self, min_invocations: usize, limit: usize) -> Vec<(&K, usize)> {
+        let mut result: Vec<(&K, usize)> = self
+            .growth
+            .iter()
+            .filter(|(_, growth)| {
+                growth.monotonic
+                    && growth.invocations >= min_invocations.max(2)
+                    && growth.last_bytes > growth.first_bytes
+            })
+            .map(|(key, growth)| (key, growth.last_bytes - growth.first_bytes))
+            .collect();
+        result.sort_by_key(|(_, bytes)| std::cmp::Reverse(*bytes));
+        result.truncate(limit);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LeakDetector;
+
+    #[test]
+    fn monotonic_growth_is_suspect() {
+        let mut detector = LeakDetector::new();
+        for i in 1..5 {
+            // Leaks 100 bytes per call:
+            detector.finished_call("leaky", i * 100);
+            // Doesn't leak:
+            detector.finished_call("fine", 100);
+        }
+        // Leaks a lot, but only called once:
+        detector.finished_call("once", 1_000_000);
+        detector.finished_call("bigger", 0);
+        detector.finished_call("bigger", 1000);
+        detector.finished_call("bigger", 2000);
+        assert_eq!(
+            detector.suspects(3, 10),
+            vec![(&"bigger", 2000), (&"leaky", 300)]
+        );
+        assert_eq!(detector.suspects(3, 1), vec![(&"bigger", 2000)]);
+        assert_eq!(detector.suspects(4, 10), vec![(&"leaky", 300)]);
+    }
+
+    #[test]
+    fn eventually_freed_is_not_suspect() {
+        let mut detector = LeakDetector::new();
+        for bytes in &[100, 200, 300, 0] {
+            detector.finished_call("cache", *bytes);
+        }
+        assert!(detector.suspects(2, 10).is_empty());
+    }
+}
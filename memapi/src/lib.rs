@@ -1,5 +1,6 @@
-use std::ffi::CStr;
+use std::ffi::{c_void, CStr, CString};
 use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
 
 #[macro_use]
 extern crate lazy_static;
@@ -11,8 +12,23 @@ use jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
+mod accounting;
+mod callstack;
+mod collapsed;
+mod eventlog;
+mod leaks;
 mod memorytracking;
+mod memoryusage;
+mod pprof;
+mod profiler;
+#[cfg(not(feature = "no-mmap-tracking"))]
 mod rangemap;
+mod recentset;
+mod ringbuffer;
+mod spillmap;
+mod timeline;
+
+pub use profiler::Profiler;
 
 #[no_mangle]
 pub extern "C" fn pymemprofile_add_allocation(
@@ -23,9 +39,131 @@ pub extern "C" fn pymemprofile_add_allocation(
     memorytracking::add_allocation(address, size, line_number, false);
 }
 
+/// Add an allocation based off the current callstack, or if there are no
+/// Python calls and native callers are being recorded, off the native code at
+/// the given caller address.
+#[no_mangle]
+pub extern "C" fn pymemprofile_add_allocation_from_caller(
+    address: usize,
+    size: libc::size_t,
+    line_number: u16,
+    caller: usize,
+) {
+    memorytracking::add_allocation_from_caller(address, size, line_number, caller);
+}
+
+/// Add an allocation attributed only to the native code at the given caller
+/// address, for when Python callstacks aren't being tracked.
+#[no_mangle]
+pub extern "C" fn pymemprofile_add_native_allocation(
+    address: usize,
+    size: libc::size_t,
+    caller: usize,
+) {
+    memorytracking::add_native_allocation(address, size, caller, false);
+}
+
+/// Record a realloc(), possibly moving the allocation.
+#[no_mangle]
+pub extern "C" fn pymemprofile_realloc_allocation(
+    old_address: usize,
+    new_address: usize,
+    new_size: libc::size_t,
+    line_number: u16,
+) {
+    memorytracking::realloc_allocation(old_address, new_address, new_size, line_number);
+}
+
+/// Returns the size of the freed allocation, or 0 if it wasn't tracked.
+#[no_mangle]
+pub extern "C" fn pymemprofile_free_allocation(address: usize) -> libc::size_t {
+    memorytracking::free_allocation(address).unwrap_or(0)
+}
+
+/// Stop tracking an allocation without it counting as a free(). Returns its
+/// size, or 0 if it wasn't tracked.
+#[no_mangle]
+pub extern "C" fn pymemprofile_forget_allocation(address: usize) -> libc::size_t {
+    memorytracking::forget_allocation(address).unwrap_or(0)
+}
+
+/// Returns how many free()s were of untracked addresses, for diagnostics.
+#[no_mangle]
+pub extern "C" fn pymemprofile_get_untracked_free_count() -> libc::size_t {
+    memorytracking::get_untracked_free_count()
+}
+
+/// Returns how many free()s were of addresses that were already free()d.
+#[no_mangle]
+pub extern "C" fn pymemprofile_get_double_free_count() -> libc::size_t {
+    memorytracking::get_double_free_count()
+}
+
+/// Returns how many allocations and mmap()ed ranges are being tracked, a
+/// measure of the profiler's own memory overhead.
+#[no_mangle]
+pub extern "C" fn pymemprofile_get_tracked_allocation_count() -> libc::size_t {
+    memorytracking::get_tracked_allocation_count()
+}
+
+/// Returns how many distinct callstacks have been seen and roughly how much
+/// memory they use, for deciding whether e.g. limiting stack depth is worth it.
+#[no_mangle]
+pub extern "C" fn pymemprofile_get_interner_stats() -> memorytracking::InternerStats {
+    memorytracking::get_interner_stats()
+}
+
+/// Returns how many bytes are currently allocated.
+#[no_mangle]
+pub extern "C" fn pymemprofile_get_current_allocated_bytes() -> libc::size_t {
+    memorytracking::get_current_allocated_bytes()
+}
+
+/// Returns how many bytes have been malloc()ed since tracking started,
+/// ignoring free()s. Sampling this over time gives the allocation rate.
+#[no_mangle]
+pub extern "C" fn pymemprofile_get_total_allocated_bytes() -> libc::size_t {
+    memorytracking::get_total_allocated_bytes()
+}
+
+/// Returns the largest number of bytes allocated at any one time.
+#[no_mangle]
+pub extern "C" fn pymemprofile_get_peak_allocated_bytes() -> libc::size_t {
+    memorytracking::get_peak_allocated_bytes()
+}
+
+/// Returns the process's resident memory in bytes as the operating system
+/// sees it, or 0 if that's unknown on this platform.
+#[no_mangle]
+pub extern "C" fn pymemprofile_get_memory_usage() -> libc::size_t {
+    memoryusage::get_memory_usage().unwrap_or(0)
+}
+
+/// Compute resident memory with this page size in bytes rather than the
+/// system's; 0 goes back to the system's.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_page_size(bytes: libc::size_t) {
+    memoryusage::set_page_size(bytes);
+}
+
+/// Returns 1 if peak memory usage so far is more than the given number of
+/// bytes, otherwise 0.
+#[no_mangle]
+pub extern "C" fn pymemprofile_peak_exceeds(max_bytes: libc::size_t) -> libc::c_int {
+    memorytracking::peak_exceeds(max_bytes) as libc::c_int
+}
+
+/// Forget interned callstacks that are no longer used, returning how many were
+/// forgotten.
+#[no_mangle]
+pub extern "C" fn pymemprofile_compact_interner() -> libc::size_t {
+    memorytracking::compact_interner()
+}
+
+/// Forget the peak so far, keeping track of current allocations.
 #[no_mangle]
-pub extern "C" fn pymemprofile_free_allocation(address: usize) {
-    memorytracking::free_allocation(address);
+pub extern "C" fn pymemprofile_reset_peak() {
+    memorytracking::reset_peak();
 }
 
 /// Returns allocation size, or 0 if not stored. Useful for tests, mostly.
@@ -34,16 +172,106 @@ pub extern "C" fn pymemprofile_get_allocation_size(address: usize) -> libc::size
     memorytracking::get_allocation_size(address)
 }
 
+/// Check that malloc() and free() calls from here get tracked, writing any
+/// discrepancies to stderr. Returns 0 if they do, otherwise 1.
+#[no_mangle]
+pub extern "C" fn pymemprofile_run_self_test() -> libc::c_int {
+    if memorytracking::run_self_test() {
+        0
+    } else {
+        1
+    }
+}
+
+/// Write the most recent allocation events to the given file descriptor, for
+/// diagnosing crashes.
+#[cfg(unix)]
+#[no_mangle]
+pub extern "C" fn pymemprofile_dump_recent_events(fd: libc::c_int) {
+    memorytracking::dump_recent_events(fd);
+}
+
 #[no_mangle]
 pub extern "C" fn pymemprofile_add_anon_mmap(address: usize, size: libc::size_t, line_number: u16) {
     memorytracking::add_allocation(address, size, line_number, true);
 }
 
+#[no_mangle]
+pub extern "C" fn pymemprofile_add_huge_page_mmap(
+    address: usize,
+    size: libc::size_t,
+    line_number: u16,
+) {
+    memorytracking::add_huge_page_mmap(address, size, line_number);
+}
+
+#[no_mangle]
+pub extern "C" fn pymemprofile_add_native_huge_page_mmap(
+    address: usize,
+    size: libc::size_t,
+    caller: usize,
+) {
+    memorytracking::add_native_huge_page_mmap(address, size, caller);
+}
+
+#[no_mangle]
+pub extern "C" fn pymemprofile_add_native_anon_mmap(
+    address: usize,
+    size: libc::size_t,
+    caller: usize,
+) {
+    memorytracking::add_native_allocation(address, size, caller, true);
+}
+
+/// Record an mremap() of an anonymous mmap().
+#[no_mangle]
+pub extern "C" fn pymemprofile_remap_anon_mmap(
+    old_address: usize,
+    old_size: libc::size_t,
+    new_address: usize,
+    new_size: libc::size_t,
+    line_number: u16,
+) {
+    memorytracking::remap_anon_mmap(old_address, old_size, new_address, new_size, line_number);
+}
+
+/// Record an mremap() attributed only to the native code at the given caller
+/// address, for when Python callstacks aren't being tracked.
+#[no_mangle]
+pub extern "C" fn pymemprofile_remap_native_anon_mmap(
+    old_address: usize,
+    old_size: libc::size_t,
+    new_address: usize,
+    new_size: libc::size_t,
+    caller: usize,
+) {
+    memorytracking::remap_native_anon_mmap(old_address, old_size, new_address, new_size, caller);
+}
+
 #[no_mangle]
 pub extern "C" fn pymemprofile_free_anon_mmap(address: usize, length: libc::size_t) {
     memorytracking::free_anon_mmap(address, length);
 }
 
+/// Record an sbrk(); previous_break is what it returned.
+#[no_mangle]
+pub extern "C" fn pymemprofile_change_heap_break(
+    previous_break: usize,
+    increment: libc::intptr_t,
+    line_number: u16,
+) {
+    memorytracking::change_heap_break(previous_break, increment, line_number);
+}
+
+#[no_mangle]
+pub extern "C" fn pymemprofile_change_native_heap_break(
+    previous_break: usize,
+    increment: libc::intptr_t,
+    caller: usize,
+) {
+    memorytracking::change_native_heap_break(previous_break, increment, caller);
+}
+
 /// # Safety
 /// Intended for use from C APIs, what can I say.
 #[no_mangle]
@@ -66,6 +294,255 @@ pub extern "C" fn pymemprofile_new_line_number(line_number: u16) {
     memorytracking::new_line_number(line_number);
 }
 
+/// Start a named region of the current thread's callstack.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_begin_region(name: *const c_char) {
+    let name = CStr::from_ptr(name).to_string_lossy();
+    memorytracking::begin_region(&name);
+}
+
+/// End the region started by the matching pymemprofile_begin_region().
+#[no_mangle]
+pub extern "C" fn pymemprofile_end_region() {
+    memorytracking::end_region();
+}
+
+/// Label the current thread's allocations until the matching
+/// pymemprofile_pop_label().
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_push_label(name: *const c_char) {
+    let name = CStr::from_ptr(name).to_string_lossy();
+    memorytracking::push_label(&name);
+}
+
+/// Go back to the label in effect before the matching
+/// pymemprofile_push_label().
+#[no_mangle]
+pub extern "C" fn pymemprofile_pop_label() {
+    memorytracking::pop_label();
+}
+
+/// Only include allocations made inside a region in flamegraphs if non-zero.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_regions_only(enabled: libc::c_int) {
+    memorytracking::set_regions_only(enabled != 0);
+}
+
+/// Set the size above which Python allocations get free()d on out-of-memory.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_oom_free_threshold(threshold: libc::size_t) {
+    memorytracking::set_oom_free_threshold(threshold);
+}
+
+/// Set the width of flamegraph SVGs in pixels; 0 means the default width.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_flamegraph_width(width: libc::size_t) {
+    memorytracking::set_flamegraph_width(width);
+}
+
+/// Include every callstack that ever allocated in text reports, with 0 bytes if
+/// it's using nothing, if non-zero.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_inactive_callstacks(enabled: libc::c_int) {
+    memorytracking::set_inactive_callstacks(enabled != 0);
+}
+
+/// Set the font size and frame height of flamegraphs in pixels, and the font
+/// name; 0 or NULL leave the default.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_set_flamegraph_style(
+    font_size: libc::size_t,
+    frame_height: libc::size_t,
+    font_type: *const c_char,
+) {
+    let font_type = if font_type.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(font_type).to_string_lossy())
+    };
+    memorytracking::set_flamegraph_style(font_size, frame_height, font_type.as_deref());
+}
+
+/// Color flamegraph frames by the module they're in if non-zero, otherwise by
+/// size.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_flamegraph_colors_by_module(by_module: libc::c_int) {
+    memorytracking::set_flamegraph_colors_by_module(by_module != 0);
+}
+
+/// Render flamegraphs with a dark background if non-zero, otherwise with the
+/// usual light theme.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_flamegraph_dark_mode(enabled: libc::c_int) {
+    memorytracking::set_flamegraph_dark_mode(enabled != 0);
+}
+
+/// Capture native backtraces for allocations with no Python callstack if
+/// non-zero. This is expensive.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_capture_native_stacks(enabled: libc::c_int) {
+    memorytracking::set_capture_native_stacks(enabled != 0);
+}
+
+/// Attribute allocations with no Python callstack to the symbol of the native
+/// code that called malloc() if non-zero. Much cheaper than native stacks.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_record_native_callers(enabled: libc::c_int) {
+    memorytracking::set_record_native_callers(enabled != 0);
+}
+
+/// Only record malloc()s made by the calling thread if non-zero, otherwise
+/// those from all threads.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_tracked_thread(enabled: libc::c_int) {
+    memorytracking::set_tracked_thread(enabled != 0);
+}
+
+/// Start flamegraph callstacks with a frame naming their thread if non-zero.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_thread_root_frames(enabled: libc::c_int) {
+    memorytracking::set_thread_root_frames(enabled != 0);
+}
+
+/// Choose which hooks get recorded: 0 for malloc() and friends only, 1 for
+/// mmap() only, 2 for both. Returns 0 on success, -1 on an unknown mode.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_allocator_mode(mode: libc::c_int) -> libc::c_int {
+    let mode = match mode {
+        0 => memorytracking::AllocatorMode::Malloc,
+        1 => memorytracking::AllocatorMode::Mmap,
+        2 => memorytracking::AllocatorMode::Both,
+        _ => {
+            eprintln!("=fil-profile= Error: unknown allocator mode {}", mode);
+            return -1;
+        }
+    };
+    memorytracking::set_allocator_mode(mode);
+    0
+}
+
+/// Merge flamegraph callstacks using less than this fraction of total memory
+/// into one; 0 keeps everything.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_min_flamegraph_fraction(fraction: f64) {
+    memorytracking::set_min_flamegraph_fraction(fraction);
+}
+
+/// Only render the largest this many callstacks into flamegraphs, merging the
+/// rest; 0 means no limit.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_max_flamegraph_lines(max_lines: libc::size_t) {
+    memorytracking::set_max_flamegraph_lines(max_lines);
+}
+
+/// Only record 1 in `rate` allocations, scaling up their sizes to match.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_sampling_rate(rate: libc::size_t) {
+    memorytracking::set_sampling_rate(rate);
+}
+
+/// Report allocations within `tolerance` bytes of `size` on stderr as they
+/// happen, with their callstack. A size of 0 turns this off.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_watchpoint(size: libc::size_t, tolerance: libc::size_t) {
+    memorytracking::set_watchpoint(size, tolerance);
+}
+
+/// Keep at most this many allocations' details in memory, spilling the rest to
+/// disk; 0 means no limit.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_max_in_memory_allocations(max: libc::size_t) {
+    memorytracking::set_max_in_memory_allocations(max);
+}
+
+/// Store at most this many allocations, estimating free()s of the rest; 0
+/// means no limit.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_max_allocations(max: libc::size_t) {
+    memorytracking::set_max_allocations(max);
+}
+
+/// Collapse calls deeper than the given depth into a single truncation frame;
+/// 0 means no limit.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_max_stack_depth(depth: libc::size_t) {
+    memorytracking::set_max_stack_depth(depth);
+}
+
+/// Set how much gets written to stderr: 0 only mentions warnings and errors, 1
+/// (the default) also mentions written reports, 2 adds debugging messages.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_verbosity(verbosity: libc::size_t) {
+    memorytracking::set_verbosity(verbosity);
+}
+
+/// Start recording memory usage over time, sampling whenever it changes by at
+/// least the given number of bytes; 0 stops recording.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_timeline_delta(delta: libc::size_t) {
+    memorytracking::set_timeline_delta(delta);
+}
+
+/// Every n milliseconds, count only resident memory of anonymous mmap()s, as
+/// reported by /proc/self/smaps; 0 goes back to counting all mapped bytes.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_resident_mmaps_interval_ms(milliseconds: u64) {
+    memorytracking::set_resident_mmaps_interval_ms(milliseconds);
+}
+
+/// Dump current allocations to the default output path every n seconds; 0
+/// stops the snapshots.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_snapshot_interval_seconds(seconds: u64) {
+    memorytracking::set_snapshot_interval_seconds(seconds);
+}
+
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_dump_memory_timeline_to_csv(
+    path: *const c_char,
+) -> libc::c_int {
+    let path = CStr::from_ptr(path)
+        .to_str()
+        .expect("Path wasn't UTF-8")
+        .to_string();
+    error_code(memorytracking::dump_memory_timeline_to_csv(&path))
+}
+
+/// Set how many MiB of memory are reserved for writing the report if we run out
+/// of memory.
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_spare_memory_mb(megabytes: libc::size_t) {
+    memorytracking::set_spare_memory_mb(megabytes);
+}
+
+#[no_mangle]
+pub extern "C" fn pymemprofile_prepare_for_fork() {
+    memorytracking::prepare_for_fork();
+}
+
+#[no_mangle]
+pub extern "C" fn pymemprofile_after_fork_in_parent() {
+    memorytracking::after_fork_in_parent();
+}
+
+#[no_mangle]
+pub extern "C" fn pymemprofile_after_fork_in_child() {
+    memorytracking::after_fork_in_child();
+}
+
 /// # Safety
 /// Intended for use from C.
 #[no_mangle]
@@ -77,16 +554,710 @@ pub unsafe extern "C" fn pymemprofile_reset(default_path: *const c_char) {
     memorytracking::reset(path);
 }
 
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_reset_and_dump(
+    path: *const c_char,
+    default_path: *const c_char,
+) -> libc::c_int {
+    let path = CStr::from_ptr(path).to_str().expect("Path wasn't UTF-8");
+    let default_path = CStr::from_ptr(default_path)
+        .to_str()
+        .expect("Path wasn't UTF-8")
+        .to_string();
+    error_code(memorytracking::reset_and_dump(path, default_path))
+}
+
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_reset_keeping_interner(default_path: *const c_char) {
+    let path = CStr::from_ptr(default_path)
+        .to_str()
+        .expect("Path wasn't UTF-8")
+        .to_string();
+    memorytracking::reset_keeping_interner(path);
+}
+
+/// Dump peak memory usage to flamegraphs in the given directory. The title
+/// and subtitle may be NULL, in which case defaults are used.
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_dump_peak_to_flamegraph(
+    path: *const c_char,
+    title: *const c_char,
+    subtitle: *const c_char,
+) -> libc::c_int {
+    let path = CStr::from_ptr(path)
+        .to_str()
+        .expect("Path wasn't UTF-8")
+        .to_string();
+    let title = optional_c_string(title);
+    let subtitle = optional_c_string(subtitle);
+    error_code(memorytracking::dump_peak_to_flamegraph(
+        &path,
+        title.as_deref(),
+        subtitle.as_deref(),
+    ))
+}
+
+/// A C string that may be NULL. Text is only displayed, so invalid UTF-8 is
+/// replaced rather than being an error.
+unsafe fn optional_c_string(text: *const c_char) -> Option<String> {
+    if text.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(text).to_string_lossy().into_owned())
+    }
+}
+
+/// Dump current and peak memory usage to flamegraphs in the given directory,
+/// with an HTML report that toggles between them.
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_dump_combined_report(path: *const c_char) -> libc::c_int {
+    let path = CStr::from_ptr(path)
+        .to_str()
+        .expect("Path wasn't UTF-8")
+        .to_string();
+    error_code(memorytracking::dump_combined_report(&path))
+}
+
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_dump_peak_by_thread_to_flamegraph(
+    path: *const c_char,
+) -> libc::c_int {
+    let path = CStr::from_ptr(path)
+        .to_str()
+        .expect("Path wasn't UTF-8")
+        .to_string();
+    error_code(memorytracking::dump_peak_by_thread_to_flamegraph(&path))
+}
+
+/// Dump current allocations whenever the process receives SIGUSR2.
+#[cfg(unix)]
+#[no_mangle]
+pub extern "C" fn pymemprofile_install_signal_dumper() {
+    memorytracking::install_signal_dumper();
+}
+
+/// Remember current memory usage under the given name, to compare against
+/// later with pymemprofile_dump_delta_flamegraph().
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_snapshot_baseline(name: *const c_char) {
+    let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+    memorytracking::snapshot_baseline(name);
+}
+
+/// Write flamegraphs of memory growth since the named baseline to the given
+/// directory.
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_dump_delta_flamegraph(
+    name: *const c_char,
+    path: *const c_char,
+) -> libc::c_int {
+    let name = CStr::from_ptr(name).to_string_lossy();
+    let path = CStr::from_ptr(path)
+        .to_str()
+        .expect("Path wasn't UTF-8")
+        .to_string();
+    error_code(memorytracking::dump_delta_flamegraph(&name, &path))
+}
+
+/// Start looking for Python calls that leak memory every time they're called.
+#[no_mangle]
+pub extern "C" fn pymemprofile_enable_leak_detection() {
+    memorytracking::enable_leak_detection();
+}
+
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_dump_leak_suspects(path: *const c_char) -> libc::c_int {
+    let path = CStr::from_ptr(path)
+        .to_str()
+        .expect("Path wasn't UTF-8")
+        .to_string();
+    error_code(memorytracking::dump_leak_suspects(&path))
+}
+
+/// Write peak memory usage by line within the named function.
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_dump_function_lines(
+    function_name: *const c_char,
+    path: *const c_char,
+) -> libc::c_int {
+    let function_name = CStr::from_ptr(function_name).to_string_lossy();
+    let path = CStr::from_ptr(path).to_str().expect("Path wasn't UTF-8");
+    error_code(memorytracking::dump_function_lines(&function_name, path))
+}
+
+/// Start tracking the peak memory of each function call, callees included.
+#[no_mangle]
+pub extern "C" fn pymemprofile_enable_call_peaks() {
+    memorytracking::enable_call_peaks();
+}
+
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_dump_call_peaks(path: *const c_char) -> libc::c_int {
+    let path = CStr::from_ptr(path)
+        .to_str()
+        .expect("Path wasn't UTF-8")
+        .to_string();
+    error_code(memorytracking::dump_call_peaks(&path))
+}
+
+/// Leave frames whose filename or function name contains the given pattern out
+/// of rendered callstacks.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_add_hidden_frame_pattern(pattern: *const c_char) {
+    let pattern = CStr::from_ptr(pattern).to_string_lossy().into_owned();
+    memorytracking::add_hidden_frame_pattern(pattern);
+}
+
+/// Leave frames whose filename starts with the given prefix out of rendered
+/// callstacks.
+///
 /// # Safety
 /// Intended for use from C.
 #[no_mangle]
-pub unsafe extern "C" fn pymemprofile_dump_peak_to_flamegraph(path: *const c_char) {
+pub unsafe extern "C" fn pymemprofile_add_hidden_prefix(prefix: *const c_char) {
+    let prefix = CStr::from_ptr(prefix).to_string_lossy().into_owned();
+    memorytracking::add_hidden_prefix(prefix);
+}
+
+/// Strip these project roots, separated like PATH entries, from the start of
+/// filenames in rendered callstacks. An empty string means no roots.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_set_path_prefix(prefix: *const c_char) {
+    let prefix = CStr::from_ptr(prefix).to_string_lossy().into_owned();
+    memorytracking::set_path_prefix(&prefix);
+}
+
+/// If enabled, replace everything up to site-packages/ in rendered filenames
+/// with "<venv>".
+#[no_mangle]
+pub extern "C" fn pymemprofile_set_collapse_site_packages(enabled: libc::c_int) {
+    memorytracking::set_collapse_site_packages(enabled != 0);
+}
+
+/// A dump's result for C: 0 on success, otherwise the OS error code (e.g.
+/// ENOSPC or EACCES), or -1 if the error didn't come from the OS.
+fn error_code(result: std::io::Result<()>) -> libc::c_int {
+    match result {
+        Ok(()) => 0,
+        Err(e) => e.raw_os_error().filter(|code| *code != 0).unwrap_or(-1),
+    }
+}
+
+/// Copy as much of the text as fits into the buffer, NUL-terminated. Returns the
+/// buffer size needed to fit all of it, including the NUL.
+fn copy_to_c_buffer(text: &str, buffer: &mut [u8]) -> usize {
+    if let Some(max_length) = buffer.len().checked_sub(1) {
+        let length = text.len().min(max_length);
+        buffer[..length].copy_from_slice(&text.as_bytes()[..length]);
+        buffer[length] = 0;
+    }
+    text.len() + 1
+}
+
+/// Write the `n` callstacks using the most memory (at peak if `peak` is
+/// non-zero, otherwise currently) into the buffer, as NUL-terminated lines of
+/// "<bytes> <callstack>". Returns the buffer size needed to fit the whole
+/// result; if that's larger than `buffer_length` the result was truncated.
+///
+/// # Safety
+/// Intended for use from C, `buffer` must have room for `buffer_length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_top_allocators(
+    n: libc::size_t,
+    peak: libc::c_int,
+    buffer: *mut c_char,
+    buffer_length: libc::size_t,
+) -> libc::size_t {
+    let text = memorytracking::top_allocators(n, peak != 0);
+    let buffer = if buffer.is_null() {
+        &mut []
+    } else {
+        std::slice::from_raw_parts_mut(buffer as *mut u8, buffer_length)
+    };
+    copy_to_c_buffer(&text, buffer)
+}
+
+/// Write the largest live allocation into the buffer, as a NUL-terminated
+/// "<address> <bytes> <callstack>", or an empty string if nothing is
+/// allocated. Returns the buffer size needed to fit the whole result.
+///
+/// # Safety
+/// Intended for use from C, `buffer` must have room for `buffer_length` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_largest_allocation(
+    buffer: *mut c_char,
+    buffer_length: libc::size_t,
+) -> libc::size_t {
+    let text = memorytracking::largest_allocation();
+    let buffer = if buffer.is_null() {
+        &mut []
+    } else {
+        std::slice::from_raw_parts_mut(buffer as *mut u8, buffer_length)
+    };
+    copy_to_c_buffer(&text, buffer)
+}
+
+/// Write peak memory usage to the given file in collapsed-stack format, for
+/// use with external flamegraph tools.
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_dump_peak_collapsed(path: *const c_char) -> libc::c_int {
     let path = CStr::from_ptr(path)
         .to_str()
         .expect("Path wasn't UTF-8")
         .to_string();
-    memorytracking::dump_peak_to_flamegraph(&path);
+    error_code(memorytracking::dump_peak_collapsed(&path))
+}
+
+/// Write peak memory usage in collapsed-stack format to an open file
+/// descriptor, which is left open.
+/// Returns 0 on success, otherwise an errno-style error code.
+#[cfg(unix)]
+#[no_mangle]
+pub extern "C" fn pymemprofile_dump_peak_collapsed_to_fd(fd: libc::c_int) -> libc::c_int {
+    error_code(memorytracking::dump_peak_collapsed_to_fd(fd))
+}
+
+/// Write a peak memory usage flamegraph SVG to an open file descriptor, which
+/// is left open.
+/// Returns 0 on success, otherwise an errno-style error code.
+#[cfg(unix)]
+#[no_mangle]
+pub extern "C" fn pymemprofile_dump_peak_svg_to_fd(fd: libc::c_int) -> libc::c_int {
+    error_code(memorytracking::dump_peak_svg_to_fd(fd))
+}
+
+/// Write peak memory usage summed by file, ignoring the rest of the
+/// callstack, to the given file.
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_dump_peak_by_file(path: *const c_char) -> libc::c_int {
+    let path = CStr::from_ptr(path)
+        .to_str()
+        .expect("Path wasn't UTF-8")
+        .to_string();
+    error_code(memorytracking::dump_peak_by_file(&path))
+}
+
+/// Write peak memory usage summed by label to the given file.
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_dump_peak_by_label(path: *const c_char) -> libc::c_int {
+    let path = CStr::from_ptr(path)
+        .to_str()
+        .expect("Path wasn't UTF-8")
+        .to_string();
+    error_code(memorytracking::dump_peak_by_label(&path))
+}
+
+/// Write peak memory usage to the given file as CSV.
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_dump_peak_to_csv(path: *const c_char) -> libc::c_int {
+    let path = CStr::from_ptr(path).to_str().expect("Path wasn't UTF-8");
+    error_code(memorytracking::dump_peak_to_csv(path))
+}
+
+/// Write peak memory usage to the given file in pprof's protobuf format.
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_dump_peak_to_pprof(path: *const c_char) -> libc::c_int {
+    let path = CStr::from_ptr(path).to_str().expect("Path wasn't UTF-8");
+    error_code(memorytracking::dump_peak_to_pprof(path))
+}
+
+/// Write peak memory usage to the given file in the compact binary
+/// collapsed-stack format.
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_dump_peak_binary(path: *const c_char) -> libc::c_int {
+    let path = CStr::from_ptr(path)
+        .to_str()
+        .expect("Path wasn't UTF-8")
+        .to_string();
+    error_code(memorytracking::dump_peak_binary(&path))
+}
+
+/// Convert a binary collapsed-stack file to the text format.
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_binary_to_collapsed(
+    binary_path: *const c_char,
+    collapsed_path: *const c_char,
+) -> libc::c_int {
+    let binary_path = CStr::from_ptr(binary_path)
+        .to_str()
+        .expect("Path wasn't UTF-8");
+    let collapsed_path = CStr::from_ptr(collapsed_path)
+        .to_str()
+        .expect("Path wasn't UTF-8");
+    let result = collapsed::binary_to_collapsed(binary_path, collapsed_path);
+    if let Err(e) = &result {
+        eprintln!(
+            "=fil-profile= Error converting binary profiling data: {}",
+            e
+        );
+    }
+    error_code(result)
+}
+
+/// Append every allocation event to the given file as it happens, so the
+/// history survives crashes.
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_enable_event_log(path: *const c_char) -> libc::c_int {
+    let path = CStr::from_ptr(path).to_str().expect("Path wasn't UTF-8");
+    error_code(memorytracking::enable_event_log(Path::new(path)))
+}
+
+/// Replay an event log, writing current-memory.prof, peak-memory.prof and
+/// their SVGs to the output directory.
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_render_event_log(
+    log_path: *const c_char,
+    output_directory: *const c_char,
+) -> libc::c_int {
+    let log_path = CStr::from_ptr(log_path)
+        .to_str()
+        .expect("Path wasn't UTF-8");
+    let output_directory = CStr::from_ptr(output_directory)
+        .to_str()
+        .expect("Path wasn't UTF-8");
+    error_code(memorytracking::render_event_log_to_flamegraph(
+        Path::new(log_path),
+        Path::new(output_directory),
+    ))
+}
+
+/// Render a binary dump of peak memory usage to peak-memory.prof plus SVGs in
+/// the output directory.
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_render_binary_to_flamegraph(
+    binary_path: *const c_char,
+    output_directory: *const c_char,
+) -> libc::c_int {
+    let binary_path = CStr::from_ptr(binary_path)
+        .to_str()
+        .expect("Path wasn't UTF-8");
+    let output_directory = CStr::from_ptr(output_directory)
+        .to_str()
+        .expect("Path wasn't UTF-8");
+    let result = memorytracking::render_binary_to_flamegraph(
+        Path::new(binary_path),
+        Path::new(output_directory),
+    );
+    if let Err(e) = &result {
+        eprintln!("=fil-profile= Error rendering binary profiling data: {}", e);
+    }
+    error_code(result)
+}
+
+/// Start a new generation; allocations made so far get one generation older.
+#[no_mangle]
+pub extern "C" fn pymemprofile_new_generation() {
+    memorytracking::new_generation();
+}
+
+/// Write flamegraphs of current allocations made at least `min_age`
+/// generations ago to the given directory.
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_dump_old_allocations_flamegraph(
+    min_age: u16,
+    path: *const c_char,
+) -> libc::c_int {
+    let path = CStr::from_ptr(path).to_str().expect("Path wasn't UTF-8");
+    error_code(memorytracking::dump_old_allocations_flamegraph(
+        min_age, path,
+    ))
+}
+
+/// Write flamegraphs of byte-seconds, i.e. allocation size times how long the
+/// allocation was live, to the given directory.
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_dump_byte_seconds_flamegraph(
+    path: *const c_char,
+) -> libc::c_int {
+    let path = CStr::from_ptr(path).to_str().expect("Path wasn't UTF-8");
+    error_code(memorytracking::dump_byte_seconds_flamegraph(path))
+}
+
+/// Dump peak memory usage to the given directory when the process exits
+/// normally, or to the default path if it's NULL or empty.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_dump_on_exit(path: *const c_char) {
+    let path = if path.is_null() {
+        ""
+    } else {
+        CStr::from_ptr(path).to_str().expect("Path wasn't UTF-8")
+    };
+    memorytracking::dump_on_exit(path);
+}
+
+/// Call `callback` with each callstack's rendered string, its memory usage at
+/// the peak in bytes, and `userdata`, largest first, so callers can build
+/// their own reports without going through files. The string is only valid
+/// for the duration of the call.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_for_each_peak_callstack(
+    callback: extern "C" fn(*const c_char, libc::size_t, *mut c_void),
+    userdata: *mut c_void,
+) {
+    memorytracking::for_each_peak_callstack(|callstack, bytes| {
+        // Rendered callstacks come from source code, which can't really have
+        // NUL bytes, but just in case:
+        let callstack =
+            CString::new(callstack.replace('\0', "")).expect("NUL bytes should have been removed");
+        callback(callstack.as_ptr(), bytes, userdata);
+    });
+}
+
+/// Merge `count` collapsed-stack profile files into merged.prof plus SVGs in
+/// the output directory.
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_merge_profiles(
+    paths: *const *const c_char,
+    count: libc::size_t,
+    output_directory: *const c_char,
+) -> libc::c_int {
+    let inputs: Vec<PathBuf> = std::slice::from_raw_parts(paths, count)
+        .iter()
+        .map(|path| PathBuf::from(CStr::from_ptr(*path).to_str().expect("Path wasn't UTF-8")))
+        .collect();
+    let output_directory = CStr::from_ptr(output_directory)
+        .to_str()
+        .expect("Path wasn't UTF-8");
+    let result = memorytracking::merge_profiles_to_flamegraph(&inputs, Path::new(output_directory));
+    if let Err(e) = &result {
+        eprintln!("=fil-profile= Error merging profiles: {}", e);
+    }
+    error_code(result)
+}
+
+/// Write a textual summary of the differences between two collapsed-stack
+/// profile files, listing the top `limit` callstacks that grew and shrank.
+/// Returns 0 on success, otherwise an errno-style error code.
+///
+/// # Safety
+/// Intended for use from C.
+#[no_mangle]
+pub unsafe extern "C" fn pymemprofile_write_diff_summary(
+    before_path: *const c_char,
+    after_path: *const c_char,
+    output_path: *const c_char,
+    limit: libc::size_t,
+) -> libc::c_int {
+    let before_path = CStr::from_ptr(before_path)
+        .to_str()
+        .expect("Path wasn't UTF-8");
+    let after_path = CStr::from_ptr(after_path)
+        .to_str()
+        .expect("Path wasn't UTF-8");
+    let output_path = CStr::from_ptr(output_path)
+        .to_str()
+        .expect("Path wasn't UTF-8");
+    let result = collapsed::write_diff_summary(before_path, after_path, output_path, limit);
+    if let Err(e) = &result {
+        eprintln!("=fil-profile= Error writing diff summary: {}", e);
+    }
+    error_code(result)
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::{
+        copy_to_c_buffer, error_code, pymemprofile_add_native_allocation,
+        pymemprofile_binary_to_collapsed, pymemprofile_for_each_peak_callstack,
+        pymemprofile_free_allocation, pymemprofile_get_allocation_size,
+        pymemprofile_merge_profiles, pymemprofile_render_binary_to_flamegraph,
+        pymemprofile_write_diff_summary,
+    };
+    use std::ffi::{c_void, CStr, CString};
+    use std::os::raw::c_char;
+    use std::sync::Mutex;
+
+    // Tests that change the global tracker's usage shouldn't run at the same
+    // time, or they'd see each other's allocations in the peak:
+    static GLOBAL_TRACKER: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn error_codes() {
+        assert_eq!(error_code(Ok(())), 0);
+        let os_error = std::io::Error::from_raw_os_error(libc::ENOSPC);
+        assert_eq!(error_code(Err(os_error)), libc::ENOSPC);
+        assert_eq!(error_code(Err(std::io::Error::other("nope"))), -1);
+
+        // Entry points that work with files report why they failed:
+        let directory = tempfile::tempdir().unwrap();
+        let output = CString::new(directory.path().to_str().unwrap()).unwrap();
+        let missing = CString::new("/nonexistent/fil-profile/input").unwrap();
+        unsafe {
+            assert_eq!(
+                pymemprofile_binary_to_collapsed(missing.as_ptr(), output.as_ptr()),
+                libc::ENOENT
+            );
+            assert_eq!(
+                pymemprofile_render_binary_to_flamegraph(missing.as_ptr(), output.as_ptr()),
+                libc::ENOENT
+            );
+            assert_eq!(
+                pymemprofile_merge_profiles(&missing.as_ptr(), 1, output.as_ptr()),
+                libc::ENOENT
+            );
+            assert_eq!(
+                pymemprofile_write_diff_summary(
+                    missing.as_ptr(),
+                    missing.as_ptr(),
+                    output.as_ptr(),
+                    10
+                ),
+                libc::ENOENT
+            );
+        }
+    }
+
+    #[test]
+    fn allocation_size_via_c_api() {
+        let _guard = GLOBAL_TRACKER.lock().unwrap();
+        // The tracker is global, so use an address no other test will:
+        let address = 0x7fab_cdef_1230;
+        assert_eq!(pymemprofile_get_allocation_size(address), 0);
+        pymemprofile_add_native_allocation(address, 12345, 0);
+        assert_eq!(pymemprofile_get_allocation_size(address), 12345);
+        assert_eq!(pymemprofile_free_allocation(address), 12345);
+        assert_eq!(pymemprofile_get_allocation_size(address), 0);
+    }
+
+    #[test]
+    fn peak_callstacks_via_c_api() {
+        extern "C" fn collect(
+            callstack: *const c_char,
+            bytes: libc::size_t,
+            userdata: *mut c_void,
+        ) {
+            let entries = unsafe { &mut *(userdata as *mut Vec<(String, usize)>) };
+            let callstack = unsafe { CStr::from_ptr(callstack) };
+            entries.push((callstack.to_str().unwrap().to_string(), bytes));
+        }
+        let _guard = GLOBAL_TRACKER.lock().unwrap();
+        // Make sure there's a peak with something in it:
+        let address = 0x7fab_cdef_4560;
+        pymemprofile_add_native_allocation(address, 10_000_000, 0);
+        let mut entries: Vec<(String, usize)> = Vec::new();
+        unsafe {
+            pymemprofile_for_each_peak_callstack(
+                collect,
+                &mut entries as *mut Vec<(String, usize)> as *mut c_void,
+            );
+        }
+        let peak_bytes = crate::memorytracking::get_peak_allocated_bytes();
+        pymemprofile_free_allocation(address);
+        assert!(!entries.is_empty());
+        let total: usize = entries.iter().map(|(_, bytes)| bytes).sum();
+        assert_eq!(total, peak_bytes);
+        assert!(entries.iter().all(|(_, bytes)| *bytes > 0));
+        assert!(entries.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+    }
+
+    #[test]
+    fn copy_to_c_buffer_truncates() {
+        let mut buffer = [0xffu8; 4];
+        assert_eq!(copy_to_c_buffer("ab", &mut buffer), 3);
+        assert_eq!(&buffer, b"ab\0\xff");
+        assert_eq!(copy_to_c_buffer("abcdef", &mut buffer), 7);
+        assert_eq!(&buffer, b"abc\0");
+        assert_eq!(copy_to_c_buffer("abc", &mut []), 4);
+    }
+}
@@ -1,18 +1,40 @@
+use super::accounting::{
+    self, Allocation, CallstackId, Frame, Generation, IdMap, LabelId, MemoryUsage, ThreadId,
+    Timestamp, UsageVector, MIB,
+};
+use super::callstack as call_peaks;
+use super::collapsed;
+use super::eventlog::{self, EventLog};
+use super::leaks::LeakDetector;
+#[cfg(not(feature = "no-mmap-tracking"))]
+use super::memoryusage::{self, ResidentRegion};
+use super::pprof;
+#[cfg(not(feature = "no-mmap-tracking"))]
 use super::rangemap::RangeMap;
+use super::recentset::RecentSet;
+use super::ringbuffer::RingBuffer;
+use super::spillmap::{SpillMap, Spillable};
+use super::timeline::Timeline;
 use core::ffi;
 use im::Vector as ImVector;
 use inferno::flamegraph;
 use itertools::Itertools;
-use libc;
-use std::cell::RefCell;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
 use std::collections;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::Write;
+#[cfg(unix)]
+use std::mem::ManuallyDrop;
+#[cfg(unix)]
+use std::os::unix::io::FromRawFd;
 use std::path::Path;
 use std::path::PathBuf;
 use std::slice;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// A function location provided by the C code. Matches struct in _filpreload.c.
 #[repr(C)]
@@ -35,6 +57,37 @@ impl FunctionLocation {
     }
 }
 
+/// A FunctionLocation that can be stored in a static.
+struct StaticFunctionLocation(FunctionLocation);
+
+unsafe impl Sync for StaticFunctionLocation {}
+
+/// Stands in for all frames beyond the maximum callstack depth.
+static TRUNCATED_FUNCTION: StaticFunctionLocation = StaticFunctionLocation(FunctionLocation {
+    filename: b"...".as_ptr(),
+    filename_length: 3,
+    function_name: b"truncated".as_ptr(),
+    function_name_length: 9,
+});
+
+/// The filename of the synthetic frames marking regions.
+const REGION_FILENAME: &str = "<region>";
+
+lazy_static! {
+    // Region frames are never freed, so there's only one per name:
+    static ref REGION_FUNCTIONS: Mutex<HashMap<String, FunctionId>> =
+        Mutex::new(HashMap::default());
+}
+
+/// The function standing in for the region with the given name.
+fn region_function(name: &str) -> FunctionId {
+    *REGION_FUNCTIONS
+        .lock()
+        .unwrap()
+        .entry(name.to_string())
+        .or_insert_with(|| FunctionId::leak_from_strings(REGION_FILENAME, name))
+}
+
 /// A Rust-y wrapper for FunctionLocation
 #[derive(Clone, Debug, PartialEq, Eq, Copy, Hash)]
 pub struct FunctionId {
@@ -49,23 +102,94 @@ impl FunctionId {
         FunctionId { function }
     }
 
-    fn get_filename(&self) -> &str {
-        unsafe {
-            let loc = &*self.function;
-            let slice = slice::from_raw_parts(loc.filename, loc.filename_length as usize);
-            std::str::from_utf8_unchecked(slice)
+    /// A FunctionId for the given strings. Like the FunctionLocations the C
+    /// code creates, the memory is never freed, so callers should only do
+    /// this once per function.
+    pub fn leak_from_strings(filename: &str, function_name: &str) -> Self {
+        let filename: &'static str = Box::leak(filename.to_string().into_boxed_str());
+        let function_name: &'static str = Box::leak(function_name.to_string().into_boxed_str());
+        let location: &'static FunctionLocation = Box::leak(Box::new(FunctionLocation {
+            filename: filename.as_ptr(),
+            filename_length: filename.len() as isize,
+            function_name: function_name.as_ptr(),
+            function_name_length: function_name.len() as isize,
+        }));
+        FunctionId::new(location)
+    }
+
+    fn get_filename(&self) -> Cow<'_, str> {
+        match unsafe { self.function.as_ref() } {
+            Some(loc) => location_string(loc.filename, loc.filename_length),
+            None => Cow::Borrowed(INVALID_LOCATION_STRING),
         }
     }
 
-    fn get_function_name(&self) -> &str {
-        unsafe {
-            let loc = &*self.function;
-            let slice = slice::from_raw_parts(loc.function_name, loc.function_name_length as usize);
-            std::str::from_utf8_unchecked(slice)
+    fn get_function_name(&self) -> Cow<'_, str> {
+        match unsafe { self.function.as_ref() } {
+            Some(loc) => location_string(loc.function_name, loc.function_name_length),
+            None => Cow::Borrowed(INVALID_LOCATION_STRING),
+        }
+    }
+}
+
+/// Filenames or function names longer than this are assumed to be garbage.
+const MAX_LOCATION_STRING_LENGTH: isize = 64 * 1024;
+
+/// Stands in for filenames or function names that can't be read.
+const INVALID_LOCATION_STRING: &str = "<invalid>";
+
+/// A string from a FunctionLocation. Bad pointers or lengths, e.g. from a
+/// corrupted frame, give "<invalid>" rather than reading random memory. Python
+/// should always give us UTF-8, but if it doesn't (exotic filenames) we
+/// substitute replacement characters rather than crashing the profiled process.
+fn location_string<'a>(pointer: *const u8, length: isize) -> Cow<'a, str> {
+    if pointer.is_null() || !(0..=MAX_LOCATION_STRING_LENGTH).contains(&length) {
+        return Cow::Borrowed(INVALID_LOCATION_STRING);
+    }
+    let slice = unsafe { slice::from_raw_parts(pointer, length as usize) };
+    String::from_utf8_lossy(slice)
+}
+
+/// How filenames are shortened in rendered callstacks, so they're readable
+/// and don't leak local paths.
+#[derive(Debug, Default)]
+struct PathDisplay {
+    // Project roots stripped from the start of filenames:
+    prefixes: Vec<String>,
+    // Whether everything up to site-packages/ or dist-packages/ is replaced
+    // with "<venv>":
+    collapse_site_packages: bool,
+}
+
+impl PathDisplay {
+    /// The filename as it should be displayed. A prefix only matches whole
+    /// path components, and if several match, the longest (i.e. innermost)
+    /// root wins.
+    fn shorten<'a>(&self, filename: &'a str) -> Cow<'a, str> {
+        if self.collapse_site_packages {
+            for packages in &["/site-packages/", "/dist-packages/"] {
+                if let Some(index) = filename.rfind(packages) {
+                    return Cow::Owned(format!("<venv>/{}", &filename[index + packages.len()..]));
+                }
+            }
         }
+        self.prefixes
+            .iter()
+            .filter_map(|prefix| {
+                let rest = filename.strip_prefix(prefix.trim_end_matches('/'))?;
+                let rest = rest.strip_prefix('/')?;
+                Some(rest)
+            })
+            .min_by_key(|rest| rest.len())
+            .map_or(Cow::Borrowed(filename), Cow::Borrowed)
     }
 }
 
+lazy_static! {
+    // Process-wide, like the other display settings that survive reset():
+    static ref PATH_DISPLAY: RwLock<PathDisplay> = RwLock::new(PathDisplay::default());
+}
+
 /// A specific location: file + function + line number.
 #[derive(Clone, Debug, PartialEq, Eq, Copy, Hash)]
 struct CallSiteId {
@@ -83,60 +207,80 @@ impl CallSiteId {
     }
 }
 
-/// The current Python callstack. We use IDs instead of Function objects for
-/// performance reasons.
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
-struct Callstack {
-    calls: Vec<CallSiteId>,
+impl Frame for CallSiteId {
+    fn set_line_number(&mut self, line_number: u16) {
+        self.line_number = line_number;
+    }
 }
 
-impl Callstack {
-    fn new() -> Callstack {
-        Callstack { calls: Vec::new() }
-    }
+/// The frame that stands in for all calls beyond the maximum stack depth.
+fn truncation_frame() -> CallSiteId {
+    CallSiteId::new(
+        FunctionId::new(&TRUNCATED_FUNCTION.0 as *const FunctionLocation),
+        0,
+    )
+}
+
+/// The current Python callstack; see accounting.rs.
+type Callstack = accounting::Callstack<CallSiteId>;
 
-    /// Is this a Python call?
-    fn in_python(&self) -> bool {
-        !self.calls.is_empty()
+impl Callstack {
+    /// Is this inside a region started with begin_region()?
+    fn in_region(&self) -> bool {
+        self.calls
+            .iter()
+            .any(|id| id.function.get_filename() == REGION_FILENAME)
     }
 
+    #[cfg(test)]
     fn start_call(&mut self, parent_line_number: u16, callsite_id: CallSiteId) {
-        if parent_line_number != 0 {
-            if let Some(mut call) = self.calls.last_mut() {
-                call.line_number = parent_line_number;
-            }
-        }
-        self.calls.push(callsite_id);
+        self.start_call_with_max_depth(parent_line_number, callsite_id, 0, truncation_frame());
     }
 
-    fn finish_call(&mut self) {
-        self.calls.pop();
+    fn as_string(&self, to_be_post_processed: bool) -> String {
+        self.as_string_hiding(to_be_post_processed, &HiddenFrames::default(), None)
     }
 
-    fn new_line_number(&mut self, line_number: u16) {
-        if let Some(callsite_id) = self.calls.last_mut() {
-            callsite_id.line_number = line_number;
+    /// Like as_string(), but frames whose filename or function name contains
+    /// one of the hidden patterns are left out, so their memory is attributed
+    /// to their caller. The outermost frame is kept if everything is hidden.
+    /// If given, the root frame is prepended as a synthetic outermost frame.
+    fn as_string_hiding(
+        &self,
+        to_be_post_processed: bool,
+        hidden: &HiddenFrames,
+        root_frame: Option<&str>,
+    ) -> String {
+        let calls = self.calls_string(to_be_post_processed, hidden);
+        match root_frame {
+            Some(root_frame) => format!("{};{}", root_frame, calls),
+            None => calls,
         }
     }
 
-    fn as_string(&self, to_be_post_processed: bool) -> String {
+    fn calls_string(&self, to_be_post_processed: bool, hidden: &HiddenFrames) -> String {
         if self.calls.is_empty() {
-            "[No Python stack]".to_string()
+            self.no_python_stack_string()
         } else {
-            self.calls
-                .iter()
+            let path_display = PATH_DISPLAY.read().unwrap();
+            self.visible_calls(hidden)
+                .into_iter()
                 .map(|id| {
+                    let filename = id.function.get_filename();
                     if to_be_post_processed {
+                        // The post-processing marker keeps the full path, since
+                        // it's used to read the source code:
                         format!(
-                            "{filename}:{line} ({function});TB@@{filename}:{line}@@TB",
-                            filename = id.function.get_filename(),
+                            "{display}:{line} ({function});TB@@{filename}:{line}@@TB",
+                            display = path_display.shorten(&filename),
+                            filename = filename,
                             line = id.line_number,
                             function = id.function.get_function_name(),
                         )
                     } else {
                         format!(
                             "{filename}:{line} ({function})",
-                            filename = id.function.get_filename(),
+                            filename = path_display.shorten(&filename),
                             line = id.line_number,
                             function = id.function.get_function_name()
                         )
@@ -145,41 +289,269 @@ impl Callstack {
                 .join(";")
         }
     }
+
+    /// The callstack one frame per line, outermost first like a Python
+    /// traceback, with full paths and nothing hidden.
+    fn traceback(&self) -> String {
+        if self.calls.is_empty() {
+            return format!("  {}", self.no_python_stack_string());
+        }
+        self.calls
+            .iter()
+            .map(|id| {
+                format!(
+                    "  {}:{} ({})",
+                    id.function.get_filename(),
+                    id.line_number,
+                    id.function.get_function_name()
+                )
+            })
+            .join("\n")
+    }
+
+    /// How to render a callstack with no Python frames.
+    fn no_python_stack_string(&self) -> String {
+        if let Some(native_stack) = native_stack_string(&self.native_frames) {
+            native_stack
+        } else if self.native_caller != 0 {
+            // Return addresses point after the call, which may already be the
+            // next function:
+            let (symbol, _, _) = resolve_native_frame(self.native_caller.saturating_sub(1));
+            format_native_caller(symbol.as_deref(), self.native_caller)
+        } else if self.before_profiling {
+            "[Before profiling started]".to_string()
+        } else {
+            "[No Python stack]".to_string()
+        }
+    }
+
+    /// The calls whose filename and function name don't contain any of the
+    /// hidden patterns, outermost first. The outermost call is kept if
+    /// everything is hidden.
+    fn visible_calls(&self, hidden: &HiddenFrames) -> Vec<&CallSiteId> {
+        let is_visible = |id: &&CallSiteId| !hidden.hides(id);
+        if self.calls.iter().any(|id| is_visible(&id)) {
+            self.calls.iter().filter(is_visible).collect()
+        } else {
+            self.calls.iter().take(1).collect()
+        }
+    }
+
+    /// The file of the innermost visible call, i.e. the file the memory was
+    /// allocated from.
+    fn allocating_file(&self, hidden: &HiddenFrames) -> String {
+        match self.visible_calls(hidden).last() {
+            Some(id) => id.function.get_filename().to_string(),
+            None => self.no_python_stack_string(),
+        }
+    }
 }
 
 thread_local!(static THREAD_CALLSTACK: RefCell<Callstack> = RefCell::new(Callstack::new()));
 
-type CallstackId = u32;
+// Whether this thread is in the middle of recording an allocation. The C code
+// has its own reentrancy guard, but if anything allocates while we're
+// recording and the allocator is hooked some other way, e.g. from Rust, the
+// nested call would deadlock on ALLOCATIONS or double-borrow THREAD_CALLSTACK.
+thread_local!(static RECORDING: Cell<bool> = const { Cell::new(false) });
 
-/// Maps Functions to integer identifiers used in CallStacks.
-struct CallstackInterner {
-    max_id: CallstackId,
-    callstack_to_id: HashMap<Callstack, u32>,
+/// Marks the current thread as recording an allocation until dropped.
+struct RecordingGuard {}
+
+impl RecordingGuard {
+    /// None if the thread is already recording, in which case the nested
+    /// allocation must be ignored. Also None while the thread is exiting and
+    /// its thread-locals are gone.
+    fn enter() -> Option<RecordingGuard> {
+        let already_recording = RECORDING
+            .try_with(|recording| recording.replace(true))
+            .unwrap_or(true);
+        if already_recording {
+            None
+        } else {
+            Some(RecordingGuard {})
+        }
+    }
+}
+
+impl Drop for RecordingGuard {
+    fn drop(&mut self) {
+        let _ = RECORDING.try_with(|recording| recording.set(false));
+    }
+}
+
+// Called while ALLOCATIONS is locked in record_allocation(), so tests can
+// allocate from inside the recording path:
+#[cfg(test)]
+thread_local!(static WHILE_RECORDING: RefCell<Option<Box<dyn Fn()>>> = RefCell::new(None));
+
+static NEXT_THREAD_ID: AtomicU32 = AtomicU32::new(1);
+
+thread_local!(static THREAD_ID: ThreadId = new_thread_id());
+
+lazy_static! {
+    // Thread names as of each thread's first allocation. This has its own
+    // lock so that it can be filled in before ALLOCATIONS is locked.
+    static ref THREAD_NAMES: Mutex<HashMap<ThreadId, String>> = Mutex::new(HashMap::default());
+}
+
+/// Assign an id to the current thread, remembering its name. This only happens
+/// once per thread, so the name lookup stays off the hot path.
+fn new_thread_id() -> ThreadId {
+    let thread_id = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+    if let Some(name) = current_thread_name() {
+        THREAD_NAMES.lock().unwrap().insert(thread_id, name);
+    }
+    thread_id
+}
+
+#[cfg(target_os = "linux")]
+fn current_thread_name() -> Option<String> {
+    // Linux thread names are at most 16 bytes, including the terminating NUL:
+    let mut buffer = [0 as libc::c_char; 16];
+    let result = unsafe {
+        libc::pthread_getname_np(libc::pthread_self(), buffer.as_mut_ptr(), buffer.len())
+    };
+    if result != 0 {
+        return None;
+    }
+    let name = unsafe { ffi::CStr::from_ptr(buffer.as_ptr()) }.to_string_lossy();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.into_owned())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn current_thread_name() -> Option<String> {
+    None
+}
+
+/// The synthetic root frame for callstacks from the given thread, using its
+/// name if known and its id otherwise. Unknown threads get no root frame.
+fn thread_root_frame(names: &HashMap<ThreadId, String>, thread_id: ThreadId) -> Option<String> {
+    if thread_id == 0 {
+        return None;
+    }
+    Some(match names.get(&thread_id) {
+        // Semicolons separate frames, so they can't appear within one:
+        Some(name) => format!("Thread {}", name.replace(';', ",")),
+        None => format!("Thread {}", thread_id),
+    })
 }
 
-impl<'a> CallstackInterner {
-    fn new() -> Self {
-        CallstackInterner {
-            max_id: 0,
-            callstack_to_id: HashMap::default(),
+lazy_static! {
+    // Label names, indexed by LabelId - 1. Labels are never forgotten, so
+    // each name only ever gets one id:
+    static ref LABELS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+// Labels pushed by the current thread, innermost last:
+thread_local!(static THREAD_LABELS: RefCell<Vec<LabelId>> = const { RefCell::new(Vec::new()) });
+
+/// The id for the label with the given name, creating it if necessary.
+fn label_id(name: &str) -> LabelId {
+    let mut labels = LABELS.lock().unwrap();
+    let index = match labels.iter().position(|label| label == name) {
+        Some(index) => index,
+        None => {
+            labels.push(name.to_string());
+            labels.len() - 1
         }
+    };
+    index as LabelId + 1
+}
+
+/// The name of the given label, as rendered in reports.
+fn label_name(labels: &[String], label: LabelId) -> String {
+    if label == 0 {
+        "[No label]".to_string()
+    } else {
+        labels[label as usize - 1].clone()
     }
+}
 
-    /// Add a (possibly) new Function, returning its ID.
-    fn get_or_insert_id<F: FnOnce() -> ()>(
-        &mut self,
+/// The label the current thread's allocations get.
+fn current_label() -> LabelId {
+    THREAD_LABELS.with(|labels| labels.borrow().last().copied().unwrap_or(0))
+}
+
+/// Statistics about the callstack interner, for monitoring the profiler's own
+/// overhead. Matches struct in _filpreload.c.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InternerStats {
+    /// How many distinct callstacks are interned.
+    pub callstacks: usize,
+    /// How many callstack ids have been handed out, which is also the length
+    /// of the per-callstack memory usage vectors.
+    pub max_id: usize,
+    /// Roughly how many bytes the interned callstacks, and their rendered
+    /// strings, use.
+    pub estimated_bytes: usize,
+}
+
+/// Maps Functions to integer identifiers used in CallStacks.
+type CallstackInterner = accounting::CallstackInterner<HashMap<Callstack, CallstackId>>;
+
+impl<K: Eq + std::hash::Hash> IdMap for HashMap<K, CallstackId> {
+    type Key = K;
+
+    fn get_id(&self, key: &K) -> Option<CallstackId> {
+        self.get(key).copied()
+    }
+
+    fn insert_id(&mut self, key: K, id: CallstackId) {
+        self.insert(key, id);
+    }
+}
+
+impl CallstackInterner {
+    /// Render the callstack with the given id like as_string_hiding(),
+    /// reusing the result of previous calls.
+    fn rendered_string(
+        &self,
+        callstack_id: CallstackId,
         callstack: &Callstack,
-        call_on_new: F,
-    ) -> CallstackId {
-        let max_id = &mut self.max_id;
-        if let Some(result) = self.callstack_to_id.get(callstack) {
-            *result
-        } else {
-            let new_id = *max_id;
-            *max_id += 1;
-            self.callstack_to_id.insert(callstack.clone(), new_id);
-            call_on_new();
-            new_id
+        to_be_post_processed: bool,
+        hidden: &HiddenFrames,
+        root_frame: Option<&str>,
+    ) -> String {
+        let calls = self.cached_rendering(callstack_id, to_be_post_processed, || {
+            callstack.as_string_hiding(to_be_post_processed, hidden, None)
+        });
+        match root_frame {
+            Some(root_frame) => format!("{};{}", root_frame, calls),
+            None => calls,
+        }
+    }
+
+    /// How many callstacks there are and roughly how much memory they use.
+    fn stats(&self) -> InternerStats {
+        let entry_overhead =
+            std::mem::size_of::<Callstack>() + std::mem::size_of::<CallstackId>() + 1;
+        let callstack_bytes: usize = self
+            .callstack_to_id
+            .keys()
+            .map(|callstack| {
+                entry_overhead
+                    + callstack.calls.capacity() * std::mem::size_of::<CallSiteId>()
+                    + callstack.native_frames.capacity() * std::mem::size_of::<usize>()
+            })
+            .sum();
+        let rendered_entry_overhead =
+            std::mem::size_of::<(CallstackId, bool)>() + std::mem::size_of::<String>() + 1;
+        let rendered_bytes: usize = self
+            .rendered
+            .borrow()
+            .values()
+            .map(|rendered| rendered_entry_overhead + rendered.capacity())
+            .sum();
+        InternerStats {
+            callstacks: self.callstack_to_id.len(),
+            max_id: self.max_id as usize,
+            estimated_bytes: callstack_bytes + rendered_bytes,
         }
     }
 
@@ -193,808 +565,7888 @@ impl<'a> CallstackInterner {
     }
 }
 
-const MIB: usize = 1024 * 1024;
-const HIGH_32BIT: u32 = 1 << 31;
+impl UsageVector for ImVector<usize> {
+    fn zeroed(n: usize) -> Self {
+        ImVector::from(vec![0; n])
+    }
+
+    fn push_callstack(&mut self) {
+        self.push_back(0);
+    }
+}
+
+impl Spillable for Allocation {
+    /// Older allocations are spilled first, and smaller ones first within a
+    /// generation; they're the least likely to be freed soon.
+    fn spill_priority(&self) -> u64 {
+        ((self.generation as u64) << 32) | self.compressed_size() as u64
+    }
+}
+
+/// An anonymous mmap()ed range.
+#[cfg(not(feature = "no-mmap-tracking"))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct AnonMmap {
+    callstack_id: CallstackId,
+    // Whether it was mmap()ed with MAP_HUGETLB, i.e. backed by 2MB or 1GB huge
+    // pages that are reserved up front rather than faulted in as used:
+    huge_pages: bool,
+    generation: Generation,
+}
+
+/// What record_allocation() is recording.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum AllocationKind {
+    Malloc,
+    AnonMmap,
+    HugePageMmap,
+}
+
+/// By default, this much memory is reserved, to be released if we run out of
+/// memory so we have room to dump the report.
+const DEFAULT_SPARE_MEMORY_SIZE: usize = 16 * MIB;
+
+/// By default, on out-of-memory we free() Python allocations larger than this
+/// many bytes, to make room for dumping the report.
+const DEFAULT_OOM_FREE_THRESHOLD: usize = 300000;
+
+/// How many recent allocation events we keep around for crash forensics.
+const RECENT_EVENTS_CAPACITY: usize = 1024;
+
+/// How many recently free()d addresses we remember, to catch double free()s.
+const RECENTLY_FREED_CAPACITY: usize = 4096;
+
+/// Don't warn about untracked free()s until we've seen this many free()s, since
+/// allocations from before tracking started are freed early on.
+const UNTRACKED_FREES_MIN_FREES: usize = 100_000;
+
+/// Warn if more than this fraction of free()s are of untracked addresses.
+const UNTRACKED_FREES_WARNING_RATIO: f64 = 0.5;
+
+/// How many memory usage samples the timeline keeps.
+const TIMELINE_CAPACITY: usize = 100_000;
+
+/// A call has to be invoked at least this often before we suspect it leaks.
+const LEAK_MIN_INVOCATIONS: usize = 3;
+
+/// How many leak suspects get reported.
+const LEAK_SUSPECTS_LIMIT: usize = 20;
+
+/// Periodic snapshots cycle through this many sets of files, so long-running
+/// processes don't fill up the disk.
+const SNAPSHOT_ROTATION: usize = 3;
+
+/// Whether finish_call() needs to do leak detection. This is separate from
+/// AllocationTracker so finish_call() doesn't have to take the lock otherwise.
+static LEAK_DETECTION_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether calls' peak memory is tracked, see callstack.rs. Checked without
+/// the lock, so calls don't pay for it otherwise.
+static CALL_PEAKS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether allocations with no Python callstack get a native backtrace. This is
+/// checked before ALLOCATIONS is locked, since that's when backtraces are
+/// captured.
+static CAPTURE_NATIVE_STACKS: AtomicBool = AtomicBool::new(false);
+
+/// Whether allocations with no Python callstack are attributed to the native
+/// code that called malloc(), rendered as its symbol name. This is much
+/// cheaper than capturing a whole native backtrace.
+static RECORD_NATIVE_CALLERS: AtomicBool = AtomicBool::new(false);
+
+/// Whether any Python call has started since the last reset. Allocations with
+/// no Python callstack before then are startup memory, e.g. from importing
+/// the profiler, rather than memory used by code that isn't Python.
+static PROFILING_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Native backtraces are truncated to this many frames.
+const MAX_NATIVE_FRAMES: usize = 64;
+
+/// Calls deeper than this are collapsed into a single truncation frame, so deep
+/// recursion doesn't create huge numbers of distinct callstacks. 0 means no
+/// limit.
+static MAX_STACK_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// How much gets written to stderr. At 0 only warnings and errors are
+/// mentioned; at NORMAL_VERBOSITY, the default, so is every report
+/// that gets written; DEBUG_VERBOSITY and above add debugging messages.
+/// Nothing is written while allocations are being tracked unless debugging.
+/// This is process-wide and survives reset(); it starts out as FIL_VERBOSITY,
+/// if that's set.
+static VERBOSITY: AtomicUsize = AtomicUsize::new(NORMAL_VERBOSITY);
+
+/// The default verbosity, at which written reports are mentioned.
+const NORMAL_VERBOSITY: usize = 1;
+
+/// The verbosity at which debugging messages are written.
+const DEBUG_VERBOSITY: usize = 2;
+
+/// Mention something that happened, e.g. a report getting written, unless
+/// we've been told to be quiet.
+fn info_message(message: impl FnOnce() -> String) {
+    if VERBOSITY.load(Ordering::Relaxed) >= NORMAL_VERBOSITY {
+        eprintln!("=fil-profile= {}", message());
+    }
+}
+
+/// Write a debugging message, if the verbosity is high enough. The message is
+/// only created if it will be written, so at the default verbosity this is
+/// just an atomic load.
+fn debug_message(message: impl FnOnce() -> String) {
+    write_debug_message(
+        &mut std::io::stderr(),
+        VERBOSITY.load(Ordering::Relaxed),
+        message,
+    );
+}
+
+fn write_debug_message<W: Write>(out: &mut W, verbosity: usize, message: impl FnOnce() -> String) {
+    if verbosity >= DEBUG_VERBOSITY {
+        // Debugging output is best effort:
+        let _ = writeln!(out, "=fil-profile= DEBUG: {}", message());
+    }
+}
+
+/// Set by the SIGUSR2 handler to ask for a dump of current allocations. Signal
+/// handlers can't safely allocate or take locks, so all the handler does is set
+/// this flag; the next allocation notices it and does the actual dump, at which
+/// point we're inside our own code and the reentrancy guard is set.
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// The kind of memory operation recorded in an AllocationEvent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum EventKind {
+    Allocation,
+    Free,
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    AnonMmap,
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    AnonMunmap,
+}
 
-/// A specific call to malloc()/calloc().
+/// A single allocation or free, as recorded in the recent events ring buffer.
 #[derive(Clone, Copy, Debug, PartialEq)]
-struct Allocation {
+struct AllocationEvent {
+    kind: EventKind,
+    address: usize,
+    size: usize,
     callstack_id: CallstackId,
-    // If high bit is set, this is MiBs (without the high bit being meaningful).
-    // Otherwise, it's bytes. We only store MiBs for allocations larger than 2
-    // ** 31 bytes (2GB), which means the loss of resolution isn't meaningful.
-    // This compression allows us to reduce memory overhead from tracking
-    // allocations.
-    compressed_size: u32,
-}
-
-impl Allocation {
-    fn new(callstack_id: CallstackId, size: libc::size_t) -> Self {
-        let compressed_size = if size >= HIGH_32BIT as usize {
-            // Rounding division by MiB, plus the high bit:
-            (((size + MIB / 2) / MIB) as u32) | HIGH_32BIT
-        } else {
-            size as u32
-        };
-        Allocation {
-            callstack_id,
-            compressed_size,
-        }
+}
+
+/// Which hooks feed the tracker.
+///
+/// A custom allocator like jemalloc or tcmalloc gets its memory from the
+/// operating system with mmap(), and then hands it out in smaller chunks. If
+/// the program calls that allocator's functions directly, rather than via
+/// malloc(), only the mmap()s are visible to us, so `Mmap` is the only mode
+/// that sees all of its memory, albeit attributed to whichever callstack
+/// happened to need a new chunk. If the allocator's chunks are visible via
+/// both hooks, tracking `Both` would count the same memory twice.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AllocatorMode {
+    /// Only malloc() and friends; memory is attributed precisely, but direct
+    /// mmap()s aren't counted at all.
+    Malloc,
+    /// Only anonymous mmap()s.
+    Mmap,
+    /// Everything; the right choice unless a custom allocator is in use.
+    #[default]
+    Both,
+}
+
+impl AllocatorMode {
+    fn tracks_malloc(self) -> bool {
+        self != AllocatorMode::Mmap
     }
 
-    fn size(&self) -> libc::size_t {
-        if self.compressed_size >= HIGH_32BIT {
-            (self.compressed_size - HIGH_32BIT) as libc::size_t * MIB
-        } else {
-            self.compressed_size as libc::size_t
-        }
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    fn tracks_mmap(self) -> bool {
+        self != AllocatorMode::Malloc
+    }
+}
+
+/// Allocations within `tolerance` bytes of `size` get reported with their
+/// callstack as they happen, for finding out where a buffer of known size
+/// comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Watchpoint {
+    size: usize,
+    tolerance: usize,
+    // How many allocations matched:
+    hits: usize,
+}
+
+impl Watchpoint {
+    fn matches(&self, size: usize) -> bool {
+        size.abs_diff(self.size) <= self.tolerance
     }
 }
 
 /// The main data structure tracking everything.
 struct AllocationTracker {
-    // malloc()/calloc():
-    current_allocations: HashMap<usize, Allocation>,
+    // malloc()/calloc(). Past a configurable limit, some of these get spilled
+    // to disk:
+    current_allocations: SpillMap<Allocation>,
+    // Growth from realloc() in a different callstack than the original
+    // allocation, keyed by the same address as current_allocations:
+    realloc_extensions: HashMap<usize, Allocation>,
     // anonymous mmap(), i.e. not file backed:
-    current_anon_mmaps: RangeMap<CallstackId>,
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    current_anon_mmaps: RangeMap<AnonMmap>,
+    // If set, this often adjust mmap() usage to only count resident memory,
+    // as reported by /proc/self/smaps:
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    resident_mmaps_interval: Option<Duration>,
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    last_resident_update: Instant,
+    // How many bytes of each callstack's mmap()s aren't resident, and so were
+    // taken out of its memory usage at the last adjustment:
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    nonresident_mmap_bytes: HashMap<CallstackId, usize>,
 
     // Map CallstackIds to Callstacks, so we can store the former and save
     // memory:
     interner: CallstackInterner,
 
+    // Only 1 in sampling_rate allocations is recorded, with its size scaled
+    // up by sampling_rate. When this is more than 1, current_allocations
+    // only holds a sample of the actual allocations, and memory usage is a
+    // statistical estimate.
+    sampling_rate: usize,
+    // Allocations seen since the last sampled one:
+    allocations_since_sample: usize,
+    // If set, only malloc()s from this thread are recorded:
+    tracked_thread: Option<ThreadId>,
+    // If non-zero, at most this many malloc()s are stored individually. Past
+    // that, new ones still count towards memory usage, but their free()s can
+    // only be estimated, so the profile is marked as truncated:
+    max_allocations: usize,
+    truncated: bool,
+    // Bytes of each callstack's malloc()s that weren't stored because of
+    // max_allocations, and how many such allocations there are:
+    unstored_bytes: HashMap<CallstackId, usize>,
+    unstored_allocations: usize,
+
+    // free()s of addresses we were and weren't tracking. A high rate of the
+    // latter suggests we're missing an allocation API:
+    tracked_frees: usize,
+    untracked_frees: usize,
+    warned_about_untracked_frees: bool,
+    // Recently free()d addresses; an untracked free() of one of these is
+    // probably a double free(). A tracked allocation at the same address
+    // takes precedence, so reuse of the address doesn't need to be noted:
+    recently_freed: RecentSet<usize>,
+    double_frees: usize,
+
     // Both malloc() and mmap():
-    current_memory_usage: ImVector<usize>, // Map CallstackId -> total memory usage
-    peak_memory_usage: ImVector<usize>,    // Map CallstackId -> total memory usage
-    current_allocated_bytes: usize,
-    peak_allocated_bytes: usize,
+    // Memory usage in total and by CallstackId, now and at the peak:
+    usage: MemoryUsage<ImVector<usize>>,
+    // Every byte malloc()ed so far, ignoring free()s, for working out the
+    // allocation rate. Wraps on overflow, which only matters on 32-bit
+    // platforms; differences are still right if taken with wrapping_sub():
+    total_allocated_bytes: usize,
+    // How much of the above is huge page mmap()s:
+    current_huge_page_bytes: usize,
+    peak_huge_page_bytes: usize,
+    // Whether the timeline has yet to hear about the current peak; it's told
+    // once memory starts going down, so it only gets the high-water mark:
+    timeline_peak_pending: bool,
+    // The most recent allocation events, for diagnosing crashes:
+    recent_events: RingBuffer<AllocationEvent>,
     // Some spare memory in case we run out:
     spare_memory: Vec<u8>,
+    // On out-of-memory, Python allocations larger than this get free()d:
+    oom_free_threshold: usize,
+    // How flamegraphs get rendered:
+    dump_options: DumpOptions,
+    // Which of the malloc() and mmap() hooks get recorded:
+    allocator_mode: AllocatorMode,
+    // If enabled, memory usage over time:
+    timeline: Option<Timeline>,
+    // If enabled, find Python calls that leak memory each time they're called:
+    leak_detector: Option<LeakDetector<Callstack>>,
+    // For leak detection, for each thread and each depth of its callstack, the
+    // callstacks allocated by the call at that depth since it started:
+    leak_touched: HashMap<ThreadId, Vec<HashSet<CallstackId>>>,
+    // For leak detection, all callstacks ever allocated by each call:
+    leak_callstacks: HashMap<Callstack, HashSet<CallstackId>>,
+    // If set, report allocations of about this size as they happen:
+    watchpoint: Option<Watchpoint>,
+    // If enabled, every event is also written to a file as it happens:
+    event_log: Option<EventLog>,
+    // Named snapshots of usage.current, to compare against later:
+    baselines: HashMap<String, ImVector<usize>>,
+    // If set, dump current allocations this often:
+    snapshot_interval: Option<Duration>,
+    last_snapshot: Instant,
+    snapshots_written: usize,
+    // Bumped on every dump and snapshot:
+    generation: Generation,
+    // When tracking started; allocation timestamps are relative to this:
+    started: Instant,
+    // Size times milliseconds live of freed allocations, per callstack:
+    byte_milliseconds: HashMap<CallstackId, u128>,
     // Default directory to write out data lacking other info:
     default_path: String,
+    // Where reports go if their directory can't be created:
+    fallback_path: PathBuf,
 }
 
-impl<'a> AllocationTracker {
-    fn new(default_path: String) -> AllocationTracker {
-        AllocationTracker {
-            current_allocations: HashMap::default(),
-            current_anon_mmaps: RangeMap::new(),
-            interner: CallstackInterner::new(),
-            current_memory_usage: ImVector::new(),
-            peak_memory_usage: ImVector::new(),
-            current_allocated_bytes: 0,
-            peak_allocated_bytes: 0,
-            spare_memory: Vec::with_capacity(16 * 1024 * 1024),
-            default_path,
-        }
+/// Which frames get left out of rendered callstacks, with their memory
+/// attributed to their callers instead.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct HiddenFrames {
+    // Frames whose filename or function name contains any of these:
+    patterns: Vec<String>,
+    // Frames whose filename starts with any of these, e.g. the standard
+    // library's directory:
+    prefixes: Vec<String>,
+}
+
+impl HiddenFrames {
+    fn hides(&self, id: &CallSiteId) -> bool {
+        let filename = id.function.get_filename();
+        self.prefixes
+            .iter()
+            .any(|prefix| filename.starts_with(prefix.as_str()))
+            || self.patterns.iter().any(|pattern| {
+                filename.contains(pattern.as_str())
+                    || id.function.get_function_name().contains(pattern.as_str())
+            })
     }
+}
 
-    /// Check if a new peak has been reached:
-    fn check_if_new_peak(&mut self) {
-        if self.current_allocated_bytes > self.peak_allocated_bytes {
-            self.peak_allocated_bytes = self.current_allocated_bytes;
-            self.peak_memory_usage
-                .clone_from(&self.current_memory_usage);
+/// Options controlling how flamegraphs are rendered.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct DumpOptions {
+    // SVG width in pixels; None means inferno's default:
+    image_width: Option<usize>,
+    // Frames left out of rendered callstacks:
+    hidden_frames: HiddenFrames,
+    // Callstacks using less than this fraction of the total get merged into
+    // a single "other" callstack:
+    min_fraction: f64,
+    // How frames get colored:
+    colors: FlamegraphColors,
+    // Whether callstacks start with a frame naming their thread:
+    thread_root_frames: bool,
+    // Whether to only include callstacks inside a region:
+    regions_only: bool,
+    // What format reports get written in:
+    format: OutputFormat,
+    // Flamegraphs with more lines than this only get the largest callstacks,
+    // since inferno gets very slow on huge inputs; 0 means no limit:
+    max_lines: usize,
+    // What the numbers in the flamegraph measure:
+    units: FlamegraphUnits,
+    // Fonts and frame sizes:
+    style: FlamegraphStyle,
+    // Whether every callstack that ever allocated is included in text
+    // reports, with 0 bytes if it's using nothing; flamegraphs leave them
+    // out, since they'd have no width:
+    inactive_callstacks: bool,
+    // Whether flamegraphs get a dark background, for dark-mode dashboards:
+    dark_mode: bool,
+}
+
+/// How flamegraph text and frames are sized, e.g. bigger for slides.
+#[derive(Clone, Debug, PartialEq)]
+struct FlamegraphStyle {
+    font_size: usize,
+    frame_height: usize,
+    font_type: String,
+}
+
+impl Default for FlamegraphStyle {
+    fn default() -> Self {
+        FlamegraphStyle {
+            font_size: 16,
+            frame_height: 22,
+            font_type: "mono".to_string(),
         }
     }
+}
 
-    fn add_memory_usage(&mut self, callstack_id: CallstackId, bytes: usize) {
-        self.current_allocated_bytes += bytes;
-        let index = callstack_id as usize;
-        self.current_memory_usage[index] += bytes;
-    }
+/// What the numbers in a flamegraph measure.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum FlamegraphUnits {
+    #[default]
+    Bytes,
+    /// Size times how many seconds memory was allocated for.
+    ByteSeconds,
+}
 
-    fn remove_memory_usage(&mut self, callstack_id: CallstackId, bytes: usize) {
-        self.current_allocated_bytes -= bytes;
-        let index = callstack_id as usize;
-        // TODO what if goes below zero? add a check I guess, in case of bugs.
-        self.current_memory_usage[index] -= bytes;
+impl FlamegraphUnits {
+    fn count_name(self) -> &'static str {
+        match self {
+            FlamegraphUnits::Bytes => "bytes",
+            FlamegraphUnits::ByteSeconds => "byte-seconds",
+        }
     }
 
-    fn get_callstack_id(&mut self, callstack: &Callstack) -> CallstackId {
-        let current_memory_usage = &mut self.current_memory_usage;
-        self.interner
-            .get_or_insert_id(callstack, || current_memory_usage.push_back(0))
+    /// The total, for the flamegraph title.
+    fn format_total(self, total: usize) -> String {
+        let mib = total as f64 / (1024.0 * 1024.0);
+        match self {
+            FlamegraphUnits::Bytes => format!("{:.1} MiB", mib),
+            FlamegraphUnits::ByteSeconds => format!("{:.1} MiB-seconds", mib),
+        }
     }
+}
 
-    /// Add a new allocation based off the current callstack.
-    fn add_allocation(&mut self, address: usize, size: libc::size_t, callstack: &Callstack) {
-        let callstack_id = self.get_callstack_id(callstack);
-        let alloc = Allocation::new(callstack_id, size);
-        let compressed_size = alloc.size();
-        self.current_allocations.insert(address, alloc);
-        self.add_memory_usage(callstack_id, compressed_size as usize);
-    }
+/// The format reports get written in. The collapsed-stack .prof file is
+/// always written, since everything else is rendered from it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum OutputFormat {
+    /// Flamegraph SVGs, plus an HTML page wrapping them where relevant.
+    #[default]
+    Svg,
+    /// A JSON object with the total bytes and a list of callstacks.
+    Json,
+    /// A CSV file with a bytes column and a stack column.
+    Csv,
+    /// Just the collapsed-stack .prof file.
+    Collapsed,
+}
 
-    /// Free an existing allocation.
-    fn free_allocation(&mut self, address: usize) {
-        // Before we reduce memory, let's check if we've previously hit a peak:
-        self.check_if_new_peak();
-        // Possibly this allocation doesn't exist; that's OK! It can if e.g. we
-        // didn't capture an allocation for some reason.
-        if let Some(removed) = self.current_allocations.remove(&address) {
-            self.remove_memory_usage(removed.callstack_id, removed.size());
+impl OutputFormat {
+    /// Parse a format name as used in FIL_FORMAT, ignoring case.
+    fn from_name(name: &str) -> Option<OutputFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "svg" => Some(OutputFormat::Svg),
+            "json" => Some(OutputFormat::Json),
+            "csv" => Some(OutputFormat::Csv),
+            "collapsed" => Some(OutputFormat::Collapsed),
+            _ => None,
         }
     }
 
-    /// Add a new anonymous mmap() based of the current callstack.
-    fn add_anon_mmap(&mut self, address: usize, size: libc::size_t, callstack: &Callstack) {
-        let callstack_id = self.get_callstack_id(callstack);
-        self.current_anon_mmaps.add(address, size, callstack_id);
-        self.add_memory_usage(callstack_id, size);
+    /// The file extension for formats that are written as a table of
+    /// callstacks.
+    fn table_extension(self) -> Option<&'static str> {
+        match self {
+            OutputFormat::Json => Some("json"),
+            OutputFormat::Csv => Some("csv"),
+            OutputFormat::Svg | OutputFormat::Collapsed => None,
+        }
     }
 
-    fn free_anon_mmap(&mut self, address: usize, size: libc::size_t) {
-        // Before we reduce memory, let's check if we've previously hit a peak:
-        self.check_if_new_peak();
-        // Now remove, and update totoal memory tracking:
-        for (callstack_id, removed) in self.current_anon_mmaps.remove(address, size) {
-            self.remove_memory_usage(callstack_id, removed);
+    /// The format named by FIL_FORMAT, or the default if it's unset. Unknown
+    /// names get a warning and the default.
+    fn from_env() -> OutputFormat {
+        match std::env::var("FIL_FORMAT") {
+            Ok(name) => OutputFormat::from_name(&name).unwrap_or_else(|| {
+                eprintln!(
+                    "=fil-profile= Warning: unknown FIL_FORMAT {:?}, using svg.",
+                    name
+                );
+                OutputFormat::default()
+            }),
+            Err(_) => OutputFormat::default(),
         }
     }
+}
 
-    /// Combine Callstacks and make them human-readable. Duplicate callstacks
-    /// have their allocated memory summed.
-    fn combine_callstacks(
-        &mut self,
-        // If false, will do the current allocations:
-        peak: bool,
-    ) -> std::collections::hash_map::IntoIter<CallstackId, usize> {
-        // First, make sure peaks are correct:
-        self.check_if_new_peak();
+/// How flamegraph frames get colored.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum FlamegraphColors {
+    /// Wider frames are redder.
+    #[default]
+    BySize,
+    /// Each top-level package (numpy, pandas, your own code...) gets its own
+    /// hue, so it's easy to see which code is responsible for what.
+    ByModule,
+}
 
-        let mut by_call: collections::HashMap<CallstackId, usize> = collections::HashMap::new();
+/// The filename of a rendered frame, either "file:line (function)" or the
+/// "TB@@file:line@@TB" marker used for post-processing.
+fn frame_filename(frame: &str) -> Option<&str> {
+    let location = match frame.strip_prefix("TB@@") {
+        Some(marker) => marker.strip_suffix("@@TB")?,
+        None => frame.rsplit_once(" (")?.0,
+    };
+    Some(location.rsplit_once(':')?.0)
+}
 
-        if peak {
-            for i in 0..self.peak_memory_usage.len() {
-                let size = self.peak_memory_usage[i];
-                if size > 0 {
-                    by_call.insert(i as CallstackId, size);
-                }
-            }
-        } else {
-            for allocation in self.current_allocations.values() {
-                let entry = by_call.entry(allocation.callstack_id).or_insert(0);
-                *entry += allocation.size();
-            }
-            for (size, callstack_id) in self.current_anon_mmaps.as_hashmap().values() {
-                let entry = by_call.entry(**callstack_id).or_insert(0);
-                *entry += size;
-            }
+/// The top-level package a file belongs to: the first directory after
+/// site-packages (or "<venv>", if that's collapsed) for installed packages,
+/// otherwise the directory the file is in.
+fn module_of(filename: &str) -> &str {
+    for packages in &["site-packages/", "dist-packages/", "<venv>/"] {
+        if let Some((_, relative)) = filename.rsplit_once(packages) {
+            return relative.split('/').next().unwrap_or(relative);
         }
-
-        // Convert callstacks to be human-readable:
-        by_call.into_iter()
     }
-
-    /// Dump all callstacks in peak memory usage to various files describing the
-    /// memory usage.
-    fn dump_peak_to_flamegraph(&mut self, path: &str) {
-        self.dump_to_flamegraph(path, true, "peak-memory", "Peak Tracked Memory Usage", true);
+    match filename.rsplit_once('/') {
+        Some((directory, _)) => directory.rsplit('/').next().unwrap_or(directory),
+        None => filename,
     }
+}
 
-    fn to_lines(
-        &mut self,
-        peak: bool,
-        to_be_post_processed: bool,
-    ) -> impl Iterator<Item = String> + '_ {
-        let by_call = self.combine_callstacks(peak);
-        let id_to_callstack = self.interner.get_reverse_map();
-        by_call.map(move |(callstack_id, size)| {
-            format!(
-                "{} {}",
-                id_to_callstack
-                    .get(&callstack_id)
-                    .unwrap()
-                    .as_string(to_be_post_processed),
-                size,
-            )
-        })
+/// A color for the module, the same on every run.
+fn module_color(module: &str) -> flamegraph::color::Color {
+    // FNV-1a, since std's hashers aren't guaranteed to be stable:
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in module.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
     }
+    // Convert a hue with fixed saturation and value to RGB:
+    let hue = (hash % 360) as f64 / 60.0;
+    let (value, saturation) = (230.0, 0.55);
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let base = value - chroma;
+    flamegraph::color::Color {
+        r: (r + base) as u8,
+        g: (g + base) as u8,
+        b: (b + base) as u8,
+    }
+}
 
-    fn dump_to_flamegraph(
-        &mut self,
-        path: &str,
-        peak: bool,
-        base_filename: &str,
-        title: &str,
-        to_be_post_processed: bool,
-    ) {
-        eprintln!("=fil-profile= Preparing to write to {}", path);
-        let directory_path = Path::new(path);
+/// A palette coloring every frame in the given collapsed-stack lines by its
+/// module.
+fn module_palette(lines: &str) -> flamegraph::color::PaletteMap {
+    let mut palette = flamegraph::color::PaletteMap::default();
+    for line in lines.lines() {
+        let callstack = line
+            .rsplit_once(' ')
+            .map_or(line, |(callstack, _)| callstack);
+        for frame in callstack.split(';') {
+            if let Some(filename) = frame_filename(frame) {
+                palette.insert(frame, module_color(module_of(filename)));
+            }
+        }
+    }
+    palette
+}
+
+/// The callstack that callstacks below DumpOptions::min_fraction get merged
+/// into.
+const SMALL_CALLSTACKS: &str = "[Other small callstacks]";
+
+/// The background of dark mode flamegraphs.
+const DARK_MODE_BACKGROUND: flamegraph::color::Color = flamegraph::color::Color {
+    r: 0x1e,
+    g: 0x1e,
+    b: 0x1e,
+};
+
+/// inferno always renders text black, which is fine on frames but unreadable
+/// on a dark background, so in dark mode the text outside frames is
+/// overridden. This goes right after inferno's own rule for text in the SVG's
+/// stylesheet.
+const DARK_MODE_CSS: &str = "
+#title, #subtitle, #details, #unzoom, #search, #matched { fill:rgb(220,220,220); }
+#frames > *:hover { stroke:white; }";
+
+/// By default, only the largest this many callstacks get rendered into a
+/// flamegraph.
+const DEFAULT_MAX_FLAMEGRAPH_LINES: usize = 50_000;
+
+/// Keep the largest callstacks, merging the rest into a single
+/// SMALL_CALLSTACKS entry so there are at most `max_lines` lines and the total
+/// is unchanged. Returns the new lines and how many callstacks were merged.
+fn limit_lines(lines: &str, max_lines: usize) -> (String, usize) {
+    let mut by_size: Vec<(&str, usize)> = collapsed::parse_collapsed(lines).into_iter().collect();
+    // Largest first, with ties broken by callstack so the result is
+    // reproducible:
+    by_size.sort_unstable_by(|(a, a_size), (b, b_size)| b_size.cmp(a_size).then(a.cmp(b)));
+    let keep = if by_size.len() <= max_lines {
+        by_size.len()
+    } else {
+        // One line is left for the merged callstacks:
+        max_lines.saturating_sub(1)
+    };
+    let mut merged = 0;
+    let mut by_string: HashMap<String, usize> = HashMap::default();
+    for (i, (callstack, size)) in by_size.into_iter().enumerate() {
+        let callstack = if i < keep {
+            callstack
+        } else {
+            merged += 1;
+            SMALL_CALLSTACKS
+        };
+        *by_string.entry(callstack.to_string()).or_insert(0) += size;
+    }
+    let limited = sorted_lines(by_string)
+        .map(|line| format!("{}\n", line))
+        .collect();
+    (limited, merged)
+}
 
-        if !directory_path.exists() {
-            fs::create_dir_all(directory_path)
-                .expect("=fil-profile= Couldn't create the output directory.");
-        } else if !directory_path.is_dir() {
-            panic!("=fil-profile= Output path must be a directory.");
+/// Merge callstacks using less than the given fraction of the total into a
+/// single SMALL_CALLSTACKS entry, so the total is unchanged.
+fn merge_small_callstacks(by_string: &mut HashMap<String, usize>, min_fraction: f64) {
+    if min_fraction <= 0.0 {
+        return;
+    }
+    let total: usize = by_string.values().sum();
+    let min_bytes = total as f64 * min_fraction;
+    let mut small_bytes = 0;
+    by_string.retain(|_, size| {
+        // Inactive callstacks are there to be listed, so they're kept:
+        if *size > 0 && (*size as f64) < min_bytes {
+            small_bytes += *size;
+            false
+        } else {
+            true
         }
+    });
+    if small_bytes > 0 {
+        *by_string.entry(SMALL_CALLSTACKS.to_string()).or_insert(0) += small_bytes;
+    }
+}
 
-        let raw_path = directory_path
-            .join(format!("{}.prof", base_filename))
-            .to_str()
-            .unwrap()
-            .to_string();
+/// Write callstacks to the event log, rendered the way dumps render them.
+fn write_callstacks<'a>(
+    log: &mut EventLog,
+    interner: &CallstackInterner,
+    hidden: &HiddenFrames,
+    callstacks: impl Iterator<Item = (CallstackId, &'a Callstack)>,
+) -> std::io::Result<()> {
+    for (callstack_id, callstack) in callstacks {
+        let rendered = interner.rendered_string(callstack_id, callstack, false, hidden, None);
+        log.write_callstack(callstack_id, &rendered)?;
+    }
+    Ok(())
+}
 
-        if let Err(e) = write_lines(self.to_lines(peak, to_be_post_processed), &raw_path) {
-            eprintln!("=fil-profile= Error writing raw profiling data: {}", e);
+/// Lines in the format flamegraph expects, sorted by callstack so the same
+/// memory usage always produces the same file, and so profiles can be diffed.
+fn sorted_lines(by_string: HashMap<String, usize>) -> impl Iterator<Item = String> {
+    let mut lines: Vec<(String, usize)> = by_string.into_iter().collect();
+    lines.sort_unstable();
+    lines
+        .into_iter()
+        .map(|(callstack, size)| format!("{} {}", callstack, size))
+}
+
+impl AllocationTracker {
+    fn new(default_path: String, spare_memory_size: usize) -> AllocationTracker {
+        AllocationTracker {
+            current_allocations: SpillMap::new(),
+            realloc_extensions: HashMap::default(),
+            #[cfg(not(feature = "no-mmap-tracking"))]
+            current_anon_mmaps: RangeMap::new(),
+            #[cfg(not(feature = "no-mmap-tracking"))]
+            resident_mmaps_interval: None,
+            #[cfg(not(feature = "no-mmap-tracking"))]
+            last_resident_update: Instant::now(),
+            #[cfg(not(feature = "no-mmap-tracking"))]
+            nonresident_mmap_bytes: HashMap::default(),
+            interner: CallstackInterner::new(),
+            sampling_rate: 1,
+            tracked_thread: None,
+            max_allocations: 0,
+            truncated: false,
+            unstored_bytes: HashMap::default(),
+            unstored_allocations: 0,
+            allocations_since_sample: 0,
+            tracked_frees: 0,
+            untracked_frees: 0,
+            warned_about_untracked_frees: false,
+            recently_freed: RecentSet::new(RECENTLY_FREED_CAPACITY),
+            double_frees: 0,
+            usage: MemoryUsage::new(0),
+            total_allocated_bytes: 0,
+            current_huge_page_bytes: 0,
+            peak_huge_page_bytes: 0,
+            timeline_peak_pending: false,
+            recent_events: RingBuffer::new(RECENT_EVENTS_CAPACITY),
+            spare_memory: Vec::with_capacity(spare_memory_size),
+            oom_free_threshold: DEFAULT_OOM_FREE_THRESHOLD,
+            dump_options: DumpOptions {
+                max_lines: DEFAULT_MAX_FLAMEGRAPH_LINES,
+                ..Default::default()
+            },
+            generation: 0,
+            started: Instant::now(),
+            fallback_path: std::env::temp_dir(),
+            byte_milliseconds: HashMap::default(),
+            allocator_mode: AllocatorMode::default(),
+            timeline: None,
+            baselines: HashMap::default(),
+            snapshot_interval: None,
+            last_snapshot: Instant::now(),
+            snapshots_written: 0,
+            leak_detector: None,
+            leak_touched: HashMap::default(),
+            leak_callstacks: HashMap::default(),
+            watchpoint: None,
+            event_log: None,
+            default_path,
         }
-        let svg_path = directory_path
-            .join(format!("{}.svg", base_filename))
-            .to_str()
-            .unwrap()
-            .to_string();
-        match write_flamegraph(
-            &raw_path,
-            &svg_path,
-            self.peak_allocated_bytes,
-            false,
-            title,
-            to_be_post_processed,
-        ) {
-            Ok(_) => {
-                eprintln!(
-                    "=fil-profile= Wrote memory usage flamegraph to {}",
-                    svg_path
-                );
-            }
-            Err(e) => {
-                eprintln!("=fil-profile= Error writing SVG: {}", e);
+    }
+
+    /// Forget all allocations and memory usage, but keep interned callstacks
+    /// (and settings) around, so that repeated profiling runs don't have to
+    /// intern the same callstacks again.
+    fn reset_keeping_interner(&mut self, default_path: String) {
+        self.current_allocations.clear();
+        self.realloc_extensions.clear();
+        self.byte_milliseconds.clear();
+        self.truncated = false;
+        self.unstored_bytes.clear();
+        self.unstored_allocations = 0;
+        #[cfg(not(feature = "no-mmap-tracking"))]
+        {
+            self.current_anon_mmaps = RangeMap::new();
+            self.nonresident_mmap_bytes.clear();
+        }
+        self.recently_freed = RecentSet::new(RECENTLY_FREED_CAPACITY);
+        // Memory usage is indexed by callstack id, so it needs an entry for
+        // each interned callstack:
+        self.usage = MemoryUsage::new(self.interner.max_id as usize);
+        self.total_allocated_bytes = 0;
+        self.current_huge_page_bytes = 0;
+        self.peak_huge_page_bytes = 0;
+        self.timeline_peak_pending = false;
+        // Baselines refer to allocations that are now forgotten:
+        self.baselines.clear();
+        self.default_path = default_path;
+    }
+
+    /// Forget interned callstacks that nothing refers to anymore: no current
+    /// or peak memory usage, no baseline usage, no allocation and no recent
+    /// event. Otherwise every callstack ever seen is kept forever, which adds
+    /// up in long-running processes. The remaining callstacks get new ids, so
+    /// everything that stores ids is rewritten. Returns how many callstacks
+    /// were forgotten.
+    fn compact_interner(&mut self) -> usize {
+        // Make sure a peak that's in effect right now is kept:
+        self.check_if_new_peak();
+        let count = self.interner.max_id as usize;
+        let mut live = vec![false; count];
+        for (id, is_live) in live.iter_mut().enumerate() {
+            *is_live = self.usage.current[id] > 0 || self.usage.peak[id] > 0;
+        }
+        for baseline in self.baselines.values() {
+            for (id, usage) in baseline.iter().enumerate() {
+                if *usage > 0 {
+                    live[id] = true;
+                }
             }
         }
-        let svg_path = directory_path
-            .join(format!("{}-reversed.svg", base_filename))
-            .to_str()
-            .unwrap()
-            .to_string();
-        match write_flamegraph(
-            &raw_path,
-            &svg_path,
-            self.peak_allocated_bytes,
-            true,
-            title,
-            to_be_post_processed,
-        ) {
-            Ok(_) => {
-                eprintln!(
-                    "=fil-profile= Wrote memory usage flamegraph to {}",
-                    svg_path
-                );
+        // Zero-sized allocations don't show up in memory usage:
+        for allocation in self
+            .current_allocations
+            .values()
+            .chain(self.realloc_extensions.values())
+        {
+            live[allocation.callstack_id as usize] = true;
+        }
+        #[cfg(not(feature = "no-mmap-tracking"))]
+        for mmap in self.current_anon_mmaps.values() {
+            live[mmap.callstack_id as usize] = true;
+        }
+        for event in self.recent_events.iter() {
+            live[event.callstack_id as usize] = true;
+        }
+
+        let mut new_ids = Vec::with_capacity(count);
+        let mut next_id = 0;
+        for is_live in &live {
+            if *is_live {
+                new_ids.push(Some(next_id));
+                next_id += 1;
+            } else {
+                new_ids.push(None);
             }
-            Err(e) => {
-                eprintln!("=fil-profile= Error writing SVG: {}", e);
+        }
+        let removed = count - next_id as usize;
+        if removed == 0 {
+            return 0;
+        }
+
+        let keep_live = |usage: &ImVector<usize>| -> ImVector<usize> {
+            usage
+                .iter()
+                .zip(&live)
+                .filter(|(_, is_live)| **is_live)
+                .map(|(bytes, _)| *bytes)
+                .collect()
+        };
+        self.usage.current = keep_live(&self.usage.current);
+        self.usage.peak = keep_live(&self.usage.peak);
+        for baseline in self.baselines.values_mut() {
+            *baseline = keep_live(baseline);
+        }
+        let new_id = |id: CallstackId| new_ids[id as usize].unwrap();
+        for allocation in self
+            .current_allocations
+            .values_mut()
+            .chain(self.realloc_extensions.values_mut())
+        {
+            allocation.callstack_id = new_id(allocation.callstack_id);
+        }
+        #[cfg(not(feature = "no-mmap-tracking"))]
+        {
+            for mmap in self.current_anon_mmaps.values_mut() {
+                mmap.callstack_id = new_id(mmap.callstack_id);
             }
+            // Only callstacks with mmap()s have non-resident bytes, so they're
+            // live:
+            self.nonresident_mmap_bytes = std::mem::take(&mut self.nonresident_mmap_bytes)
+                .into_iter()
+                .map(|(id, bytes)| (new_id(id), bytes))
+                .collect();
+        }
+        for event in self.recent_events.iter_mut() {
+            event.callstack_id = new_id(event.callstack_id);
+        }
+        // Forgotten callstacks have no memory usage, so leak detection doesn't
+        // need them:
+        let remap = |ids: &mut HashSet<CallstackId>| {
+            *ids = ids.iter().filter_map(|id| new_ids[*id as usize]).collect();
+        };
+        for touched in self.leak_touched.values_mut() {
+            touched.iter_mut().for_each(remap);
         }
+        self.leak_callstacks.values_mut().for_each(remap);
+        // Unstored bytes are memory usage, so their callstacks are live:
+        self.unstored_bytes = std::mem::take(&mut self.unstored_bytes)
+            .into_iter()
+            .map(|(id, bytes)| (new_id(id), bytes))
+            .collect();
+        self.interner.remap_ids(&new_ids);
+        self.log_all_callstacks();
+        removed
     }
 
-    /// Uh-oh, we just ran out of memory.
-    fn oom_break_glass(&mut self) {
-        // Get some emergency memory:
-        self.spare_memory.shrink_to_fit();
-        // fork()
+    /// Check if a new peak has been reached. This happens after every
+    /// allocation, so a program that never frees still has an up-to-date
+    /// peak; cloning an ImVector is cheap, since it shares structure.
+    fn check_if_new_peak(&mut self) {
+        if self.usage.check_if_new_peak() {
+            self.peak_huge_page_bytes = self.current_huge_page_bytes;
+            self.timeline_peak_pending = true;
+        }
     }
 
-    /// Dump information about where we are.
-    fn oom_dump(&mut self) {
-        unsafe {
-            // We want to free memory, but that can corrupt other threads. So first,
-            // fork() to get rid of the threads.
-            eprintln!("=fil-profile= Out of memory. First, we'll try to fork() and exit parent.");
-            let pid = libc::fork();
-            if pid != 0 && pid != -1 {
-                // We successfully forked, and we're the parent. Just exit.
-                libc::_exit(5);
+    /// Whether the peak so far, including one in effect right now, is more
+    /// than the given number of bytes.
+    fn peak_exceeds(&mut self, max_bytes: usize) -> bool {
+        self.get_peak_allocated_bytes() > max_bytes
+    }
+
+    /// Forget the peak so far, so the next peak is measured from current
+    /// memory usage, e.g. at the start of a new phase of the program.
+    fn reset_peak(&mut self) {
+        self.usage.reset_peak();
+        self.peak_huge_page_bytes = self.current_huge_page_bytes;
+    }
+
+    /// How many allocations and mmap()ed ranges are being tracked, which is
+    /// most of our own memory overhead.
+    fn tracked_allocation_count(&self) -> usize {
+        #[cfg(feature = "no-mmap-tracking")]
+        let mmaps = 0;
+        #[cfg(not(feature = "no-mmap-tracking"))]
+        let mmaps = self.current_anon_mmaps.len();
+        self.current_allocations.len() + mmaps
+    }
+
+    /// The peak allocated bytes so far, including a peak that's in effect
+    /// right now.
+    fn get_peak_allocated_bytes(&mut self) -> usize {
+        self.check_if_new_peak();
+        self.usage.peak_bytes
+    }
+
+    fn add_memory_usage(&mut self, callstack_id: CallstackId, bytes: usize) {
+        self.usage.add(callstack_id, bytes);
+        if let Some(timeline) = &mut self.timeline {
+            timeline.memory_changed(self.usage.current_bytes);
+        }
+    }
+
+    fn remove_memory_usage(&mut self, callstack_id: CallstackId, bytes: usize) {
+        // Memory is going down, so this is the last moment the peak was in
+        // effect:
+        if self.timeline_peak_pending {
+            self.timeline_peak_pending = false;
+            if let Some(timeline) = &mut self.timeline {
+                timeline.new_peak(self.usage.peak_bytes);
             }
+        }
+        self.usage.remove(callstack_id, bytes);
+        if let Some(timeline) = &mut self.timeline {
+            timeline.memory_changed(self.usage.current_bytes);
+        }
+    }
 
-            eprintln!("=fil-profile= Next, we'll free large memory allocations.");
-            // free() all the things, so we have memory to dump an SVG. These should
-            // only be _Python_ objects, Rust code shouldn't be tracked here since
-            // we prevent reentrancy. We're not going to return to Python so
-            // free()ing should be OK.
-            let id_to_callstack = self.interner.get_reverse_map();
-            for (address, allocation) in self.current_allocations.iter() {
-                // Only clear large allocations that came out of a Python stack,
-                // to reduce chances of deallocating random important things.
-                if id_to_callstack
-                    .get(&allocation.callstack_id)
-                    .unwrap()
-                    .in_python()
-                    && allocation.size() > 300000
-                {
-                    libc::free(*address as *mut ffi::c_void);
-                }
+    fn get_callstack_id(&mut self, callstack: &Callstack) -> CallstackId {
+        let usage = &mut self.usage.current;
+        let callstack_id = self
+            .interner
+            .get_or_insert_id(callstack, || usage.push_callstack());
+        if self.leak_detector.is_some() && !callstack.calls.is_empty() {
+            let depth = callstack.calls.len();
+            let touched = self.leak_touched.entry(callstack.thread_id).or_default();
+            if touched.len() < depth {
+                touched.resize_with(depth, HashSet::default);
             }
+            touched[depth - 1].insert(callstack_id);
         }
-        eprintln!(
-            "=fil-profile= And now, we'll dump out SVGs. Note that no HTML file will be written."
-        );
-        let default_path = self.default_path.clone();
-        self.dump_to_flamegraph(
-            &default_path,
-            false,
-            "out-of-memory",
-            "Current allocations at out-of-memory time",
-            false,
-        );
-        unsafe {
-            libc::_exit(5);
+        if let Some(log) = self.event_log.as_mut() {
+            if log.needs_callstack(callstack_id) {
+                let result = write_callstacks(
+                    log,
+                    &self.interner,
+                    &self.dump_options.hidden_frames,
+                    std::iter::once((callstack_id, callstack)),
+                );
+                if let Err(e) = result {
+                    self.stop_event_log(e);
+                }
+            }
         }
+        callstack_id
     }
-}
 
-lazy_static! {
-    static ref ALLOCATIONS: Mutex<AllocationTracker> =
-        Mutex::new(AllocationTracker::new("/tmp".to_string()));
-}
+    /// Add a new allocation based off the current callstack.
+    fn add_allocation(&mut self, address: usize, size: libc::size_t, callstack: &Callstack) {
+        if !self.allocator_mode.tracks_malloc() {
+            return;
+        }
+        // Checked before sampling, so a matching allocation can't be missed:
+        self.check_watchpoint(address, size, callstack);
+        if self
+            .tracked_thread
+            .is_some_and(|thread_id| thread_id != callstack.thread_id)
+        {
+            return;
+        }
+        if self.sampling_rate > 1 {
+            self.allocations_since_sample += 1;
+            if self.allocations_since_sample < self.sampling_rate {
+                return;
+            }
+            self.allocations_since_sample = 0;
+        }
+        let size = size.saturating_mul(self.sampling_rate);
+        let callstack_id = self.get_callstack_id(callstack);
+        if self.max_allocations != 0
+            && self.current_allocations.len() >= self.max_allocations
+            && self.current_allocations.get(&address).is_none()
+        {
+            self.add_unstored_allocation(address, size, callstack_id);
+            return;
+        }
+        let alloc = Allocation::new(callstack_id, size, self.generation, self.timestamp());
+        let compressed_size = alloc.size();
+        if let Some(previous) = self.current_allocations.insert(address, alloc) {
+            // We missed the free() of whatever was here before, so it needs to
+            // stop counting towards its callstack's memory usage:
+            debug_message(|| format!("Untracked reuse of address {:#x}", address));
+            self.check_if_new_peak();
+            self.add_lifetime(&previous);
+            self.remove_memory_usage(previous.callstack_id, previous.size());
+            self.record_event(
+                EventKind::Free,
+                address,
+                previous.size(),
+                previous.callstack_id,
+            );
+            if let Some(extension) = self.realloc_extensions.remove(&address) {
+                self.add_lifetime(&extension);
+                self.remove_memory_usage(extension.callstack_id, extension.size());
+                self.record_event(
+                    EventKind::Free,
+                    address,
+                    extension.size(),
+                    extension.callstack_id,
+                );
+            }
+        }
+        self.add_memory_usage(callstack_id, compressed_size);
+        self.total_allocated_bytes = self.total_allocated_bytes.wrapping_add(compressed_size);
+        self.record_event(
+            EventKind::Allocation,
+            address,
+            compressed_size,
+            callstack_id,
+        );
+        self.check_if_new_peak();
+    }
 
-/// Add to per-thread function stack:
-pub fn start_call(call_site: FunctionId, parent_line_number: u16, line_number: u16) {
-    THREAD_CALLSTACK.with(|cs| {
-        cs.borrow_mut()
-            .start_call(parent_line_number, CallSiteId::new(call_site, line_number));
-    });
-}
+    /// Count an allocation towards memory usage without storing it, because
+    /// max_allocations has been reached.
+    fn add_unstored_allocation(&mut self, address: usize, size: usize, callstack_id: CallstackId) {
+        if !self.truncated {
+            self.truncated = true;
+            info_message(|| {
+                format!(
+                    "Reached the maximum of {} tracked allocations; memory usage from now on is approximate",
+                    self.max_allocations
+                )
+            });
+        }
+        if size > 0 {
+            *self.unstored_bytes.entry(callstack_id).or_insert(0) += size;
+        }
+        self.unstored_allocations += 1;
+        self.add_memory_usage(callstack_id, size);
+        self.total_allocated_bytes = self.total_allocated_bytes.wrapping_add(size);
+        self.record_event(EventKind::Allocation, address, size, callstack_id);
+        self.check_if_new_peak();
+    }
 
-/// Finish off (and move to reporting structure) current function in function
-/// stack.
-pub fn finish_call() {
-    THREAD_CALLSTACK.with(|cs| {
-        cs.borrow_mut().finish_call();
-    });
-}
+    /// Guess at a free() of an allocation that wasn't stored, since we don't
+    /// know its size or callstack: the average unstored size gets taken off
+    /// the callstack with the most unstored bytes. Returns the guessed size,
+    /// or None if there's nothing to guess. With sampling, or when tracking a
+    /// single thread, an unknown address is most likely an allocation that
+    /// was never recorded, so there's no guessing then.
+    fn free_unstored_allocation(&mut self, address: usize) -> Option<usize> {
+        if address == 0 || self.unstored_allocations == 0 || !self.records_every_allocation() {
+            return None;
+        }
+        let total: usize = self.unstored_bytes.values().sum();
+        let average = total / self.unstored_allocations;
+        self.unstored_allocations -= 1;
+        let (callstack_id, unstored) = self
+            .unstored_bytes
+            .iter()
+            .max_by_key(|(_, bytes)| **bytes)
+            .map(|(id, bytes)| (*id, *bytes))?;
+        let freed = average.min(unstored);
+        if freed == unstored {
+            self.unstored_bytes.remove(&callstack_id);
+        } else {
+            self.unstored_bytes.insert(callstack_id, unstored - freed);
+        }
+        self.remove_memory_usage(callstack_id, freed);
+        self.record_event(EventKind::Free, address, freed, callstack_id);
+        Some(freed)
+    }
 
-/// Change line number on current function in per-thread function stack:
-pub fn new_line_number(line_number: u16) {
-    THREAD_CALLSTACK.with(|cs| {
-        cs.borrow_mut().new_line_number(line_number);
-    });
-}
+    /// If the allocation matches the watchpoint, write its callstack to
+    /// stderr.
+    fn check_watchpoint(&mut self, address: usize, size: usize, callstack: &Callstack) {
+        let watchpoint = match self.watchpoint.as_mut() {
+            Some(watchpoint) if watchpoint.matches(size) => watchpoint,
+            _ => return,
+        };
+        watchpoint.hits += 1;
+        eprintln!(
+            "=fil-profile= Watchpoint: allocated {} bytes at {:#x}, from:\n{}",
+            size,
+            address,
+            callstack.traceback()
+        );
+    }
 
-/// Add a new allocation based off the current callstack.
-pub fn add_allocation(address: usize, size: libc::size_t, line_number: u16, is_mmap: bool) {
-    if address == 0 {
-        // Uh-oh, we're out of memory.
-        let allocations = &mut ALLOCATIONS.lock().unwrap();
-        allocations.oom_break_glass();
+    /// Free an existing allocation, returning its size including any growth
+    /// from realloc(), or None if it wasn't being tracked.
+    fn free_allocation(&mut self, address: usize) -> Option<usize> {
+        if !self.allocator_mode.tracks_malloc() {
+            // Everything would look like an untracked free():
+            return None;
+        }
+        // Before we reduce memory, let's check if we've previously hit a peak:
+        self.check_if_new_peak();
+        // Possibly this allocation doesn't exist; that's OK! It can if e.g. we
+        // didn't capture an allocation for some reason.
+        if let Some(freed) = self.remove_allocation(address) {
+            self.tracked_frees += 1;
+            self.recently_freed.insert(address);
+            Some(freed)
+        } else {
+            // With sampling, when tracking a single thread, or past
+            // max_allocations, many free()d addresses were never tracked, so a
+            // reused address may well have been allocated without us seeing
+            // it:
+            if self.records_every_allocation()
+                && !self.truncated
+                && self.recently_freed.contains(&address)
+            {
+                debug_message(|| format!("Double free() of address {:#x}", address));
+                self.double_frees += 1;
+            }
+            if address != 0 {
+                // Only worked out when debugging, since it's a linear search:
+                debug_message(|| self.untracked_free_message(address));
+                self.untracked_frees += 1;
+                self.check_untracked_frees();
+            }
+            // This may well have been an allocation that wasn't stored:
+            self.free_unstored_allocation(address);
+            None
+        }
     }
 
-    let mut callstack: Callstack = THREAD_CALLSTACK.with(|cs| (*cs.borrow()).clone());
-    if line_number != 0 && !callstack.calls.is_empty() {
-        callstack.new_line_number(line_number);
+    /// Stop tracking an allocation, including any growth from realloc(),
+    /// taking it out of memory usage. Returns its size, or None if it wasn't
+    /// being tracked.
+    fn remove_allocation(&mut self, address: usize) -> Option<usize> {
+        let removed = self.current_allocations.remove(&address)?;
+        self.add_lifetime(&removed);
+        self.remove_memory_usage(removed.callstack_id, removed.size());
+        self.record_event(
+            EventKind::Free,
+            address,
+            removed.size(),
+            removed.callstack_id,
+        );
+        let mut freed = removed.size();
+        if let Some(extension) = self.realloc_extensions.remove(&address) {
+            self.add_lifetime(&extension);
+            self.remove_memory_usage(extension.callstack_id, extension.size());
+            self.record_event(
+                EventKind::Free,
+                address,
+                extension.size(),
+                extension.callstack_id,
+            );
+            freed += extension.size();
+        }
+        Some(freed)
     }
-    let mut allocations = ALLOCATIONS.lock().unwrap();
-    if is_mmap {
-        allocations.add_anon_mmap(address, size, &callstack);
-    } else {
-        allocations.add_allocation(address, size, &callstack);
+
+    /// Stop tracking an allocation that was handed off or freed in a way we
+    /// can't see, returning its size, or None if it wasn't being tracked.
+    /// Memory usage goes down just as for a free(), but it doesn't count as
+    /// one, so e.g. a later free() of the address isn't a double free().
+    fn forget_allocation(&mut self, address: usize) -> Option<usize> {
+        self.check_if_new_peak();
+        self.remove_allocation(address)
     }
-    if address == 0 {
-        // Uh-oh, we're out of memory.
-        allocations.oom_dump();
+
+    /// Milliseconds since tracking started.
+    fn timestamp(&self) -> Timestamp {
+        self.started
+            .elapsed()
+            .as_millis()
+            .min(Timestamp::MAX as u128) as Timestamp
     }
-}
 
-/// Free an existing allocation.
-pub fn free_allocation(address: usize) {
-    let mut allocations = ALLOCATIONS.lock().unwrap();
-    allocations.free_allocation(address);
-}
+    /// Add how long a freed allocation was live, times its size, to its
+    /// callstack's total.
+    fn add_lifetime(&mut self, allocation: &Allocation) {
+        let lifetime = self.timestamp().saturating_sub(allocation.created);
+        *self
+            .byte_milliseconds
+            .entry(allocation.callstack_id)
+            .or_insert(0) += allocation.size() as u128 * lifetime as u128;
+    }
 
-/// Get the size of an allocation, or 0 if it's not tracked.
-pub fn get_allocation_size(address: usize) -> libc::size_t {
-    let allocations = ALLOCATIONS.lock().unwrap();
-    if let Some(allocation) = allocations.current_allocations.get(&address) {
-        allocation.size()
-    } else {
-        0
+    /// The tracked allocation whose bytes include the given address, as its
+    /// address, size including realloc() growth, and callstack id. This looks
+    /// at every allocation, so it's slow.
+    fn allocation_containing(&self, address: usize) -> Option<(usize, usize, CallstackId)> {
+        self.current_allocations
+            .iter()
+            .map(|(start, allocation)| {
+                (
+                    *start,
+                    self.allocation_size(*start),
+                    allocation.callstack_id,
+                )
+            })
+            .find(|(start, size, _)| *start <= address && address - *start < *size)
     }
-}
 
-/// Free an anonymous mmap().
-pub fn free_anon_mmap(address: usize, length: libc::size_t) {
-    let mut allocations = ALLOCATIONS.lock().unwrap();
-    allocations.free_anon_mmap(address, length);
-}
+    /// Describe a free() of an address that isn't a tracked allocation. If
+    /// it's inside one, it's probably an interior pointer or memory from a
+    /// different allocator, both bugs, so the likely owner gets mentioned.
+    fn untracked_free_message(&self, address: usize) -> String {
+        match self.allocation_containing(address) {
+            Some((start, size, callstack_id)) => {
+                let id_to_callstack = self.interner.get_reverse_map();
+                let traceback = id_to_callstack
+                    .get(&callstack_id)
+                    .map_or_else(String::new, |callstack| callstack.traceback());
+                format!(
+                    "free() of address {:#x}, which is {} bytes into the {} byte allocation at {:#x}; is it an interior pointer, or from a different allocator? The allocation was made by:\n{}",
+                    address,
+                    address - start,
+                    size,
+                    start,
+                    traceback
+                )
+            }
+            None => format!("free() of untracked address {:#x}", address),
+        }
+    }
 
-/// Reset internal state.
-pub fn reset(default_path: String) {
-    *ALLOCATIONS.lock().unwrap() = AllocationTracker::new(default_path);
-}
+    /// Whether every malloc() gets recorded, rather than a sample or only those
+    /// from one thread.
+    fn records_every_allocation(&self) -> bool {
+        self.sampling_rate == 1 && self.tracked_thread.is_none()
+    }
 
-/// Dump all callstacks in peak memory usage to format used by flamegraph.
-pub fn dump_peak_to_flamegraph(path: &str) {
-    let mut allocations = ALLOCATIONS.lock().unwrap();
-    allocations.dump_peak_to_flamegraph(path);
-}
+    /// Warn, once, if too many free()s are of addresses we don't know about.
+    fn check_untracked_frees(&mut self) {
+        // With sampling, or past max_allocations, most free()s are expected to
+        // be untracked:
+        if self.warned_about_untracked_frees || !self.records_every_allocation() || self.truncated {
+            return;
+        }
+        let total = self.tracked_frees + self.untracked_frees;
+        if total >= UNTRACKED_FREES_MIN_FREES
+            && self.untracked_frees as f64 > total as f64 * UNTRACKED_FREES_WARNING_RATIO
+        {
+            self.warned_about_untracked_frees = true;
+            eprintln!(
+                "=fil-profile= WARNING: {} of {} free()s were of untracked memory; some allocations are probably not being tracked, so the profile may be inaccurate.",
+                self.untracked_frees, total
+            );
+        }
+    }
 
-/// Write strings to disk, one line per string.
-fn write_lines<I: Iterator<Item = String>>(lines: I, path: &str) -> std::io::Result<()> {
-    let mut file = fs::File::create(path)?;
-    for line in lines {
-        file.write_all(line.as_bytes())?;
-        file.write_all(b"\n")?;
+    /// Resize an existing allocation, possibly moving it. Bytes that were
+    /// already allocated stay attributed to the original callstack, and any
+    /// growth is attributed to the callstack doing the realloc().
+    fn realloc_allocation(
+        &mut self,
+        old_address: usize,
+        new_address: usize,
+        new_size: libc::size_t,
+        callstack: &Callstack,
+    ) {
+        if !self.allocator_mode.tracks_malloc() {
+            return;
+        }
+        let original = match self.current_allocations.get(&old_address) {
+            Some(original) => *original,
+            None => {
+                // realloc(NULL, size) is malloc(), and we may also not know
+                // about the old allocation for some reason, e.g. because it
+                // wasn't stored:
+                if new_address != old_address {
+                    self.free_unstored_allocation(old_address);
+                }
+                if new_address != 0 {
+                    self.add_allocation(new_address, new_size, callstack);
+                }
+                return;
+            }
+        };
+        // Removes the extension too, if any:
+        let previous_size = self.free_allocation(old_address).unwrap_or(0);
+        if new_address == 0 {
+            // realloc(ptr, 0) is free():
+            return;
+        }
+        let new_size = new_size.saturating_mul(self.sampling_rate);
+        let callstack_id = self.get_callstack_id(callstack);
+        // The old allocation's lifetime was counted when it was freed above,
+        // so the resized one starts from now:
+        let now = self.timestamp();
+        let base = if new_size <= original.size() || callstack_id == original.callstack_id {
+            Allocation::new(original.callstack_id, new_size, original.generation, now)
+        } else {
+            let extension = Allocation::new(
+                callstack_id,
+                new_size - original.size(),
+                self.generation,
+                now,
+            );
+            self.add_memory_usage(callstack_id, extension.size());
+            self.realloc_extensions.insert(new_address, extension);
+            self.record_event(
+                EventKind::Allocation,
+                new_address,
+                extension.size(),
+                extension.callstack_id,
+            );
+            let mut base = original;
+            base.created = now;
+            base
+        };
+        self.add_memory_usage(base.callstack_id, base.size());
+        self.current_allocations.insert(new_address, base);
+        // Growth is newly allocated memory; the rest was already counted:
+        let growth = self
+            .allocation_size(new_address)
+            .saturating_sub(previous_size);
+        self.total_allocated_bytes = self.total_allocated_bytes.wrapping_add(growth);
+        self.record_event(
+            EventKind::Allocation,
+            new_address,
+            base.size(),
+            base.callstack_id,
+        );
+        self.check_if_new_peak();
     }
-    file.flush()?;
-    Ok(())
-}
 
-/// Write a flamegraph SVG to disk, given lines in summarized format.
-fn write_flamegraph(
-    lines_file_path: &str,
-    path: &str,
-    peak_bytes: usize,
-    reversed: bool,
-    title: &str,
-    to_be_post_processed: bool,
-) -> std::io::Result<()> {
-    let mut file = std::fs::File::create(path)?;
-    let title = format!(
-        "{}{} ({:.1} MiB)",
-        title,
-        if reversed { ", Reversed" } else { "" },
-        peak_bytes as f64 / (1024.0 * 1024.0)
-    );
-    let mut options = flamegraph::Options {
-        title,
-        count_name: "bytes".to_string(),
-        font_size: 16,
-        font_type: "mono".to_string(),
-        frame_height: 22,
-        reverse_stack_order: reversed,
-        color_diffusion: true,
-        direction: flamegraph::Direction::Inverted,
-        // Maybe disable this some day, but for now it makes debugging much
-        // easier:
-        pretty_xml: true,
-        ..Default::default()
-    };
-    if to_be_post_processed {
-        options.subtitle = Some("SUBTITLE-HERE".to_string());
+    /// The size of an allocation, including any growth from realloc(), or 0 if
+    /// it's not tracked.
+    fn allocation_size(&self, address: usize) -> libc::size_t {
+        match self.current_allocations.get(&address) {
+            Some(allocation) => {
+                allocation.size()
+                    + self
+                        .realloc_extensions
+                        .get(&address)
+                        .map_or(0, |extension| extension.size())
+            }
+            None => 0,
+        }
     }
-    if let Err(e) = flamegraph::from_files(&mut options, &[PathBuf::from(lines_file_path)], &file) {
-        Err(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("{}", e),
-        ))
-    } else {
-        file.flush()?;
-        Ok(())
+
+    /// The single largest live allocation or anonymous mmap(), as its address,
+    /// size and callstack. This tells one giant buffer apart from lots of
+    /// small allocations adding up.
+    fn largest_allocation(&self) -> Option<(usize, usize, String)> {
+        let allocations = self
+            .current_allocations
+            .iter()
+            .map(|(address, allocation)| {
+                (
+                    *address,
+                    self.allocation_size(*address),
+                    allocation.callstack_id,
+                )
+            });
+        #[cfg(feature = "no-mmap-tracking")]
+        let mmaps = std::iter::empty();
+        #[cfg(not(feature = "no-mmap-tracking"))]
+        let mmaps = self
+            .current_anon_mmaps
+            .as_hashmap()
+            .into_iter()
+            .map(|(address, (size, mmap))| (address, size, mmap.callstack_id));
+        // Ties go to the lowest address, so the result is consistent:
+        let (address, size, callstack_id) = allocations
+            .chain(mmaps)
+            .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(&a.0)))?;
+        let id_to_callstack = self.interner.get_reverse_map();
+        let callstack = id_to_callstack.get(&callstack_id).unwrap();
+        let rendered = self.interner.rendered_string(
+            callstack_id,
+            callstack,
+            false,
+            &self.dump_options.hidden_frames,
+            None,
+        );
+        Some((address, size, rendered))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::{
-        Allocation, AllocationTracker, CallSiteId, Callstack, CallstackInterner, FunctionId,
-        FunctionLocation, HIGH_32BIT, MIB,
-    };
-    use im;
-    use proptest::prelude::*;
-    use std::collections;
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    /// Add a new anonymous mmap() based of the current callstack.
+    fn add_anon_mmap(&mut self, address: usize, size: libc::size_t, callstack: &Callstack) {
+        self.add_anon_mmap_with_pages(address, size, callstack, false);
+    }
 
-    proptest! {
-        // Allocation sizes smaller than 2 ** 31 are round-tripped.
-        #[test]
-        fn small_allocation(size in 0..(HIGH_32BIT - 1)) {
-            let allocation = Allocation::new(0, size as usize);
-            prop_assert_eq!(size as usize, allocation.size());
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    /// Add a new anonymous mmap(), noting whether it uses huge pages.
+    fn add_anon_mmap_with_pages(
+        &mut self,
+        address: usize,
+        size: libc::size_t,
+        callstack: &Callstack,
+        huge_pages: bool,
+    ) {
+        if !self.allocator_mode.tracks_mmap() {
+            return;
+        }
+        let callstack_id = self.get_callstack_id(callstack);
+        let mmap = AnonMmap {
+            callstack_id,
+            huge_pages,
+            generation: self.generation,
+        };
+        // An mmap(MAP_FIXED) can replace existing mappings, which stop
+        // counting as of now:
+        let replaced = self.current_anon_mmaps.add(address, size, mmap);
+        if !replaced.is_empty() {
+            self.check_if_new_peak();
         }
+        for (old_mmap, removed) in replaced {
+            self.remove_mmap_usage(old_mmap, removed);
+            self.record_event(
+                EventKind::AnonMunmap,
+                address,
+                removed,
+                old_mmap.callstack_id,
+            );
+        }
+        self.add_mmap_usage(mmap, size);
+        self.record_event(EventKind::AnonMmap, address, size, callstack_id);
+        self.check_if_new_peak();
+    }
 
-        // Allocation sizes larger than 2 ** 31 are stored as MiBs, with some
-        // loss of resolution.
-        #[test]
-        fn large_allocation(size in (HIGH_32BIT as usize)..(1 << 50)) {
-            let allocation = Allocation::new(0, size as usize);
-            let result_size = allocation.size();
-            let diff = if size < result_size {
-                result_size - size
-            } else {
-                size - result_size
-            };
-            prop_assert!(diff <= MIB / 2)
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    fn add_mmap_usage(&mut self, mmap: AnonMmap, size: usize) {
+        if mmap.huge_pages {
+            self.current_huge_page_bytes += size;
         }
+        self.add_memory_usage(mmap.callstack_id, size);
+    }
 
-        // Test for https://github.com/pythonspeed/filprofiler/issues/66
-        #[test]
-        fn correct_allocation_size_tracked(size in (1 as usize)..(1<< 50)) {
-            let mut tracker = AllocationTracker::new(".".to_string());
-            tracker.add_allocation(0, size, &Callstack::new());
-            tracker.add_anon_mmap(1, size * 2, &Callstack::new());
-            // We don't track (large) allocations exactly right, but they should
-            // be quite close:
-            let ratio = ((size * 3) as f64) / (tracker.current_memory_usage[0] as f64);
-            prop_assert!(0.999 < ratio);
-            prop_assert!(ratio < 1.001);
-            tracker.free_allocation(0);
-            tracker.free_anon_mmap(1, size * 2);
-            // Once we've freed everything, it should be _exactly_ 0.
-            prop_assert_eq!(&im::vector![0], &tracker.current_memory_usage);
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    fn remove_mmap_usage(&mut self, mmap: AnonMmap, size: usize) {
+        if mmap.huge_pages {
+            self.current_huge_page_bytes -= size;
         }
+        // We don't know which pages are resident, so assume the removed bytes
+        // were the non-resident ones, which were never counted. This way usage
+        // can't go below zero.
+        let mut counted = size;
+        if let Some(nonresident) = self.nonresident_mmap_bytes.get_mut(&mmap.callstack_id) {
+            let uncounted = size.min(*nonresident);
+            *nonresident -= uncounted;
+            counted -= uncounted;
+            if *nonresident == 0 {
+                self.nonresident_mmap_bytes.remove(&mmap.callstack_id);
+            }
+        }
+        self.remove_memory_usage(mmap.callstack_id, counted);
+    }
 
-        #[test]
-        fn current_allocated_matches_sum_of_allocations(
-            // Allocated bytes. Will use index as the memory address.
-            allocated_sizes in prop::collection::vec(1..1000 as usize, 10..20),
-            // Allocations to free.
-            free_indices in prop::collection::btree_set(0..10 as usize, 1..5)
-        ) {
-            let mut tracker = AllocationTracker::new(".".to_string());
-            let mut expected_memory_usage = im::vector![];
-            for i in 0..allocated_sizes.len() {
-                let mut cs = Callstack::new();
-                cs.start_call(0, CallSiteId::new(FunctionId::new(i as *const FunctionLocation), 0));
-                tracker.add_allocation(i as usize,*allocated_sizes.get(i).unwrap(), &cs);
-                expected_memory_usage.push_back(*allocated_sizes.get(i).unwrap());
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    /// Adjust mmap() memory usage so it only counts resident bytes, given the
+    /// process's mapped regions. Huge page mmap()s are left alone, since
+    /// their memory is reserved up front.
+    fn adjust_mmaps_to_resident(&mut self, regions: &[ResidentRegion]) {
+        // A peak in effect now may be about to go down:
+        self.check_if_new_peak();
+        let mut nonresident: HashMap<CallstackId, usize> = HashMap::default();
+        for (start, size, mmap) in self.current_anon_mmaps.iter() {
+            if mmap.huge_pages {
+                continue;
             }
-            let mut expected_sum = allocated_sizes.iter().sum();
-            let expected_peak : usize = expected_sum;
-            prop_assert_eq!(tracker.current_allocated_bytes, expected_sum);
-            prop_assert_eq!(&tracker.current_memory_usage, &expected_memory_usage);
-            for i in free_indices.iter() {
-                expected_sum -= allocated_sizes.get(*i).unwrap();
-                tracker.free_allocation(*i);
-                expected_memory_usage[*i] -= allocated_sizes.get(*i).unwrap();
-                prop_assert_eq!(tracker.current_allocated_bytes, expected_sum);
-                prop_assert_eq!(&tracker.current_memory_usage, &expected_memory_usage);
+            let end = start + size;
+            let resident: usize = regions
+                .iter()
+                .map(|region| region.resident_bytes_within(start, end))
+                .sum();
+            *nonresident.entry(mmap.callstack_id).or_insert(0) += size.saturating_sub(resident);
+        }
+        let previous = std::mem::take(&mut self.nonresident_mmap_bytes);
+        for (callstack_id, bytes) in &nonresident {
+            let before = previous.get(callstack_id).copied().unwrap_or(0);
+            if *bytes > before {
+                self.remove_memory_usage(*callstack_id, bytes - before);
+            }
+        }
+        for (callstack_id, before) in &previous {
+            let bytes = nonresident.get(callstack_id).copied().unwrap_or(0);
+            if *before > bytes {
+                self.add_memory_usage(*callstack_id, before - bytes);
             }
-            prop_assert_eq!(tracker.peak_allocated_bytes, expected_peak);
         }
+        nonresident.retain(|_, bytes| *bytes > 0);
+        self.nonresident_mmap_bytes = nonresident;
+        self.check_if_new_peak();
+    }
 
-        #[test]
-        fn current_allocated_anon_maps_matches_sum_of_allocations(
-            // Allocated bytes. Will use index as the memory address.
-            allocated_sizes in prop::collection::vec(1..1000 as usize, 10..20),
-            // Allocations to free.
-            free_indices in prop::collection::btree_set(0..10 as usize, 1..5)
-        ) {
-            let mut tracker = AllocationTracker::new(".".to_string());
-            let mut expected_memory_usage = im::vector![];
-            // Make sure addresses don't overlap:
-            let addresses : Vec<usize> = (0..allocated_sizes.len()).map(|i| i * 10000).collect();
-            for i in 0..allocated_sizes.len() {
-                let mut cs = Callstack::new();
-                cs.start_call(0, CallSiteId::new(FunctionId::new(i as *const FunctionLocation), 0));
-                tracker.add_anon_mmap(addresses[i] as usize, *allocated_sizes.get(i).unwrap(), &cs);
-                expected_memory_usage.push_back(*allocated_sizes.get(i).unwrap());
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    /// Undo adjust_mmaps_to_resident(), going back to counting all mapped
+    /// bytes.
+    fn count_all_mapped_bytes(&mut self) {
+        for (callstack_id, bytes) in std::mem::take(&mut self.nonresident_mmap_bytes) {
+            self.add_memory_usage(callstack_id, bytes);
+        }
+        self.check_if_new_peak();
+    }
+
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    /// If resident mmap() accounting is enabled and an update is due, read
+    /// /proc/self/smaps and adjust mmap() usage to match.
+    fn update_resident_mmaps_if_due(&mut self) {
+        let interval = match self.resident_mmaps_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        let now = Instant::now();
+        if now.duration_since(self.last_resident_update) < interval {
+            return;
+        }
+        self.last_resident_update = now;
+        if let Some(regions) = memoryusage::get_resident_regions() {
+            self.adjust_mmaps_to_resident(&regions);
+        }
+    }
+
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    /// Resize an anonymous mmap(), possibly moving it, as mremap() does. Bytes
+    /// that survive keep their original callstack, and growth is attributed to
+    /// the given callstack.
+    fn remap_anon_mmap(
+        &mut self,
+        old_address: usize,
+        old_size: libc::size_t,
+        new_address: usize,
+        new_size: libc::size_t,
+        callstack: &Callstack,
+    ) {
+        if !self.allocator_mode.tracks_mmap() {
+            return;
+        }
+        // Before we reduce memory, let's check if we've previously hit a peak:
+        self.check_if_new_peak();
+        let callstack_id = self.get_callstack_id(callstack);
+        // Growth uses the same kind of pages as the original mapping:
+        let huge_pages = self
+            .current_anon_mmaps
+            .get(old_address)
+            .map(|mmap| mmap.huge_pages)
+            .unwrap_or(false);
+        let (removed, added) = self.current_anon_mmaps.resize(
+            old_address,
+            old_size,
+            new_address,
+            new_size,
+            AnonMmap {
+                callstack_id,
+                huge_pages,
+                generation: self.generation,
+            },
+        );
+        for (mmap, removed) in removed {
+            self.remove_mmap_usage(mmap, removed);
+            self.record_event(
+                EventKind::AnonMunmap,
+                old_address,
+                removed,
+                mmap.callstack_id,
+            );
+        }
+        for (mmap, added) in added {
+            self.add_mmap_usage(mmap, added);
+            self.record_event(EventKind::AnonMmap, new_address, added, mmap.callstack_id);
+        }
+        self.check_if_new_peak();
+    }
+
+    /// Record an sbrk() that moved the heap's break by `increment` bytes from
+    /// `previous_break`. Growth is tracked like an anonymous mmap() of the
+    /// new range, and shrinking frees the end of whatever was tracked.
+    fn change_heap_break(
+        &mut self,
+        previous_break: usize,
+        increment: isize,
+        callstack: &Callstack,
+    ) {
+        let size = increment.unsigned_abs();
+        if increment > 0 {
+            self.add_anon_mmap(previous_break, size, callstack);
+        } else if increment < 0 {
+            self.free_anon_mmap(previous_break.saturating_sub(size), size);
+        }
+    }
+
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    fn free_anon_mmap(&mut self, address: usize, size: libc::size_t) {
+        if !self.allocator_mode.tracks_mmap() {
+            return;
+        }
+        // Before we reduce memory, let's check if we've previously hit a peak:
+        self.check_if_new_peak();
+        // Now remove, and update totoal memory tracking:
+        for (mmap, removed) in self.current_anon_mmaps.remove(address, size) {
+            self.remove_mmap_usage(mmap, removed);
+            self.record_event(EventKind::AnonMunmap, address, removed, mmap.callstack_id);
+        }
+    }
+
+    fn record_event(
+        &mut self,
+        kind: EventKind,
+        address: usize,
+        size: usize,
+        callstack_id: CallstackId,
+    ) {
+        self.recent_events.push(AllocationEvent {
+            kind,
+            address,
+            size,
+            callstack_id,
+        });
+        let log = match self.event_log.as_mut() {
+            Some(log) => log,
+            None => return,
+        };
+        let op = match kind {
+            EventKind::Allocation => eventlog::Op::Allocate,
+            EventKind::Free => eventlog::Op::Free,
+            #[cfg(not(feature = "no-mmap-tracking"))]
+            EventKind::AnonMmap => eventlog::Op::Mmap,
+            #[cfg(not(feature = "no-mmap-tracking"))]
+            EventKind::AnonMunmap => eventlog::Op::Munmap,
+        };
+        // Callstacks are logged when they're interned:
+        debug_assert!(!log.needs_callstack(callstack_id));
+        if let Err(e) = log.write_event(op, address, size, callstack_id) {
+            self.stop_event_log(e);
+        }
+    }
+
+    /// Write all interned callstacks to the event log, if there is one, e.g.
+    /// because their ids changed.
+    fn log_all_callstacks(&mut self) {
+        if let Some(log) = self.event_log.as_mut() {
+            log.forget_callstacks();
+            let result = write_callstacks(
+                log,
+                &self.interner,
+                &self.dump_options.hidden_frames,
+                self.interner.get_reverse_map().into_iter(),
+            );
+            if let Err(e) = result {
+                self.stop_event_log(e);
             }
-            let mut expected_sum = allocated_sizes.iter().sum();
-            let expected_peak : usize = expected_sum;
-            prop_assert_eq!(tracker.current_allocated_bytes, expected_sum);
-            prop_assert_eq!(&tracker.current_memory_usage, &expected_memory_usage);
-            for i in free_indices.iter() {
-                expected_sum -= allocated_sizes.get(*i).unwrap();
-                tracker.free_anon_mmap(addresses[*i], *allocated_sizes.get(*i).unwrap());
-                expected_memory_usage[*i] -= allocated_sizes.get(*i).unwrap();
-                prop_assert_eq!(tracker.current_allocated_bytes, expected_sum);
-                prop_assert_eq!(&tracker.current_memory_usage, &expected_memory_usage);
+        }
+    }
+
+    fn stop_event_log(&mut self, error: std::io::Error) {
+        eprintln!(
+            "=fil-profile= Error writing event log, so it's no longer being written: {}",
+            error
+        );
+        self.event_log = None;
+    }
+
+    /// Write out the recent events, oldest first, one per line. This doesn't
+    /// allocate, so it can be used after a crash or from a signal handler.
+    fn write_recent_events<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        for event in self.recent_events.iter() {
+            let mut buffer = [0u8; 128];
+            let mut cursor = &mut buffer[..];
+            writeln!(
+                cursor,
+                "{:?} {:#x} {} {}",
+                event.kind, event.address, event.size, event.callstack_id
+            )?;
+            let remaining = cursor.len();
+            out.write_all(&buffer[..buffer.len() - remaining])?;
+        }
+        Ok(())
+    }
+
+    /// Combine Callstacks and make them human-readable. Duplicate callstacks
+    /// have their allocated memory summed.
+    fn combine_callstacks(
+        &mut self,
+        // If false, will do the current allocations:
+        peak: bool,
+    ) -> std::collections::hash_map::IntoIter<CallstackId, usize> {
+        // First, make sure peaks are correct:
+        self.check_if_new_peak();
+
+        let mut by_call: collections::HashMap<CallstackId, usize> = collections::HashMap::new();
+
+        // Usage is kept up to date per callstack, including memory that isn't
+        // in current_allocations like realloc() growth, so it matches the
+        // total exactly:
+        let usage = if peak {
+            &self.usage.peak
+        } else {
+            &self.usage.current
+        };
+        for (i, size) in usage.iter().enumerate() {
+            if *size > 0 {
+                by_call.insert(i as CallstackId, *size);
+            }
+        }
+
+        // Convert callstacks to be human-readable:
+        by_call.into_iter()
+    }
+
+    /// Sum of peak memory usage for each thread.
+    fn peak_bytes_by_thread(&mut self) -> HashMap<ThreadId, usize> {
+        let by_call = self.combine_callstacks(true);
+        let id_to_callstack = self.interner.get_reverse_map();
+        let mut result = HashMap::default();
+        for (callstack_id, size) in by_call {
+            let thread_id = id_to_callstack.get(&callstack_id).unwrap().thread_id;
+            *result.entry(thread_id).or_insert(0) += size;
+        }
+        result
+    }
+
+    /// A Python call with the given callstack is about to return, so figure out
+    /// how much memory allocated by it and its callees is still allocated.
+    /// Only the callstacks allocated since the call started are looked at, on
+    /// top of those from its previous invocations.
+    fn leak_detection_finished_call(&mut self, callstack: &Callstack) {
+        // Calls beyond the maximum depth aren't in the callstack, so the
+        // returning call isn't the innermost frame:
+        if self.leak_detector.is_none()
+            || callstack.calls.is_empty()
+            || callstack.truncated_calls > 0
+        {
+            return;
+        }
+        let depth = callstack.calls.len();
+        let mut touched: HashSet<CallstackId> = HashSet::default();
+        if let Some(by_depth) = self.leak_touched.get_mut(&callstack.thread_id) {
+            // Deeper calls that returned without being seen left their
+            // callstacks behind, and they're callees of this call:
+            for ids in by_depth.drain(depth - 1..) {
+                touched.extend(ids);
+            }
+            // Whatever this call allocated, its caller allocated too:
+            if depth > 1 {
+                by_depth.resize_with(depth - 1, HashSet::default);
+                by_depth[depth - 2].extend(touched.iter().copied());
+            }
+        }
+        // Allocations anywhere in the returning function count, regardless of
+        // which line they happened on:
+        let mut key = Callstack::new();
+        key.calls.extend_from_slice(&callstack.calls);
+        key.calls[depth - 1].line_number = 0;
+        let callstack_ids = self.leak_callstacks.entry(key.clone()).or_default();
+        callstack_ids.extend(touched);
+        let current = &self.usage.current;
+        let bytes = callstack_ids.iter().map(|id| current[*id as usize]).sum();
+        if let Some(leak_detector) = &mut self.leak_detector {
+            leak_detector.finished_call(key, bytes);
+        }
+    }
+
+    /// Write the Python calls that appear to leak memory, worst first, to the
+    /// given file.
+    fn dump_leak_suspects(&self, path: &Path) -> std::io::Result<()> {
+        let mut file = fs::File::create(path)?;
+        if let Some(leak_detector) = &self.leak_detector {
+            for (callstack, bytes) in
+                leak_detector.suspects(LEAK_MIN_INVOCATIONS, LEAK_SUSPECTS_LIMIT)
+            {
+                writeln!(file, "{} {}", callstack.as_string(false), bytes)?;
             }
-            prop_assert_eq!(tracker.peak_allocated_bytes, expected_peak);
         }
+        file.flush()
+    }
+
+    /// Dump all callstacks in peak memory usage to various files describing the
+    /// memory usage, with the given title and subtitle, if any.
+    fn dump_peak_to_flamegraph(
+        &mut self,
+        path: &str,
+        title: Option<&str>,
+        subtitle: Option<&str>,
+    ) -> std::io::Result<()> {
+        if let Some(summary) = self.huge_page_summary() {
+            info_message(|| summary);
+        }
+        let result = self.dump_to_flamegraph(
+            path,
+            true,
+            None,
+            "peak-memory",
+            title.unwrap_or("Peak Tracked Memory Usage"),
+            subtitle,
+            true,
+            // The Python code writes a more detailed HTML report:
+            false,
+        );
+        self.new_generation();
+        result
+    }
+
+    /// Dump peak memory usage to SVGs and a minimal HTML report, without the
+    /// markers the Python code post-processes.
+    fn dump_peak_report(&mut self, path: &str) -> std::io::Result<()> {
+        let result = self.dump_to_flamegraph(
+            path,
+            true,
+            None,
+            "peak-memory",
+            "Peak Tracked Memory Usage",
+            None,
+            false,
+            true,
+        );
+        self.new_generation();
+        result
+    }
+
+    /// How much of the peak was huge page mmap()s, if any was. Huge pages are
+    /// resident as soon as they're mapped, so they add up differently from
+    /// normal pages when comparing with the process's RSS.
+    fn huge_page_summary(&mut self) -> Option<String> {
+        let peak = self.get_peak_allocated_bytes();
+        if self.peak_huge_page_bytes == 0 {
+            return None;
+        }
+        Some(format!(
+            "At peak, {} of {} bytes were huge page (MAP_HUGETLB) mmap()s, and {} bytes were normal memory.",
+            self.peak_huge_page_bytes,
+            peak,
+            peak - self.peak_huge_page_bytes
+        ))
+    }
+
+    /// Start over with a fresh tracker in a forked child process. The child's
+    /// reports go in their own directory, so they don't overwrite the
+    /// parent's.
+    fn reset_after_fork(&mut self, child_pid: u32) {
+        let default_path = Path::new(&self.default_path)
+            .join(format!("fork-{}", child_pid))
+            .to_str()
+            .unwrap()
+            .to_string();
+        *self = new_tracker(default_path);
+    }
+
+    /// Dump peak memory usage, then start over with a fresh tracker. The reset
+    /// happens even if the dump fails.
+    fn dump_peak_and_reset(&mut self, path: &str, default_path: String) -> std::io::Result<()> {
+        let result = self.dump_peak_to_flamegraph(path, None, None);
+        *self = new_tracker(default_path);
+        result
+    }
+
+    /// Write peak memory usage as clean collapsed-stack lines, without any of
+    /// our post-processing markers, for use with external tools like
+    /// flamegraph.pl.
+    fn dump_peak_collapsed(&mut self, path: &str) -> std::io::Result<()> {
+        write_lines(self.to_lines(true, false), path)
+    }
+
+    /// Like dump_peak_collapsed(), but writing to an already open file, e.g.
+    /// one passed in by a host that doesn't let us create files ourselves.
+    fn dump_peak_collapsed_to<W: Write>(&mut self, out: &mut W) -> std::io::Result<()> {
+        write_lines_to(self.to_lines(true, false), out)
+    }
+
+    /// Write a peak memory usage flamegraph SVG to an already open file.
+    fn dump_peak_svg_to<W: Write>(&mut self, out: &mut W) -> std::io::Result<()> {
+        let lines: String = self.to_lines(true, false).map(|line| line + "\n").collect();
+        let peak_bytes = self.get_peak_allocated_bytes();
+        write_flamegraph_to(
+            &lines,
+            out,
+            peak_bytes,
+            false,
+            "Peak Tracked Memory Usage",
+            None,
+            false,
+            &self.dump_options,
+        )
+    }
+
+    /// Write peak memory usage in pprof's protobuf format.
+    fn dump_peak_to_pprof(&mut self, path: &str) -> std::io::Result<()> {
+        let by_call: Vec<(CallstackId, usize)> = self.combine_callstacks(true).collect();
+        let id_to_callstack = self.interner.get_reverse_map();
+        let hidden = &self.dump_options.hidden_frames;
+        let samples: Vec<(Vec<pprof::Frame>, usize)> = by_call
+            .into_iter()
+            .map(|(callstack_id, size)| {
+                let callstack = id_to_callstack.get(&callstack_id).unwrap();
+                let frames = if callstack.calls.is_empty() {
+                    vec![pprof::Frame {
+                        function: callstack.no_python_stack_string(),
+                        filename: String::new(),
+                        line: 0,
+                    }]
+                } else {
+                    callstack
+                        .visible_calls(hidden)
+                        .into_iter()
+                        .map(|id| pprof::Frame {
+                            function: id.function.get_function_name().into_owned(),
+                            filename: id.function.get_filename().into_owned(),
+                            line: id.line_number,
+                        })
+                        .collect()
+                };
+                (frames, size)
+            })
+            .collect();
+        let mut file = std::io::BufWriter::new(fs::File::create(path)?);
+        pprof::write_profile(&mut file, &samples)
+    }
+
+    /// Write peak memory usage in the compact binary collapsed-stack format.
+    fn dump_peak_binary<W: Write>(&mut self, out: &mut W) -> std::io::Result<()> {
+        let rows: Vec<(CallstackId, usize)> = self.combine_callstacks(true).collect();
+        let id_to_callstack = self.interner.get_reverse_map();
+        let hidden = &self.dump_options.hidden_frames;
+        let callstacks: HashMap<CallstackId, String> = rows
+            .iter()
+            .map(|(callstack_id, _)| {
+                let callstack = id_to_callstack.get(callstack_id).unwrap();
+                (
+                    *callstack_id,
+                    self.interner
+                        .rendered_string(*callstack_id, callstack, true, hidden, None),
+                )
+            })
+            .collect();
+        collapsed::write_binary(out, &callstacks, &rows)
+    }
+
+    /// Like dump_peak_to_flamegraph(), but also dump a separate set of files
+    /// for each thread. This is still one dump, so one generation bump.
+    fn dump_peak_by_thread_to_flamegraph(&mut self, path: &str) -> std::io::Result<()> {
+        let mut result = self.dump_peak_to_flamegraph(path, None, None);
+        let mut thread_ids: Vec<ThreadId> = self.peak_bytes_by_thread().keys().copied().collect();
+        thread_ids.sort_unstable();
+        for thread_id in thread_ids {
+            result = result.and(self.dump_to_flamegraph(
+                path,
+                true,
+                Some(thread_id),
+                &format!("peak-memory-thread-{}", thread_id),
+                &format!("Peak Tracked Memory Usage, Thread {}", thread_id),
+                None,
+                true,
+                false,
+            ));
+        }
+        result
+    }
+
+    /// Lines in the format flamegraph expects. Callstacks that render the
+    /// same, e.g. the same callstack in different threads, or callstacks that
+    /// only differ in hidden frames, are merged into one line.
+    #[allow(clippy::wrong_self_convention)]
+    fn to_lines(
+        &mut self,
+        peak: bool,
+        to_be_post_processed: bool,
+    ) -> impl Iterator<Item = String> + '_ {
+        let regions_only = self.dump_options.regions_only;
+        self.lines_for_callstacks(peak, to_be_post_processed, move |callstack| {
+            !regions_only || callstack.in_region()
+        })
+    }
+
+    /// Like to_lines(), but only for callstacks from the given thread.
+    #[allow(clippy::wrong_self_convention)]
+    fn to_lines_for_thread(
+        &mut self,
+        peak: bool,
+        to_be_post_processed: bool,
+        thread_id: ThreadId,
+    ) -> impl Iterator<Item = String> + '_ {
+        let regions_only = self.dump_options.regions_only;
+        self.lines_for_callstacks(peak, to_be_post_processed, move |callstack| {
+            callstack.thread_id == thread_id && (!regions_only || callstack.in_region())
+        })
+    }
+
+    /// Lines for the callstacks matching the given predicate, merging those
+    /// that render the same, as well as those that are too small to matter.
+    fn lines_for_callstacks<P: Fn(&Callstack) -> bool>(
+        &mut self,
+        peak: bool,
+        to_be_post_processed: bool,
+        include: P,
+    ) -> impl Iterator<Item = String> {
+        let mut by_string = self.sizes_by_rendered_callstack(peak, to_be_post_processed, include);
+        merge_small_callstacks(&mut by_string, self.dump_options.min_fraction);
+        sorted_lines(by_string)
+    }
+
+    /// Memory usage for the callstacks matching the given predicate, keyed by
+    /// their rendered string.
+    fn sizes_by_rendered_callstack<P: Fn(&Callstack) -> bool>(
+        &mut self,
+        peak: bool,
+        to_be_post_processed: bool,
+        include: P,
+    ) -> HashMap<String, usize> {
+        let mut by_call: HashMap<CallstackId, usize> = self.combine_callstacks(peak).collect();
+        let id_to_callstack = self.interner.get_reverse_map();
+        if self.dump_options.inactive_callstacks {
+            for callstack_id in id_to_callstack.keys() {
+                by_call.entry(*callstack_id).or_insert(0);
+            }
+        }
+        let hidden = &self.dump_options.hidden_frames;
+        let thread_names = if self.dump_options.thread_root_frames {
+            Some(THREAD_NAMES.lock().unwrap())
+        } else {
+            None
+        };
+        let mut by_string: HashMap<String, usize> = HashMap::default();
+        for (callstack_id, size) in by_call {
+            let callstack = *id_to_callstack.get(&callstack_id).unwrap();
+            if include(callstack) {
+                let root_frame = thread_names
+                    .as_ref()
+                    .and_then(|names| thread_root_frame(names, callstack.thread_id));
+                *by_string
+                    .entry(self.interner.rendered_string(
+                        callstack_id,
+                        callstack,
+                        to_be_post_processed,
+                        hidden,
+                        root_frame.as_deref(),
+                    ))
+                    .or_insert(0) += size;
+            }
+        }
+        by_string
+    }
+
+    /// Memory usage summed by the file each allocation happened in, ignoring
+    /// the rest of the callstack, largest first.
+    fn bytes_by_file(&mut self, peak: bool) -> Vec<(String, usize)> {
+        let by_call = self.combine_callstacks(peak);
+        let id_to_callstack = self.interner.get_reverse_map();
+        let hidden = &self.dump_options.hidden_frames;
+        let mut by_file: HashMap<String, usize> = HashMap::default();
+        for (callstack_id, size) in by_call {
+            let callstack = id_to_callstack.get(&callstack_id).unwrap();
+            *by_file
+                .entry(callstack.allocating_file(hidden))
+                .or_insert(0) += size;
+        }
+        let mut result: Vec<(String, usize)> = by_file.into_iter().collect();
+        // Sort by filename too, so ties come out in a consistent order:
+        result.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        result
+    }
+
+    /// Write peak memory usage by file, as lines of "<bytes> <filename>".
+    fn dump_peak_by_file(&mut self, path: &str) -> std::io::Result<()> {
+        let lines = self
+            .bytes_by_file(true)
+            .into_iter()
+            .map(|(filename, size)| format!("{} {}", size, filename));
+        write_lines(lines, path)
+    }
+
+    /// Memory usage summed by the label allocations were made under, largest
+    /// first. Unlike regions, a label groups memory across all callstacks.
+    fn bytes_by_label(&mut self, peak: bool) -> Vec<(String, usize)> {
+        let by_call = self.combine_callstacks(peak);
+        let id_to_callstack = self.interner.get_reverse_map();
+        let mut by_label: HashMap<LabelId, usize> = HashMap::default();
+        for (callstack_id, size) in by_call {
+            let callstack = id_to_callstack.get(&callstack_id).unwrap();
+            *by_label.entry(callstack.label).or_insert(0) += size;
+        }
+        let labels = LABELS.lock().unwrap();
+        let mut result: Vec<(String, usize)> = by_label
+            .into_iter()
+            .map(|(label, size)| (label_name(&labels, label), size))
+            .collect();
+        // Sort by label too, so ties come out in a consistent order:
+        result.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        result
+    }
+
+    /// Start writing every event to a log at the given path. What's currently
+    /// allocated is logged first, so replaying the log gives the same memory
+    /// usage as we're tracking.
+    fn enable_event_log(&mut self, path: &Path) -> std::io::Result<()> {
+        let mut log = EventLog::create(path)?;
+        write_callstacks(
+            &mut log,
+            &self.interner,
+            &self.dump_options.hidden_frames,
+            self.interner.get_reverse_map().into_iter(),
+        )?;
+        for (address, allocation) in self
+            .current_allocations
+            .iter()
+            .chain(self.realloc_extensions.iter())
+        {
+            log.write_event(
+                eventlog::Op::Allocate,
+                *address,
+                allocation.size(),
+                allocation.callstack_id,
+            )?;
+        }
+        #[cfg(not(feature = "no-mmap-tracking"))]
+        for (address, size, mmap) in self.current_anon_mmaps.iter() {
+            log.write_event(eventlog::Op::Mmap, address, size, mmap.callstack_id)?;
+        }
+        self.event_log = Some(log);
+        Ok(())
+    }
+
+    /// Write peak memory usage by label, as lines of "<bytes> <label>".
+    fn dump_peak_by_label(&mut self, path: &str) -> std::io::Result<()> {
+        let lines = self
+            .bytes_by_label(true)
+            .into_iter()
+            .map(|(label, size)| format!("{} {}", size, label));
+        write_lines(lines, path)
+    }
+
+    /// Peak memory usage per rendered callstack, largest first, without
+    /// merging small callstacks.
+    fn peak_callstacks(&mut self) -> Vec<(String, usize)> {
+        let mut callstacks: Vec<(String, usize)> = self
+            .sizes_by_rendered_callstack(true, false, |_| true)
+            .into_iter()
+            .collect();
+        callstacks.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        callstacks
+    }
+
+    /// Memory usage by line within functions with the given name, as
+    /// filename, line and bytes, largest first. A callstack's memory goes to
+    /// the line its innermost call of the function was on, so recursion isn't
+    /// counted twice; callstacks that don't call the function are left out.
+    fn bytes_by_function_line(
+        &mut self,
+        function_name: &str,
+        peak: bool,
+    ) -> Vec<(String, u16, usize)> {
+        let by_call = self.combine_callstacks(peak);
+        let id_to_callstack = self.interner.get_reverse_map();
+        let mut by_line: HashMap<(String, u16), usize> = HashMap::default();
+        for (callstack_id, size) in by_call {
+            let callstack = id_to_callstack.get(&callstack_id).unwrap();
+            if let Some(call) = callstack
+                .calls
+                .iter()
+                .rev()
+                .find(|call| call.function.get_function_name() == function_name)
+            {
+                let filename = call.function.get_filename().into_owned();
+                *by_line.entry((filename, call.line_number)).or_insert(0) += size;
+            }
+        }
+        let mut result: Vec<(String, u16, usize)> = by_line
+            .into_iter()
+            .map(|((filename, line), size)| (filename, line, size))
+            .collect();
+        // Sort by location too, so ties come out in a consistent order:
+        result.sort_by(|a, b| {
+            b.2.cmp(&a.2)
+                .then_with(|| a.0.cmp(&b.0))
+                .then_with(|| a.1.cmp(&b.1))
+        });
+        result
+    }
+
+    /// Write peak memory usage by line within the given function, as lines of
+    /// "<bytes> <filename>:<line>".
+    fn dump_function_lines(&mut self, function_name: &str, path: &str) -> std::io::Result<()> {
+        let lines = self
+            .bytes_by_function_line(function_name, true)
+            .into_iter()
+            .map(|(filename, line, size)| format!("{} {}:{}", size, filename, line));
+        write_lines(lines, path)
+    }
+
+    /// The n callstacks using the most memory, largest first.
+    fn top_allocators(&mut self, n: usize, peak: bool) -> Vec<(String, usize)> {
+        let mut result: Vec<(String, usize)> = self
+            .sizes_by_rendered_callstack(peak, false, |_| true)
+            .into_iter()
+            .collect();
+        // Sort by callstack too, so ties come out in a consistent order:
+        result.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        result.truncate(n);
+        result
+    }
+
+    /// Write peak memory usage as CSV, with a "bytes,stack" header and one
+    /// row per callstack, largest first.
+    fn dump_peak_to_csv(&mut self, path: &str) -> std::io::Result<()> {
+        let rows = self.top_allocators(usize::MAX, true);
+        let lines = std::iter::once("bytes,stack".to_string()).chain(
+            rows.into_iter()
+                .map(|(callstack, size)| format!("{},{}", size, csv_quote(&callstack))),
+        );
+        write_lines(lines, path)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn dump_to_flamegraph(
+        &mut self,
+        path: &str,
+        peak: bool,
+        // If set, only include callstacks from this thread:
+        thread: Option<ThreadId>,
+        base_filename: &str,
+        title: &str,
+        subtitle: Option<&str>,
+        to_be_post_processed: bool,
+        // Whether to also write an index.html wrapping the SVGs:
+        html: bool,
+    ) -> std::io::Result<()> {
+        let title_bytes = match thread {
+            None => {
+                self.check_if_new_peak();
+                self.usage.peak_bytes
+            }
+            Some(thread_id) => *self.peak_bytes_by_thread().get(&thread_id).unwrap_or(&0),
+        };
+        self.write_dump(
+            path,
+            base_filename,
+            |tracker, to_be_post_processed| match thread {
+                None => tracker.to_lines(peak, to_be_post_processed).collect(),
+                Some(thread_id) => tracker
+                    .to_lines_for_thread(peak, to_be_post_processed, thread_id)
+                    .collect(),
+            },
+            title_bytes,
+            title,
+            subtitle,
+            to_be_post_processed,
+            html,
+        )
+    }
+
+    /// Write a dump to the output directory, falling back if it can't be
+    /// created: the lines from lines() go to <base_filename>.prof, plus
+    /// whatever the configured format and the given options add. lines() is
+    /// told whether its lines will be post-processed.
+    #[allow(clippy::too_many_arguments)]
+    fn write_dump<L: FnMut(&mut Self, bool) -> Vec<String>>(
+        &mut self,
+        path: &str,
+        base_filename: &str,
+        mut lines: L,
+        title_bytes: usize,
+        title: &str,
+        subtitle: Option<&str>,
+        to_be_post_processed: bool,
+        // Whether to also write an index.html wrapping the SVGs:
+        html: bool,
+    ) -> std::io::Result<()> {
+        info_message(|| format!("Preparing to write to {}", path));
+        let truncated_title;
+        let title = if self.truncated {
+            truncated_title = format!("{} (approximate — allocation tracking truncated)", title);
+            truncated_title.as_str()
+        } else {
+            title
+        };
+        let directory_path = create_output_directory(Path::new(path), &self.fallback_path)?;
+        let directory_path = directory_path.as_path();
+        // Everything gets written that can be, but the first error is what
+        // gets returned:
+        let mut result = Ok(());
+
+        let raw_path = directory_path
+            .join(format!("{}.prof", base_filename))
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let written = write_lines(lines(self, to_be_post_processed).into_iter(), &raw_path);
+        if let Err(e) = written {
+            eprintln!("=fil-profile= Error writing raw profiling data: {}", e);
+            result = result.and(Err(e));
+        }
+        let format = self.dump_options.format;
+        if let Some(extension) = format.table_extension() {
+            let table_path = directory_path.join(format!("{}.{}", base_filename, extension));
+            // Tables are for other tools, so they never have post-processing
+            // markers:
+            let written = write_table(
+                lines(self, false).into_iter(),
+                &table_path,
+                format,
+                title_bytes,
+            );
+            match written {
+                Ok(_) => {
+                    info_message(|| format!("Wrote memory usage to {}", table_path.display()));
+                }
+                Err(e) => {
+                    eprintln!("=fil-profile= Error writing {}: {}", extension, e);
+                    result = result.and(Err(e));
+                }
+            }
+        }
+        // The Python report is built from the SVGs, so it always gets them:
+        if format != OutputFormat::Svg && !to_be_post_processed {
+            if format == OutputFormat::Collapsed && result.is_ok() {
+                info_message(|| format!("Wrote collapsed memory usage to {}", raw_path));
+            }
+            return result;
+        }
+        let svg_path = directory_path
+            .join(format!("{}.svg", base_filename))
+            .to_str()
+            .unwrap()
+            .to_string();
+        match write_flamegraph(
+            &raw_path,
+            &svg_path,
+            title_bytes,
+            false,
+            title,
+            subtitle,
+            to_be_post_processed,
+            &self.dump_options,
+        ) {
+            Ok(_) => {
+                info_message(|| format!("Wrote memory usage flamegraph to {}", svg_path));
+            }
+            Err(e) => {
+                eprintln!("=fil-profile= Error writing SVG: {}", e);
+                result = result.and(Err(e));
+            }
+        }
+        let svg_path = directory_path
+            .join(format!("{}-reversed.svg", base_filename))
+            .to_str()
+            .unwrap()
+            .to_string();
+        match write_flamegraph(
+            &raw_path,
+            &svg_path,
+            title_bytes,
+            true,
+            title,
+            subtitle,
+            to_be_post_processed,
+            &self.dump_options,
+        ) {
+            Ok(_) => {
+                info_message(|| format!("Wrote memory usage flamegraph to {}", svg_path));
+            }
+            Err(e) => {
+                eprintln!("=fil-profile= Error writing SVG: {}", e);
+                result = result.and(Err(e));
+            }
+        }
+        if html {
+            match write_html_report(directory_path, base_filename, title_bytes) {
+                Ok(html_path) => {
+                    info_message(|| format!("Wrote HTML report to {}", html_path.display()));
+                }
+                Err(e) => {
+                    eprintln!("=fil-profile= Error writing HTML report: {}", e);
+                    result = result.and(Err(e));
+                }
+            }
+        }
+        result
+    }
+
+    /// Start a new generation; allocations from before now get older.
+    fn new_generation(&mut self) {
+        self.generation = self.generation.saturating_add(1);
+    }
+
+    /// Current memory usage per rendered callstack, counting only memory
+    /// allocated at least `min_age` generations ago. Growth from realloc()
+    /// and mremap() has the age of the growth, not of the original
+    /// allocation.
+    fn old_allocations(&self, min_age: Generation) -> HashMap<String, usize> {
+        let is_old = |generation: Generation| self.generation - generation >= min_age;
+        let mut by_call: HashMap<CallstackId, usize> = HashMap::default();
+        for allocation in self
+            .current_allocations
+            .values()
+            .chain(self.realloc_extensions.values())
+        {
+            if is_old(allocation.generation) {
+                *by_call.entry(allocation.callstack_id).or_insert(0) += allocation.size();
+            }
+        }
+        #[cfg(not(feature = "no-mmap-tracking"))]
+        for (size, mmap) in self.current_anon_mmaps.as_hashmap().values() {
+            if is_old(mmap.generation) {
+                *by_call.entry(mmap.callstack_id).or_insert(0) += size;
+            }
+        }
+        let id_to_callstack = self.interner.get_reverse_map();
+        let hidden = &self.dump_options.hidden_frames;
+        let mut by_string: HashMap<String, usize> = HashMap::default();
+        for (callstack_id, size) in by_call {
+            let callstack = id_to_callstack.get(&callstack_id).unwrap();
+            *by_string
+                .entry(callstack.as_string_hiding(false, hidden, None))
+                .or_insert(0) += size;
+        }
+        by_string
+    }
+
+    /// Write flamegraphs of current allocations at least `min_age`
+    /// generations old to the given directory, highlighting objects that
+    /// persist across phases of the program.
+    fn dump_old_allocations_flamegraph(
+        &mut self,
+        min_age: Generation,
+        path: &str,
+    ) -> std::io::Result<()> {
+        let old = self.old_allocations(min_age);
+        let total = old.values().sum();
+        let result = self.write_dump(
+            path,
+            "old-allocations",
+            |_, _| sorted_lines(old.clone()).collect(),
+            total,
+            &format!("Memory Allocated At Least {} Generations Ago", min_age),
+            None,
+            false,
+            false,
+        );
+        self.new_generation();
+        result
+    }
+
+    /// Byte-seconds per rendered callstack: each allocation's size times how
+    /// long it was live. Allocations that are still live count up to now.
+    /// Callstacks with less than a byte-second are left out.
+    fn byte_seconds(&self) -> HashMap<String, usize> {
+        let now = self.timestamp();
+        let mut by_call = self.byte_milliseconds.clone();
+        for allocation in self
+            .current_allocations
+            .values()
+            .chain(self.realloc_extensions.values())
+        {
+            let lifetime = now.saturating_sub(allocation.created);
+            *by_call.entry(allocation.callstack_id).or_insert(0) +=
+                allocation.size() as u128 * lifetime as u128;
+        }
+        let id_to_callstack = self.interner.get_reverse_map();
+        let hidden = &self.dump_options.hidden_frames;
+        let mut by_string: HashMap<String, u128> = HashMap::default();
+        for (callstack_id, byte_milliseconds) in by_call {
+            let callstack = id_to_callstack.get(&callstack_id).unwrap();
+            *by_string
+                .entry(callstack.as_string_hiding(false, hidden, None))
+                .or_insert(0) += byte_milliseconds;
+        }
+        by_string
+            .into_iter()
+            .map(|(callstack, byte_milliseconds)| {
+                let byte_seconds = byte_milliseconds / 1000;
+                (callstack, byte_seconds.min(usize::MAX as u128) as usize)
+            })
+            .filter(|(_, byte_seconds)| *byte_seconds > 0)
+            .collect()
+    }
+
+    /// Write flamegraphs of byte-seconds per callstack to the given
+    /// directory, surfacing large allocations that live a long time.
+    fn dump_byte_seconds_flamegraph(&mut self, path: &str) -> std::io::Result<()> {
+        let byte_seconds = self.byte_seconds();
+        let total = byte_seconds
+            .values()
+            .fold(0usize, |total, value| total.saturating_add(*value));
+        let units = std::mem::replace(&mut self.dump_options.units, FlamegraphUnits::ByteSeconds);
+        let result = self.write_dump(
+            path,
+            "byte-seconds",
+            |_, _| sorted_lines(byte_seconds.clone()).collect(),
+            total,
+            "Memory Allocated Over Time",
+            None,
+            false,
+            false,
+        );
+        self.dump_options.units = units;
+        result
+    }
+
+    /// Memory growth per rendered callstack since the named baseline was
+    /// taken, or None if there's no such baseline. Callstacks that shrank are
+    /// left out.
+    fn growth_since_baseline(&self, name: &str) -> Option<HashMap<String, usize>> {
+        let baseline = self.baselines.get(name)?;
+        let id_to_callstack = self.interner.get_reverse_map();
+        let hidden = &self.dump_options.hidden_frames;
+        let mut by_string: HashMap<String, usize> = HashMap::default();
+        for (callstack_id, usage) in self.usage.current.iter().enumerate() {
+            // Callstacks interned after the baseline was taken are all growth:
+            let before = baseline.get(callstack_id).copied().unwrap_or(0);
+            if *usage > before {
+                let callstack = id_to_callstack.get(&(callstack_id as CallstackId)).unwrap();
+                *by_string
+                    .entry(callstack.as_string_hiding(false, hidden, None))
+                    .or_insert(0) += usage - before;
+            }
+        }
+        Some(by_string)
+    }
+
+    /// Write flamegraphs of memory growth since the named baseline to the
+    /// given directory.
+    fn dump_delta_flamegraph(&mut self, name: &str, path: &str) -> std::io::Result<()> {
+        let growth = self.growth_since_baseline(name).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no baseline named {}", name),
+            )
+        })?;
+        let total = growth.values().sum();
+        self.write_dump(
+            path,
+            "memory-growth",
+            |_, _| sorted_lines(growth.clone()).collect(),
+            total,
+            "Memory Growth",
+            None,
+            false,
+            false,
+        )
+    }
+
+    /// Uh-oh, we just ran out of memory.
+    fn oom_break_glass(&mut self) {
+        // Get some emergency memory, by releasing the spare memory reserve:
+        self.spare_memory = Vec::new();
+    }
+
+    /// Addresses of allocations we can free() on out-of-memory. We only clear
+    /// large allocations that came out of a Python stack, to reduce chances of
+    /// deallocating random important things.
+    fn oom_freeable_addresses(&self) -> Vec<usize> {
+        let id_to_callstack = self.interner.get_reverse_map();
+        self.current_allocations
+            .iter()
+            .filter(|(_, allocation)| {
+                id_to_callstack
+                    .get(&allocation.callstack_id)
+                    .unwrap()
+                    .in_python()
+                    && allocation.size() > self.oom_free_threshold
+            })
+            .map(|(address, _)| *address)
+            .collect()
+    }
+
+    /// If a dump was requested via SIGUSR2, dump current allocations to a
+    /// timestamped set of files in the default path. Returns the base filename
+    /// used, if any.
+    fn dump_if_requested(&mut self) -> Option<String> {
+        if !DUMP_REQUESTED.swap(false, Ordering::Relaxed) {
+            return None;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis())
+            .unwrap_or(0);
+        let base_filename = format!("snapshot-{}", timestamp);
+        let default_path = self.default_path.clone();
+        // Nobody's waiting on the result, and errors have been reported:
+        let _ = self.dump_to_flamegraph(
+            &default_path,
+            false,
+            None,
+            &base_filename,
+            "Current allocations at snapshot time",
+            None,
+            false,
+            false,
+        );
+        self.new_generation();
+        Some(base_filename)
+    }
+
+    /// If periodic snapshots are enabled and one is due as of `now`, the base
+    /// filename to write it to.
+    fn snapshot_due(&mut self, now: Instant) -> Option<String> {
+        let interval = self.snapshot_interval?;
+        if now.duration_since(self.last_snapshot) < interval {
+            return None;
+        }
+        self.last_snapshot = now;
+        let base_filename = format!(
+            "periodic-snapshot-{}",
+            self.snapshots_written % SNAPSHOT_ROTATION
+        );
+        self.snapshots_written += 1;
+        Some(base_filename)
+    }
+
+    /// Dump current allocations if a periodic snapshot is due. This is only
+    /// called when allocations are recorded, which never happens from inside
+    /// our own code since the reentrancy guard is set then, so the dump's own
+    /// allocations can't trigger another dump.
+    fn dump_snapshot_if_due(&mut self) {
+        if let Some(base_filename) = self.snapshot_due(Instant::now()) {
+            let default_path = self.default_path.clone();
+            let _ = self.dump_to_flamegraph(
+                &default_path,
+                false,
+                None,
+                &base_filename,
+                "Current allocations at snapshot time",
+                None,
+                false,
+                false,
+            );
+            self.new_generation();
+        }
+    }
+
+    /// Dump current and peak memory usage to flamegraphs, plus an HTML page
+    /// that toggles between them. Both are rendered under the same lock, so
+    /// they're consistent with each other.
+    fn dump_combined_report(&mut self, path: &str) -> std::io::Result<()> {
+        // Worked out up front, so the HTML page ends up next to the SVGs even
+        // if they had to go to the fallback directory:
+        let directory = create_output_directory(Path::new(path), &self.fallback_path)?;
+        let path = directory.to_str().unwrap();
+        // The HTML page needs the SVGs, whatever the configured format:
+        let format = std::mem::replace(&mut self.dump_options.format, OutputFormat::Svg);
+        let current = self.dump_to_flamegraph(
+            path,
+            false,
+            None,
+            "current-memory",
+            "Current Tracked Memory Usage",
+            None,
+            false,
+            false,
+        );
+        let peak = self.dump_to_flamegraph(
+            path,
+            true,
+            None,
+            "peak-memory",
+            "Peak Tracked Memory Usage",
+            None,
+            false,
+            false,
+        );
+        self.dump_options.format = format;
+        self.new_generation();
+        current?;
+        peak?;
+        match write_combined_html_report(
+            &directory,
+            self.usage.current_bytes,
+            self.usage.peak_bytes,
+        ) {
+            Ok(html_path) => {
+                info_message(|| format!("Wrote HTML report to {}", html_path.display()));
+                Ok(())
+            }
+            Err(e) => {
+                eprintln!("=fil-profile= Error writing HTML report: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Dump information about where we are.
+    fn oom_dump(&mut self) {
+        unsafe {
+            // We want to free memory, but that can corrupt other threads. So first,
+            // fork() to get rid of the threads. Without fork() (i.e. on Windows)
+            // we just have to hope for the best. We're already inside the
+            // tracker, so the pthread_atfork() handlers leave it alone, and the
+            // child keeps our allocations to dump.
+            #[cfg(unix)]
+            {
+                eprintln!(
+                    "=fil-profile= Out of memory. First, we'll try to fork() and exit parent."
+                );
+                let pid = libc::fork();
+                if pid != 0 && pid != -1 {
+                    // We successfully forked, and we're the parent. Just exit.
+                    libc::_exit(5);
+                }
+            }
+
+            eprintln!("=fil-profile= Next, we'll free large memory allocations.");
+            // free() all the things, so we have memory to dump an SVG. These should
+            // only be _Python_ objects, Rust code shouldn't be tracked here since
+            // we prevent reentrancy. We're not going to return to Python so
+            // free()ing should be OK.
+            for address in self.oom_freeable_addresses() {
+                libc::free(address as *mut ffi::c_void);
+            }
+        }
+        eprintln!("=fil-profile= And now, we'll dump out SVGs and a minimal HTML report.");
+        let default_path = self.default_path.clone();
+        // Best effort; we're about to exit either way:
+        let _ = self.dump_to_flamegraph(
+            &default_path,
+            false,
+            None,
+            "out-of-memory",
+            "Current allocations at out-of-memory time",
+            None,
+            false,
+            true,
+        );
+        unsafe {
+            libc::_exit(5);
+        }
+    }
+}
+
+/// With the no-mmap-tracking feature, mmap() and friends are ignored, so only
+/// malloc() and friends cost any memory or time.
+#[cfg(feature = "no-mmap-tracking")]
+impl AllocationTracker {
+    fn add_anon_mmap(&mut self, _address: usize, _size: libc::size_t, _callstack: &Callstack) {}
+
+    fn add_anon_mmap_with_pages(
+        &mut self,
+        _address: usize,
+        _size: libc::size_t,
+        _callstack: &Callstack,
+        _huge_pages: bool,
+    ) {
+    }
+
+    fn remap_anon_mmap(
+        &mut self,
+        _old_address: usize,
+        _old_size: libc::size_t,
+        _new_address: usize,
+        _new_size: libc::size_t,
+        _callstack: &Callstack,
+    ) {
+    }
+
+    fn free_anon_mmap(&mut self, _address: usize, _size: libc::size_t) {}
+}
+
+lazy_static! {
+    static ref ALLOCATIONS: Mutex<AllocationTracker> = Mutex::new(initial_tracker());
+}
+
+/// A fresh tracker, configured from the environment: FIL_FORMAT sets the
+/// output format.
+fn new_tracker(default_path: String) -> AllocationTracker {
+    let mut tracker = AllocationTracker::new(default_path, DEFAULT_SPARE_MEMORY_SIZE);
+    tracker.dump_options.format = OutputFormat::from_env();
+    tracker
+}
+
+/// The tracker we start with when first loaded. This is also when
+/// FIL_VERBOSITY gets read, so it applies from the start.
+fn initial_tracker() -> AllocationTracker {
+    if let Ok(verbosity) = std::env::var("FIL_VERBOSITY") {
+        match verbosity.trim().parse::<usize>() {
+            Ok(verbosity) => VERBOSITY.store(verbosity, Ordering::Relaxed),
+            Err(_) => eprintln!(
+                "=fil-profile= Warning: FIL_VERBOSITY should be a number, got {:?}.",
+                verbosity
+            ),
+        }
+    }
+    new_tracker(default_output_path())
+}
+
+/// Where to write out data before reset() tells us otherwise, e.g. on running
+/// out of memory: $FIL_OUTPUT_PATH, falling back to /tmp.
+fn default_output_path() -> String {
+    match std::env::var("FIL_OUTPUT_PATH") {
+        Ok(path) if !path.is_empty() => path,
+        _ => "/tmp".to_string(),
+    }
+}
+
+/// Add to per-thread function stack:
+pub fn start_call(call_site: FunctionId, parent_line_number: u16, line_number: u16) {
+    PROFILING_STARTED.store(true, Ordering::Relaxed);
+    if CALL_PEAKS_ENABLED.load(Ordering::Relaxed) {
+        let name = format!(
+            "{}:{}",
+            call_site.get_filename(),
+            call_site.get_function_name()
+        );
+        let current_bytes = ALLOCATIONS.lock().unwrap().usage.current_bytes;
+        call_peaks::start_call(name, current_bytes);
+    }
+    let max_depth = MAX_STACK_DEPTH.load(Ordering::Relaxed);
+    THREAD_CALLSTACK.with(|cs| {
+        cs.borrow_mut().start_call_with_max_depth(
+            parent_line_number,
+            CallSiteId::new(call_site, line_number),
+            max_depth,
+            truncation_frame(),
+        );
+    });
+}
+
+/// Finish off (and move to reporting structure) current function in function
+/// stack.
+pub fn finish_call() {
+    THREAD_CALLSTACK.with(|cs| {
+        if LEAK_DETECTION_ENABLED.load(Ordering::Relaxed) {
+            let callstack = cs.borrow().clone();
+            let mut allocations = ALLOCATIONS.lock().unwrap();
+            allocations.leak_detection_finished_call(&callstack);
+        }
+        cs.borrow_mut().finish_call();
+    });
+    if CALL_PEAKS_ENABLED.load(Ordering::Relaxed) {
+        call_peaks::finish_call();
+    }
+}
+
+/// Start a named region of the current thread's callstack. It shows up as a
+/// frame, and dumps can be limited to allocations inside regions.
+pub fn begin_region(name: &str) {
+    start_call(region_function(name), 0, 0);
+}
+
+/// End the region started by the matching begin_region().
+pub fn end_region() {
+    finish_call();
+}
+
+/// Only include allocations made inside a region in flamegraphs.
+pub fn set_regions_only(enabled: bool) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.dump_options.regions_only = enabled;
+}
+
+/// Change line number on current function in per-thread function stack:
+pub fn new_line_number(line_number: u16) {
+    THREAD_CALLSTACK.with(|cs| {
+        cs.borrow_mut().new_line_number(line_number);
+    });
+}
+
+/// Add a new allocation based off the current callstack.
+pub fn add_allocation(address: usize, size: libc::size_t, line_number: u16, is_mmap: bool) {
+    record_allocation(
+        address,
+        size,
+        || current_python_callstack(line_number),
+        kind_of_allocation(is_mmap),
+    );
+}
+
+/// Add a new allocation based off the current callstack, or if there are no
+/// Python calls and native callers are being recorded, off the native code at
+/// the given return address.
+pub fn add_allocation_from_caller(
+    address: usize,
+    size: libc::size_t,
+    line_number: u16,
+    caller: usize,
+) {
+    record_allocation(
+        address,
+        size,
+        || {
+            let mut callstack = current_python_callstack(line_number);
+            if !callstack.in_python() && RECORD_NATIVE_CALLERS.load(Ordering::Relaxed) {
+                callstack.native_caller = caller;
+            }
+            callstack
+        },
+        AllocationKind::Malloc,
+    );
+}
+
+/// Add a new anonymous mmap() that uses huge pages, based off the current
+/// callstack.
+pub fn add_huge_page_mmap(address: usize, size: libc::size_t, line_number: u16) {
+    record_allocation(
+        address,
+        size,
+        || current_python_callstack(line_number),
+        AllocationKind::HugePageMmap,
+    );
+}
+
+/// The current thread's Python callstack, with the given line number (if
+/// non-zero) in the innermost frame.
+fn current_python_callstack(line_number: u16) -> Callstack {
+    let mut callstack: Callstack = THREAD_CALLSTACK.with(|cs| (*cs.borrow()).clone());
+    if line_number != 0 && !callstack.calls.is_empty() {
+        callstack.new_line_number(line_number);
+    }
+    callstack.before_profiling =
+        callstack.calls.is_empty() && !PROFILING_STARTED.load(Ordering::Relaxed);
+    callstack
+}
+
+/// Record a realloc() of an existing allocation based off the current
+/// callstack.
+pub fn realloc_allocation(
+    old_address: usize,
+    new_address: usize,
+    new_size: libc::size_t,
+    line_number: u16,
+) {
+    if new_address == 0 && new_size != 0 {
+        // Out of memory; the old allocation is still there, and this will
+        // deal with the consequences.
+        add_allocation(new_address, new_size, line_number, false);
+        return;
+    }
+    let _guard = match RecordingGuard::enter() {
+        Some(guard) => guard,
+        None => return,
+    };
+    let mut callstack = current_python_callstack(line_number);
+    callstack.thread_id = THREAD_ID.with(|thread_id| *thread_id);
+    callstack.label = current_label();
+    add_native_stack_if_enabled(&mut callstack);
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.realloc_allocation(old_address, new_address, new_size, &callstack);
+    if CALL_PEAKS_ENABLED.load(Ordering::Relaxed) {
+        call_peaks::update_memory_usage(allocations.usage.current_bytes);
+    }
+}
+
+/// Add a new allocation attributed only to the native code that called
+/// malloc() and friends, bypassing the Python callstack entirely. This is the
+/// low-overhead mode used when Python calls aren't being tracked.
+pub fn add_native_allocation(address: usize, size: libc::size_t, caller: usize, is_mmap: bool) {
+    record_allocation(
+        address,
+        size,
+        || Callstack::from_native_caller(caller),
+        kind_of_allocation(is_mmap),
+    );
+}
+
+/// Like add_huge_page_mmap(), but attributed only to the native code that
+/// called mmap().
+pub fn add_native_huge_page_mmap(address: usize, size: libc::size_t, caller: usize) {
+    record_allocation(
+        address,
+        size,
+        || Callstack::from_native_caller(caller),
+        AllocationKind::HugePageMmap,
+    );
+}
+
+fn kind_of_allocation(is_mmap: bool) -> AllocationKind {
+    if is_mmap {
+        AllocationKind::AnonMmap
+    } else {
+        AllocationKind::Malloc
+    }
+}
+
+#[cfg(any(all(target_os = "linux", target_env = "gnu"), target_os = "macos"))]
+fn capture_native_stack() -> Vec<usize> {
+    let mut buffer = [std::ptr::null_mut(); MAX_NATIVE_FRAMES];
+    let count = unsafe { libc::backtrace(buffer.as_mut_ptr(), MAX_NATIVE_FRAMES as libc::c_int) };
+    buffer[..count.max(0) as usize]
+        .iter()
+        .map(|address| *address as usize)
+        .collect()
+}
+
+#[cfg(not(any(all(target_os = "linux", target_env = "gnu"), target_os = "macos")))]
+fn capture_native_stack() -> Vec<usize> {
+    Vec::new()
+}
+
+/// If enabled, give a callstack with no Python calls a native backtrace.
+fn add_native_stack_if_enabled(callstack: &mut Callstack) {
+    if !callstack.in_python() && CAPTURE_NATIVE_STACKS.load(Ordering::Relaxed) {
+        callstack.native_frames = capture_native_stack();
+    }
+}
+
+/// The symbol name, library path and library base address for a code
+/// address, via dladdr().
+#[cfg(unix)]
+fn resolve_native_frame(address: usize) -> (Option<String>, Option<String>, usize) {
+    let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+    if unsafe { libc::dladdr(address as *const libc::c_void, &mut info) } == 0 {
+        return (None, None, 0);
+    }
+    let to_string = |name: *const libc::c_char| {
+        if name.is_null() {
+            None
+        } else {
+            Some(
+                unsafe { ffi::CStr::from_ptr(name) }
+                    .to_string_lossy()
+                    .into_owned(),
+            )
+        }
+    };
+    (
+        to_string(info.dli_sname),
+        to_string(info.dli_fname),
+        info.dli_fbase as usize,
+    )
+}
+
+#[cfg(not(unix))]
+fn resolve_native_frame(_address: usize) -> (Option<String>, Option<String>, usize) {
+    (None, None, 0)
+}
+
+/// Render a native frame as "symbol (library)", falling back to the address
+/// if there's no symbol.
+fn format_native_frame(symbol: Option<&str>, library: Option<&str>, address: usize) -> String {
+    let name = match symbol {
+        Some(symbol) => symbol.to_string(),
+        None => format!("{:#x}", address),
+    };
+    match library {
+        Some(library) => format!(
+            "{} ({})",
+            name,
+            Path::new(library)
+                .file_name()
+                .map_or(Cow::Borrowed(library), |name| name.to_string_lossy())
+        ),
+        None => name,
+    }
+}
+
+/// Render the native code that called malloc() as a single frame, falling
+/// back to its address if there's no symbol.
+fn format_native_caller(symbol: Option<&str>, address: usize) -> String {
+    match symbol {
+        Some(symbol) => format!("[Native code in {}]", symbol),
+        None => format!("[Native code at {:#x}]", address),
+    }
+}
+
+/// Symbolicate a native backtrace into collapsed-stack frames, outermost
+/// first. Frames in Fil itself are left out; None if nothing is left.
+fn native_stack_string(frames: &[usize]) -> Option<String> {
+    if frames.is_empty() {
+        return None;
+    }
+    let (_, _, own_base) = resolve_native_frame(native_stack_string as *const () as usize);
+    let rendered: Vec<String> = frames
+        .iter()
+        .rev()
+        .filter_map(|address| {
+            // Return addresses point after the call, which may already be
+            // the next function:
+            let (symbol, library, base) = resolve_native_frame(address.saturating_sub(1));
+            if base == own_base {
+                None
+            } else {
+                Some(format_native_frame(
+                    symbol.as_deref(),
+                    library.as_deref(),
+                    *address,
+                ))
+            }
+        })
+        .collect();
+    if rendered.is_empty() {
+        None
+    } else {
+        Some(rendered.join(";"))
+    }
+}
+
+/// Record an allocation. The callstack is only created once we've dealt with
+/// running out of memory, since creating it may itself need memory.
+fn record_allocation<F: FnOnce() -> Callstack>(
+    address: usize,
+    size: libc::size_t,
+    get_callstack: F,
+    kind: AllocationKind,
+) {
+    // Don't bother creating a callstack for something we'd ignore:
+    if cfg!(feature = "no-mmap-tracking") && kind != AllocationKind::Malloc {
+        return;
+    }
+    let _guard = match RecordingGuard::enter() {
+        Some(guard) => guard,
+        None => return,
+    };
+    if address == 0 {
+        // Uh-oh, we're out of memory.
+        let allocations = &mut ALLOCATIONS.lock().unwrap();
+        allocations.oom_break_glass();
+    }
+
+    let mut callstack = get_callstack();
+    callstack.thread_id = THREAD_ID.with(|thread_id| *thread_id);
+    callstack.label = current_label();
+    add_native_stack_if_enabled(&mut callstack);
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    match kind {
+        AllocationKind::Malloc => allocations.add_allocation(address, size, &callstack),
+        AllocationKind::AnonMmap => allocations.add_anon_mmap(address, size, &callstack),
+        AllocationKind::HugePageMmap => {
+            allocations.add_anon_mmap_with_pages(address, size, &callstack, true)
+        }
+    }
+    if address == 0 {
+        // Uh-oh, we're out of memory.
+        allocations.oom_dump();
+    }
+    allocations.dump_if_requested();
+    allocations.dump_snapshot_if_due();
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    allocations.update_resident_mmaps_if_due();
+    if CALL_PEAKS_ENABLED.load(Ordering::Relaxed) {
+        call_peaks::update_memory_usage(allocations.usage.current_bytes);
+    }
+    #[cfg(test)]
+    WHILE_RECORDING.with(|hook| {
+        if let Some(hook) = &*hook.borrow() {
+            hook();
+        }
+    });
+}
+
+#[cfg(unix)]
+extern "C" fn request_dump(_signal: libc::c_int) {
+    // Only async-signal-safe operations are allowed here; see DUMP_REQUESTED.
+    DUMP_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Install a SIGUSR2 handler that dumps current allocations on the next
+/// allocation after the signal arrives.
+#[cfg(unix)]
+pub fn install_signal_dumper() {
+    unsafe {
+        libc::signal(
+            libc::SIGUSR2,
+            request_dump as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+lazy_static! {
+    // Where to dump when the process exits, if anywhere. Taken by the dump,
+    // so it only happens once:
+    static ref EXIT_DUMP_PATH: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// Whether dump_at_exit() has been registered with atexit().
+static EXIT_DUMP_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+/// Dump peak memory usage as the process exits, as registered by
+/// dump_on_exit(). Much of the process may already be torn down, so this does
+/// as little as possible: no waiting on poisoned locks, and no tracking of its
+/// own allocations.
+extern "C" fn dump_at_exit() {
+    let _guard = match RecordingGuard::enter() {
+        Some(guard) => guard,
+        None => return,
+    };
+    let path = match EXIT_DUMP_PATH.lock().ok().and_then(|mut path| path.take()) {
+        Some(path) => path,
+        None => return,
+    };
+    let mut allocations = match ALLOCATIONS.lock() {
+        Ok(allocations) => allocations,
+        Err(_) => return,
+    };
+    let path = if path.is_empty() {
+        allocations.default_path.clone()
+    } else {
+        path
+    };
+    // Python won't be around to post-process the report, so it's the
+    // self-contained version:
+    if allocations.dump_peak_report(&path).is_ok() {
+        info_message(|| format!("Wrote peak memory usage on exit to {}", path));
+    }
+}
+
+/// Dump peak memory usage to the given directory when the process exits
+/// normally, or to the default path if it's empty. Calling this again changes
+/// the directory.
+pub fn dump_on_exit(path: &str) {
+    *EXIT_DUMP_PATH.lock().unwrap() = Some(path.to_string());
+    if !EXIT_DUMP_REGISTERED.swap(true, Ordering::Relaxed) {
+        unsafe {
+            libc::atexit(dump_at_exit);
+        }
+    }
+}
+
+/// Free an existing allocation, returning its size, or None if it wasn't being
+/// tracked.
+pub fn free_allocation(address: usize) -> Option<usize> {
+    let _guard = RecordingGuard::enter()?;
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.free_allocation(address)
+}
+
+/// Stop tracking an allocation without it counting as a free(), e.g. because
+/// it was handed off to code whose free()s aren't tracked. Returns its size,
+/// or None if it wasn't being tracked.
+pub fn forget_allocation(address: usize) -> Option<usize> {
+    let _guard = RecordingGuard::enter()?;
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.forget_allocation(address)
+}
+
+/// How many free()s were of addresses that weren't being tracked.
+pub fn get_untracked_free_count() -> usize {
+    let allocations = ALLOCATIONS.lock().unwrap();
+    allocations.untracked_frees
+}
+
+/// How many free()s were of addresses that had already been free()d.
+pub fn get_double_free_count() -> usize {
+    let allocations = ALLOCATIONS.lock().unwrap();
+    allocations.double_frees
+}
+
+/// Statistics about interned callstacks.
+pub fn get_interner_stats() -> InternerStats {
+    let allocations = ALLOCATIONS.lock().unwrap();
+    allocations.interner.stats()
+}
+
+/// How many allocations and mmap()ed ranges are being tracked.
+pub fn get_tracked_allocation_count() -> usize {
+    let allocations = ALLOCATIONS.lock().unwrap();
+    allocations.tracked_allocation_count()
+}
+
+/// How many bytes are currently allocated.
+pub fn get_current_allocated_bytes() -> usize {
+    let allocations = ALLOCATIONS.lock().unwrap();
+    allocations.usage.current_bytes
+}
+
+/// How many bytes have been malloc()ed since tracking started, ignoring
+/// free()s. Sampling this over time gives the allocation rate.
+pub fn get_total_allocated_bytes() -> usize {
+    let allocations = ALLOCATIONS.lock().unwrap();
+    allocations.total_allocated_bytes
+}
+
+/// The largest number of bytes that were allocated at any one time.
+pub fn get_peak_allocated_bytes() -> usize {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.get_peak_allocated_bytes()
+}
+
+/// Whether peak memory usage so far is more than the given number of bytes.
+pub fn peak_exceeds(max_bytes: usize) -> bool {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.peak_exceeds(max_bytes)
+}
+
+/// Forget interned callstacks that are no longer used, returning how many were
+/// forgotten.
+pub fn compact_interner() -> usize {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.compact_interner()
+}
+
+/// Forget the peak so far, keeping track of current allocations, so the next
+/// dump shows the peak from this point on.
+pub fn reset_peak() {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.reset_peak();
+}
+
+/// Get the size of an allocation, or 0 if it's not tracked.
+pub fn get_allocation_size(address: usize) -> libc::size_t {
+    let allocations = ALLOCATIONS.lock().unwrap();
+    allocations.allocation_size(address)
+}
+
+/// The sizes of the allocations the self-test makes: small, medium, and large
+/// enough that malloc() uses mmap() for it.
+const SELF_TEST_SIZES: [usize; 4] = [17, 4000, 300_000, 20 * MIB];
+
+/// Check that malloc() and free() are being tracked, by calling them with
+/// known sizes and looking at what got recorded. This catches allocations
+/// bypassing the hooks, e.g. from a custom allocator. Discrepancies are
+/// written to stderr; returns whether there were none.
+pub fn run_self_test() -> bool {
+    run_self_test_with(
+        |size| unsafe { libc::malloc(size) as usize },
+        |address| unsafe { libc::free(address as *mut libc::c_void) },
+    )
+}
+
+fn run_self_test_with<M: FnMut(usize) -> usize, F: FnMut(usize)>(
+    mut malloc: M,
+    mut free: F,
+) -> bool {
+    let mut problems = Vec::new();
+    for size in SELF_TEST_SIZES.iter() {
+        let address = malloc(*size);
+        if address == 0 {
+            problems.push(format!("malloc({}) failed", size));
+            continue;
+        }
+        let recorded = get_allocation_size(address);
+        free(address);
+        if recorded != *size {
+            problems.push(format!(
+                "malloc({}) was recorded as {} bytes",
+                size, recorded
+            ));
+        } else if get_allocation_size(address) != 0 {
+            problems.push(format!(
+                "free() of the {} byte allocation wasn't recorded",
+                size
+            ));
+        }
+    }
+    if problems.is_empty() {
+        info_message(|| "Self-test passed: malloc() and free() are being tracked.".to_string());
+        return true;
+    }
+    for problem in &problems {
+        eprintln!("=fil-profile= Self-test: {}", problem);
+    }
+    eprintln!("=fil-profile= Self-test failed: allocations may be bypassing the profiler's hooks, e.g. via a custom allocator, or tracking may be off or sampled.");
+    false
+}
+
+/// Resize an anonymous mmap() based off the current callstack.
+pub fn remap_anon_mmap(
+    old_address: usize,
+    old_size: libc::size_t,
+    new_address: usize,
+    new_size: libc::size_t,
+    line_number: u16,
+) {
+    record_remap(old_address, old_size, new_address, new_size, || {
+        current_python_callstack(line_number)
+    });
+}
+
+/// Resize an anonymous mmap(), attributing growth only to the native code that
+/// called mremap().
+pub fn remap_native_anon_mmap(
+    old_address: usize,
+    old_size: libc::size_t,
+    new_address: usize,
+    new_size: libc::size_t,
+    caller: usize,
+) {
+    record_remap(old_address, old_size, new_address, new_size, || {
+        Callstack::from_native_caller(caller)
+    });
+}
+
+fn record_remap<F: FnOnce() -> Callstack>(
+    old_address: usize,
+    old_size: libc::size_t,
+    new_address: usize,
+    new_size: libc::size_t,
+    get_callstack: F,
+) {
+    if cfg!(feature = "no-mmap-tracking") {
+        return;
+    }
+    let _guard = match RecordingGuard::enter() {
+        Some(guard) => guard,
+        None => return,
+    };
+    let mut callstack = get_callstack();
+    callstack.thread_id = THREAD_ID.with(|thread_id| *thread_id);
+    callstack.label = current_label();
+    add_native_stack_if_enabled(&mut callstack);
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.remap_anon_mmap(old_address, old_size, new_address, new_size, &callstack);
+}
+
+/// Free an anonymous mmap().
+pub fn free_anon_mmap(address: usize, length: libc::size_t) {
+    let _guard = match RecordingGuard::enter() {
+        Some(guard) => guard,
+        None => return,
+    };
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.free_anon_mmap(address, length);
+}
+
+/// Record an sbrk() based off the current callstack; `previous_break` is what
+/// sbrk() returned.
+pub fn change_heap_break(previous_break: usize, increment: isize, line_number: u16) {
+    record_heap_break_change(previous_break, increment, || {
+        current_python_callstack(line_number)
+    });
+}
+
+/// Like change_heap_break(), but attributed only to the native code that
+/// called sbrk().
+pub fn change_native_heap_break(previous_break: usize, increment: isize, caller: usize) {
+    record_heap_break_change(previous_break, increment, || {
+        Callstack::from_native_caller(caller)
+    });
+}
+
+fn record_heap_break_change<F: FnOnce() -> Callstack>(
+    previous_break: usize,
+    increment: isize,
+    get_callstack: F,
+) {
+    if cfg!(feature = "no-mmap-tracking") {
+        return;
+    }
+    let _guard = match RecordingGuard::enter() {
+        Some(guard) => guard,
+        None => return,
+    };
+    // Shrinking doesn't need a callstack:
+    let mut callstack = if increment > 0 {
+        get_callstack()
+    } else {
+        Callstack::new()
+    };
+    callstack.thread_id = THREAD_ID.with(|thread_id| *thread_id);
+    callstack.label = current_label();
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.change_heap_break(previous_break, increment, &callstack);
+}
+
+/// Capture a native backtrace for allocations with no Python callstack, so
+/// it's possible to tell which C code allocated. This is expensive.
+pub fn set_capture_native_stacks(enabled: bool) {
+    if enabled {
+        // The first backtrace may load libraries, which allocates and takes
+        // the dynamic linker's lock. Doing that from a malloc() made by the
+        // dynamic linker would deadlock, so get it over with now:
+        capture_native_stack();
+    }
+    CAPTURE_NATIVE_STACKS.store(enabled, Ordering::Relaxed);
+}
+
+/// Attribute allocations with no Python callstack to the symbol of the native
+/// code that called malloc(), if enabled.
+pub fn set_record_native_callers(enabled: bool) {
+    RECORD_NATIVE_CALLERS.store(enabled, Ordering::Relaxed);
+}
+
+/// Set the size above which Python allocations get free()d on out-of-memory.
+pub fn set_oom_free_threshold(threshold: usize) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.oom_free_threshold = threshold;
+}
+
+/// Set the width of flamegraph SVGs in pixels; 0 means the default width.
+pub fn set_flamegraph_width(width: usize) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.dump_options.image_width = if width == 0 { None } else { Some(width) };
+}
+
+/// Include every callstack that ever allocated in text reports, with 0 bytes if
+/// it's using nothing, if enabled.
+pub fn set_inactive_callstacks(enabled: bool) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.dump_options.inactive_callstacks = enabled;
+}
+
+/// Set the font size and frame height of flamegraphs in pixels, and the font;
+/// 0 or None leave the default.
+pub fn set_flamegraph_style(font_size: usize, frame_height: usize, font_type: Option<&str>) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    let default = FlamegraphStyle::default();
+    allocations.dump_options.style = FlamegraphStyle {
+        font_size: if font_size == 0 {
+            default.font_size
+        } else {
+            font_size
+        },
+        frame_height: if frame_height == 0 {
+            default.frame_height
+        } else {
+            frame_height
+        },
+        font_type: font_type.map_or(default.font_type, str::to_string),
+    };
+}
+
+/// Color flamegraph frames by size (false), or by the module they're in
+/// (true).
+pub fn set_flamegraph_colors_by_module(by_module: bool) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.dump_options.colors = if by_module {
+        FlamegraphColors::ByModule
+    } else {
+        FlamegraphColors::BySize
+    };
+}
+
+/// Render flamegraphs with a dark background and light text if enabled,
+/// otherwise with inferno's usual light theme.
+pub fn set_flamegraph_dark_mode(enabled: bool) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.dump_options.dark_mode = enabled;
+}
+
+/// Start flamegraph callstacks with a frame naming the thread that allocated,
+/// e.g. "Thread python".
+pub fn set_thread_root_frames(enabled: bool) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.dump_options.thread_root_frames = enabled;
+}
+
+/// Choose which of the malloc() and mmap() hooks get recorded; see
+/// AllocatorMode for the accounting implications.
+pub fn set_allocator_mode(mode: AllocatorMode) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.allocator_mode = mode;
+}
+
+/// Merge callstacks using less than this fraction of total memory into one
+/// when rendering flamegraphs, e.g. 0.001 for 0.1%. 0 keeps everything.
+pub fn set_min_flamegraph_fraction(fraction: f64) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.dump_options.min_fraction = if fraction.is_nan() {
+        0.0
+    } else {
+        fraction.clamp(0.0, 1.0)
+    };
+}
+
+/// Only render the largest this many callstacks into flamegraphs, merging the
+/// rest, since rendering huge profiles is very slow. 0 means no limit.
+pub fn set_max_flamegraph_lines(max_lines: usize) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.dump_options.max_lines = max_lines;
+}
+
+/// Only record 1 in `rate` allocations, scaling up their sizes to match. A
+/// rate of 0 or 1 records every allocation.
+pub fn set_sampling_rate(rate: usize) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.sampling_rate = rate.max(1);
+    allocations.allocations_since_sample = 0;
+}
+
+/// Only record malloc()s made by the calling thread if enabled, otherwise
+/// record those from all threads. Other threads' memory still shows up in
+/// resident memory, but not in flamegraphs.
+pub fn set_tracked_thread(enabled: bool) {
+    let thread_id = THREAD_ID.with(|thread_id| *thread_id);
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.tracked_thread = if enabled { Some(thread_id) } else { None };
+}
+
+/// Store at most this many malloc()ed allocations; 0, the default, means no
+/// limit. Allocations past the limit still count towards memory usage, but
+/// their free()s can only be estimated, so reports are marked as approximate.
+/// Unlike set_max_in_memory_allocations(), this bounds the profiler's
+/// overhead at the cost of precision.
+pub fn set_max_allocations(max: usize) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.max_allocations = max;
+}
+
+/// Report every malloc()ed allocation within `tolerance` bytes of `size` on
+/// stderr, with its callstack; a size of 0 turns this off.
+pub fn set_watchpoint(size: usize, tolerance: usize) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.watchpoint = if size == 0 {
+        None
+    } else {
+        Some(Watchpoint {
+            size,
+            tolerance,
+            hits: 0,
+        })
+    };
+}
+
+/// Keep at most this many malloc()ed allocations' details in memory, spilling
+/// the rest to a memory-mapped file in the temporary directory; 0, the
+/// default, means no limit. This is for when the profiler's own bookkeeping
+/// would otherwise use up too much memory.
+pub fn set_max_in_memory_allocations(max: usize) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.current_allocations.set_max_in_memory(max);
+}
+
+/// Leave frames whose filename or function name contains the given pattern out
+/// of rendered callstacks, attributing their memory to their callers instead.
+pub fn add_hidden_frame_pattern(pattern: String) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations
+        .dump_options
+        .hidden_frames
+        .patterns
+        .push(pattern);
+    allocations.interner.clear_rendered();
+}
+
+/// Leave frames whose filename starts with the given prefix, e.g. the standard
+/// library's directory, out of rendered callstacks, attributing their memory
+/// to their callers instead.
+pub fn add_hidden_prefix(prefix: String) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.dump_options.hidden_frames.prefixes.push(prefix);
+    allocations.interner.clear_rendered();
+}
+
+/// Strip these project roots from the start of filenames in rendered
+/// callstacks. They're separated like PATH entries, so frames from several
+/// roots can all be shortened; an empty string means no roots. This is
+/// process-wide and survives reset().
+pub fn set_path_prefix(prefixes: &str) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    PATH_DISPLAY.write().unwrap().prefixes = std::env::split_paths(prefixes)
+        .filter_map(|path| path.to_str().map(str::to_string))
+        .filter(|prefix| !prefix.is_empty())
+        .collect();
+    allocations.interner.clear_rendered();
+}
+
+/// Whether to replace everything up to site-packages/ in rendered filenames
+/// with "<venv>". This is process-wide and survives reset().
+pub fn set_collapse_site_packages(enabled: bool) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    PATH_DISPLAY.write().unwrap().collapse_site_packages = enabled;
+    allocations.interner.clear_rendered();
+}
+
+/// Collapse calls deeper than the given depth into a single truncation frame;
+/// 0 means no limit.
+pub fn set_max_stack_depth(depth: usize) {
+    MAX_STACK_DEPTH.store(depth, Ordering::Relaxed);
+}
+
+/// Set how much gets written to stderr: 0 only mentions warnings and errors, NORMAL_VERBOSITY (the default) also mentions written
+/// reports, and DEBUG_VERBOSITY adds debugging messages.
+pub fn set_verbosity(verbosity: usize) {
+    VERBOSITY.store(verbosity, Ordering::Relaxed);
+}
+
+/// Record memory usage over time, sampling whenever it changes by at least
+/// `delta` bytes. A delta of 0 stops recording.
+pub fn set_timeline_delta(delta: usize) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.timeline = if delta == 0 {
+        None
+    } else {
+        Some(Timeline::new(delta, TIMELINE_CAPACITY))
+    };
+}
+
+/// Every `milliseconds`, count only the resident part of anonymous mmap()s,
+/// as reported by /proc/self/smaps, rather than everything that was mapped.
+/// This is more accurate for sparsely touched mmap()s, but reading smaps is
+/// slow, and it's only available on Linux. 0 goes back to counting all mapped
+/// bytes.
+#[cfg(not(feature = "no-mmap-tracking"))]
+pub fn set_resident_mmaps_interval_ms(milliseconds: u64) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    if milliseconds == 0 {
+        allocations.resident_mmaps_interval = None;
+        allocations.count_all_mapped_bytes();
+    } else {
+        allocations.resident_mmaps_interval = Some(Duration::from_millis(milliseconds));
+        allocations.last_resident_update = Instant::now();
+    }
+}
+
+/// mmap()s aren't tracked at all, so there's nothing to adjust.
+#[cfg(feature = "no-mmap-tracking")]
+pub fn set_resident_mmaps_interval_ms(_milliseconds: u64) {}
+
+/// Dump current allocations to the default path every `seconds` seconds,
+/// rotating between a few sets of files. 0 stops the snapshots.
+pub fn set_snapshot_interval_seconds(seconds: u64) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.snapshot_interval = if seconds == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(seconds))
+    };
+    allocations.last_snapshot = Instant::now();
+}
+
+/// Write memory usage over time to the given path as CSV.
+pub fn dump_memory_timeline_to_csv(path: &str) -> std::io::Result<()> {
+    let allocations = ALLOCATIONS.lock().unwrap();
+    let timeline = match &allocations.timeline {
+        Some(timeline) => timeline,
+        None => {
+            eprintln!("=fil-profile= Memory timeline wasn't enabled, so not writing it.");
+            return Err(std::io::Error::other("memory timeline wasn't enabled"));
+        }
+    };
+    let written = fs::File::create(path).and_then(|file| {
+        let mut file = std::io::BufWriter::new(file);
+        timeline.write_csv(&mut file)
+    });
+    match written {
+        Ok(_) => {
+            info_message(|| format!("Wrote memory timeline to {}", path));
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("=fil-profile= Error writing memory timeline: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Set how much memory is reserved for use after running out of memory.
+pub fn set_spare_memory_mb(megabytes: usize) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.spare_memory = Vec::with_capacity(megabytes * MIB);
+}
+
+/// Reset internal state.
+pub fn reset(default_path: String) {
+    LEAK_DETECTION_ENABLED.store(false, Ordering::Relaxed);
+    MAX_STACK_DEPTH.store(0, Ordering::Relaxed);
+    CAPTURE_NATIVE_STACKS.store(false, Ordering::Relaxed);
+    RECORD_NATIVE_CALLERS.store(false, Ordering::Relaxed);
+    PROFILING_STARTED.store(false, Ordering::Relaxed);
+    *ALLOCATIONS.lock().unwrap() = new_tracker(default_path);
+}
+
+// The ALLOCATIONS lock, held by the forking thread for the duration of a
+// fork(). This way no other thread is halfway through changing the tracker
+// when the child's copy of memory is made.
+thread_local!(static FORK_GUARD: RefCell<Option<MutexGuard<'static, AllocationTracker>>> = const { RefCell::new(None) });
+
+/// Called before fork(), via pthread_atfork().
+pub fn prepare_for_fork() {
+    let allocations = ALLOCATIONS.lock().unwrap();
+    FORK_GUARD.with(|guard| *guard.borrow_mut() = Some(allocations));
+}
+
+/// Called in the parent after fork(), via pthread_atfork(). Nothing changes
+/// in the parent, it just releases the lock.
+pub fn after_fork_in_parent() {
+    FORK_GUARD.with(|guard| guard.borrow_mut().take());
+}
+
+/// Called in the child after fork(), via pthread_atfork(). The child only
+/// has the forking thread, and its allocations are its own, so it starts
+/// over with a fresh tracker, as if reset() had been called, writing reports
+/// to a fork-<pid> subdirectory of the parent's default path. The forking
+/// thread's callstack is cleared too; calls that were in progress during the
+/// fork don't show up in the child's reports.
+pub fn after_fork_in_child() {
+    FORK_GUARD.with(|guard| {
+        if let Some(mut allocations) = guard.borrow_mut().take() {
+            allocations.reset_after_fork(std::process::id());
+            debug_message(|| {
+                format!(
+                    "Forked child will write reports to {}",
+                    allocations.default_path
+                )
+            });
+        }
+    });
+    LEAK_DETECTION_ENABLED.store(false, Ordering::Relaxed);
+    MAX_STACK_DEPTH.store(0, Ordering::Relaxed);
+    CAPTURE_NATIVE_STACKS.store(false, Ordering::Relaxed);
+    RECORD_NATIVE_CALLERS.store(false, Ordering::Relaxed);
+    THREAD_CALLSTACK.with(|cs| *cs.borrow_mut() = Callstack::new());
+}
+
+/// Like reset(), but first dump the peak of the run that's ending. Both happen
+/// under the same lock, so no allocations are lost in between.
+pub fn reset_and_dump(path: &str, default_path: String) -> std::io::Result<()> {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    let result = allocations.dump_peak_and_reset(path, default_path);
+    LEAK_DETECTION_ENABLED.store(false, Ordering::Relaxed);
+    MAX_STACK_DEPTH.store(0, Ordering::Relaxed);
+    CAPTURE_NATIVE_STACKS.store(false, Ordering::Relaxed);
+    RECORD_NATIVE_CALLERS.store(false, Ordering::Relaxed);
+    PROFILING_STARTED.store(false, Ordering::Relaxed);
+    result
+}
+
+/// Like reset(), but callstacks that were already interned keep their ids,
+/// which speeds up profiling the same code repeatedly.
+pub fn reset_keeping_interner(default_path: String) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.reset_keeping_interner(default_path);
+}
+
+/// Dump all callstacks in peak memory usage to format used by flamegraph. The
+/// title and subtitle have defaults if not given.
+pub fn dump_peak_to_flamegraph(
+    path: &str,
+    title: Option<&str>,
+    subtitle: Option<&str>,
+) -> std::io::Result<()> {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.dump_peak_to_flamegraph(path, title, subtitle)
+}
+
+/// Dump current and peak memory usage to SVGs, and an HTML report that
+/// toggles between them.
+pub fn dump_combined_report(path: &str) -> std::io::Result<()> {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.dump_combined_report(path)
+}
+
+/// Dump peak memory usage to SVGs and a minimal HTML report, without the
+/// markers the Python code post-processes.
+pub fn dump_peak_report(path: &str) -> std::io::Result<()> {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.dump_peak_report(path)
+}
+
+/// Merge collapsed-stack profiles, e.g. from runs with different inputs, into
+/// merged.prof in the given directory, and render it as flamegraphs titled
+/// with the combined total.
+pub fn merge_profiles_to_flamegraph(inputs: &[PathBuf], directory: &Path) -> std::io::Result<()> {
+    let dump_options = ALLOCATIONS.lock().unwrap().dump_options.clone();
+    fs::create_dir_all(directory)?;
+    let raw_path = directory.join("merged.prof");
+    let total = collapsed::merge_collapsed_files(inputs, &raw_path)?;
+    let raw_path = raw_path.to_str().unwrap();
+    // If the inputs were written for post-processing, so is the output:
+    let to_be_post_processed = fs::read_to_string(raw_path)?.contains("TB@@");
+    for (reversed, filename) in &[(false, "merged.svg"), (true, "merged-reversed.svg")] {
+        write_flamegraph(
+            raw_path,
+            directory.join(filename).to_str().unwrap(),
+            total,
+            *reversed,
+            "Merged Memory Usage",
+            None,
+            to_be_post_processed,
+            &dump_options,
+        )?;
+    }
+    Ok(())
+}
+
+/// Write every allocation event to the given file from now on. Unlike dumps,
+/// the log survives the process crashing or being killed, and can be turned
+/// into flamegraphs afterwards with render_event_log_to_flamegraph().
+pub fn enable_event_log(path: &Path) -> std::io::Result<()> {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.enable_event_log(path)
+}
+
+/// Replay an event log written after enable_event_log(), writing current and
+/// peak memory usage as of the end of the log to current-memory.prof and
+/// peak-memory.prof, plus flamegraphs, in the given directory.
+pub fn render_event_log_to_flamegraph(log_path: &Path, directory: &Path) -> std::io::Result<()> {
+    let dump_options = ALLOCATIONS.lock().unwrap().dump_options.clone();
+    let replayed = eventlog::replay_file(log_path)?;
+    fs::create_dir_all(directory)?;
+    for (usage, total, name, title) in [
+        (
+            replayed.current,
+            replayed.current_bytes,
+            "current-memory",
+            "Current Tracked Memory Usage",
+        ),
+        (
+            replayed.peak,
+            replayed.peak_bytes,
+            "peak-memory",
+            "Peak Tracked Memory Usage",
+        ),
+    ] {
+        let raw_path = directory.join(format!("{}.prof", name));
+        let raw_path = raw_path.to_str().unwrap();
+        let mut file = std::io::BufWriter::new(fs::File::create(raw_path)?);
+        for line in sorted_lines(usage) {
+            writeln!(file, "{}", line)?;
+        }
+        file.flush()?;
+        drop(file);
+        for (reversed, suffix) in &[(false, ""), (true, "-reversed")] {
+            write_flamegraph(
+                raw_path,
+                directory
+                    .join(format!("{}{}.svg", name, suffix))
+                    .to_str()
+                    .unwrap(),
+                total,
+                *reversed,
+                title,
+                None,
+                false,
+                &dump_options,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Render a peak written by dump_peak_binary(), possibly on another machine,
+/// to peak-memory.prof and flamegraphs in the given directory. This way
+/// collecting the data and the more expensive rendering can happen separately.
+pub fn render_binary_to_flamegraph(binary_path: &Path, directory: &Path) -> std::io::Result<()> {
+    let dump_options = ALLOCATIONS.lock().unwrap().dump_options.clone();
+    let mut input = std::io::BufReader::new(fs::File::open(binary_path)?);
+    let lines = collapsed::read_binary(&mut input)?;
+    let text = lines
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect::<String>();
+    let total = collapsed::parse_collapsed(&text).values().sum();
+    fs::create_dir_all(directory)?;
+    let raw_path = directory.join("peak-memory.prof");
+    let raw_path = raw_path.to_str().unwrap();
+    fs::write(raw_path, &text)?;
+    // Binary dumps are written for post-processing:
+    let to_be_post_processed = text.contains("TB@@");
+    for (reversed, filename) in &[
+        (false, "peak-memory.svg"),
+        (true, "peak-memory-reversed.svg"),
+    ] {
+        write_flamegraph(
+            raw_path,
+            directory.join(filename).to_str().unwrap(),
+            total,
+            *reversed,
+            "Peak Tracked Memory Usage",
+            None,
+            to_be_post_processed,
+            &dump_options,
+        )?;
+    }
+    Ok(())
+}
+
+/// Remember current memory usage under the given name, so growth since now can
+/// be dumped later with dump_delta_flamegraph().
+pub fn snapshot_baseline(name: String) {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    let usage = allocations.usage.current.clone();
+    allocations.baselines.insert(name, usage);
+    allocations.new_generation();
+}
+
+/// Start a new generation, so allocations made so far count as one generation
+/// older. This also happens on every dump and snapshot.
+pub fn new_generation() {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.new_generation();
+}
+
+/// Write flamegraphs of current allocations made at least `min_age`
+/// generations ago to the given directory.
+pub fn dump_old_allocations_flamegraph(min_age: u16, path: &str) -> std::io::Result<()> {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    match allocations.dump_old_allocations_flamegraph(min_age, path) {
+        Ok(_) => {
+            info_message(|| format!("Wrote old allocation flamegraphs to {}", path));
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!(
+                "=fil-profile= Error writing old allocation flamegraphs: {}",
+                e
+            );
+            Err(e)
+        }
+    }
+}
+
+/// Write flamegraphs of byte-seconds, i.e. allocation size times how long the
+/// allocation was live, to the given directory.
+pub fn dump_byte_seconds_flamegraph(path: &str) -> std::io::Result<()> {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    match allocations.dump_byte_seconds_flamegraph(path) {
+        Ok(_) => {
+            info_message(|| format!("Wrote byte-seconds flamegraphs to {}", path));
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!(
+                "=fil-profile= Error writing byte-seconds flamegraphs: {}",
+                e
+            );
+            Err(e)
+        }
+    }
+}
+
+/// Write flamegraphs of memory growth since the named baseline to the given
+/// directory.
+pub fn dump_delta_flamegraph(name: &str, path: &str) -> std::io::Result<()> {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    match allocations.dump_delta_flamegraph(name, path) {
+        Ok(_) => {
+            info_message(|| format!("Wrote memory growth flamegraphs to {}", path));
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!(
+                "=fil-profile= Error writing memory growth flamegraphs: {}",
+                e
+            );
+            Err(e)
+        }
+    }
+}
+
+/// Start looking for Python calls that leak memory every time they're called.
+/// This is slow, so it's opt-in.
+pub fn enable_leak_detection() {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    if allocations.leak_detector.is_none() {
+        allocations.leak_detector = Some(LeakDetector::new());
+    }
+    LEAK_DETECTION_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Start tracking the peak memory of each function call, including the calls
+/// it makes. Calls already in progress aren't tracked.
+pub fn enable_call_peaks() {
+    call_peaks::reset_call_peaks();
+    CALL_PEAKS_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Write the most memory any single call to each function added, including
+/// its callees, to call-peaks.txt in the given directory, as "function bytes"
+/// lines.
+pub fn dump_call_peaks(path: &str) -> std::io::Result<()> {
+    let path = Path::new(path).join("call-peaks.txt");
+    let mut contents = call_peaks::call_peak_lines().join("\n");
+    contents.push('\n');
+    match fs::write(&path, contents) {
+        Ok(_) => {
+            info_message(|| format!("Wrote call peaks to {}", path.display()));
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("=fil-profile= Error writing call peaks: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Write out the Python calls that appear to leak memory to leak-suspects.txt
+/// in the given directory.
+pub fn dump_leak_suspects(path: &str) -> std::io::Result<()> {
+    let allocations = ALLOCATIONS.lock().unwrap();
+    let path = Path::new(path).join("leak-suspects.txt");
+    match allocations.dump_leak_suspects(&path) {
+        Ok(_) => {
+            info_message(|| format!("Wrote leak suspects to {}", path.display()));
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("=fil-profile= Error writing leak suspects: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// The largest live allocation, as "<address> <bytes> <callstack>", or an
+/// empty string if nothing is allocated.
+pub fn largest_allocation() -> String {
+    let allocations = ALLOCATIONS.lock().unwrap();
+    match allocations.largest_allocation() {
+        Some((address, size, callstack)) => format!("{:#x} {} {}", address, size, callstack),
+        None => String::new(),
+    }
+}
+
+/// The n callstacks using the most memory, at peak or currently, as lines of
+/// "<bytes> <callstack>", largest first.
+pub fn top_allocators(n: usize, peak: bool) -> String {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations
+        .top_allocators(n, peak)
+        .into_iter()
+        .map(|(callstack, size)| format!("{} {}\n", size, callstack))
+        .collect()
+}
+
+/// Write peak memory usage to the given file in collapsed-stack format.
+pub fn dump_peak_collapsed(path: &str) -> std::io::Result<()> {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    match allocations.dump_peak_collapsed(path) {
+        Ok(_) => {
+            info_message(|| format!("Wrote collapsed stacks to {}", path));
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("=fil-profile= Error writing collapsed stacks: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Write peak memory usage in collapsed-stack format to the given file
+/// descriptor, which is left open.
+#[cfg(unix)]
+pub fn dump_peak_collapsed_to_fd(fd: libc::c_int) -> std::io::Result<()> {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    // We don't own the file descriptor, so make sure it doesn't get closed:
+    let mut file = ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fd) });
+    match allocations.dump_peak_collapsed_to(&mut *file) {
+        Ok(_) => {
+            info_message(|| format!("Wrote collapsed stacks to file descriptor {}", fd));
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("=fil-profile= Error writing collapsed stacks: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Write a peak memory usage flamegraph SVG to the given file descriptor,
+/// which is left open.
+#[cfg(unix)]
+pub fn dump_peak_svg_to_fd(fd: libc::c_int) -> std::io::Result<()> {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    let mut file = ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fd) });
+    match allocations.dump_peak_svg_to(&mut *file) {
+        Ok(_) => {
+            info_message(|| format!("Wrote memory usage flamegraph to file descriptor {}", fd));
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("=fil-profile= Error writing SVG: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Write peak memory usage summed by file, regardless of callstack, to the
+/// given file.
+pub fn dump_peak_by_file(path: &str) -> std::io::Result<()> {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    match allocations.dump_peak_by_file(path) {
+        Ok(_) => {
+            info_message(|| format!("Wrote memory usage by file to {}", path));
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("=fil-profile= Error writing memory usage by file: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Allocations made by the current thread get the given label until the
+/// matching pop_label(). Labels nest.
+pub fn push_label(name: &str) {
+    let label = label_id(name);
+    THREAD_LABELS.with(|labels| labels.borrow_mut().push(label));
+}
+
+/// Go back to the label in effect before the matching push_label().
+pub fn pop_label() {
+    THREAD_LABELS.with(|labels| {
+        labels.borrow_mut().pop();
+    });
+}
+
+/// Write peak memory usage summed by label to the given file.
+pub fn dump_peak_by_label(path: &str) -> std::io::Result<()> {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    match allocations.dump_peak_by_label(path) {
+        Ok(_) => {
+            info_message(|| format!("Wrote memory usage by label to {}", path));
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("=fil-profile= Error writing memory usage by label: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Call the given function with each callstack's rendered string and its
+/// memory usage at the peak, largest first. The tracker isn't locked while
+/// the function runs, so it's free to call back into the profiler.
+pub fn for_each_peak_callstack<F: FnMut(&str, usize)>(mut f: F) {
+    let callstacks = {
+        let mut allocations = ALLOCATIONS.lock().unwrap();
+        allocations.peak_callstacks()
+    };
+    for (callstack, bytes) in callstacks {
+        f(&callstack, bytes);
+    }
+}
+
+/// Write peak memory usage by line within the function with the given name to
+/// the given file, largest first, to see which of its lines allocate the most.
+pub fn dump_function_lines(function_name: &str, path: &str) -> std::io::Result<()> {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    match allocations.dump_function_lines(function_name, path) {
+        Ok(_) => {
+            info_message(|| {
+                format!(
+                    "Wrote memory usage by line of {} to {}",
+                    function_name, path
+                )
+            });
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("=fil-profile= Error writing memory usage by line: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Write peak memory usage to the given file as CSV.
+pub fn dump_peak_to_csv(path: &str) -> std::io::Result<()> {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    match allocations.dump_peak_to_csv(path) {
+        Ok(_) => {
+            info_message(|| format!("Wrote CSV to {}", path));
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("=fil-profile= Error writing CSV: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Write peak memory usage to the given file in pprof's protobuf format.
+pub fn dump_peak_to_pprof(path: &str) -> std::io::Result<()> {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    match allocations.dump_peak_to_pprof(path) {
+        Ok(_) => {
+            info_message(|| format!("Wrote pprof profile to {}", path));
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("=fil-profile= Error writing pprof profile: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Write peak memory usage to the given file in the compact binary
+/// collapsed-stack format.
+pub fn dump_peak_binary(path: &str) -> std::io::Result<()> {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    let written = fs::File::create(path).and_then(|file| {
+        let mut file = std::io::BufWriter::new(file);
+        allocations.dump_peak_binary(&mut file)
+    });
+    match written {
+        Ok(_) => {
+            info_message(|| format!("Wrote binary profiling data to {}", path));
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("=fil-profile= Error writing binary profiling data: {}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Dump all callstacks in peak memory usage to format used by flamegraph, both
+/// combined and for each thread separately.
+pub fn dump_peak_by_thread_to_flamegraph(path: &str) -> std::io::Result<()> {
+    let mut allocations = ALLOCATIONS.lock().unwrap();
+    allocations.dump_peak_by_thread_to_flamegraph(path)
+}
+
+/// Write the most recent allocation events to the given file descriptor.
+///
+/// Meant for crash forensics, so we don't block if the tracker is already
+/// locked (e.g. we crashed while holding the lock): we just give up.
+#[cfg(unix)]
+pub fn dump_recent_events(fd: libc::c_int) {
+    let allocations = match ALLOCATIONS.try_lock() {
+        Ok(allocations) => allocations,
+        Err(std::sync::TryLockError::Poisoned(e)) => e.into_inner(),
+        Err(std::sync::TryLockError::WouldBlock) => return,
+    };
+    // We don't own the file descriptor, so make sure it doesn't get closed:
+    let mut file = ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fd) });
+    let _ = allocations.write_recent_events(&mut *file);
+}
+
+/// Quote a CSV field, doubling any quotes within it, so commas, quotes and
+/// newlines in filenames and function names survive.
+fn csv_quote(field: &str) -> String {
+    format!("\"{}\"", field.replace('"', "\"\""))
+}
+
+/// Make sure the given directory exists.
+fn ensure_directory(path: &Path) -> std::io::Result<()> {
+    if !path.exists() {
+        fs::create_dir_all(path)
+    } else if !path.is_dir() {
+        Err(std::io::Error::other("output path must be a directory"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Create the output directory if needed, returning where reports should be
+/// written. If that fails, e.g. on a container's read-only filesystem, fall
+/// back to a directory of the same name under fallback/fil-result, so the
+/// report isn't lost.
+fn create_output_directory(path: &Path, fallback: &Path) -> std::io::Result<PathBuf> {
+    let error = match ensure_directory(path) {
+        Ok(()) => return Ok(path.to_path_buf()),
+        Err(e) => e,
+    };
+    let fallback_path = fallback
+        .join("fil-result")
+        .join(path.file_name().unwrap_or_else(|| "output".as_ref()));
+    if fallback_path == path {
+        eprintln!(
+            "=fil-profile= Couldn't create the output directory: {}",
+            error
+        );
+        return Err(error);
+    }
+    match ensure_directory(&fallback_path) {
+        Ok(()) => {
+            eprintln!(
+                "=fil-profile= Couldn't create the output directory {} ({}), writing to {} instead.",
+                path.display(),
+                error,
+                fallback_path.display()
+            );
+            Ok(fallback_path)
+        }
+        Err(e) => {
+            eprintln!(
+                "=fil-profile= Couldn't create the output directory {} ({}) or {} ({}).",
+                path.display(),
+                error,
+                fallback_path.display(),
+                e
+            );
+            Err(error)
+        }
+    }
+}
+
+/// Write strings to disk, one line per string.
+fn write_lines<I: Iterator<Item = String>>(lines: I, path: &str) -> std::io::Result<()> {
+    let mut file = fs::File::create(path)?;
+    write_lines_to(lines, &mut file)
+}
+
+/// Write strings, one line per string.
+fn write_lines_to<I: Iterator<Item = String>, W: Write>(
+    lines: I,
+    out: &mut W,
+) -> std::io::Result<()> {
+    for line in lines {
+        out.write_all(line.as_bytes())?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()
+}
+
+/// Write an index.html embedding the forward and reversed SVGs for the given
+/// base filename, returning its path.
+fn write_html_report(
+    directory: &Path,
+    base_filename: &str,
+    peak_bytes: usize,
+) -> std::io::Result<PathBuf> {
+    let path = directory.join("index.html");
+    let mut file = fs::File::create(&path)?;
+    write!(
+        file,
+        r#"<html>
+<head>
+  <meta charset="utf-8">
+  <title>Fil Memory Profile</title>
+</head>
+<body>
+<h1>Fil Memory Profile</h1>
+<h2>Peak tracked memory usage: {peak_mib:.1} MiB</h2>
+
+<p>The flame graphs show the callstacks responsible for allocations.
+The wider the bar, the more memory was allocated by that function or its callers.
+The second graph shows the reversed callgraph, merging all calls to the same function.</p>
+
+<div><iframe src="{base}.svg" width="100%" height="400" scrolling="auto" frameborder="0"></iframe></div>
+<br>
+<div><iframe src="{base}-reversed.svg" width="100%" height="400" scrolling="auto" frameborder="0"></iframe></div>
+</body>
+</html>
+"#,
+        peak_mib = peak_bytes as f64 / MIB as f64,
+        base = base_filename,
+    )?;
+    file.flush()?;
+    Ok(path)
+}
+
+/// Write an index.html showing either the current-memory or peak-memory
+/// flamegraphs, with buttons to switch between them, returning its path.
+fn write_combined_html_report(
+    directory: &Path,
+    current_bytes: usize,
+    peak_bytes: usize,
+) -> std::io::Result<PathBuf> {
+    let path = directory.join("index.html");
+    let mut file = fs::File::create(&path)?;
+    let section = |id: &str, base: &str, display: &str| {
+        format!(
+            r#"<div id="{id}" style="display: {display}">
+<div><iframe src="{base}.svg" width="100%" height="400" scrolling="auto" frameborder="0"></iframe></div>
+<br>
+<div><iframe src="{base}-reversed.svg" width="100%" height="400" scrolling="auto" frameborder="0"></iframe></div>
+</div>"#,
+            id = id,
+            base = base,
+            display = display,
+        )
+    };
+    write!(
+        file,
+        r#"<html>
+<head>
+  <meta charset="utf-8">
+  <title>Fil Memory Profile</title>
+  <script>
+    function show(id) {{
+      for (const other of ["current", "peak"]) {{
+        document.getElementById(other).style.display = (other === id) ? "block" : "none";
+      }}
+    }}
+  </script>
+</head>
+<body>
+<h1>Fil Memory Profile</h1>
+<h2>Current tracked memory usage: {current_mib:.1} MiB; peak: {peak_mib:.1} MiB</h2>
+
+<p>The flame graphs show the callstacks responsible for allocations, either
+those currently allocated or those allocated at the time of peak memory usage.
+The wider the bar, the more memory was allocated by that function or its callers.
+The second graph shows the reversed callgraph, merging all calls to the same function.</p>
+
+<p><button onclick="show('current')">Current</button> <button onclick="show('peak')">Peak</button></p>
+
+{current}
+{peak}
+</body>
+</html>
+"#,
+        current_mib = current_bytes as f64 / MIB as f64,
+        peak_mib = peak_bytes as f64 / MIB as f64,
+        current = section("current", "current-memory", "none"),
+        peak = section("peak", "peak-memory", "block"),
+    )?;
+    file.flush()?;
+    Ok(path)
+}
+
+/// Write a flamegraph SVG to disk, given lines in summarized format.
+#[allow(clippy::too_many_arguments)]
+fn write_flamegraph(
+    lines_file_path: &str,
+    path: &str,
+    peak_bytes: usize,
+    reversed: bool,
+    title: &str,
+    // If not given and post-processing, the Python code adds a subtitle:
+    subtitle: Option<&str>,
+    to_be_post_processed: bool,
+    dump_options: &DumpOptions,
+) -> std::io::Result<()> {
+    let lines = fs::read_to_string(lines_file_path)?;
+    let mut file = std::fs::File::create(path)?;
+    write_flamegraph_to(
+        &lines,
+        &mut file,
+        peak_bytes,
+        reversed,
+        title,
+        subtitle,
+        to_be_post_processed,
+        dump_options,
+    )
+}
+
+/// Write lines in summarized format as a JSON or CSV table of callstacks,
+/// biggest first.
+fn write_table<I: Iterator<Item = String>>(
+    lines: I,
+    path: &Path,
+    format: OutputFormat,
+    total_bytes: usize,
+) -> std::io::Result<()> {
+    let lines: Vec<String> = lines.collect();
+    let mut rows: Vec<(&str, usize)> = lines
+        .iter()
+        .filter_map(|line| {
+            let (stack, bytes) = line.rsplit_once(' ')?;
+            Some((stack, bytes.parse().ok()?))
+        })
+        .collect();
+    rows.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    if format == OutputFormat::Json {
+        write!(file, "{{\"total_bytes\":{},\"callstacks\":[", total_bytes)?;
+        for (i, (stack, bytes)) in rows.iter().enumerate() {
+            if i > 0 {
+                write!(file, ",")?;
+            }
+            write!(
+                file,
+                "{{\"stack\":{},\"bytes\":{}}}",
+                json_quote(stack),
+                bytes
+            )?;
+        }
+        writeln!(file, "]}}")?;
+    } else {
+        writeln!(file, "bytes,stack")?;
+        for (stack, bytes) in rows {
+            writeln!(file, "{},{}", bytes, csv_quote(stack))?;
+        }
+    }
+    file.flush()
+}
+
+/// Quote a string for use in JSON.
+fn json_quote(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+/// Write a flamegraph SVG, given lines in summarized format.
+#[allow(clippy::too_many_arguments)]
+fn write_flamegraph_to<W: Write>(
+    lines: &str,
+    out: &mut W,
+    peak_bytes: usize,
+    reversed: bool,
+    title: &str,
+    subtitle: Option<&str>,
+    to_be_post_processed: bool,
+    dump_options: &DumpOptions,
+) -> std::io::Result<()> {
+    // Inactive callstacks would have no width anyway:
+    let active;
+    let lines = if lines.lines().any(|line| line.ends_with(" 0")) {
+        active = lines.lines().filter(|line| !line.ends_with(" 0")).fold(
+            String::new(),
+            |mut active, line| {
+                active.push_str(line);
+                active.push('\n');
+                active
+            },
+        );
+        active.as_str()
+    } else {
+        lines
+    };
+    let limited;
+    let lines = if dump_options.max_lines > 0 && lines.lines().count() > dump_options.max_lines {
+        let (text, merged) = limit_lines(lines, dump_options.max_lines);
+        eprintln!(
+            "=fil-profile= The flamegraph would have too many callstacks to render quickly, so the {} smallest were merged into \"{}\".",
+            merged, SMALL_CALLSTACKS
+        );
+        limited = text;
+        limited.as_str()
+    } else {
+        lines
+    };
+    let mut palette_map = match dump_options.colors {
+        FlamegraphColors::BySize => None,
+        FlamegraphColors::ByModule => Some(module_palette(lines)),
+    };
+    let title = format!(
+        "{}{} ({})",
+        title,
+        if reversed { ", Reversed" } else { "" },
+        dump_options.units.format_total(peak_bytes)
+    );
+    let mut options = flamegraph::Options {
+        title,
+        count_name: dump_options.units.count_name().to_string(),
+        font_size: dump_options.style.font_size,
+        font_type: dump_options.style.font_type.clone(),
+        frame_height: dump_options.style.frame_height,
+        reverse_stack_order: reversed,
+        color_diffusion: true,
+        direction: flamegraph::Direction::Inverted,
+        // Maybe disable this some day, but for now it makes debugging much
+        // easier:
+        pretty_xml: true,
+        image_width: dump_options.image_width,
+        ..Default::default()
+    };
+    if palette_map.is_some() {
+        // Color diffusion overrides the palette:
+        options.color_diffusion = false;
+        options.palette_map = palette_map.as_mut();
+    }
+    if let Some(subtitle) = subtitle {
+        options.subtitle = Some(subtitle.to_string());
+    } else if to_be_post_processed {
+        options.subtitle = Some("SUBTITLE-HERE".to_string());
+    }
+    if !dump_options.dark_mode {
+        return render_flamegraph(&mut options, lines, out);
+    }
+    options.bgcolors = Some(flamegraph::color::BackgroundColor::Flat(
+        DARK_MODE_BACKGROUND,
+    ));
+    let mut svg = Vec::new();
+    render_flamegraph(&mut options, lines, &mut svg)?;
+    let svg = String::from_utf8_lossy(&svg);
+    let text_rule = "fill:rgb(0,0,0); }";
+    let svg = svg.replacen(text_rule, &format!("{}{}", text_rule, DARK_MODE_CSS), 1);
+    out.write_all(svg.as_bytes())?;
+    out.flush()
+}
+
+/// Render lines in summarized format with inferno.
+fn render_flamegraph<W: Write>(
+    options: &mut flamegraph::Options,
+    lines: &str,
+    out: &mut W,
+) -> std::io::Result<()> {
+    if let Err(e) = flamegraph::from_lines(options, lines.lines(), &mut *out) {
+        Err(std::io::Error::other(format!("{}", e)))
+    } else {
+        out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        add_allocation, begin_region, capture_native_stack, collapsed, create_output_directory,
+        current_label, current_python_callstack, default_output_path, dump_at_exit, end_region,
+        eventlog, finish_call, format_native_caller, format_native_frame, frame_filename,
+        free_allocation, get_allocation_size, limit_lines, module_color, module_of, module_palette,
+        native_stack_string, new_line_number, new_tracker, pop_label, pprof, push_label,
+        realloc_allocation, region_function, render_binary_to_flamegraph, request_dump,
+        run_self_test, run_self_test_with, sorted_lines, start_call, thread_root_frame,
+        truncation_frame, write_debug_message, write_flamegraph, write_flamegraph_to,
+        write_html_report, write_lines, Allocation, AllocationTracker, CallSiteId, Callstack,
+        CallstackInterner, DumpOptions, FlamegraphColors, FlamegraphStyle, FunctionId,
+        FunctionLocation, HiddenFrames, LeakDetector, OutputFormat, PathDisplay, Timeline,
+        Watchpoint, DEBUG_VERBOSITY, DEFAULT_SPARE_MEMORY_SIZE, EXIT_DUMP_PATH,
+        LEAK_MIN_INVOCATIONS, LEAK_SUSPECTS_LIMIT, MIB, PATH_DISPLAY, SMALL_CALLSTACKS,
+        UNTRACKED_FREES_MIN_FREES, WHILE_RECORDING,
+    };
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    use super::{AllocatorMode, RECENT_EVENTS_CAPACITY};
+    use crate::accounting::{COMPRESSION_UNIT, HIGH_32BIT, MAX_COMPRESSED_UNITS};
+    use proptest::prelude::*;
+    use std::collections;
+    use std::time::Duration;
+
+    // The largest allocation size the mmap() proptests try; on 32-bit
+    // platforms this is bounded by the address space.
+    #[cfg(all(
+        target_pointer_width = "64",
+        not(feature = "kib-resolution"),
+        not(feature = "no-mmap-tracking")
+    ))]
+    const MAX_TEST_SIZE: usize = 1 << 50;
+    #[cfg(all(
+        target_pointer_width = "64",
+        feature = "kib-resolution",
+        not(feature = "no-mmap-tracking")
+    ))]
+    const MAX_TEST_SIZE: usize = 1 << 40;
+    #[cfg(all(target_pointer_width = "32", not(feature = "no-mmap-tracking")))]
+    const MAX_TEST_SIZE: usize = usize::MAX;
+
+    // The largest size a compressed size can represent, beyond which sizes
+    // get clamped; on 32-bit platforms every size is representable.
+    #[cfg(target_pointer_width = "64")]
+    const MAX_REPRESENTABLE_SIZE: usize = MAX_COMPRESSED_UNITS * COMPRESSION_UNIT;
+    #[cfg(target_pointer_width = "32")]
+    const MAX_REPRESENTABLE_SIZE: usize = usize::MAX;
+
+    proptest! {
+        // Allocation sizes smaller than 2 ** 31 are round-tripped.
+        #[test]
+        fn small_allocation(size in 0..(HIGH_32BIT - 1)) {
+            let allocation = Allocation::new(0, size as usize, 0, 0);
+            prop_assert_eq!(size as usize, allocation.size());
+        }
+
+        // Allocation sizes larger than 2 ** 31 are stored as MiBs (or KiBs),
+        // with some loss of resolution.
+        #[test]
+        fn large_allocation(size in (HIGH_32BIT as usize)..=MAX_REPRESENTABLE_SIZE) {
+            let allocation = Allocation::new(0, size, 0, 0);
+            let result_size = allocation.size();
+            let diff = size.abs_diff(result_size);
+            prop_assert!(diff <= COMPRESSION_UNIT / 2)
+        }
+
+        // Sizes too large to store are clamped to the largest
+        // representable size, rather than wrapping around.
+        #[cfg(target_pointer_width = "64")]
+        #[test]
+        fn huge_allocation(size in (MAX_COMPRESSED_UNITS * COMPRESSION_UNIT)..usize::MAX) {
+            let allocation = Allocation::new(0, size, 0, 0);
+            prop_assert_eq!(allocation.size(), MAX_COMPRESSED_UNITS * COMPRESSION_UNIT);
+        }
+
+        // Bigger allocations never end up with a smaller size, including
+        // across the switch to units and when clamping.
+        #[test]
+        fn allocation_size_is_monotonic(a in any::<usize>(), b in any::<usize>()) {
+            let (smaller, larger) = (a.min(b), a.max(b));
+            prop_assert!(
+                Allocation::new(0, smaller, 0, 0).size() <= Allocation::new(0, larger, 0, 0).size()
+            );
+        }
+
+        // Test for https://github.com/pythonspeed/filprofiler/issues/66
+        #[cfg(not(feature = "no-mmap-tracking"))]
+        #[test]
+        fn correct_allocation_size_tracked(size in 1usize..(MAX_TEST_SIZE / 3)) {
+            let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+            tracker.add_allocation(0, size, &Callstack::new());
+            tracker.add_anon_mmap(1, size * 2, &Callstack::new());
+            // We don't track (large) allocations exactly right, but they should
+            // be quite close:
+            let ratio = ((size * 3) as f64) / (tracker.usage.current[0] as f64);
+            prop_assert!(0.999 < ratio);
+            prop_assert!(ratio < 1.001);
+            tracker.free_allocation(0);
+            tracker.free_anon_mmap(1, size * 2);
+            // Once we've freed everything, it should be _exactly_ 0.
+            prop_assert_eq!(&im::vector![0], &tracker.usage.current);
+        }
+
+        #[test]
+        fn current_allocated_matches_sum_of_allocations(
+            // Allocated bytes. Will use index as the memory address.
+            allocated_sizes in prop::collection::vec(1..1000usize, 10..20),
+            // Allocations to free.
+            free_indices in prop::collection::btree_set(0..10usize, 1..5)
+        ) {
+            let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+            let mut expected_memory_usage = im::vector![];
+            for i in 0..allocated_sizes.len() {
+                let mut cs = Callstack::new();
+                cs.start_call(0, CallSiteId::new(FunctionId::new(i as *const FunctionLocation), 0));
+                tracker.add_allocation(i, *allocated_sizes.get(i).unwrap(), &cs);
+                expected_memory_usage.push_back(*allocated_sizes.get(i).unwrap());
+            }
+            let mut expected_sum = allocated_sizes.iter().sum();
+            let expected_peak : usize = expected_sum;
+            prop_assert_eq!(tracker.usage.current_bytes, expected_sum);
+            prop_assert_eq!(&tracker.usage.current, &expected_memory_usage);
+            for i in free_indices.iter() {
+                expected_sum -= allocated_sizes.get(*i).unwrap();
+                tracker.free_allocation(*i);
+                expected_memory_usage[*i] -= allocated_sizes.get(*i).unwrap();
+                prop_assert_eq!(tracker.usage.current_bytes, expected_sum);
+                prop_assert_eq!(&tracker.usage.current, &expected_memory_usage);
+            }
+            prop_assert_eq!(tracker.usage.peak_bytes, expected_peak);
+        }
+
+        #[cfg(not(feature = "no-mmap-tracking"))]
+        #[test]
+        fn current_allocated_anon_maps_matches_sum_of_allocations(
+            // Allocated bytes. Will use index as the memory address.
+            allocated_sizes in prop::collection::vec(1..1000usize, 10..20),
+            // Allocations to free.
+            free_indices in prop::collection::btree_set(0..10usize, 1..5)
+        ) {
+            let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+            let mut expected_memory_usage = im::vector![];
+            // Make sure addresses don't overlap:
+            let addresses : Vec<usize> = (0..allocated_sizes.len()).map(|i| i * 10000).collect();
+            for (i, address) in addresses.iter().enumerate() {
+                let mut cs = Callstack::new();
+                cs.start_call(0, CallSiteId::new(FunctionId::new(i as *const FunctionLocation), 0));
+                tracker.add_anon_mmap(*address, *allocated_sizes.get(i).unwrap(), &cs);
+                expected_memory_usage.push_back(*allocated_sizes.get(i).unwrap());
+            }
+            let mut expected_sum = allocated_sizes.iter().sum();
+            let expected_peak : usize = expected_sum;
+            prop_assert_eq!(tracker.usage.current_bytes, expected_sum);
+            prop_assert_eq!(&tracker.usage.current, &expected_memory_usage);
+            for i in free_indices.iter() {
+                expected_sum -= allocated_sizes.get(*i).unwrap();
+                tracker.free_anon_mmap(addresses[*i], *allocated_sizes.get(*i).unwrap());
+                expected_memory_usage[*i] -= allocated_sizes.get(*i).unwrap();
+                prop_assert_eq!(tracker.usage.current_bytes, expected_sum);
+                prop_assert_eq!(&tracker.usage.current, &expected_memory_usage);
+            }
+            prop_assert_eq!(tracker.usage.peak_bytes, expected_peak);
+        }
+    }
+
+    #[test]
+    fn functionlocation_and_functionid_strings() {
+        let func = FunctionLocation::from_strings("a", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        assert_eq!(fid.get_filename(), "a");
+        assert_eq!(fid.get_function_name(), "af");
+    }
+
+    #[test]
+    fn invalid_function_locations() {
+        let fid = FunctionId::new(std::ptr::null());
+        assert_eq!(fid.get_filename(), "<invalid>");
+        assert_eq!(fid.get_function_name(), "<invalid>");
+
+        let mut func = FunctionLocation::from_strings("a", "af");
+        func.filename_length = -1;
+        func.function_name_length = isize::MAX;
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        assert_eq!(fid.get_filename(), "<invalid>");
+        assert_eq!(fid.get_function_name(), "<invalid>");
+
+        let mut func = FunctionLocation::from_strings("a", "af");
+        func.filename = std::ptr::null();
+        func.filename_length = 0;
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        assert_eq!(fid.get_filename(), "<invalid>");
+        assert_eq!(fid.get_function_name(), "af");
+    }
+
+    #[test]
+    fn non_utf8_strings_are_replaced() {
+        let filename = b"bad\xff.py";
+        let function_name = b"f\xc3";
+        let func = FunctionLocation {
+            filename: filename.as_ptr(),
+            filename_length: filename.len() as isize,
+            function_name: function_name.as_ptr(),
+            function_name_length: function_name.len() as isize,
+        };
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        assert_eq!(fid.get_filename(), "bad\u{FFFD}.py");
+        assert_eq!(fid.get_function_name(), "f\u{FFFD}");
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid, 3));
+        assert_eq!(cs.as_string(false), "bad\u{FFFD}.py:3 (f\u{FFFD})");
+    }
+
+    #[test]
+    fn callstack_line_numbers() {
+        let func1 = FunctionLocation::from_strings("a", "af");
+        let func3 = FunctionLocation::from_strings("b", "bf");
+        let func5 = FunctionLocation::from_strings("c", "cf");
+
+        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
+        let fid3 = FunctionId::new(&func3 as *const FunctionLocation);
+        let fid5 = FunctionId::new(&func5 as *const FunctionLocation);
+
+        // Parent line number does nothing if it's first call:
+        let mut cs1 = Callstack::new();
+        let id1 = CallSiteId::new(fid1, 2);
+        let id2 = CallSiteId::new(fid3, 45);
+        let id3 = CallSiteId::new(fid5, 6);
+        cs1.start_call(123, id1);
+        assert_eq!(cs1.calls, vec![id1]);
+
+        // Parent line number does nothing if it's 0:
+        cs1.start_call(0, id2);
+        assert_eq!(cs1.calls, vec![id1, id2]);
+
+        // Parent line number overrides previous level if it's non-0:
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, id1);
+        cs2.start_call(10, id2);
+        cs2.start_call(12, id3);
+        assert_eq!(
+            cs2.calls,
+            vec![CallSiteId::new(fid1, 10), CallSiteId::new(fid3, 12), id3]
+        );
+    }
+
+    #[test]
+    fn callstackinterner_notices_duplicates() {
+        let func1 = FunctionLocation::from_strings("a", "af");
+        let func3 = FunctionLocation::from_strings("b", "bf");
+        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
+        let fid3 = FunctionId::new(&func3 as *const FunctionLocation);
+
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, 2));
+        let cs1b = cs1.clone();
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid3, 4));
+        let cs3 = Callstack::new();
+        let cs3b = Callstack::new();
+
+        let mut interner = CallstackInterner::new();
+
+        let mut new = false;
+        let id1 = interner.get_or_insert_id(&cs1, || new = true);
+        assert!(new);
+
+        new = false;
+        let id1b = interner.get_or_insert_id(&cs1b, || new = true);
+        assert!(!new);
+
+        new = false;
+        let id2 = interner.get_or_insert_id(&cs2, || new = true);
+        assert!(new);
+
+        new = false;
+        let id3 = interner.get_or_insert_id(&cs3, || new = true);
+        assert!(new);
+
+        new = false;
+        let id3b = interner.get_or_insert_id(&cs3b, || new = true);
+        assert!(!new);
+
+        assert_eq!(id1, id1b);
+        assert_ne!(id1, id2);
+        assert_ne!(id1, id3);
+        assert_ne!(id2, id3);
+        assert_eq!(id3, id3b);
+        let mut expected = collections::HashMap::default();
+        expected.insert(id1, &cs1);
+        expected.insert(id2, &cs2);
+        expected.insert(id3, &cs3);
+        assert_eq!(interner.get_reverse_map(), expected);
+    }
+
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    #[test]
+    fn peak_allocations_only_updated_on_new_peaks() {
+        let func1 = FunctionLocation::from_strings("a", "af");
+        let func3 = FunctionLocation::from_strings("b", "bf");
+        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
+        let fid3 = FunctionId::new(&func3 as *const FunctionLocation);
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, 2));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid3, 4));
+
+        tracker.add_allocation(1, 1000, &cs1);
+        tracker.check_if_new_peak();
+        // Peak should now match current allocations:
+        assert_eq!(tracker.usage.current, im::vector![1000]);
+        assert_eq!(tracker.usage.current, tracker.usage.peak);
+        assert_eq!(tracker.usage.peak_bytes, 1000);
+        let previous_peak = tracker.usage.peak.clone();
+
+        // Free the allocation:
+        tracker.free_allocation(1);
+        assert_eq!(tracker.usage.current_bytes, 0);
+        assert_eq!(tracker.usage.current, im::vector![0]);
+        assert_eq!(previous_peak, tracker.usage.peak);
+        assert_eq!(tracker.usage.peak_bytes, 1000);
+
+        // Add allocation, still less than 1000:
+        tracker.add_allocation(3, 123, &cs1);
+        assert_eq!(tracker.usage.current, im::vector![123]);
+        tracker.check_if_new_peak();
+        assert_eq!(previous_peak, tracker.usage.peak);
+        assert_eq!(tracker.usage.peak_bytes, 1000);
+
+        // Add allocation that goes past previous peak
+        tracker.add_allocation(2, 2000, &cs2);
+        tracker.check_if_new_peak();
+        assert_eq!(tracker.usage.current, im::vector![123, 2000]);
+        assert_eq!(tracker.usage.current, tracker.usage.peak);
+        assert_eq!(tracker.usage.peak_bytes, 2123);
+        let previous_peak = tracker.usage.peak.clone();
+
+        // Add anonymous mmap() that doesn't go past previous peak:
+        tracker.free_allocation(2);
+        assert_eq!(tracker.usage.current, im::vector![123, 0]);
+        tracker.add_anon_mmap(50000, 1000, &cs2);
+        assert_eq!(tracker.usage.current, im::vector![123, 1000]);
+        tracker.check_if_new_peak();
+        assert_eq!(tracker.usage.current_bytes, 1123);
+        assert_eq!(tracker.usage.peak_bytes, 2123);
+        assert_eq!(tracker.usage.peak, previous_peak);
+        assert_eq!(tracker.current_allocations.len(), 1);
+        assert!(tracker.current_allocations.contains_key(&3));
+        assert!(tracker.current_anon_mmaps.size() > 0);
+
+        // Add anonymous mmap() that does go past previous peak:
+        tracker.add_anon_mmap(600000, 2000, &cs2);
+        assert_eq!(tracker.usage.current, im::vector![123, 3000]);
+        tracker.check_if_new_peak();
+        assert_eq!(tracker.usage.current, tracker.usage.peak);
+        assert_eq!(tracker.usage.current_bytes, 3123);
+        assert_eq!(tracker.usage.peak_bytes, 3123);
+
+        // Remove mmap():
+        tracker.free_anon_mmap(50000, 1000);
+        assert_eq!(tracker.usage.current, im::vector![123, 2000]);
+        tracker.check_if_new_peak();
+        assert_eq!(tracker.usage.current_bytes, 2123);
+        assert_eq!(tracker.usage.peak_bytes, 3123);
+        assert_eq!(tracker.current_anon_mmaps.size(), 2000);
+        assert!(tracker
+            .current_anon_mmaps
+            .as_hashmap()
+            .contains_key(&600000));
+
+        // Partial removal of anonmyous mmap():
+        tracker.free_anon_mmap(600100, 1000);
+        assert_eq!(tracker.usage.current, im::vector![123, 1000]);
+        assert_eq!(tracker.usage.current_bytes, 1123);
+        assert_eq!(tracker.usage.peak_bytes, 3123);
+        assert_eq!(tracker.current_anon_mmaps.size(), 1000);
+    }
+
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    #[test]
+    fn combine_callstacks_and_sum_allocations() {
+        let func1 = FunctionLocation::from_strings("a", "af");
+        let func2 = FunctionLocation::from_strings("b", "bf");
+        let func3 = FunctionLocation::from_strings("c", "cf");
+
+        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
+        let fid2 = FunctionId::new(&func2 as *const FunctionLocation);
+        let fid3 = FunctionId::new(&func3 as *const FunctionLocation);
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        let id1 = CallSiteId::new(fid1, 1);
+        // Same function, different line number—should be different item:
+        let id1_different = CallSiteId::new(fid1, 7);
+        let id2 = CallSiteId::new(fid2, 2);
+
+        let id3 = CallSiteId::new(fid3, 3);
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, id1);
+        cs1.start_call(0, id2);
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, id3);
+        let mut cs3 = Callstack::new();
+        cs3.start_call(0, id1_different);
+        cs3.start_call(0, id2);
+
+        tracker.add_allocation(1, 1000, &cs1);
+        tracker.add_allocation(2, 234, &cs2);
+        tracker.add_anon_mmap(3, 50000, &cs1);
+        tracker.add_allocation(4, 6000, &cs3);
+
+        let mut expected = vec![
+            "a:1 (af);TB@@a:1@@TB;b:2 (bf);TB@@b:2@@TB 51000".to_string(),
+            "c:3 (cf);TB@@c:3@@TB 234".to_string(),
+            "a:7 (af);TB@@a:7@@TB;b:2 (bf);TB@@b:2@@TB 6000".to_string(),
+        ];
+        let mut result: Vec<String> = tracker.to_lines(true, true).collect();
+        result.sort();
+        expected.sort();
+        assert_eq!(expected, result);
+
+        let mut expected2 = vec![
+            "a:1 (af);b:2 (bf) 51000",
+            "c:3 (cf) 234",
+            "a:7 (af);b:2 (bf) 6000",
+        ];
+        let mut result2: Vec<String> = tracker.to_lines(true, false).collect();
+        result2.sort();
+        expected2.sort();
+        assert_eq!(expected2, result2);
+    }
+
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    #[test]
+    fn recent_events_only_keeps_most_recent() {
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        let cs = Callstack::new();
+        // Fill the ring beyond capacity; each allocation is immediately freed,
+        // so every iteration adds two events:
+        for i in 0..RECENT_EVENTS_CAPACITY {
+            tracker.add_allocation(i + 1, 100 + i, &cs);
+            tracker.free_allocation(i + 1);
+        }
+        tracker.add_anon_mmap(50000, 4096, &cs);
+        tracker.free_anon_mmap(50000, 4096);
+        assert_eq!(tracker.recent_events.len(), RECENT_EVENTS_CAPACITY);
+
+        let mut output = vec![];
+        tracker.write_recent_events(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), RECENT_EVENTS_CAPACITY);
+        // Oldest retained event is an allocation from halfway through, since
+        // the last two events pushed out two more of the earlier ones:
+        let i = RECENT_EVENTS_CAPACITY / 2 + 1;
+        assert_eq!(lines[0], format!("Allocation {:#x} {} 0", i + 1, 100 + i));
+        assert_eq!(lines[lines.len() - 2], "AnonMmap 0xc350 4096 0");
+        assert_eq!(lines[lines.len() - 1], "AnonMunmap 0xc350 4096 0");
+    }
+
+    #[test]
+    fn oom_frees_large_python_allocations() {
+        let func1 = FunctionLocation::from_strings("a", "af");
+        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
+        let mut python_cs = Callstack::new();
+        python_cs.start_call(0, CallSiteId::new(fid1, 2));
+        let native_cs = Callstack::new();
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 1000, &python_cs);
+        tracker.add_allocation(2, 500_000, &python_cs);
+        tracker.add_allocation(3, 500_000, &native_cs);
+        assert_eq!(tracker.oom_freeable_addresses(), vec![2]);
+
+        // Lower threshold means more allocations get freed, but still only
+        // ones from Python:
+        tracker.oom_free_threshold = 100;
+        let mut addresses = tracker.oom_freeable_addresses();
+        addresses.sort();
+        assert_eq!(addresses, vec![1, 2]);
+    }
+
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    #[test]
+    fn native_only_attribution() {
+        // No Python frames are ever pushed; allocations are attributed purely
+        // by the native caller address:
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 1000, &Callstack::from_native_caller(0x1234));
+        tracker.add_allocation(2, 200, &Callstack::from_native_caller(0x5678));
+        tracker.add_allocation(3, 30, &Callstack::from_native_caller(0x1234));
+        tracker.add_anon_mmap(5000, 4000, &Callstack::from_native_caller(0x5678));
+        tracker.add_allocation(4, 7, &Callstack::new());
+        assert!(!Callstack::from_native_caller(0x1234).in_python());
+
+        let mut expected = vec![
+            "[Native code at 0x1234] 1030",
+            "[Native code at 0x5678] 4200",
+            "[No Python stack] 7",
+        ];
+        let mut result: Vec<String> = tracker.to_lines(true, true).collect();
+        result.sort();
+        expected.sort();
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn spare_memory_released_on_oom() {
+        let mut tracker = AllocationTracker::new(".".to_string(), 3 * MIB);
+        assert!(tracker.spare_memory.capacity() >= 3 * MIB);
+        tracker.oom_break_glass();
+        assert_eq!(tracker.spare_memory.capacity(), 0);
+    }
+
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    #[test]
+    fn per_thread_callstacks() {
+        let func1 = FunctionLocation::from_strings("a", "af");
+        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
+        let mut cs_thread1 = Callstack::new();
+        cs_thread1.start_call(0, CallSiteId::new(fid1, 2));
+        let mut cs_thread2 = cs_thread1.clone();
+        cs_thread1.thread_id = 1;
+        cs_thread2.thread_id = 2;
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 1000, &cs_thread1);
+        tracker.add_allocation(2, 234, &cs_thread2);
+        tracker.add_anon_mmap(5000, 4000, &cs_thread2);
+
+        // Combined, the threads are merged:
+        let result: Vec<String> = tracker.to_lines(true, false).collect();
+        assert_eq!(result, vec!["a:2 (af) 5234"]);
+
+        // Per thread, they're separate:
+        let result: Vec<String> = tracker.to_lines_for_thread(true, false, 1).collect();
+        assert_eq!(result, vec!["a:2 (af) 1000"]);
+        let result: Vec<String> = tracker.to_lines_for_thread(true, false, 2).collect();
+        assert_eq!(result, vec!["a:2 (af) 4234"]);
+        let mut expected = collections::HashMap::new();
+        expected.insert(1, 1000);
+        expected.insert(2, 4234);
+        assert_eq!(tracker.peak_bytes_by_thread(), expected);
+
+        // And they get written out to separate files:
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().to_str().unwrap();
+        tracker.dump_peak_by_thread_to_flamegraph(path).unwrap();
+        for base in &[
+            "peak-memory",
+            "peak-memory-thread-1",
+            "peak-memory-thread-2",
+        ] {
+            for suffix in &[".prof", ".svg", "-reversed.svg"] {
+                assert!(directory
+                    .path()
+                    .join(format!("{}{}", base, suffix))
+                    .exists());
+            }
+        }
+        let thread2 =
+            std::fs::read_to_string(directory.path().join("peak-memory-thread-2.prof")).unwrap();
+        assert_eq!(thread2, "a:2 (af);TB@@a:2@@TB 4234\n");
+    }
+
+    /// Render a flamegraph SVG of a single callstack with the given options.
+    fn render_flamegraph(dump_options: &DumpOptions) -> String {
+        let directory = tempfile::tempdir().unwrap();
+        let lines_path = directory.path().join("lines.prof");
+        std::fs::write(&lines_path, "a:1 (af) 1000\n").unwrap();
+        let svg_path = directory.path().join("out.svg");
+        write_flamegraph(
+            lines_path.to_str().unwrap(),
+            svg_path.to_str().unwrap(),
+            1000,
+            false,
+            "T",
+            None,
+            false,
+            dump_options,
+        )
+        .unwrap();
+        std::fs::read_to_string(svg_path).unwrap()
+    }
+
+    #[test]
+    fn flamegraph_width_is_configurable() {
+        let default_svg = render_flamegraph(&DumpOptions::default());
+        assert!(default_svg.contains("width=\"1200\""));
+        let wide_svg = render_flamegraph(&DumpOptions {
+            image_width: Some(3456),
+            ..Default::default()
+        });
+        assert!(wide_svg.contains("width=\"3456\""));
+        assert!(!wide_svg.contains("width=\"1200\""));
+    }
+
+    #[test]
+    fn flamegraph_font_style_is_configurable() {
+        let default_svg = render_flamegraph(&DumpOptions::default());
+        let styled_svg = render_flamegraph(&DumpOptions {
+            style: FlamegraphStyle {
+                font_size: 31,
+                frame_height: 40,
+                font_type: "Verdana".to_string(),
+            },
+            ..Default::default()
+        });
+        assert!(!default_svg.contains("font-size:31px"));
+        assert!(styled_svg.contains("font-size:31px"));
+        assert!(styled_svg.contains("Verdana"));
+    }
+
+    #[test]
+    fn flamegraph_dark_mode_background() {
+        let default_svg = render_flamegraph(&DumpOptions::default());
+        let dark_svg = render_flamegraph(&DumpOptions {
+            dark_mode: true,
+            ..Default::default()
+        });
+        assert!(!default_svg.contains("stop-color=\"#1e1e1e\""));
+        assert!(dark_svg.contains("stop-color=\"#1e1e1e\""));
+        assert!(dark_svg.contains("#title, #subtitle"));
+    }
+
+    #[test]
+    fn huge_flamegraphs_are_limited() {
+        let lines: String = (1..=1000)
+            .map(|i| format!("a:{} (af) {}\n", i, i))
+            .collect();
+        let (limited, merged) = limit_lines(&lines, 100);
+        assert_eq!(merged, 901);
+        assert_eq!(limited.lines().count(), 100);
+        assert!(limited.contains("a:1000 (af) 1000\n"));
+        assert!(limited.contains("a:902 (af) 902\n"));
+        assert!(!limited.contains("a:901 (af)"));
+        assert!(limited.contains(&format!(
+            "{} {}\n",
+            SMALL_CALLSTACKS,
+            (1..=901).sum::<usize>()
+        )));
+        // Under the limit nothing changes:
+        assert_eq!(limit_lines(&limited, 100).1, 0);
+
+        let render = |max_lines| {
+            let mut svg = Vec::new();
+            write_flamegraph_to(
+                &lines,
+                &mut svg,
+                500500,
+                false,
+                "T",
+                None,
+                false,
+                &DumpOptions {
+                    max_lines,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            String::from_utf8(svg).unwrap()
+        };
+        assert!(render(100).contains(SMALL_CALLSTACKS));
+        assert!(!render(0).contains(SMALL_CALLSTACKS));
+    }
+
+    #[test]
+    fn flamegraph_title_and_subtitle() {
+        let directory = tempfile::tempdir().unwrap();
+        let lines_path = directory.path().join("lines.prof");
+        std::fs::write(&lines_path, "a:1 (af);TB@@a:1@@TB 1000\n").unwrap();
+        let svg_path = directory.path().join("out.svg");
+        let render = |subtitle| {
+            write_flamegraph(
+                lines_path.to_str().unwrap(),
+                svg_path.to_str().unwrap(),
+                1000,
+                false,
+                "My Title",
+                subtitle,
+                true,
+                &DumpOptions::default(),
+            )
+            .unwrap();
+            std::fs::read_to_string(&svg_path).unwrap()
+        };
+        let svg = render(Some("Run #3 & friends"));
+        assert!(svg.contains("My Title (0.0 MiB)"));
+        assert!(svg.contains("Run #3 &amp; friends"));
+        assert!(!svg.contains("SUBTITLE-HERE"));
+        // Without a subtitle, Python fills one in later:
+        assert!(render(None).contains("SUBTITLE-HERE"));
+    }
+
+    #[test]
+    fn module_colors() {
+        assert_eq!(
+            module_of("/usr/lib/python3.8/site-packages/numpy/core/a.py"),
+            "numpy"
+        );
+        assert_eq!(module_of("/venv/lib/dist-packages/pandas/io.py"), "pandas");
+        assert_eq!(module_of("/home/me/project/analysis.py"), "project");
+        assert_eq!(module_of("script.py"), "script.py");
+        assert_eq!(frame_filename("a/b.py:10 (f)"), Some("a/b.py"));
+        assert_eq!(frame_filename("TB@@a/b.py:10@@TB"), Some("a/b.py"));
+        assert_eq!(frame_filename("[No Python stack]"), None);
+
+        // Colors are stable and distinct by module:
+        assert_eq!(module_color("numpy"), module_color("numpy"));
+        assert_ne!(module_color("numpy"), module_color("pandas"));
+        let palette = module_palette(
+            "/sp/site-packages/numpy/a.py:1 (f);/sp/site-packages/numpy/b.py:2 (g) 10\n\
+             /sp/site-packages/pandas/c.py:3 (h) 20\n\
+             [No Python stack] 5\n",
+        );
+        let numpy_a = palette.get("/sp/site-packages/numpy/a.py:1 (f)").unwrap();
+        let numpy_b = palette.get("/sp/site-packages/numpy/b.py:2 (g)").unwrap();
+        let pandas = palette.get("/sp/site-packages/pandas/c.py:3 (h)").unwrap();
+        assert_eq!(numpy_a, numpy_b);
+        assert_eq!(numpy_a, module_color("numpy"));
+        assert_ne!(numpy_a, pandas);
+        assert_eq!(palette.get("[No Python stack]"), None);
+    }
+
+    #[test]
+    fn flamegraph_colored_by_module() {
+        let directory = tempfile::tempdir().unwrap();
+        let lines_path = directory.path().join("lines.prof");
+        std::fs::write(&lines_path, "numpy/a.py:1 (af) 1000\n").unwrap();
+        let svg_path = directory.path().join("out.svg");
+        let dump_options = DumpOptions {
+            colors: FlamegraphColors::ByModule,
+            ..Default::default()
+        };
+        write_flamegraph(
+            lines_path.to_str().unwrap(),
+            svg_path.to_str().unwrap(),
+            1000,
+            false,
+            "T",
+            None,
+            false,
+            &dump_options,
+        )
+        .unwrap();
+        let color = module_color("numpy");
+        let fill = format!("rgb({},{},{})", color.r, color.g, color.b);
+        assert!(std::fs::read_to_string(svg_path).unwrap().contains(&fill));
+    }
+
+    #[test]
+    fn sampling_rate_of_one_records_everything() {
+        let func1 = FunctionLocation::from_strings("a", "af");
+        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, 2));
+
+        let mut unsampled = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        let mut sampled = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        sampled.sampling_rate = 1;
+        for tracker in &mut [&mut unsampled, &mut sampled] {
+            for i in 1..10 {
+                tracker.add_allocation(i, i * 100, &cs1);
+            }
+            tracker.free_allocation(3);
+        }
+        assert_eq!(sampled.current_allocations, unsampled.current_allocations);
+        assert_eq!(sampled.usage.current_bytes, 4200);
+        assert_eq!(sampled.usage.peak_bytes, 4500);
+    }
+
+    #[test]
+    fn sampling_scales_up_sizes() {
+        let func1 = FunctionLocation::from_strings("a", "af");
+        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, 2));
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.sampling_rate = 3;
+        for i in 1..10 {
+            tracker.add_allocation(i, 100, &cs1);
+        }
+        // Only every third allocation is recorded:
+        let mut addresses: Vec<usize> = tracker.current_allocations.keys().copied().collect();
+        addresses.sort_unstable();
+        assert_eq!(addresses, vec![3, 6, 9]);
+        assert_eq!(tracker.usage.current_bytes, 900);
+        // Freeing an unsampled allocation does nothing:
+        tracker.free_allocation(4);
+        assert_eq!(tracker.usage.current_bytes, 900);
+        tracker.free_allocation(6);
+        assert_eq!(tracker.usage.current_bytes, 600);
+    }
+
+    #[test]
+    fn repeated_leaking_call_is_flagged() {
+        let main = FunctionLocation::from_strings("a", "main");
+        let main_id = FunctionId::new(&main as *const FunctionLocation);
+        let leaky = FunctionLocation::from_strings("a", "leaky");
+        let leaky_id = FunctionId::new(&leaky as *const FunctionLocation);
+        let fine = FunctionLocation::from_strings("a", "fine");
+        let fine_id = FunctionId::new(&fine as *const FunctionLocation);
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.leak_detector = Some(LeakDetector::new());
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(main_id, 1));
+        let mut address = 1;
+        for _ in 0..5 {
+            // leaky() allocates on two different lines, freeing only one:
+            cs.start_call(2, CallSiteId::new(leaky_id, 10));
+            tracker.add_allocation(address, 100, &cs);
+            cs.new_line_number(11);
+            tracker.add_allocation(address + 1, 50, &cs);
+            tracker.free_allocation(address + 1);
+            tracker.leak_detection_finished_call(&cs);
+            cs.finish_call();
+            // fine() frees everything it allocates:
+            cs.start_call(3, CallSiteId::new(fine_id, 20));
+            tracker.add_allocation(address + 2, 1000, &cs);
+            tracker.free_allocation(address + 2);
+            tracker.leak_detection_finished_call(&cs);
+            cs.finish_call();
+            address += 3;
+        }
+        let suspects: Vec<(String, usize)> = tracker
+            .leak_detector
+            .as_ref()
+            .unwrap()
+            .suspects(LEAK_MIN_INVOCATIONS, LEAK_SUSPECTS_LIMIT)
+            .into_iter()
+            .map(|(callstack, bytes)| (callstack.as_string(false), bytes))
+            .collect();
+        assert_eq!(suspects, vec![("a:2 (main);a:0 (leaky)".to_string(), 400)]);
+    }
+
+    #[test]
+    fn leaking_callee_is_flagged_in_callers() {
+        let main = FunctionLocation::from_strings("a", "main");
+        let main_id = FunctionId::new(&main as *const FunctionLocation);
+        let outer = FunctionLocation::from_strings("a", "outer");
+        let outer_id = FunctionId::new(&outer as *const FunctionLocation);
+        let inner = FunctionLocation::from_strings("a", "inner");
+        let inner_id = FunctionId::new(&inner as *const FunctionLocation);
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.leak_detector = Some(LeakDetector::new());
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(main_id, 1));
+        let mut address = 1;
+        for i in 0..5 {
+            cs.start_call(2, CallSiteId::new(outer_id, 10));
+            // A temporary allocation whose callstack gets forgotten:
+            cs.new_line_number(10 + i);
+            tracker.add_allocation(address, 1000, &cs);
+            tracker.free_allocation(address);
+            tracker.compact_interner();
+            // inner() leaks a different line every time:
+            cs.start_call(11, CallSiteId::new(inner_id, 20 + i));
+            tracker.add_allocation(address + 1, 100, &cs);
+            tracker.leak_detection_finished_call(&cs);
+            cs.finish_call();
+            tracker.leak_detection_finished_call(&cs);
+            cs.finish_call();
+            address += 2;
+        }
+        let mut suspects: Vec<(String, usize)> = tracker
+            .leak_detector
+            .as_ref()
+            .unwrap()
+            .suspects(LEAK_MIN_INVOCATIONS, LEAK_SUSPECTS_LIMIT)
+            .into_iter()
+            .map(|(callstack, bytes)| (callstack.as_string(false), bytes))
+            .collect();
+        suspects.sort();
+        assert_eq!(
+            suspects,
+            vec![
+                ("a:2 (main);a:0 (outer)".to_string(), 400),
+                ("a:2 (main);a:11 (outer);a:0 (inner)".to_string(), 400)
+            ]
+        );
+    }
+
+    #[test]
+    fn html_report_references_svgs() {
+        let directory = tempfile::tempdir().unwrap();
+        let html_path =
+            write_html_report(directory.path(), "out-of-memory", 3 * MIB + MIB / 2).unwrap();
+        assert_eq!(html_path, directory.path().join("index.html"));
+        let html = std::fs::read_to_string(html_path).unwrap();
+        assert!(html.contains("src=\"out-of-memory.svg\""));
+        assert!(html.contains("src=\"out-of-memory-reversed.svg\""));
+        assert!(html.contains("3.5 MiB"));
+    }
+
+    #[test]
+    fn spilled_allocations() {
+        let func = FunctionLocation::from_strings("a", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let callstack = |line| {
+            let mut cs = Callstack::new();
+            cs.start_call(0, CallSiteId::new(fid, line));
+            cs
+        };
+        let mut spilling = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        spilling.current_allocations.set_max_in_memory(100);
+        let mut unlimited = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        for tracker in &mut [&mut spilling, &mut unlimited] {
+            // Starting in the future keeps timestamps at 0, so the trackers'
+            // allocations are identical:
+            tracker.started = std::time::Instant::now() + Duration::from_secs(3600);
+            for i in 1..=5000 {
+                tracker.add_allocation(i * 16, i, &callstack((i % 7) as u16));
+                if i % 1000 == 0 {
+                    tracker.new_generation();
+                }
+            }
+            for i in (1..=5000).step_by(3) {
+                tracker.free_allocation(i * 16);
+            }
+            tracker.realloc_allocation(32, 16, 64, &callstack(3));
+        }
+        assert!(spilling.current_allocations.in_memory_len() <= 100);
+        assert_eq!(spilling.current_allocations, unlimited.current_allocations);
+        assert_eq!(spilling.usage.current_bytes, unlimited.usage.current_bytes);
+        assert_eq!(spilling.allocation_size(32), 0);
+        assert_eq!(spilling.allocation_size(16), 64);
+        assert_eq!(spilling.allocation_size(200 * 16), 200);
+        let mut spilled_lines: Vec<_> = spilling.to_lines(true, false).collect();
+        let mut unlimited_lines: Vec<_> = unlimited.to_lines(true, false).collect();
+        spilled_lines.sort();
+        unlimited_lines.sort();
+        assert_eq!(spilled_lines, unlimited_lines);
+        spilling.reset_keeping_interner(".".to_string());
+        assert!(spilling.current_allocations.is_empty());
+    }
+
+    #[test]
+    fn path_prefixes_are_stripped() {
+        let display = PathDisplay {
+            prefixes: vec!["/home/user/project".to_string(), "/opt/lib/".to_string()],
+            collapse_site_packages: false,
+        };
+        assert_eq!(
+            display.shorten("/home/user/project/app/main.py"),
+            "app/main.py"
+        );
+        assert_eq!(display.shorten("/opt/lib/util.py"), "util.py");
+        // Only whole path components match:
+        assert_eq!(
+            display.shorten("/home/user/project2/main.py"),
+            "/home/user/project2/main.py"
+        );
+        assert_eq!(display.shorten("/usr/lib/os.py"), "/usr/lib/os.py");
+        assert_eq!(display.shorten("<region>"), "<region>");
+
+        // With nested roots, the innermost wins:
+        let display = PathDisplay {
+            prefixes: vec!["/home/user".to_string(), "/home/user/project".to_string()],
+            collapse_site_packages: true,
+        };
+        assert_eq!(display.shorten("/home/user/project/main.py"), "main.py");
+        assert_eq!(display.shorten("/home/user/other/x.py"), "other/x.py");
+        assert_eq!(
+            display.shorten("/home/user/venv/lib/python3.9/site-packages/numpy/core/a.py"),
+            "<venv>/numpy/core/a.py"
+        );
+        assert_eq!(module_of("<venv>/numpy/core/a.py"), "numpy");
+
+        // Rendering uses the process-wide setting; the full path is kept for
+        // post-processing:
+        let func = FunctionLocation::from_strings("/fil-test-root/pkg/a.py", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid, 2));
+        PATH_DISPLAY.write().unwrap().prefixes = vec!["/fil-test-root".to_string()];
+        let rendered = cs.as_string(false);
+        let post_processed = cs.as_string(true);
+        PATH_DISPLAY.write().unwrap().prefixes.clear();
+        assert_eq!(rendered, "pkg/a.py:2 (af)");
+        assert_eq!(
+            post_processed,
+            "pkg/a.py:2 (af);TB@@/fil-test-root/pkg/a.py:2@@TB"
+        );
+    }
+
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    #[test]
+    fn heap_break_changes() {
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        let cs = Callstack::new();
+        tracker.change_heap_break(0x10000, 8192, &cs);
+        tracker.change_heap_break(0x12000, 4096, &cs);
+        tracker.change_heap_break(0x13000, 0, &cs);
+        assert_eq!(tracker.usage.current_bytes, 12288);
+        // Shrinking frees the end of the heap, even across growths:
+        tracker.change_heap_break(0x13000, -6144, &cs);
+        assert_eq!(tracker.usage.current_bytes, 6144);
+        assert_eq!(tracker.get_peak_allocated_bytes(), 12288);
+        tracker.change_heap_break(0x11800, 1024, &cs);
+        assert_eq!(tracker.usage.current_bytes, 7168);
+        tracker.change_heap_break(0x11c00, -7168, &cs);
+        assert_eq!(tracker.usage.current_bytes, 0);
+        assert_eq!(tracker.current_anon_mmaps.len(), 0);
+    }
+
+    #[test]
+    fn address_reused_without_free() {
+        let func = FunctionLocation::from_strings("a", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid, 1));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid, 2));
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 1000, &cs1);
+        tracker.realloc_allocation(1, 1, 1500, &cs2);
+        // The free() of address 1 was missed:
+        tracker.add_allocation(1, 300, &cs2);
+        assert_eq!(tracker.usage.current_bytes, 300);
+        assert_eq!(tracker.usage.current, im::vector![0, 300]);
+        assert_eq!(tracker.usage.peak_bytes, 1500);
+        assert_eq!(tracker.free_allocation(1), Some(300));
+        assert_eq!(tracker.usage.current_bytes, 0);
+    }
+
+    #[test]
+    fn event_log_replays_to_tracked_usage() {
+        let functions: Vec<FunctionLocation> = (0..4)
+            .map(|_| FunctionLocation::from_strings("a.py", "af"))
+            .collect();
+        let callstacks: Vec<Callstack> = functions
+            .iter()
+            .map(|function| {
+                let mut cs = Callstack::new();
+                cs.start_call(0, CallSiteId::new(FunctionId::new(function), 1));
+                cs
+            })
+            .collect();
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("events.log");
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        // Allocated before the log was enabled:
+        tracker.add_allocation(1, 1000, &callstacks[0]);
+        tracker.enable_event_log(&path).unwrap();
+        tracker.add_allocation(2, 500, &callstacks[1]);
+        // Grows by 700 bytes, attributed to another callstack:
+        tracker.realloc_allocation(1, 3, 1700, &callstacks[2]);
+        tracker.free_allocation(2);
+        // The free() of address 3 was missed:
+        tracker.add_allocation(3, 200, &callstacks[1]);
+        // Callstack ids get reused after compaction:
+        tracker.compact_interner();
+        tracker.add_allocation(4, 50, &callstacks[3]);
+        tracker.add_allocation(5, 2000, &callstacks[2]);
+        tracker.free_allocation(5);
+        drop(tracker.event_log.take());
+
+        let replayed = eventlog::replay_file(&path).unwrap();
+        assert_eq!(replayed.current_bytes, tracker.usage.current_bytes);
+        assert_eq!(replayed.peak_bytes, tracker.usage.peak_bytes);
+        let mut expected_current: Vec<String> = tracker.to_lines(false, false).collect();
+        expected_current.sort_unstable();
+        let mut expected_peak: Vec<String> = tracker.to_lines(true, false).collect();
+        expected_peak.sort_unstable();
+        assert_eq!(
+            sorted_lines(replayed.current).collect::<Vec<_>>(),
+            expected_current
+        );
+        assert_eq!(
+            sorted_lines(replayed.peak).collect::<Vec<_>>(),
+            expected_peak
+        );
+    }
+
+    #[test]
+    fn interner_stats() {
+        let functions: Vec<FunctionLocation> = (0..3)
+            .map(|_| FunctionLocation::from_strings("a.py", "af"))
+            .collect();
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        let empty = tracker.interner.stats();
+        assert_eq!(
+            (empty.callstacks, empty.max_id, empty.estimated_bytes),
+            (0, 0, 0)
+        );
+        for (i, function) in functions.iter().enumerate() {
+            for line in 1..=5 {
+                let mut cs = Callstack::new();
+                cs.start_call(0, CallSiteId::new(FunctionId::new(function), line));
+                // Allocating twice with the same callstack only interns it once:
+                tracker.add_allocation(i * 10 + line as usize, 100, &cs);
+                tracker.add_allocation(1000 + i * 10 + line as usize, 100, &cs);
+            }
+        }
+        let stats = tracker.interner.stats();
+        assert_eq!(stats.callstacks, 15);
+        assert_eq!(stats.max_id, 15);
+        assert!(stats.estimated_bytes >= 15 * std::mem::size_of::<Callstack>());
+        // Rendering adds the cached strings:
+        tracker.to_lines(true, false).count();
+        assert!(tracker.interner.stats().estimated_bytes > stats.estimated_bytes);
+    }
+
+    #[test]
+    fn watchpoint() {
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 1000, &Callstack::new());
+        assert_eq!(tracker.watchpoint, None);
+        tracker.watchpoint = Some(Watchpoint {
+            size: 1000,
+            tolerance: 10,
+            hits: 0,
+        });
+        // Sampling doesn't hide matching allocations:
+        tracker.sampling_rate = 100;
+        for (address, size) in [(2, 989), (3, 990), (4, 1000), (5, 1010), (6, 1011), (7, 5)] {
+            tracker.add_allocation(address, size, &Callstack::new());
+        }
+        assert_eq!(tracker.watchpoint.unwrap().hits, 3);
+
+        let func = FunctionLocation::from_strings("a.py", "af");
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(FunctionId::new(&func), 7));
+        assert_eq!(cs.traceback(), "  a.py:7 (af)");
+        assert_eq!(Callstack::new().traceback(), "  [No Python stack]");
+    }
+
+    #[test]
+    fn dumps_are_reproducible() {
+        // FunctionLocation points at the strings, so they must outlive it:
+        let filenames: Vec<String> = (0..20).map(|i| format!("file{}.py", i)).collect();
+        let functions: Vec<FunctionLocation> = filenames
+            .iter()
+            .map(|filename| FunctionLocation::from_strings(filename, "func"))
+            .collect();
+        let callstacks: Vec<Callstack> = functions
+            .iter()
+            .map(|function| {
+                let mut cs = Callstack::new();
+                cs.start_call(
+                    0,
+                    CallSiteId::new(FunctionId::new(function as *const FunctionLocation), 1),
+                );
+                cs
+            })
+            .collect();
+        let directory = tempfile::tempdir().unwrap();
+        let dump = |name: &str, order: &mut dyn Iterator<Item = usize>| {
+            let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+            for i in order {
+                tracker.add_allocation(i + 1, (i + 1) * 1000, &callstacks[i]);
+            }
+            let output = directory.path().join(name);
+            tracker
+                .dump_peak_to_flamegraph(output.to_str().unwrap(), None, None)
+                .unwrap();
+            std::fs::read(output.join("peak-memory.prof")).unwrap()
+        };
+        let first = dump("first", &mut (0..20));
+        let second = dump("second", &mut (0..20).rev());
+        assert!(!first.is_empty());
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn dump_errors_are_returned() {
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, MIB, &Callstack::new());
+        let directory = tempfile::tempdir().unwrap();
+        // A regular file can't be used as an output directory, nor can a
+        // path inside one, and nor can the fallback:
+        let file = directory.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        tracker.fallback_path = file.clone();
+        assert!(tracker
+            .dump_peak_to_flamegraph(file.to_str().unwrap(), None, None)
+            .is_err());
+        let nested = file.join("out");
+        let error = tracker
+            .dump_peak_to_flamegraph(nested.to_str().unwrap(), None, None)
+            .unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::ENOTDIR));
+        // Still works once given somewhere writable:
+        let output = directory.path().join("out");
+        tracker
+            .dump_peak_to_flamegraph(output.to_str().unwrap(), None, None)
+            .unwrap();
+        assert!(output.join("peak-memory.svg").exists());
+    }
+
+    #[test]
+    fn combined_report() {
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 3 * MIB, &Callstack::new());
+        tracker.free_allocation(1);
+        tracker.add_allocation(2, MIB, &Callstack::new());
+        let directory = tempfile::tempdir().unwrap();
+        tracker
+            .dump_combined_report(directory.path().to_str().unwrap())
+            .unwrap();
+        for svg in &[
+            "current-memory.svg",
+            "current-memory-reversed.svg",
+            "peak-memory.svg",
+            "peak-memory-reversed.svg",
+        ] {
+            assert!(directory.path().join(svg).exists());
+        }
+        let html = std::fs::read_to_string(directory.path().join("index.html")).unwrap();
+        assert!(html.contains("src=\"current-memory.svg\""));
+        assert!(html.contains("src=\"current-memory-reversed.svg\""));
+        assert!(html.contains("src=\"peak-memory.svg\""));
+        assert!(html.contains("src=\"peak-memory-reversed.svg\""));
+        assert!(html.contains("Current tracked memory usage: 1.0 MiB; peak: 3.0 MiB"));
+    }
+
+    #[test]
+    fn collapsed_output_has_no_markers() {
+        let func1 = FunctionLocation::from_strings("a", "af");
+        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
+        let func2 = FunctionLocation::from_strings("b", "bf");
+        let fid2 = FunctionId::new(&func2 as *const FunctionLocation);
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, 2));
+        cs1.start_call(3, CallSiteId::new(fid2, 4));
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 1000, &cs1);
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("collapsed.txt");
+        let path = path.to_str().unwrap();
+        tracker.dump_peak_collapsed(path).unwrap();
+        let written = std::fs::read_to_string(path).unwrap();
+        assert!(!written.contains("TB@@"));
+        assert_eq!(written, "a:3 (af);b:4 (bf) 1000\n");
+    }
+
+    #[test]
+    fn hidden_frames_are_collapsed_into_caller() {
+        let main = FunctionLocation::from_strings("a", "main");
+        let main_id = FunctionId::new(&main as *const FunctionLocation);
+        let wrapper = FunctionLocation::from_strings("decorators.py", "wrapper");
+        let wrapper_id = FunctionId::new(&wrapper as *const FunctionLocation);
+        let dispatch = FunctionLocation::from_strings("b", "dispatch_shim");
+        let dispatch_id = FunctionId::new(&dispatch as *const FunctionLocation);
+        let work = FunctionLocation::from_strings("a", "work");
+        let work_id = FunctionId::new(&work as *const FunctionLocation);
+
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(main_id, 1));
+        cs1.start_call(2, CallSiteId::new(wrapper_id, 10));
+        cs1.start_call(11, CallSiteId::new(dispatch_id, 20));
+        cs1.start_call(21, CallSiteId::new(work_id, 30));
+        // Same as cs1, except via different lines in the hidden frames:
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(main_id, 1));
+        cs2.start_call(2, CallSiteId::new(wrapper_id, 10));
+        cs2.start_call(12, CallSiteId::new(dispatch_id, 20));
+        cs2.start_call(22, CallSiteId::new(work_id, 30));
+        // Allocation in a hidden frame gets attributed to the caller:
+        let mut cs3 = Callstack::new();
+        cs3.start_call(0, CallSiteId::new(main_id, 1));
+        cs3.start_call(2, CallSiteId::new(wrapper_id, 10));
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 1000, &cs1);
+        tracker.add_allocation(2, 200, &cs2);
+        tracker.add_allocation(3, 30, &cs3);
+        tracker.dump_options.hidden_frames.patterns =
+            vec!["decorators.py".to_string(), "_shim".to_string()];
+
+        let mut result: Vec<String> = tracker.to_lines(true, false).collect();
+        result.sort();
+        assert_eq!(result, vec!["a:2 (main) 30", "a:2 (main);a:30 (work) 1200"]);
+        let total: usize = result
+            .iter()
+            .map(|line| line.rsplit(' ').next().unwrap().parse::<usize>().unwrap())
+            .sum();
+        assert_eq!(total, tracker.usage.peak_bytes);
+    }
+
+    #[test]
+    fn frames_with_hidden_prefixes_are_collapsed_into_caller() {
+        let main = FunctionLocation::from_strings("/app/main.py", "main");
+        let main_id = FunctionId::new(&main as *const FunctionLocation);
+        let stdlib = FunctionLocation::from_strings("/usr/lib/python3.8/json/decoder.py", "decode");
+        let stdlib_id = FunctionId::new(&stdlib as *const FunctionLocation);
+        // Only a prefix, not a substring, gets hidden:
+        let vendored = FunctionLocation::from_strings("/app/usr/lib/python3.8/x.py", "f");
+        let vendored_id = FunctionId::new(&vendored as *const FunctionLocation);
+
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(main_id, 1));
+        cs1.start_call(2, CallSiteId::new(stdlib_id, 10));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(main_id, 2));
+        cs2.start_call(3, CallSiteId::new(vendored_id, 5));
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 1000, &cs1);
+        tracker.add_allocation(2, 200, &cs2);
+        tracker
+            .dump_options
+            .hidden_frames
+            .prefixes
+            .push("/usr/lib/python3.8/".to_string());
+
+        let mut result: Vec<String> = tracker.to_lines(true, false).collect();
+        result.sort();
+        assert_eq!(
+            result,
+            vec![
+                "/app/main.py:2 (main) 1000",
+                "/app/main.py:3 (main);/app/usr/lib/python3.8/x.py:5 (f) 200"
+            ]
+        );
+    }
+
+    #[test]
+    fn current_dump_includes_realloc_growth() {
+        let func1 = FunctionLocation::from_strings("a", "af");
+        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, 1));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid1, 2));
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 100, &cs1);
+        // Growth from a different callstack is stored separately:
+        tracker.realloc_allocation(1, 2, 400, &cs2);
+        assert_eq!(tracker.realloc_extensions.len(), 1);
+        let id1 = tracker.get_callstack_id(&cs1);
+        let id2 = tracker.get_callstack_id(&cs2);
+        let dumped: std::collections::HashMap<_, _> = tracker.combine_callstacks(false).collect();
+        assert_eq!(dumped.values().sum::<usize>(), tracker.usage.current_bytes);
+        assert_eq!(dumped[&id1], 100);
+        assert_eq!(dumped[&id2], 300);
+    }
+
+    #[test]
+    fn realloc_grow_shrink_and_move() {
+        let func1 = FunctionLocation::from_strings("a", "af");
+        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, 1));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid1, 2));
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 100, &cs1);
+        let id1 = tracker.get_callstack_id(&cs1);
+
+        // Grow in place from the same callstack:
+        tracker.realloc_allocation(1, 1, 150, &cs1);
+        assert_eq!(tracker.allocation_size(1), 150);
+        assert_eq!(tracker.usage.current_bytes, 150);
+        assert_eq!(tracker.usage.current[id1 as usize], 150);
+
+        // Grow in place from a different callstack: growth is attributed to
+        // the new callstack.
+        tracker.realloc_allocation(1, 1, 400, &cs2);
+        let id2 = tracker.get_callstack_id(&cs2);
+        assert_eq!(tracker.allocation_size(1), 400);
+        assert_eq!(tracker.usage.current_bytes, 400);
+        assert_eq!(tracker.usage.current[id1 as usize], 150);
+        assert_eq!(tracker.usage.current[id2 as usize], 250);
+
+        // Move, keeping the split:
+        tracker.realloc_allocation(1, 2, 400, &cs2);
+        assert_eq!(tracker.allocation_size(1), 0);
+        assert_eq!(tracker.allocation_size(2), 400);
+        assert_eq!(tracker.usage.current_bytes, 400);
+        assert_eq!(tracker.usage.current[id1 as usize], 150);
+        assert_eq!(tracker.usage.current[id2 as usize], 250);
+
+        // Shrink: everything goes back to the original callstack.
+        tracker.realloc_allocation(2, 2, 50, &cs2);
+        assert_eq!(tracker.allocation_size(2), 50);
+        assert_eq!(tracker.usage.current_bytes, 50);
+        assert_eq!(tracker.usage.current[id1 as usize], 50);
+        assert_eq!(tracker.usage.current[id2 as usize], 0);
+        assert_eq!(tracker.usage.peak_bytes, 400);
+
+        // Grow, then free everything:
+        tracker.realloc_allocation(2, 3, 1000, &cs2);
+        assert_eq!(tracker.usage.current_bytes, 1000);
+        tracker.free_allocation(3);
+        assert_eq!(tracker.usage.current_bytes, 0);
+        assert_eq!(tracker.usage.current[id1 as usize], 0);
+        assert_eq!(tracker.usage.current[id2 as usize], 0);
+        assert!(tracker.realloc_extensions.is_empty());
+
+        // realloc() of unknown address is malloc(), realloc() to 0 is free():
+        tracker.realloc_allocation(0, 4, 70, &cs1);
+        assert_eq!(tracker.usage.current_bytes, 70);
+        tracker.realloc_allocation(4, 0, 0, &cs1);
+        assert_eq!(tracker.usage.current_bytes, 0);
+        assert!(tracker.current_allocations.is_empty());
+    }
+
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    #[test]
+    fn remap_anon_mmap_grow_shrink_and_move() {
+        let func1 = FunctionLocation::from_strings("a", "af");
+        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, 1));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid1, 2));
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_anon_mmap(4096, 4096, &cs1);
+        let id1 = tracker.get_callstack_id(&cs1);
+        let id2 = tracker.get_callstack_id(&cs2);
+
+        // In-place growth, attributed to the callstack doing the mremap():
+        tracker.remap_anon_mmap(4096, 4096, 4096, 3 * 4096, &cs2);
+        assert_eq!(tracker.usage.current_bytes, 3 * 4096);
+        assert_eq!(tracker.usage.current[id1 as usize], 4096);
+        assert_eq!(tracker.usage.current[id2 as usize], 2 * 4096);
+
+        // In-place shrink:
+        tracker.remap_anon_mmap(4096, 3 * 4096, 4096, 4096 + 100, &cs2);
+        assert_eq!(tracker.usage.current_bytes, 4096 + 100);
+        assert_eq!(tracker.usage.current[id1 as usize], 4096);
+        assert_eq!(tracker.usage.current[id2 as usize], 100);
+        assert_eq!(tracker.usage.peak_bytes, 3 * 4096);
+
+        // Relocation to a new address:
+        tracker.remap_anon_mmap(4096, 4096 + 100, 100_000, 4096 + 100, &cs2);
+        assert_eq!(tracker.usage.current_bytes, 4096 + 100);
+        assert_eq!(tracker.usage.current[id1 as usize], 4096);
+        assert_eq!(tracker.usage.current[id2 as usize], 100);
+        tracker.free_anon_mmap(4096, 4096 + 100);
+        assert_eq!(tracker.usage.current_bytes, 4096 + 100);
+        tracker.free_anon_mmap(100_000, 4096 + 100);
+        assert_eq!(tracker.usage.current_bytes, 0);
+
+        // Untracked mmap()s are ignored:
+        tracker.remap_anon_mmap(1_000_000, 4096, 1_000_000, 8192, &cs2);
+        assert_eq!(tracker.usage.current_bytes, 0);
+    }
+
+    #[test]
+    fn output_directory_falls_back_when_it_cant_be_created() {
+        let directory = tempfile::tempdir().unwrap();
+        let fallback = directory.path().join("tmp");
+        // Existing directories, and ones that can be created, are used as is:
+        let wanted = directory.path().join("a").join("b");
+        assert_eq!(create_output_directory(&wanted, &fallback).unwrap(), wanted);
+        assert!(wanted.is_dir());
+        assert_eq!(create_output_directory(&wanted, &fallback).unwrap(), wanted);
+
+        // A directory can't be created inside a file:
+        let file = directory.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        let unwritable = file.join("2020-01-01T00:00:00.000");
+        let used = create_output_directory(&unwritable, &fallback).unwrap();
+        assert_eq!(
+            used,
+            fallback.join("fil-result").join("2020-01-01T00:00:00.000")
+        );
+        assert!(used.is_dir());
+        // Same for an output path that's a file:
+        assert_eq!(
+            create_output_directory(&file, &fallback).unwrap(),
+            fallback.join("fil-result").join("file")
+        );
+
+        // If the fallback doesn't work either, the original error is returned:
+        assert!(create_output_directory(&unwritable, &file).is_err());
+
+        // Dumps end up in the fallback:
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.fallback_path = fallback.clone();
+        tracker.add_allocation(1, 100, &Callstack::new());
+        tracker
+            .dump_to_flamegraph(
+                unwritable.to_str().unwrap(),
+                true,
+                None,
+                "peak-memory",
+                "Peak",
+                None,
+                false,
+                false,
+            )
+            .unwrap();
+        assert!(used.join("peak-memory.prof").exists());
+        // Including the combined report's HTML page:
+        std::fs::remove_dir_all(&used).unwrap();
+        tracker
+            .dump_combined_report(unwritable.to_str().unwrap())
+            .unwrap();
+        assert!(used.join("current-memory.svg").exists());
+        assert!(used.join("peak-memory.svg").exists());
+        assert!(used.join("index.html").exists());
+        // And the other kinds of dump:
+        tracker
+            .dump_old_allocations_flamegraph(0, unwritable.to_str().unwrap())
+            .unwrap();
+        assert!(used.join("old-allocations.svg").exists());
+        // A freed allocation that lived for a while:
+        tracker.byte_milliseconds.insert(0, 100_000);
+        tracker
+            .dump_byte_seconds_flamegraph(unwritable.to_str().unwrap())
+            .unwrap();
+        assert!(used.join("byte-seconds.svg").exists());
+        tracker.baselines.insert("start".to_string(), im::vector![]);
+        tracker
+            .dump_delta_flamegraph("start", unwritable.to_str().unwrap())
+            .unwrap();
+        assert!(used.join("memory-growth.svg").exists());
+    }
+
+    #[test]
+    fn allocations_before_profiling_are_separate() {
+        let mut startup = Callstack::new();
+        startup.before_profiling = true;
+        assert_eq!(startup.traceback(), "  [Before profiling started]");
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 1000, &startup);
+        tracker.add_allocation(2, 200, &Callstack::new());
+        tracker.add_allocation(3, 30, &startup);
+        let mut lines: Vec<String> = tracker.to_lines(true, false).collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec!["[Before profiling started] 1030", "[No Python stack] 200"]
+        );
+    }
+
+    #[test]
+    fn forgetting_is_like_freeing() {
+        let func = FunctionLocation::from_strings("a", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let mut callstack = Callstack::new();
+        callstack.start_call(0, CallSiteId::new(fid, 1));
+        let mut freeing = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        let mut forgetting = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        for tracker in [&mut freeing, &mut forgetting] {
+            tracker.add_allocation(1, 100, &callstack);
+            tracker.add_allocation(2, 200, &callstack);
+            tracker.realloc_allocation(2, 2, 300, &Callstack::new());
+        }
+        assert_eq!(freeing.free_allocation(2), Some(300));
+        assert_eq!(forgetting.forget_allocation(2), Some(300));
+        assert_eq!(forgetting.forget_allocation(2), None);
+        assert_eq!(freeing.current_allocations, forgetting.current_allocations);
+        assert_eq!(freeing.usage.current, forgetting.usage.current);
+        assert_eq!(freeing.usage.current_bytes, 100);
+        assert_eq!(forgetting.usage.current_bytes, 100);
+        assert_eq!(forgetting.usage.peak_bytes, 400);
+
+        // Forgetting isn't a free(), so freeing afterwards isn't a double
+        // free():
+        assert_eq!(forgetting.tracked_frees + 1, freeing.tracked_frees);
+        assert_eq!(forgetting.forget_allocation(1), Some(100));
+        assert_eq!(forgetting.free_allocation(1), None);
+        assert_eq!(forgetting.double_frees, 0);
+    }
+
+    #[test]
+    fn max_allocations_truncates_tracking() {
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.max_allocations = 2;
+        let func = FunctionLocation::from_strings("a", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid, 1));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid, 2));
+        tracker.add_allocation(1, 100, &cs1);
+        // Before the cap is hit, unknown addresses are just untracked:
+        assert_eq!(tracker.free_allocation(0x1234), None);
+        tracker.add_allocation(2, 200, &cs1);
+        assert!(!tracker.truncated);
+        // Past the cap, allocations count but aren't stored:
+        tracker.add_allocation(3, 300, &cs2);
+        tracker.add_allocation(4, 500, &cs2);
+        assert!(tracker.truncated);
+        assert_eq!(tracker.current_allocations.len(), 2);
+        assert!(!tracker.current_allocations.contains_key(&3));
+        assert_eq!(tracker.usage.current_bytes, 1100);
+        assert_eq!(tracker.usage.current, im::vector![300, 800]);
+        // Current dumps include the unstored allocations:
+        let mut dumped: Vec<_> = tracker.combine_callstacks(false).collect();
+        dumped.sort();
+        assert_eq!(dumped, vec![(0, 300), (1, 800)]);
+
+        // free(NULL) is never a guess:
+        assert_eq!(tracker.free_allocation(0), None);
+        assert_eq!(tracker.usage.current_bytes, 1100);
+
+        // Stored allocations are freed precisely, unstored ones by the
+        // average unstored size, though they still count as untracked:
+        assert_eq!(tracker.free_allocation(1), Some(100));
+        assert_eq!(tracker.free_allocation(3), None);
+        assert_eq!(tracker.usage.current, im::vector![200, 400]);
+        assert_eq!(tracker.free_allocation(4), None);
+        assert_eq!(tracker.usage.current, im::vector![200, 0]);
+        assert_eq!(tracker.free_allocation(5), None);
+        assert_eq!(tracker.usage.current, im::vector![200, 0]);
+        assert_eq!(tracker.untracked_frees, 4);
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().to_str().unwrap();
+        tracker.dump_peak_to_flamegraph(path, None, None).unwrap();
+        let svg = std::fs::read_to_string(directory.path().join("peak-memory.svg")).unwrap();
+        assert!(
+            svg.contains("Peak Tracked Memory Usage (approximate — allocation tracking truncated)")
+        );
+
+        // Resetting starts afresh:
+        tracker.reset_keeping_interner(".".to_string());
+        assert!(!tracker.truncated);
+        assert_eq!(tracker.unstored_allocations, 0);
+    }
+
+    #[test]
+    fn unknown_frees_dont_drain_truncated_usage() {
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.max_allocations = 1;
+        tracker.add_allocation(1, 100, &Callstack::new());
+        tracker.add_allocation(2, 200, &Callstack::new());
+        assert!(tracker.truncated);
+        tracker.free_allocation(0);
+        tracker.realloc_allocation(0, 0, 0, &Callstack::new());
+        assert_eq!(tracker.usage.current_bytes, 300);
+
+        // With sampling, unknown addresses were probably never sampled:
+        tracker.sampling_rate = 2;
+        tracker.free_allocation(0x1234);
+        assert_eq!(tracker.usage.current_bytes, 300);
+        tracker.sampling_rate = 1;
+        tracker.free_allocation(0x1234);
+        assert_eq!(tracker.usage.current_bytes, 100);
+    }
+
+    #[test]
+    fn dump_at_exit_writes_once() {
+        let directory = tempfile::tempdir().unwrap();
+        let output = directory.path().join("exit");
+        // Nothing registered, nothing dumped:
+        dump_at_exit();
+        assert!(!output.exists());
+
+        // Set the path without registering with atexit(), which would dump
+        // when the tests finish:
+        *EXIT_DUMP_PATH.lock().unwrap() = Some(output.to_str().unwrap().to_string());
+        dump_at_exit();
+        assert!(output.join("peak-memory.prof").exists());
+        assert!(output.join("index.html").exists());
+
+        // It only happens once:
+        std::fs::remove_dir_all(&output).unwrap();
+        dump_at_exit();
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn interior_frees_are_reported() {
+        let func = FunctionLocation::from_strings("a", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let mut callstack = Callstack::new();
+        callstack.start_call(0, CallSiteId::new(fid, 12));
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(0x1000, 100, &callstack);
+        // Growth is part of the allocation too:
+        tracker.realloc_allocation(0x1000, 0x2000, 200, &Callstack::new());
+
+        assert_eq!(
+            tracker.allocation_containing(0x2000 + 150).map(|a| a.0),
+            Some(0x2000)
+        );
+        assert_eq!(tracker.allocation_containing(0x2000 + 200), None);
+        assert_eq!(tracker.allocation_containing(0x1fff), None);
+        let message = tracker.untracked_free_message(0x2010);
+        assert!(message.contains("16 bytes into the 200 byte allocation at 0x2000"));
+        assert!(message.contains("a:12 (af)"));
+        assert_eq!(
+            tracker.untracked_free_message(0x5000),
+            "free() of untracked address 0x5000"
+        );
+
+        // The free() itself is still ignored:
+        assert_eq!(tracker.free_allocation(0x2010), None);
+        assert_eq!(tracker.usage.current_bytes, 200);
+    }
+
+    #[test]
+    fn inactive_callstacks() {
+        let func = FunctionLocation::from_strings("a", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let callstack = |line| {
+            let mut cs = Callstack::new();
+            cs.start_call(0, CallSiteId::new(fid, line));
+            cs
+        };
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.dump_options.min_fraction = 0.01;
+        tracker.add_allocation(1, 1000, &callstack(1));
+        tracker.add_allocation(2, 1, &callstack(2));
+        tracker.add_allocation(3, 2000, &callstack(3));
+        tracker.free_allocation(3);
+        let lines = |tracker: &mut AllocationTracker| {
+            let mut lines: Vec<String> = tracker.to_lines(false, false).collect();
+            lines.sort();
+            lines
+        };
+        assert_eq!(
+            lines(&mut tracker),
+            vec![
+                format!("{} 1", SMALL_CALLSTACKS),
+                "a:1 (af) 1000".to_string(),
+            ]
+        );
+
+        tracker.dump_options.inactive_callstacks = true;
+        let all_lines = lines(&mut tracker);
+        assert_eq!(
+            all_lines,
+            vec![
+                format!("{} 1", SMALL_CALLSTACKS),
+                "a:1 (af) 1000".to_string(),
+                "a:3 (af) 0".to_string(),
+            ]
+        );
+        // At the peak, callstack 3 was using memory, and 2 wasn't yet:
+        let mut peak_lines: Vec<String> = tracker.to_lines(true, false).collect();
+        peak_lines.sort();
+        assert!(peak_lines.contains(&"a:3 (af) 2000".to_string()));
+
+        // The flamegraph leaves out what's using nothing:
+        let mut svg = Vec::new();
+        write_flamegraph_to(
+            &all_lines.join("\n"),
+            &mut svg,
+            1001,
+            false,
+            "T",
+            None,
+            false,
+            &tracker.dump_options,
+        )
+        .unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+        assert!(svg.contains("a:1 (af)"));
+        assert!(!svg.contains("a:3 (af)"));
+    }
+
+    #[test]
+    fn only_tracked_thread_is_recorded() {
+        let func = FunctionLocation::from_strings("a", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let callstack = |thread_id| {
+            let mut cs = Callstack::new();
+            cs.start_call(0, CallSiteId::new(fid, 1));
+            cs.thread_id = thread_id;
+            cs
+        };
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.tracked_thread = Some(7);
+        tracker.add_allocation(1, 100, &callstack(7));
+        tracker.add_allocation(2, 200, &callstack(8));
+        tracker.add_allocation(3, 400, &callstack(0));
+        assert_eq!(tracker.usage.current_bytes, 100);
+        assert_eq!(tracker.current_allocations.len(), 1);
+        assert_eq!(tracker.allocation_size(2), 0);
+        // Skipped allocations' free()s are expected, so they're not counted
+        // as double free()s:
+        tracker.free_allocation(1);
+        tracker.add_allocation(1, 300, &callstack(8));
+        assert_eq!(tracker.free_allocation(1), None);
+        assert_eq!(tracker.double_frees, 0);
+        assert_eq!(tracker.usage.current_bytes, 0);
+
+        tracker.tracked_thread = None;
+        tracker.add_allocation(2, 200, &callstack(8));
+        assert_eq!(tracker.usage.current_bytes, 200);
+    }
+
+    #[test]
+    fn total_allocated_bytes() {
+        let func = FunctionLocation::from_strings("a", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid, 1));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid, 2));
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 100, &cs1);
+        tracker.add_allocation(2, 250, &cs2);
+        assert_eq!(tracker.total_allocated_bytes, 350);
+        // Frees don't make it go down:
+        tracker.free_allocation(1);
+        tracker.free_allocation(12345);
+        assert_eq!(tracker.total_allocated_bytes, 350);
+        tracker.add_allocation(3, 40, &cs1);
+        assert_eq!(tracker.total_allocated_bytes, 390);
+        // Only realloc() growth is new memory, whichever callstack it's
+        // attributed to:
+        tracker.realloc_allocation(2, 4, 300, &cs2);
+        assert_eq!(tracker.total_allocated_bytes, 440);
+        tracker.realloc_allocation(4, 5, 400, &cs1);
+        assert_eq!(tracker.total_allocated_bytes, 540);
+        tracker.realloc_allocation(5, 6, 10, &cs1);
+        assert_eq!(tracker.total_allocated_bytes, 540);
+        tracker.free_allocation(6);
+        assert_eq!(tracker.total_allocated_bytes, 540);
+        assert_eq!(tracker.usage.current_bytes, 40);
+    }
+
+    #[test]
+    fn peak_callstacks() {
+        let func = FunctionLocation::from_strings("a", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let callstack = |line| {
+            let mut cs = Callstack::new();
+            cs.start_call(0, CallSiteId::new(fid, line));
+            cs
+        };
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 100, &callstack(1));
+        tracker.add_allocation(2, 3000, &callstack(2));
+        tracker.add_allocation(3, 200, &callstack(1));
+        tracker.free_allocation(2);
+        tracker.add_allocation(4, 50, &callstack(3));
+
+        let entries = tracker.peak_callstacks();
+        assert_eq!(
+            entries,
+            vec![
+                ("a:2 (af)".to_string(), 3000),
+                ("a:1 (af)".to_string(), 300)
+            ]
+        );
+        let total: usize = entries.iter().map(|(_, bytes)| bytes).sum();
+        assert_eq!(total, tracker.usage.peak_bytes);
+    }
+
+    #[test]
+    fn byte_seconds() {
+        let func = FunctionLocation::from_strings("a", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let callstack = |line| {
+            let mut cs = Callstack::new();
+            cs.start_call(0, CallSiteId::new(fid, line));
+            cs
+        };
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        // Pretend time passes by moving the start of tracking back:
+        let advance = |tracker: &mut AllocationTracker, seconds| {
+            tracker.started -= Duration::from_secs(seconds);
+        };
+        tracker.add_allocation(1, 1000, &callstack(1));
+        tracker.add_allocation(2, 3000, &callstack(2));
+        advance(&mut tracker, 2);
+        tracker.free_allocation(1);
+        advance(&mut tracker, 3);
+        // Reallocating keeps counting the old bytes' lifetime:
+        tracker.realloc_allocation(2, 3, 6000, &callstack(3));
+        advance(&mut tracker, 1);
+        tracker.free_allocation(3);
+        // Still live, so counts up to now:
+        tracker.add_allocation(4, 500, &callstack(4));
+        advance(&mut tracker, 10);
+
+        // Allow for the milliseconds the test itself takes:
+        let byte_seconds = tracker.byte_seconds();
+        let expected = [
+            ("a:1 (af)", 1000 * 2),
+            ("a:2 (af)", 3000 * 5 + 3000),
+            ("a:3 (af)", 3000),
+            ("a:4 (af)", 500 * 10),
+        ];
+        assert_eq!(byte_seconds.len(), expected.len());
+        for (callstack, expected) in expected {
+            let actual = byte_seconds[callstack];
+            assert!(
+                actual >= expected && actual <= expected + expected / 20,
+                "{}: {} vs {}",
+                callstack,
+                actual,
+                expected
+            );
+        }
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().to_str().unwrap();
+        tracker.dump_byte_seconds_flamegraph(path).unwrap();
+        let svg = std::fs::read_to_string(directory.path().join("byte-seconds.svg")).unwrap();
+        assert!(svg.contains("byte-seconds"));
+        assert!(directory.path().join("byte-seconds-reversed.svg").exists());
+    }
+
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    #[test]
+    fn mmap_over_existing_mmap_replaces_it() {
+        let func1 = FunctionLocation::from_strings("a", "af");
+        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, 1));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid1, 2));
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        // Reserve a region, then map part of its middle with MAP_FIXED:
+        tracker.add_anon_mmap(4096, 10 * 4096, &cs1);
+        tracker.add_anon_mmap(3 * 4096, 2 * 4096, &cs2);
+        let id1 = tracker.get_callstack_id(&cs1);
+        let id2 = tracker.get_callstack_id(&cs2);
+        assert_eq!(tracker.usage.current_bytes, 10 * 4096);
+        assert_eq!(tracker.usage.current[id1 as usize], 8 * 4096);
+        assert_eq!(tracker.usage.current[id2 as usize], 2 * 4096);
+        assert_eq!(tracker.usage.peak_bytes, 10 * 4096);
+
+        // Unmapping across the boundary removes from both:
+        tracker.free_anon_mmap(4 * 4096, 2 * 4096);
+        assert_eq!(tracker.usage.current_bytes, 8 * 4096);
+        assert_eq!(tracker.usage.current[id1 as usize], 7 * 4096);
+        assert_eq!(tracker.usage.current[id2 as usize], 4096);
+        tracker.free_anon_mmap(0, 20 * 4096);
+        assert_eq!(tracker.usage.current_bytes, 0);
+        assert_eq!(tracker.current_anon_mmaps.len(), 0);
+    }
+
+    #[test]
+    fn binary_intermediate_renders_the_same() {
+        let func1 = FunctionLocation::from_strings("a", "af");
+        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
+        let func2 = FunctionLocation::from_strings("b", "bf");
+        let fid2 = FunctionId::new(&func2 as *const FunctionLocation);
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, 2));
+        let mut cs2 = cs1.clone();
+        cs2.start_call(3, CallSiteId::new(fid2, 4));
+        let mut cs3 = cs2.clone();
+        cs3.thread_id = 2;
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 1000, &cs1);
+        tracker.add_allocation(2, 234, &cs2);
+        tracker.add_allocation(3, 50, &cs3);
+        tracker.add_anon_mmap(5000, 4000, &Callstack::new());
+
+        let directory = tempfile::tempdir().unwrap();
+        let text_path = directory.path().join("text.prof");
+        let text_path = text_path.to_str().unwrap();
+        write_lines(tracker.to_lines(true, true), text_path).unwrap();
+
+        let mut binary = vec![];
+        tracker.dump_peak_binary(&mut binary).unwrap();
+        let loaded_path = directory.path().join("loaded.prof");
+        let loaded_path = loaded_path.to_str().unwrap();
+        write_lines(
+            collapsed::read_binary(&mut &binary[..])
+                .unwrap()
+                .into_iter(),
+            loaded_path,
+        )
+        .unwrap();
+
+        let render = |lines_path: &str| {
+            let svg_path = directory.path().join("out.svg");
+            let svg_path = svg_path.to_str().unwrap();
+            write_flamegraph(
+                lines_path,
+                svg_path,
+                5284,
+                false,
+                "T",
+                None,
+                true,
+                &DumpOptions::default(),
+            )
+            .unwrap();
+            std::fs::read_to_string(svg_path).unwrap()
+        };
+        assert_eq!(render(text_path), render(loaded_path));
+    }
+
+    #[test]
+    fn rendered_callstacks_are_cached() {
+        let func1 = FunctionLocation::from_strings("a.py", "af");
+        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
+        let func2 = FunctionLocation::from_strings("b.py", "bf");
+        let fid2 = FunctionId::new(&func2 as *const FunctionLocation);
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid1, 2));
+        cs.start_call(2, CallSiteId::new(fid2, 3));
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 1000, &cs);
+        let lines = |tracker: &mut AllocationTracker, to_be_post_processed| {
+            tracker
+                .to_lines(true, to_be_post_processed)
+                .collect::<Vec<String>>()
+        };
+        let plain = vec!["a.py:2 (af);b.py:3 (bf) 1000"];
+        let post_processed = vec!["a.py:2 (af);TB@@a.py:2@@TB;b.py:3 (bf);TB@@b.py:3@@TB 1000"];
+
+        assert_eq!(lines(&mut tracker, false), plain);
+        assert_eq!(lines(&mut tracker, true), post_processed);
+        assert_eq!(tracker.interner.rendered.borrow().len(), 2);
+        // Cached versions are the same, and still distinguish post-processing:
+        assert_eq!(lines(&mut tracker, false), plain);
+        assert_eq!(lines(&mut tracker, true), post_processed);
+        assert_eq!(
+            tracker.interner.rendered_string(
+                0,
+                &cs,
+                false,
+                &HiddenFrames::default(),
+                Some("Thread 1")
+            ),
+            "Thread 1;a.py:2 (af);b.py:3 (bf)"
+        );
+
+        // Changing hidden frames needs the cache to be cleared:
+        tracker
+            .dump_options
+            .hidden_frames
+            .patterns
+            .push("bf".to_string());
+        tracker.interner.clear_rendered();
+        assert_eq!(lines(&mut tracker, false), vec!["a.py:2 (af) 1000"]);
+    }
+
+    #[test]
+    fn peak_to_csv() {
+        let func1 = FunctionLocation::from_strings("a, \"b\".py", "af");
+        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
+        let func2 = FunctionLocation::from_strings("c.py", "cf");
+        let fid2 = FunctionId::new(&func2 as *const FunctionLocation);
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid1, 2));
+        cs.start_call(2, CallSiteId::new(fid2, 3));
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 1000, &cs);
+        tracker.add_allocation(2, 50, &Callstack::new());
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("peak.csv");
+        tracker.dump_peak_to_csv(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(path).unwrap(),
+            "bytes,stack\n1000,\"a, \"\"b\"\".py:2 (af);c.py:3 (cf)\"\n50,\"[No Python stack]\"\n"
+        );
+    }
+
+    #[test]
+    fn peak_to_pprof() {
+        let func = FunctionLocation::from_strings("a.py", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid, 2));
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 1000, &cs);
+        tracker.add_allocation(2, 234, &Callstack::new());
+        tracker.free_allocation(2);
+        tracker.add_allocation(3, 5, &Callstack::new());
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("peak.pb");
+        tracker.dump_peak_to_pprof(path.to_str().unwrap()).unwrap();
+        let data = std::fs::read(path).unwrap();
+        // The first field is the sample type, a length-delimited field 1:
+        assert_eq!(data[0], 0x0a);
+        assert_eq!(pprof::decode::sample_total(&data), 1234);
+        let strings = pprof::decode::strings(&data);
+        for expected in &["", "space", "bytes", "af", "a.py", "[No Python stack]"] {
+            assert!(strings.contains(&expected.to_string()));
+        }
+    }
+
+    #[test]
+    fn free_returns_size() {
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        let cs = Callstack::new();
+        tracker.add_allocation(1, 100, &cs);
+        assert_eq!(tracker.free_allocation(1), Some(100));
+        assert_eq!(tracker.free_allocation(1), None);
+        assert_eq!(tracker.free_allocation(2), None);
+        // Growth from realloc() is included:
+        tracker.add_allocation(3, 100, &cs);
+        let mut other = Callstack::new();
+        other.thread_id = 1;
+        tracker.realloc_allocation(3, 4, 250, &other);
+        assert_eq!(tracker.free_allocation(4), Some(250));
+    }
+
+    #[test]
+    fn untracked_frees_are_counted() {
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 100, &Callstack::new());
+        tracker.free_allocation(1);
+        tracker.free_allocation(2);
+        tracker.free_allocation(1);
+        // free(NULL) is fine:
+        tracker.free_allocation(0);
+        assert_eq!(tracker.tracked_frees, 1);
+        assert_eq!(tracker.untracked_frees, 2);
+        assert!(!tracker.warned_about_untracked_frees);
+
+        for i in 0..UNTRACKED_FREES_MIN_FREES {
+            tracker.free_allocation(i + 1000);
+        }
+        assert!(tracker.warned_about_untracked_frees);
+    }
+
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    #[test]
+    fn reset_keeping_interner_keeps_ids() {
+        let func1 = FunctionLocation::from_strings("a", "af");
+        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, 1));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid1, 2));
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 100, &cs1);
+        tracker.add_anon_mmap(4096, 4096, &cs2);
+        let id1 = tracker.get_callstack_id(&cs1);
+        let id2 = tracker.get_callstack_id(&cs2);
+
+        tracker.reset_keeping_interner("/tmp/new".to_string());
+        assert!(tracker.current_allocations.is_empty());
+        assert_eq!(tracker.current_anon_mmaps.size(), 0);
+        assert_eq!(tracker.usage.current_bytes, 0);
+        assert_eq!(tracker.usage.peak_bytes, 0);
+        assert_eq!(tracker.default_path, "/tmp/new");
+        assert_eq!(tracker.get_callstack_id(&cs2), id2);
+        assert_eq!(tracker.get_callstack_id(&cs1), id1);
+
+        // Tracking works as usual afterwards:
+        let mut cs3 = Callstack::new();
+        cs3.start_call(0, CallSiteId::new(fid1, 3));
+        tracker.add_allocation(1, 50, &cs1);
+        tracker.add_allocation(2, 70, &cs3);
+        assert_eq!(tracker.usage.current[id1 as usize], 50);
+        assert_eq!(tracker.usage.current[id2 as usize], 0);
+        assert_eq!(tracker.get_callstack_id(&cs3), 2);
+        assert_eq!(tracker.usage.current[2], 70);
+        assert_eq!(tracker.usage.current_bytes, 120);
+    }
+
+    #[test]
+    fn snapshot_interval() {
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        let start = tracker.last_snapshot;
+        let later = |seconds| start + Duration::from_secs(seconds);
+        // Disabled by default:
+        assert_eq!(tracker.snapshot_due(later(1000)), None);
+
+        tracker.snapshot_interval = Some(Duration::from_secs(10));
+        assert_eq!(tracker.snapshot_due(later(9)), None);
+        let mut due = vec![];
+        for seconds in &[10, 15, 19, 20, 35, 40, 44, 50] {
+            if let Some(base_filename) = tracker.snapshot_due(later(*seconds)) {
+                due.push(base_filename);
+            }
+        }
+        // Intervals are measured from the last snapshot, and filenames are
+        // reused after a few snapshots:
+        assert_eq!(
+            due,
+            vec![
+                "periodic-snapshot-0",
+                "periodic-snapshot-1",
+                "periodic-snapshot-2",
+                "periodic-snapshot-0",
+            ]
+        );
+        assert_eq!(tracker.snapshots_written, 4);
+    }
+
+    #[test]
+    fn dump_peak_and_reset() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut tracker = AllocationTracker::new(
+            directory.path().to_str().unwrap().to_string(),
+            DEFAULT_SPARE_MEMORY_SIZE,
+        );
+        tracker.add_allocation(1, 300, &Callstack::new());
+        tracker.free_allocation(1);
+        tracker.add_allocation(2, 100, &Callstack::new());
+        let output = directory.path().join("final");
+        tracker
+            .dump_peak_and_reset(output.to_str().unwrap(), "elsewhere".to_string())
+            .unwrap();
+
+        // The dump has the peak from before the reset:
+        assert_eq!(
+            std::fs::read_to_string(output.join("peak-memory.prof")).unwrap(),
+            "[No Python stack] 300\n"
+        );
+        // And the tracker is empty afterwards:
+        assert!(tracker.current_allocations.is_empty());
+        assert_eq!(tracker.usage.current_bytes, 0);
+        assert_eq!(tracker.get_peak_allocated_bytes(), 0);
+        assert_eq!(tracker.default_path, "elsewhere");
+    }
+
+    #[test]
+    fn format_from_environment() {
+        // Other tests don't create trackers through new_tracker(), so
+        // briefly setting the variable doesn't affect them:
+        std::env::set_var("FIL_FORMAT", "json");
+        let mut tracker = new_tracker(".".to_string());
+        std::env::remove_var("FIL_FORMAT");
+        assert_eq!(tracker.dump_options.format, OutputFormat::Json);
+        assert_eq!(OutputFormat::from_name("CSV"), Some(OutputFormat::Csv));
+        assert_eq!(OutputFormat::from_name("png"), None);
+
+        let func = FunctionLocation::from_strings("a\"b", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid, 2));
+        tracker.add_allocation(1, 100, &cs);
+        tracker.add_allocation(2, 300, &Callstack::new());
+
+        let dir = tempfile::tempdir().unwrap();
+        tracker
+            .dump_peak_to_flamegraph(dir.path().to_str().unwrap(), None, None)
+            .unwrap();
+        let json = std::fs::read_to_string(dir.path().join("peak-memory.json")).unwrap();
+        assert_eq!(
+            json,
+            "{\"total_bytes\":400,\"callstacks\":[{\"stack\":\"[No Python stack]\",\"bytes\":300},{\"stack\":\"a\\\"b:2 (af)\",\"bytes\":100}]}\n"
+        );
+        // The Python report still needs the SVGs:
+        assert!(dir.path().join("peak-memory.svg").exists());
+    }
+
+    #[test]
+    fn debug_messages_need_verbosity() {
+        let mut out = vec![];
+        write_debug_message(&mut out, 0, || panic!("Message shouldn't be created"));
+        assert!(out.is_empty());
+        write_debug_message(&mut out, DEBUG_VERBOSITY, || "hello".to_string());
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "=fil-profile= DEBUG: hello\n"
+        );
+    }
+
+    #[test]
+    fn binary_round_trip_renders() {
+        let func = FunctionLocation::from_strings("a", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        for line in 1..200u16 {
+            let mut cs = Callstack::new();
+            cs.start_call(0, CallSiteId::new(fid, line));
+            tracker.add_allocation(line as usize, line as usize * 1000 + 7, &cs);
+        }
+        tracker.add_anon_mmap(1 << 20, 4096, &Callstack::new());
+
+        let directory = tempfile::tempdir().unwrap();
+        let binary_path = directory.path().join("peak.bin");
+        let mut file = std::fs::File::create(&binary_path).unwrap();
+        tracker.dump_peak_binary(&mut file).unwrap();
+        drop(file);
+        let output = directory.path().join("rendered");
+        render_binary_to_flamegraph(&binary_path, &output).unwrap();
+
+        let expected_lines: Vec<String> = tracker.to_lines(true, true).collect();
+        let expected_text = expected_lines.join("\n");
+        let expected = collapsed::parse_collapsed(&expected_text);
+        let loaded_text = std::fs::read_to_string(output.join("peak-memory.prof")).unwrap();
+        assert_eq!(collapsed::parse_collapsed(&loaded_text), expected);
+        let svg = std::fs::read_to_string(output.join("peak-memory.svg")).unwrap();
+        assert!(svg.contains("a:199 (af)"));
+        assert!(output.join("peak-memory-reversed.svg").exists());
+    }
+
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    #[test]
+    fn largest_allocation() {
+        let func = FunctionLocation::from_strings("a", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid, 7));
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        assert_eq!(tracker.largest_allocation(), None);
+        tracker.add_allocation(1, 100, &Callstack::new());
+        tracker.add_allocation(2, 5000, &cs);
+        tracker.add_allocation(3, 300, &Callstack::new());
+        tracker.add_anon_mmap(1 << 20, 4096, &Callstack::new());
+        assert_eq!(
+            tracker.largest_allocation(),
+            Some((2, 5000, "a:7 (af)".to_string()))
+        );
+        // Growth from realloc() counts towards the allocation's size:
+        tracker.realloc_allocation(3, 3, 6000, &Callstack::new());
+        assert_eq!(tracker.largest_allocation().unwrap().1, 6000);
+        // And so do anonymous mmap()s:
+        tracker.add_anon_mmap(1 << 30, 10000, &cs);
+        assert_eq!(
+            tracker.largest_allocation(),
+            Some((1 << 30, 10000, "a:7 (af)".to_string()))
+        );
+    }
+
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    #[test]
+    fn compact_interner() {
+        let func = FunctionLocation::from_strings("a", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let callstack = |line| {
+            let mut cs = Callstack::new();
+            cs.start_call(0, CallSiteId::new(fid, line));
+            cs
+        };
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        // Callstack 1 is only in a peak that then gets reset:
+        tracker.add_allocation(1, 1000, &callstack(1));
+        tracker.free_allocation(1);
+        tracker.reset_peak();
+        // Callstacks 2 and 3 are freed without being part of a peak:
+        tracker.add_allocation(2, 10, &callstack(2));
+        tracker.free_allocation(2);
+        tracker.add_allocation(3, 10, &callstack(3));
+        tracker.free_allocation(3);
+        // Callstacks 4 and 5 are still in use:
+        tracker.add_allocation(4, 0, &callstack(4));
+        tracker.add_anon_mmap(1 << 20, 4096, &callstack(5));
+        // Push out the events that refer to callstacks 2 and 3:
+        for _ in 0..RECENT_EVENTS_CAPACITY {
+            tracker.add_allocation(4, 0, &callstack(4));
+        }
+        assert_eq!(tracker.interner.max_id, 5);
+
+        assert_eq!(tracker.compact_interner(), 3);
+        assert_eq!(tracker.compact_interner(), 0);
+        assert_eq!(tracker.interner.max_id, 2);
+        assert_eq!(tracker.usage.current, im::vector![0, 4096]);
+        assert_eq!(tracker.usage.peak, im::vector![0, 4096]);
+        let reverse = tracker.interner.get_reverse_map();
+        let id4 = tracker.current_allocations[&4].callstack_id;
+        assert_eq!(*reverse[&id4], callstack(4));
+        let id5 = tracker
+            .current_anon_mmaps
+            .get(1 << 20)
+            .unwrap()
+            .callstack_id;
+        assert_eq!(*reverse[&id5], callstack(5));
+        // Tracking keeps working with the new ids:
+        tracker.free_anon_mmap(1 << 20, 4096);
+        tracker.add_allocation(5, 100, &callstack(2));
+        assert_eq!(tracker.usage.current, im::vector![0, 0, 100]);
+        assert_eq!(tracker.free_allocation(4), Some(0));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn peak_to_pipe() {
+        use std::io::Read;
+        use std::os::unix::io::FromRawFd;
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 1000, &Callstack::new());
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut reader = unsafe { std::fs::File::from_raw_fd(fds[0]) };
+        let mut writer = unsafe { std::fs::File::from_raw_fd(fds[1]) };
+        tracker.dump_peak_collapsed_to(&mut writer).unwrap();
+        tracker.dump_peak_svg_to(&mut writer).unwrap();
+        // Close the write end, so reading stops at the end of the output:
+        drop(writer);
+        let mut output = String::new();
+        reader.read_to_string(&mut output).unwrap();
+        let (collapsed, svg) = output.split_at(output.find('<').unwrap());
+        assert_eq!(collapsed, "[No Python stack] 1000\n");
+        assert!(svg.contains("Peak Tracked Memory Usage"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+    }
+
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    #[test]
+    fn mmaps_adjusted_to_resident_memory() {
+        use super::ResidentRegion;
+        let func = FunctionLocation::from_strings("a", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid, 1));
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_anon_mmap(1 << 20, 1 << 20, &cs);
+        tracker.add_anon_mmap_with_pages(1 << 30, 1 << 20, &cs, true);
+        tracker.add_allocation(1, 100, &cs);
+        // A quarter of the normal mmap() is resident; huge pages always are:
+        let region = |resident_bytes| ResidentRegion {
+            start: 1 << 20,
+            end: 2 << 20,
+            resident_bytes,
+        };
+        tracker.adjust_mmaps_to_resident(&[region(256 << 10)]);
+        assert_eq!(tracker.usage.current_bytes, (256 << 10) + (1 << 20) + 100);
+        assert_eq!(tracker.usage.peak_bytes, (2 << 20) + 100);
+        // Current dumps only count the resident bytes too:
+        let dumped: Vec<_> = tracker.combine_callstacks(false).collect();
+        assert_eq!(dumped, vec![(0, (256 << 10) + (1 << 20) + 100)]);
+        // More pages got touched:
+        tracker.adjust_mmaps_to_resident(&[region(512 << 10)]);
+        assert_eq!(tracker.usage.current_bytes, (512 << 10) + (1 << 20) + 100);
+        // Unmapping can't take usage below zero:
+        tracker.free_anon_mmap(1 << 20, 768 << 10);
+        assert_eq!(tracker.usage.current_bytes, (256 << 10) + (1 << 20) + 100);
+        tracker.free_anon_mmap(1 << 20, 1 << 20);
+        tracker.free_anon_mmap(1 << 30, 1 << 20);
+        assert_eq!(tracker.usage.current_bytes, 100);
+        assert!(tracker.nonresident_mmap_bytes.is_empty());
+        // Turning it off counts all mapped bytes again:
+        tracker.add_anon_mmap(1 << 20, 1 << 20, &cs);
+        tracker.adjust_mmaps_to_resident(&[region(0)]);
+        assert_eq!(tracker.usage.current_bytes, 100);
+        tracker.count_all_mapped_bytes();
+        assert_eq!(tracker.usage.current_bytes, (1 << 20) + 100);
+    }
+
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    #[test]
+    fn huge_page_mmaps() {
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        let cs = Callstack::new();
+        tracker.add_anon_mmap(1 << 30, 4096, &cs);
+        tracker.add_anon_mmap_with_pages(1 << 32, 4 << 20, &cs, true);
+        assert_eq!(tracker.current_huge_page_bytes, 4 << 20);
+        // Growth of a huge page mapping is also huge pages:
+        tracker.remap_anon_mmap(1 << 32, 4 << 20, 1 << 33, 6 << 20, &cs);
+        assert_eq!(tracker.current_huge_page_bytes, 6 << 20);
+        assert!(
+            tracker
+                .current_anon_mmaps
+                .get((1 << 33) + (5 << 20))
+                .unwrap()
+                .huge_pages
+        );
+        assert!(!tracker.current_anon_mmaps.get(1 << 30).unwrap().huge_pages);
+        assert_eq!(
+            tracker.huge_page_summary().unwrap(),
+            format!(
+                "At peak, {} of {} bytes were huge page (MAP_HUGETLB) mmap()s, and 4096 bytes were normal memory.",
+                6 << 20,
+                (6 << 20) + 4096
+            )
+        );
+        // Partial munmap()s reduce huge page usage, and the peak stays:
+        tracker.free_anon_mmap(1 << 33, 2 << 20);
+        assert_eq!(tracker.current_huge_page_bytes, 4 << 20);
+        assert_eq!(tracker.peak_huge_page_bytes, 6 << 20);
+        tracker.free_anon_mmap(1 << 33, 6 << 20);
+        tracker.reset_peak();
+        assert_eq!(tracker.huge_page_summary(), None);
+    }
+
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    #[test]
+    fn old_allocations() {
+        let func = FunctionLocation::from_strings("a", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let callstack = |line| {
+            let mut cs = Callstack::new();
+            cs.start_call(0, CallSiteId::new(fid, line));
+            cs
+        };
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 100, &callstack(1));
+        tracker.add_anon_mmap(1 << 20, 4096, &callstack(2));
+        tracker.new_generation();
+        tracker.add_allocation(2, 200, &callstack(3));
+        // Growth happens in the new generation, but the original bytes stay
+        // old:
+        tracker.realloc_allocation(1, 1, 150, &callstack(4));
+        tracker.new_generation();
+        tracker.add_allocation(3, 300, &callstack(5));
+
+        let sizes = |tracker: &AllocationTracker, min_age| {
+            let mut sizes: Vec<(String, usize)> =
+                tracker.old_allocations(min_age).into_iter().collect();
+            sizes.sort();
+            sizes
+        };
+        assert_eq!(
+            sizes(&tracker, 2),
+            vec![
+                ("a:1 (af)".to_string(), 100),
+                ("a:2 (af)".to_string(), 4096)
+            ]
+        );
+        assert_eq!(
+            sizes(&tracker, 1),
+            vec![
+                ("a:1 (af)".to_string(), 100),
+                ("a:2 (af)".to_string(), 4096),
+                ("a:3 (af)".to_string(), 200),
+                ("a:4 (af)".to_string(), 50),
+            ]
+        );
+        assert_eq!(sizes(&tracker, 0).len(), 5);
+        assert_eq!(sizes(&tracker, 3), vec![]);
+
+        // Dumping is a generation bump too:
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().to_str().unwrap();
+        tracker.dump_old_allocations_flamegraph(2, path).unwrap();
+        assert!(directory.path().join("old-allocations.svg").exists());
+        assert_eq!(sizes(&tracker, 3).len(), 2);
+        // Dumps that write several sets of files are still a single bump:
+        let generation = tracker.generation;
+        tracker.dump_combined_report(path).unwrap();
+        assert_eq!(tracker.generation, generation + 1);
+        tracker.dump_peak_by_thread_to_flamegraph(path).unwrap();
+        assert!(directory.path().join("peak-memory-thread-0.svg").exists());
+        assert_eq!(tracker.generation, generation + 2);
+    }
+
+    #[test]
+    fn double_free() {
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 100, &Callstack::new());
+        assert_eq!(tracker.free_allocation(1), Some(100));
+        assert_eq!(tracker.double_frees, 0);
+        assert_eq!(tracker.free_allocation(1), None);
+        assert_eq!(tracker.double_frees, 1);
+        // Other untracked frees aren't double frees:
+        tracker.free_allocation(2);
+        assert_eq!(tracker.untracked_frees, 2);
+        assert_eq!(tracker.double_frees, 1);
+        // Reusing the address is fine:
+        tracker.add_allocation(1, 100, &Callstack::new());
+        assert_eq!(tracker.free_allocation(1), Some(100));
+        assert_eq!(tracker.double_frees, 1);
+    }
+
+    #[test]
+    fn reset_after_fork() {
+        let mut tracker = AllocationTracker::new("parent".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 300, &Callstack::new());
+        tracker.add_anon_mmap(4096, 8192, &Callstack::new());
+        tracker.reset_after_fork(1234);
+        assert!(tracker.current_allocations.is_empty());
+        assert_eq!(tracker.usage.current_bytes, 0);
+        assert_eq!(tracker.get_peak_allocated_bytes(), 0);
+        assert_eq!(tracker.default_path, "parent/fork-1234");
+        // The child's own allocations are tracked as usual:
+        tracker.add_allocation(1, 50, &Callstack::new());
+        assert_eq!(tracker.free_allocation(1), Some(50));
+    }
+
+    #[test]
+    fn dump_on_request() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut tracker = AllocationTracker::new(
+            directory.path().to_str().unwrap().to_string(),
+            DEFAULT_SPARE_MEMORY_SIZE,
+        );
+        tracker.add_allocation(1, 100, &Callstack::new());
+        // Nothing requested, so nothing happens:
+        assert_eq!(tracker.dump_if_requested(), None);
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 0);
+
+        // Simulate the signal handler:
+        request_dump(libc::SIGUSR2);
+        let base_filename = tracker.dump_if_requested().unwrap();
+        assert!(base_filename.starts_with("snapshot-"));
+        let prof = directory.path().join(format!("{}.prof", base_filename));
+        assert_eq!(
+            std::fs::read_to_string(prof).unwrap(),
+            "[No Python stack] 100\n"
+        );
+        assert!(directory
+            .path()
+            .join(format!("{}.svg", base_filename))
+            .exists());
+
+        // The request was handled, so it doesn't happen again:
+        assert_eq!(tracker.dump_if_requested(), None);
+    }
+
+    #[test]
+    fn default_output_path_from_environment() {
+        std::env::set_var("FIL_OUTPUT_PATH", "/somewhere/writable");
+        assert_eq!(default_output_path(), "/somewhere/writable");
+        let tracker = AllocationTracker::new(default_output_path(), DEFAULT_SPARE_MEMORY_SIZE);
+        assert_eq!(tracker.default_path, "/somewhere/writable");
+        std::env::set_var("FIL_OUTPUT_PATH", "");
+        assert_eq!(default_output_path(), "/tmp");
+        std::env::remove_var("FIL_OUTPUT_PATH");
+        assert_eq!(default_output_path(), "/tmp");
+    }
+
+    #[test]
+    fn deep_recursion_is_truncated() {
+        let main = FunctionLocation::from_strings("a", "main");
+        let main_id = FunctionId::new(&main as *const FunctionLocation);
+        let recurse = FunctionLocation::from_strings("a", "recurse");
+        let recurse_id = FunctionId::new(&recurse as *const FunctionLocation);
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        let mut cs = Callstack::new();
+        cs.start_call_with_max_depth(0, CallSiteId::new(main_id, 1), 100, truncation_frame());
+        for i in 0..10000 {
+            cs.start_call_with_max_depth(
+                2,
+                CallSiteId::new(recurse_id, 10),
+                100,
+                truncation_frame(),
+            );
+            cs.new_line_number(11);
+            tracker.add_allocation(i + 1, 10, &cs);
+        }
+        // 99 distinct recursion depths, and then the truncated one:
+        assert_eq!(tracker.interner.callstack_to_id.len(), 100);
+        assert_eq!(cs.calls.len(), 101);
+        let truncated = cs.as_string(false);
+        assert!(truncated.ends_with("a:2 (recurse);...:0 (truncated)"));
+
+        // Unwinding gets us back where we started:
+        for _ in 0..10000 {
+            cs.finish_call();
+        }
+        assert_eq!(cs.calls, vec![CallSiteId::new(main_id, 2)]);
+        assert_eq!(cs.truncated_calls, 0);
+        // And recursing again uses the same interned callstacks:
+        for i in 0..10000 {
+            cs.start_call_with_max_depth(
+                2,
+                CallSiteId::new(recurse_id, 10),
+                100,
+                truncation_frame(),
+            );
+            cs.new_line_number(11);
+            tracker.add_allocation(i + 20000, 10, &cs);
+        }
+        assert_eq!(tracker.interner.callstack_to_id.len(), 100);
     }
 
     #[test]
-    fn functionlocation_and_functionid_strings() {
-        let func = FunctionLocation::from_strings("a", "af");
+    fn regions_only() {
+        let func = FunctionLocation::from_strings("a.py", "af");
         let fid = FunctionId::new(&func as *const FunctionLocation);
-        assert_eq!(fid.get_filename(), "a");
-        assert_eq!(fid.get_function_name(), "af");
+        start_call(fid, 0, 1);
+        let outside = current_python_callstack(0);
+        begin_region("load");
+        new_line_number(3);
+        let inside = current_python_callstack(0);
+        start_call(fid, 0, 5);
+        let nested = current_python_callstack(0);
+        finish_call();
+        end_region();
+        assert_eq!(current_python_callstack(0), outside);
+        // The same region name gets the same frame:
+        assert_eq!(region_function("load"), region_function("load"));
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 1000, &outside);
+        tracker.add_allocation(2, 200, &inside);
+        tracker.add_allocation(3, 30, &nested);
+        tracker.add_allocation(4, 4, &Callstack::new());
+        assert_eq!(tracker.to_lines(true, false).count(), 4);
+
+        tracker.dump_options.regions_only = true;
+        let mut lines: Vec<String> = tracker.to_lines(true, false).collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "a.py:1 (af);<region>:3 (load) 200",
+                "a.py:1 (af);<region>:3 (load);a.py:5 (af) 30",
+            ]
+        );
     }
 
     #[test]
-    fn callstack_line_numbers() {
-        let func1 = FunctionLocation::from_strings("a", "af");
-        let func3 = FunctionLocation::from_strings("b", "bf");
-        let func5 = FunctionLocation::from_strings("c", "cf");
+    fn peak_budget() {
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 1000, &Callstack::new());
+        assert!(!tracker.peak_exceeds(1000));
+        assert!(tracker.peak_exceeds(999));
+        // The peak counts even once memory has gone back down:
+        tracker.free_allocation(1);
+        tracker.add_allocation(2, 10, &Callstack::new());
+        assert!(tracker.peak_exceeds(999));
+        assert!(!tracker.peak_exceeds(1000));
+    }
 
+    #[test]
+    fn reset_peak_keeps_current_allocations() {
+        let func1 = FunctionLocation::from_strings("a", "load");
         let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
-        let fid3 = FunctionId::new(&func3 as *const FunctionLocation);
-        let fid5 = FunctionId::new(&func5 as *const FunctionLocation);
+        let func2 = FunctionLocation::from_strings("b", "process");
+        let fid2 = FunctionId::new(&func2 as *const FunctionLocation);
+        let mut load = Callstack::new();
+        load.start_call(0, CallSiteId::new(fid1, 1));
+        let mut process = Callstack::new();
+        process.start_call(0, CallSiteId::new(fid2, 2));
 
-        // Parent line number does nothing if it's first call:
-        let mut cs1 = Callstack::new();
-        let id1 = CallSiteId::new(fid1, 2);
-        let id2 = CallSiteId::new(fid3, 45);
-        let id3 = CallSiteId::new(fid5, 6);
-        cs1.start_call(123, id1);
-        assert_eq!(cs1.calls, vec![id1]);
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        // First phase: a big temporary allocation, and the loaded data:
+        tracker.add_allocation(1, 10000, &load);
+        tracker.add_allocation(2, 1000, &load);
+        tracker.free_allocation(1);
+        assert_eq!(tracker.get_peak_allocated_bytes(), 11000);
 
-        // Parent line number does nothing if it's 0:
-        cs1.start_call(0, id2);
-        assert_eq!(cs1.calls, vec![id1, id2]);
+        tracker.reset_peak();
+        assert_eq!(tracker.get_peak_allocated_bytes(), 1000);
+        // Second phase has a smaller peak, which is no longer masked:
+        tracker.add_allocation(3, 500, &process);
+        tracker.free_allocation(3);
+        assert_eq!(tracker.get_peak_allocated_bytes(), 1500);
+        assert_eq!(tracker.usage.current_bytes, 1000);
+        let mut lines: Vec<String> = tracker.to_lines(true, false).collect();
+        lines.sort();
+        assert_eq!(lines, vec!["a:1 (load) 1000", "b:2 (process) 500"]);
+    }
 
-        // Parent line number overrides previous level if it's non-0:
-        let mut cs2 = Callstack::new();
-        cs2.start_call(0, id1);
-        cs2.start_call(10, id2);
-        cs2.start_call(12, id3);
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    #[test]
+    fn peak_tracks_growth_without_frees() {
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        let cs = Callstack::new();
+        let mut total = 0;
+        for i in 1..10 {
+            tracker.add_allocation(i, i * 1000, &cs);
+            total += i * 1000;
+            assert_eq!(tracker.usage.peak_bytes, total);
+            assert_eq!(tracker.usage.peak, tracker.usage.current);
+        }
+        tracker.add_anon_mmap(1 << 20, 5000, &cs);
+        assert_eq!(tracker.usage.peak_bytes, total + 5000);
+        assert_eq!(tracker.usage.peak, tracker.usage.current);
+    }
+
+    #[test]
+    fn native_frame_formatting() {
         assert_eq!(
-            cs2.calls,
-            vec![CallSiteId::new(fid1, 10), CallSiteId::new(fid3, 12), id3]
+            format_native_frame(
+                Some("PyList_Append"),
+                Some("/usr/lib/libpython3.8.so"),
+                0x1234
+            ),
+            "PyList_Append (libpython3.8.so)"
+        );
+        assert_eq!(
+            format_native_frame(None, Some("/opt/ext.so"), 0x1234),
+            "0x1234 (ext.so)"
         );
+        assert_eq!(format_native_frame(Some("f"), None, 0x1234), "f");
+        assert_eq!(format_native_frame(None, None, 0x1234), "0x1234");
     }
 
     #[test]
-    fn callstackinterner_notices_duplicates() {
-        let func1 = FunctionLocation::from_strings("a", "af");
-        let func3 = FunctionLocation::from_strings("b", "bf");
-        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
-        let fid3 = FunctionId::new(&func3 as *const FunctionLocation);
+    fn native_caller_formatting() {
+        assert_eq!(
+            format_native_caller(Some("PyMem_RawMalloc"), 0x1234),
+            "[Native code in PyMem_RawMalloc]"
+        );
+        assert_eq!(
+            format_native_caller(None, 0x1234),
+            "[Native code at 0x1234]"
+        );
+        // Addresses dladdr() knows nothing about fall back to the address:
+        let mut callstack = Callstack::new();
+        callstack.native_caller = 0x10;
+        assert_eq!(
+            callstack.as_string_hiding(false, &HiddenFrames::default(), None),
+            "[Native code at 0x10]"
+        );
+    }
 
-        let mut cs1 = Callstack::new();
-        cs1.start_call(0, CallSiteId::new(fid1, 2));
-        let cs1b = cs1.clone();
-        let mut cs2 = Callstack::new();
-        cs2.start_call(0, CallSiteId::new(fid3, 4));
-        let cs3 = Callstack::new();
-        let cs3b = Callstack::new();
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    #[test]
+    fn native_stacks() {
+        assert!(!capture_native_stack().is_empty());
 
-        let mut interner = CallstackInterner::new();
+        // Frames in Fil itself are left out, so if there's nothing else we
+        // fall back to the usual rendering:
+        let mut cs = Callstack::new();
+        cs.native_frames = vec![native_stack_string as *const () as usize + 1];
+        assert_eq!(cs.as_string(false), "[No Python stack]");
 
-        let mut new = false;
-        let id1 = interner.get_or_insert_id(&cs1, || new = true);
-        assert!(new);
+        // Frames elsewhere are symbolicated:
+        let free = unsafe { libc::dlsym(libc::RTLD_DEFAULT, b"free\0".as_ptr() as *const _) };
+        cs.native_frames = vec![
+            free as usize + 1,
+            native_stack_string as *const () as usize + 1,
+        ];
+        let rendered = cs.as_string(false);
+        assert!(rendered.contains("free"), "{}", rendered);
+        assert!(rendered.contains("libc"), "{}", rendered);
+        assert!(!rendered.contains(';'));
+    }
 
-        new = false;
-        let id1b = interner.get_or_insert_id(&cs1b, || new = true);
-        assert!(!new);
+    #[test]
+    fn thread_root_frame_rendering() {
+        let func = FunctionLocation::from_strings("a.py", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let mut cs = Callstack::new();
+        cs.start_call(0, CallSiteId::new(fid, 2));
+        assert_eq!(
+            cs.as_string_hiding(false, &HiddenFrames::default(), Some("Thread MainThread")),
+            "Thread MainThread;a.py:2 (af)"
+        );
+        assert_eq!(
+            cs.as_string_hiding(true, &HiddenFrames::default(), Some("Thread MainThread")),
+            "Thread MainThread;a.py:2 (af);TB@@a.py:2@@TB"
+        );
+        assert_eq!(
+            Callstack::new().as_string_hiding(false, &HiddenFrames::default(), Some("Thread 3")),
+            "Thread 3;[No Python stack]"
+        );
 
-        new = false;
-        let id2 = interner.get_or_insert_id(&cs2, || new = true);
-        assert!(new);
+        let mut names = std::collections::HashMap::new();
+        names.insert(1, "MainThread".to_string());
+        names.insert(2, "odd;name".to_string());
+        assert_eq!(
+            thread_root_frame(&names, 1),
+            Some("Thread MainThread".to_string())
+        );
+        assert_eq!(
+            thread_root_frame(&names, 2),
+            Some("Thread odd,name".to_string())
+        );
+        // Falls back to the id:
+        assert_eq!(thread_root_frame(&names, 7), Some("Thread 7".to_string()));
+        // Unknown thread, no root frame:
+        assert_eq!(thread_root_frame(&names, 0), None);
+    }
 
-        new = false;
-        let id3 = interner.get_or_insert_id(&cs3, || new = true);
-        assert!(new);
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    #[test]
+    fn allocator_modes() {
+        let cs = Callstack::new();
+        let track = |mode| {
+            let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+            tracker.allocator_mode = mode;
+            // A custom allocator mmap()s a chunk, and hands out part of it via
+            // malloc():
+            tracker.add_anon_mmap(1 << 20, 4096, &cs);
+            tracker.add_allocation(1 << 20, 1000, &cs);
+            let added = tracker.usage.current_bytes;
+            tracker.realloc_allocation(1 << 20, 1 << 20, 2000, &cs);
+            let resized = tracker.usage.current_bytes;
+            tracker.free_allocation(1 << 20);
+            tracker.free_anon_mmap(1 << 20, 4096);
+            assert_eq!(tracker.usage.current_bytes, 0);
+            assert_eq!(tracker.untracked_frees, 0);
+            (added, resized)
+        };
+        assert_eq!(track(AllocatorMode::Malloc), (1000, 2000));
+        assert_eq!(track(AllocatorMode::Mmap), (4096, 4096));
+        assert_eq!(track(AllocatorMode::Both), (5096, 6096));
+    }
 
-        new = false;
-        let id3b = interner.get_or_insert_id(&cs3b, || new = true);
-        assert!(!new);
+    #[cfg(feature = "no-mmap-tracking")]
+    #[test]
+    fn mmaps_are_ignored() {
+        let cs = Callstack::new();
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_anon_mmap(1 << 20, 4096, &cs);
+        tracker.change_heap_break(1 << 30, 8192, &cs);
+        tracker.add_allocation(1, 1000, &cs);
+        tracker.remap_anon_mmap(1 << 20, 4096, 1 << 21, 8192, &cs);
+        assert_eq!(tracker.usage.current_bytes, 1000);
+        assert_eq!(tracker.tracked_allocation_count(), 1);
+        tracker.free_anon_mmap(1 << 20, 4096);
+        tracker.free_allocation(1);
+        assert_eq!(tracker.usage.current_bytes, 0);
+    }
 
-        assert_eq!(id1, id1b);
-        assert_ne!(id1, id2);
-        assert_ne!(id1, id3);
-        assert_ne!(id2, id3);
-        assert_eq!(id3, id3b);
-        let mut expected = collections::HashMap::default();
-        expected.insert(id1, &cs1);
-        expected.insert(id2, &cs2);
-        expected.insert(id3, &cs3);
-        assert_eq!(interner.get_reverse_map(), expected);
+    #[test]
+    fn timeline_tracks_memory_and_peak() {
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.timeline = Some(Timeline::new(100, 10));
+        let cs = Callstack::new();
+        tracker.add_allocation(1, 60, &cs);
+        tracker.add_allocation(2, 60, &cs);
+        tracker.add_allocation(3, 200, &cs);
+        tracker.free_allocation(3);
+        let mut csv = vec![];
+        tracker
+            .timeline
+            .as_ref()
+            .unwrap()
+            .write_csv(&mut csv)
+            .unwrap();
+        let rows: Vec<String> = String::from_utf8(csv)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|line| line.split_once(',').unwrap().1.to_string())
+            .collect();
+        assert_eq!(rows, vec!["120,0", "320,0", "320,1", "120,0"]);
     }
 
     #[test]
-    fn peak_allocations_only_updated_on_new_peaks() {
+    fn top_allocators_sorted_and_truncated() {
         let func1 = FunctionLocation::from_strings("a", "af");
-        let func3 = FunctionLocation::from_strings("b", "bf");
         let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
-        let fid3 = FunctionId::new(&func3 as *const FunctionLocation);
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, 1));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid1, 2));
+        let mut cs3 = Callstack::new();
+        cs3.start_call(0, CallSiteId::new(fid1, 3));
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 200, &cs1);
+        tracker.add_allocation(2, 1000, &cs2);
+        tracker.add_allocation(3, 50, &cs3);
+        tracker.add_allocation(4, 100, &cs3);
+        tracker.free_allocation(2);
+
+        assert_eq!(
+            tracker.top_allocators(2, true),
+            vec![
+                ("a:2 (af)".to_string(), 1000),
+                ("a:1 (af)".to_string(), 200)
+            ]
+        );
+        assert_eq!(
+            tracker.top_allocators(10, false),
+            vec![("a:1 (af)".to_string(), 200), ("a:3 (af)".to_string(), 150)]
+        );
+        assert!(tracker.top_allocators(0, true).is_empty());
+    }
 
-        let mut tracker = AllocationTracker::new(".".to_string());
+    #[test]
+    fn small_callstacks_are_merged() {
+        let func1 = FunctionLocation::from_strings("a", "af");
+        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
         let mut cs1 = Callstack::new();
-        cs1.start_call(0, CallSiteId::new(fid1, 2));
+        cs1.start_call(0, CallSiteId::new(fid1, 1));
         let mut cs2 = Callstack::new();
-        cs2.start_call(0, CallSiteId::new(fid3, 4));
+        cs2.start_call(0, CallSiteId::new(fid1, 2));
+        let mut cs3 = Callstack::new();
+        cs3.start_call(0, CallSiteId::new(fid1, 3));
 
-        tracker.add_allocation(1, 1000, &cs1);
-        tracker.check_if_new_peak();
-        // Peak should now match current allocations:
-        assert_eq!(tracker.current_memory_usage, im::vector![1000]);
-        assert_eq!(tracker.current_memory_usage, tracker.peak_memory_usage);
-        assert_eq!(tracker.peak_allocated_bytes, 1000);
-        let previous_peak = tracker.peak_memory_usage.clone();
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 9980, &cs1);
+        // 0.1% and 0.1% of the total:
+        tracker.add_allocation(2, 10, &cs2);
+        tracker.add_allocation(3, 10, &cs3);
+        let lines = |tracker: &mut AllocationTracker| {
+            let mut lines: Vec<String> = tracker.to_lines(true, false).collect();
+            lines.sort();
+            lines
+        };
+        assert_eq!(
+            lines(&mut tracker),
+            vec!["a:1 (af) 9980", "a:2 (af) 10", "a:3 (af) 10"]
+        );
+        tracker.dump_options.min_fraction = 0.005;
+        assert_eq!(
+            lines(&mut tracker),
+            vec!["[Other small callstacks] 20", "a:1 (af) 9980"]
+        );
+    }
 
-        // Free the allocation:
-        tracker.free_allocation(1);
-        assert_eq!(tracker.current_allocated_bytes, 0);
-        assert_eq!(tracker.current_memory_usage, im::vector![0]);
-        assert_eq!(previous_peak, tracker.peak_memory_usage);
-        assert_eq!(tracker.peak_allocated_bytes, 1000);
+    #[test]
+    fn growth_since_baseline() {
+        let func1 = FunctionLocation::from_strings("a", "af");
+        let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid1, 1));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid1, 2));
+        let mut cs3 = Callstack::new();
+        cs3.start_call(0, CallSiteId::new(fid1, 3));
 
-        // Add allocation, still less than 1000:
-        tracker.add_allocation(3, 123, &cs1);
-        assert_eq!(tracker.current_memory_usage, im::vector![123]);
-        tracker.check_if_new_peak();
-        assert_eq!(previous_peak, tracker.peak_memory_usage);
-        assert_eq!(tracker.peak_allocated_bytes, 1000);
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 100, &cs1);
+        tracker.add_allocation(2, 200, &cs2);
+        assert_eq!(tracker.growth_since_baseline("start"), None);
+        tracker
+            .baselines
+            .insert("start".to_string(), tracker.usage.current.clone());
+        // Grows:
+        tracker.add_allocation(3, 50, &cs1);
+        // Shrinks:
+        tracker.free_allocation(2);
+        // A callstack that didn't exist at baseline time:
+        tracker.add_allocation(4, 70, &cs3);
 
-        // Add allocation that goes past previous peak
-        tracker.add_allocation(2, 2000, &cs2);
-        tracker.check_if_new_peak();
-        assert_eq!(tracker.current_memory_usage, im::vector![123, 2000]);
-        assert_eq!(tracker.current_memory_usage, tracker.peak_memory_usage);
-        assert_eq!(tracker.peak_allocated_bytes, 2123);
-        let previous_peak = tracker.peak_memory_usage.clone();
+        let mut expected = std::collections::HashMap::new();
+        expected.insert("a:1 (af)".to_string(), 50);
+        expected.insert("a:3 (af)".to_string(), 70);
+        assert_eq!(tracker.growth_since_baseline("start"), Some(expected));
 
-        // Add anonymous mmap() that doesn't go past previous peak:
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().to_str().unwrap();
+        tracker.dump_delta_flamegraph("start", path).unwrap();
+        assert!(directory.path().join("memory-growth.svg").exists());
+        assert!(directory.path().join("memory-growth-reversed.svg").exists());
+        assert!(tracker.dump_delta_flamegraph("other", path).is_err());
+    }
+
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    #[test]
+    fn tracked_allocation_count() {
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        let cs = Callstack::new();
+        assert_eq!(tracker.tracked_allocation_count(), 0);
+        tracker.add_allocation(1, 100, &cs);
+        tracker.add_allocation(2, 100, &cs);
+        tracker.add_anon_mmap(4096, 4096 * 3, &cs);
+        assert_eq!(tracker.tracked_allocation_count(), 3);
+        // munmap() in the middle of a range splits it in two:
+        tracker.free_anon_mmap(4096 * 2, 4096);
+        assert_eq!(tracker.tracked_allocation_count(), 4);
+        tracker.free_allocation(1);
+        // Untracked frees don't change anything:
+        tracker.free_allocation(12345);
+        assert_eq!(tracker.tracked_allocation_count(), 3);
+        tracker.free_anon_mmap(4096, 4096 * 3);
         tracker.free_allocation(2);
-        assert_eq!(tracker.current_memory_usage, im::vector![123, 0]);
-        tracker.add_anon_mmap(50000, 1000, &cs2);
-        assert_eq!(tracker.current_memory_usage, im::vector![123, 1000]);
-        tracker.check_if_new_peak();
-        assert_eq!(tracker.current_allocated_bytes, 1123);
-        assert_eq!(tracker.peak_allocated_bytes, 2123);
-        assert_eq!(tracker.peak_memory_usage, previous_peak);
-        assert_eq!(tracker.current_allocations.len(), 1);
-        assert!(tracker.current_allocations.contains_key(&3));
-        assert!(tracker.current_anon_mmaps.size() > 0);
+        assert_eq!(tracker.tracked_allocation_count(), 0);
+    }
 
-        // Add anonymous mmap() that does go past previous peak:
-        tracker.add_anon_mmap(600000, 2000, &cs2);
-        assert_eq!(tracker.current_memory_usage, im::vector![123, 3000]);
-        tracker.check_if_new_peak();
-        assert_eq!(tracker.current_memory_usage, tracker.peak_memory_usage);
-        assert_eq!(tracker.current_allocated_bytes, 3123);
-        assert_eq!(tracker.peak_allocated_bytes, 3123);
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    #[test]
+    fn peak_allocated_bytes_includes_current_peak() {
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        let cs = Callstack::new();
+        assert_eq!(tracker.get_peak_allocated_bytes(), 0);
+        tracker.add_allocation(1, 100, &cs);
+        tracker.add_anon_mmap(2, 4096, &cs);
+        // The peak is still in effect, so hasn't been recorded yet, but it
+        // is reported:
+        assert_eq!(tracker.usage.current_bytes, 4196);
+        assert_eq!(tracker.get_peak_allocated_bytes(), 4196);
+        tracker.free_anon_mmap(2, 4096);
+        tracker.add_allocation(3, 1000, &cs);
+        assert_eq!(tracker.usage.current_bytes, 1100);
+        assert_eq!(tracker.get_peak_allocated_bytes(), 4196);
+        tracker.add_allocation(4, 5000, &cs);
+        tracker.free_allocation(1);
+        assert_eq!(tracker.usage.current_bytes, 6000);
+        assert_eq!(tracker.get_peak_allocated_bytes(), 6100);
+        tracker.free_allocation(3);
+        tracker.free_allocation(4);
+        assert_eq!(tracker.usage.current_bytes, 0);
+        assert_eq!(tracker.get_peak_allocated_bytes(), 6100);
+    }
 
-        // Remove mmap():
-        tracker.free_anon_mmap(50000, 1000);
-        assert_eq!(tracker.current_memory_usage, im::vector![123, 2000]);
-        tracker.check_if_new_peak();
-        assert_eq!(tracker.current_allocated_bytes, 2123);
-        assert_eq!(tracker.peak_allocated_bytes, 3123);
-        assert_eq!(tracker.current_anon_mmaps.size(), 2000);
-        assert!(tracker
-            .current_anon_mmaps
-            .as_hashmap()
-            .contains_key(&600000));
+    #[test]
+    fn self_test() {
+        // There are no hooks in tests, so nothing gets recorded:
+        assert!(!run_self_test());
+        // Recording like the hooks do passes:
+        assert!(run_self_test_with(
+            |size| {
+                let address = unsafe { libc::malloc(size) } as usize;
+                add_allocation(address, size, 0, false);
+                address
+            },
+            |address| {
+                free_allocation(address);
+                unsafe { libc::free(address as *mut libc::c_void) };
+            }
+        ));
+        // Sizes being wrong is caught:
+        assert!(!run_self_test_with(
+            |size| {
+                let address = unsafe { libc::malloc(size) } as usize;
+                add_allocation(address, size / 2, 0, false);
+                address
+            },
+            |address| {
+                free_allocation(address);
+                unsafe { libc::free(address as *mut libc::c_void) };
+            }
+        ));
+    }
 
-        // Partial removal of anonmyous mmap():
-        tracker.free_anon_mmap(600100, 1000);
-        assert_eq!(tracker.current_memory_usage, im::vector![123, 1000]);
-        assert_eq!(tracker.current_allocated_bytes, 1123);
-        assert_eq!(tracker.peak_allocated_bytes, 3123);
-        assert_eq!(tracker.current_anon_mmaps.size(), 1000);
+    #[test]
+    fn function_lines() {
+        let hot = FunctionLocation::from_strings("a.py", "hot");
+        let other_hot = FunctionLocation::from_strings("b.py", "hot");
+        let callee = FunctionLocation::from_strings("a.py", "callee");
+        let hot = FunctionId::new(&hot as *const FunctionLocation);
+        let other_hot = FunctionId::new(&other_hot as *const FunctionLocation);
+        let callee = FunctionId::new(&callee as *const FunctionLocation);
+        let callstack = |calls: &[(FunctionId, u16)]| {
+            let mut cs = Callstack::new();
+            for (function, line) in calls {
+                cs.start_call(0, CallSiteId::new(*function, *line));
+            }
+            cs
+        };
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 100, &callstack(&[(hot, 10)]));
+        tracker.add_allocation(2, 50, &callstack(&[(hot, 10)]));
+        // Allocations in callees count towards the line doing the call:
+        tracker.add_allocation(3, 300, &callstack(&[(hot, 12), (callee, 30)]));
+        // Recursion only counts the innermost call:
+        tracker.add_allocation(4, 70, &callstack(&[(hot, 12), (hot, 11)]));
+        tracker.add_allocation(5, 20, &callstack(&[(other_hot, 12)]));
+        // Not called from hot at all:
+        tracker.add_allocation(6, 1000, &callstack(&[(callee, 30)]));
+        assert_eq!(
+            tracker.bytes_by_function_line("hot", true),
+            vec![
+                ("a.py".to_string(), 12, 300),
+                ("a.py".to_string(), 10, 150),
+                ("a.py".to_string(), 11, 70),
+                ("b.py".to_string(), 12, 20),
+            ]
+        );
+        assert!(tracker.bytes_by_function_line("missing", true).is_empty());
+
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("lines.txt");
+        tracker
+            .dump_function_lines("hot", path.to_str().unwrap())
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "300 a.py:12\n150 a.py:10\n70 a.py:11\n20 b.py:12\n"
+        );
     }
 
     #[test]
-    fn combine_callstacks_and_sum_allocations() {
-        let func1 = FunctionLocation::from_strings("a", "af");
-        let func2 = FunctionLocation::from_strings("b", "bf");
-        let func3 = FunctionLocation::from_strings("c", "cf");
+    fn allocating_while_recording_is_ignored() {
+        let threads: Vec<_> = (1..=4usize)
+            .map(|thread| {
+                std::thread::spawn(move || {
+                    let base = thread * 0x1000_0000;
+                    let nested = base + 0x100_0000;
+                    WHILE_RECORDING.with(|hook| {
+                        *hook.borrow_mut() = Some(Box::new(move || {
+                            // As if recording allocated via a hooked malloc():
+                            add_allocation(nested, 10, 0, false);
+                            realloc_allocation(nested, nested, 20, 0);
+                            free_allocation(nested);
+                        }));
+                    });
+                    for i in 0..1000 {
+                        add_allocation(base + i, 100, 0, false);
+                    }
+                    WHILE_RECORDING.with(|hook| *hook.borrow_mut() = None);
+                    assert_eq!(get_allocation_size(nested), 0);
+                    for i in 0..1000 {
+                        assert_eq!(free_allocation(base + i), Some(100));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn labels_group_across_callstacks() {
+        let func = FunctionLocation::from_strings("a.py", "af");
+        let fid = FunctionId::new(&func as *const FunctionLocation);
+        let mut cs1 = Callstack::new();
+        cs1.start_call(0, CallSiteId::new(fid, 1));
+        let mut cs2 = Callstack::new();
+        cs2.start_call(0, CallSiteId::new(fid, 2));
+        let labelled = |callstack: &Callstack| {
+            let mut callstack = callstack.clone();
+            callstack.label = current_label();
+            callstack
+        };
+
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 5, &labelled(&cs1));
+        push_label("request-pool");
+        tracker.add_allocation(2, 100, &labelled(&cs1));
+        tracker.add_allocation(3, 200, &labelled(&cs2));
+        push_label("cache");
+        tracker.add_allocation(4, 1000, &labelled(&cs1));
+        pop_label();
+        tracker.add_allocation(5, 50, &labelled(&cs2));
+        pop_label();
+        assert_eq!(current_label(), 0);
+        assert_eq!(
+            tracker.bytes_by_label(true),
+            vec![
+                ("cache".to_string(), 1000),
+                ("request-pool".to_string(), 350),
+                ("[No label]".to_string(), 5)
+            ]
+        );
+        // Flamegraphs still merge the same callstack across labels:
+        let mut lines: Vec<String> = tracker.to_lines(true, false).collect();
+        lines.sort();
+        assert_eq!(lines, vec!["a.py:1 (af) 1105", "a.py:2 (af) 250"]);
+    }
 
+    #[test]
+    fn bytes_by_file_ignores_callstack() {
+        let func1 = FunctionLocation::from_strings("a.py", "af");
+        let func2 = FunctionLocation::from_strings("b.py", "bf");
+        let func3 = FunctionLocation::from_strings("b.py", "bg");
         let fid1 = FunctionId::new(&func1 as *const FunctionLocation);
         let fid2 = FunctionId::new(&func2 as *const FunctionLocation);
         let fid3 = FunctionId::new(&func3 as *const FunctionLocation);
-
-        let mut tracker = AllocationTracker::new(".".to_string());
-        let id1 = CallSiteId::new(fid1, 1);
-        // Same function, different line number—should be different item:
-        let id1_different = CallSiteId::new(fid1, 7);
-        let id2 = CallSiteId::new(fid2, 2);
-
-        let id3 = CallSiteId::new(fid3, 3);
+        // Two different callstacks ending in b.py:
         let mut cs1 = Callstack::new();
-        cs1.start_call(0, id1);
-        cs1.start_call(0, id2.clone());
+        cs1.start_call(0, CallSiteId::new(fid1, 1));
+        cs1.start_call(1, CallSiteId::new(fid2, 5));
         let mut cs2 = Callstack::new();
-        cs2.start_call(0, id3);
+        cs2.start_call(0, CallSiteId::new(fid1, 2));
+        cs2.start_call(2, CallSiteId::new(fid3, 7));
         let mut cs3 = Callstack::new();
-        cs3.start_call(0, id1_different);
-        cs3.start_call(0, id2);
-
-        tracker.add_allocation(1, 1000, &cs1);
-        tracker.add_allocation(2, 234, &cs2);
-        tracker.add_anon_mmap(3, 50000, &cs1);
-        tracker.add_allocation(4, 6000, &cs3);
+        cs3.start_call(0, CallSiteId::new(fid1, 3));
 
-        let mut expected = vec![
-            "a:1 (af);TB@@a:1@@TB;b:2 (bf);TB@@b:2@@TB 51000".to_string(),
-            "c:3 (cf);TB@@c:3@@TB 234".to_string(),
-            "a:7 (af);TB@@a:7@@TB;b:2 (bf);TB@@b:2@@TB 6000".to_string(),
-        ];
-        let mut result: Vec<String> = tracker.to_lines(true, true).collect();
-        result.sort();
-        expected.sort();
-        assert_eq!(expected, result);
+        let mut tracker = AllocationTracker::new(".".to_string(), DEFAULT_SPARE_MEMORY_SIZE);
+        tracker.add_allocation(1, 100, &cs1);
+        tracker.add_allocation(2, 200, &cs2);
+        tracker.add_allocation(3, 250, &cs3);
+        tracker.add_allocation(4, 5, &Callstack::new());
+        assert_eq!(
+            tracker.bytes_by_file(true),
+            vec![
+                ("b.py".to_string(), 300),
+                ("a.py".to_string(), 250),
+                ("[No Python stack]".to_string(), 5)
+            ]
+        );
 
-        let mut expected2 = vec![
-            "a:1 (af);b:2 (bf) 51000",
-            "c:3 (cf) 234",
-            "a:7 (af);b:2 (bf) 6000",
-        ];
-        let mut result2: Vec<String> = tracker.to_lines(true, false).collect();
-        result2.sort();
-        expected2.sort();
-        assert_eq!(expected2, result2);
+        // Hidden frames are attributed to their caller's file:
+        tracker
+            .dump_options
+            .hidden_frames
+            .patterns
+            .push("bg".to_string());
+        assert_eq!(
+            tracker.bytes_by_file(false),
+            vec![
+                ("a.py".to_string(), 450),
+                ("b.py".to_string(), 100),
+                ("[No Python stack]".to_string(), 5)
+            ]
+        );
     }
 
     // TODO test to_lines(false)
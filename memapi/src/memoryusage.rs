@@ -0,0 +1,255 @@
+//! The process's memory usage as the operating system sees it, i.e. resident
+//! memory, for comparing with what we've tracked.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// If non-zero, the page size used to turn resident pages into bytes, instead
+/// of the one the system reports.
+static PAGE_SIZE_OVERRIDE: AtomicUsize = AtomicUsize::new(0);
+
+/// Use the given page size, in bytes, when computing resident memory, e.g. to
+/// get reproducible numbers in tests or to match a machine with different
+/// pages. 0 goes back to the system's page size.
+pub fn set_page_size(bytes: usize) {
+    PAGE_SIZE_OVERRIDE.store(bytes, Ordering::Relaxed);
+}
+
+/// The page size resident memory is computed with, or None if it couldn't be
+/// found out.
+#[cfg(target_os = "linux")]
+fn page_size() -> Option<usize> {
+    match PAGE_SIZE_OVERRIDE.load(Ordering::Relaxed) {
+        0 => {
+            let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+            if page_size <= 0 {
+                None
+            } else {
+                Some(page_size as usize)
+            }
+        }
+        page_size => Some(page_size),
+    }
+}
+
+/// Resident memory in bytes, or None if it couldn't be found out.
+#[cfg(target_os = "linux")]
+pub fn get_memory_usage() -> Option<usize> {
+    resident_bytes(&std::fs::read_to_string("/proc/self/statm").ok()?)
+}
+
+/// Resident memory in bytes given the contents of /proc/self/statm.
+#[cfg(target_os = "linux")]
+fn resident_bytes(statm: &str) -> Option<usize> {
+    // The second field is resident pages:
+    let resident_pages: usize = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(resident_pages * page_size()?)
+}
+
+/// Resident memory in bytes, or None if it couldn't be found out. Windows
+/// reports bytes rather than pages, so the page size doesn't matter.
+#[cfg(windows)]
+pub fn get_memory_usage() -> Option<usize> {
+    windows::working_set_size()
+}
+
+/// Resident memory in bytes, or None if it couldn't be found out.
+#[cfg(not(any(target_os = "linux", windows)))]
+pub fn get_memory_usage() -> Option<usize> {
+    None
+}
+
+/// A mapped region of memory from /proc/self/smaps, and how much of it is
+/// resident.
+#[cfg(not(feature = "no-mmap-tracking"))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResidentRegion {
+    pub start: usize,
+    pub end: usize,
+    pub resident_bytes: usize,
+}
+
+#[cfg(not(feature = "no-mmap-tracking"))]
+impl ResidentRegion {
+    /// Resident bytes within [start, end), assuming resident pages are spread
+    /// evenly across the region; smaps only gives a total per region.
+    pub fn resident_bytes_within(&self, start: usize, end: usize) -> usize {
+        let overlap_start = start.max(self.start);
+        let overlap_end = end.min(self.end);
+        if overlap_end <= overlap_start || self.end <= self.start {
+            return 0;
+        }
+        let overlap = (overlap_end - overlap_start) as u128;
+        (self.resident_bytes as u128 * overlap / (self.end - self.start) as u128) as usize
+    }
+}
+
+/// Parse the contents of /proc/self/smaps. Each region starts with a line
+/// like "7f12c4000000-7f12c4021000 rw-p 00000000 00:00 0", followed by lines
+/// of fields, including "Rss:" in kB.
+#[cfg(not(feature = "no-mmap-tracking"))]
+pub fn parse_smaps(smaps: &str) -> Vec<ResidentRegion> {
+    let mut regions: Vec<ResidentRegion> = Vec::new();
+    for line in smaps.lines() {
+        let mut fields = line.split_whitespace();
+        let first = match fields.next() {
+            Some(first) => first,
+            None => continue,
+        };
+        if first == "Rss:" {
+            if let (Some(region), Some(Ok(kilobytes))) =
+                (regions.last_mut(), fields.next().map(str::parse::<usize>))
+            {
+                region.resident_bytes = kilobytes * 1024;
+            }
+        } else if let Some((start, end)) = first.split_once('-') {
+            if let (Ok(start), Ok(end)) = (
+                usize::from_str_radix(start, 16),
+                usize::from_str_radix(end, 16),
+            ) {
+                regions.push(ResidentRegion {
+                    start,
+                    end,
+                    resident_bytes: 0,
+                });
+            }
+        }
+    }
+    regions
+}
+
+/// The process's mapped regions and how much of each is resident, or None if
+/// that couldn't be found out. Reading smaps is relatively slow, since the
+/// kernel walks the page tables of every region.
+#[cfg(all(target_os = "linux", not(feature = "no-mmap-tracking")))]
+pub fn get_resident_regions() -> Option<Vec<ResidentRegion>> {
+    let smaps = std::fs::read_to_string("/proc/self/smaps").ok()?;
+    Some(parse_smaps(&smaps))
+}
+
+/// The process's mapped regions and how much of each is resident, or None if
+/// that couldn't be found out.
+#[cfg(all(not(target_os = "linux"), not(feature = "no-mmap-tracking")))]
+pub fn get_resident_regions() -> Option<Vec<ResidentRegion>> {
+    None
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::ffi::c_void;
+
+    // PROCESS_MEMORY_COUNTERS from psapi.h:
+    #[repr(C)]
+    #[derive(Default)]
+    struct ProcessMemoryCounters {
+        cb: u32,
+        page_fault_count: u32,
+        peak_working_set_size: usize,
+        working_set_size: usize,
+        quota_peak_paged_pool_usage: usize,
+        quota_paged_pool_usage: usize,
+        quota_peak_non_paged_pool_usage: usize,
+        quota_non_paged_pool_usage: usize,
+        pagefile_usage: usize,
+        peak_pagefile_usage: usize,
+    }
+
+    // Both are in kernel32, so no extra libraries are needed:
+    extern "system" {
+        fn GetCurrentProcess() -> *mut c_void;
+        fn K32GetProcessMemoryInfo(
+            process: *mut c_void,
+            counters: *mut ProcessMemoryCounters,
+            cb: u32,
+        ) -> i32;
+    }
+
+    /// The working set is Windows' equivalent of resident memory.
+    pub fn working_set_size() -> Option<usize> {
+        let mut counters = ProcessMemoryCounters {
+            cb: std::mem::size_of::<ProcessMemoryCounters>() as u32,
+            ..Default::default()
+        };
+        let succeeded =
+            unsafe { K32GetProcessMemoryInfo(GetCurrentProcess(), &mut counters, counters.cb) };
+        if succeeded == 0 {
+            None
+        } else {
+            Some(counters.working_set_size)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::get_memory_usage;
+    use std::sync::Mutex;
+
+    // The page size override is global, so tests that look at memory usage
+    // take turns:
+    static MEMORY_USAGE_LOCK: Mutex<()> = Mutex::new(());
+
+    #[cfg(not(feature = "no-mmap-tracking"))]
+    #[test]
+    fn smaps_are_parsed() {
+        let smaps = "\
+7f12c4000000-7f12c4021000 rw-p 00000000 00:00 0 
+Size:                132 kB
+KernelPageSize:        4 kB
+Rss:                  12 kB
+Pss:                  12 kB
+VmFlags: rd wr mr mw me nr sd 
+7ffd1a2b3000-7ffd1a2d4000 rw-p 00000000 00:00 0                          [stack]
+Size:                132 kB
+Rss:                 132 kB
+";
+        use super::{parse_smaps, ResidentRegion};
+        let regions = parse_smaps(smaps);
+        assert_eq!(
+            regions,
+            vec![
+                ResidentRegion {
+                    start: 0x7f12c4000000,
+                    end: 0x7f12c4021000,
+                    resident_bytes: 12 * 1024,
+                },
+                ResidentRegion {
+                    start: 0x7ffd1a2b3000,
+                    end: 0x7ffd1a2d4000,
+                    resident_bytes: 132 * 1024,
+                },
+            ]
+        );
+        // Half the stack region is half its resident memory:
+        let stack = &regions[1];
+        let middle = stack.start + (stack.end - stack.start) / 2;
+        assert_eq!(stack.resident_bytes_within(0, middle), 66 * 1024);
+        assert_eq!(stack.resident_bytes_within(stack.end, usize::MAX), 0);
+    }
+
+    /// Touching memory makes resident memory go up.
+    #[cfg(any(target_os = "linux", windows))]
+    #[test]
+    fn memory_usage_grows() {
+        let _lock = MEMORY_USAGE_LOCK.lock().unwrap();
+        let before = get_memory_usage().unwrap();
+        assert!(before > 0);
+        let data = vec![1u8; 64 * 1024 * 1024];
+        let after = get_memory_usage().unwrap();
+        assert!(after >= before + 32 * 1024 * 1024);
+        drop(data);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn page_size_can_be_overridden() {
+        use super::{page_size, resident_bytes, set_page_size};
+        let _lock = MEMORY_USAGE_LOCK.lock().unwrap();
+        let statm = "1000 250 100 10 0 300 0\n";
+        let system_page_size = page_size().unwrap();
+        assert_eq!(resident_bytes(statm), Some(250 * system_page_size));
+        set_page_size(16384);
+        assert_eq!(resident_bytes(statm), Some(250 * 16384));
+        set_page_size(0);
+        assert_eq!(resident_bytes(statm), Some(250 * system_page_size));
+    }
+}
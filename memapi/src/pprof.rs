@@ -0,0 +1,292 @@
+//! Writing profiles in pprof's protobuf format, so they can be viewed with
+//! `go tool pprof` and other tooling that understands it. The encoding is
+//! simple enough that it's done by hand, rather than pulling in a protobuf
+//! library. See
+//! https://github.com/google/pprof/blob/master/proto/profile.proto for the
+//! schema.
+use std::collections::HashMap;
+use std::io::Write;
+
+/// A single frame of a callstack.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub function: String,
+    pub filename: String,
+    pub line: u16,
+}
+
+// Protobuf wire types:
+const VARINT: u64 = 0;
+const LENGTH_DELIMITED: u64 = 2;
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn write_key(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    write_varint(buf, (field << 3) | wire_type);
+}
+
+fn write_uint(buf: &mut Vec<u8>, field: u64, value: u64) {
+    write_key(buf, field, VARINT);
+    write_varint(buf, value);
+}
+
+fn write_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_key(buf, field, LENGTH_DELIMITED);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_packed(buf: &mut Vec<u8>, field: u64, values: &[u64]) {
+    let mut packed = vec![];
+    for value in values {
+        write_varint(&mut packed, *value);
+    }
+    write_bytes(buf, field, &packed);
+}
+
+/// Strings are referred to by their index in the string table, and the first
+/// string must be empty.
+struct StringTable {
+    indexes: HashMap<String, u64>,
+    strings: Vec<String>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        let mut table = StringTable {
+            indexes: HashMap::default(),
+            strings: vec![],
+        };
+        table.index("");
+        table
+    }
+
+    fn index(&mut self, string: &str) -> u64 {
+        if let Some(index) = self.indexes.get(string) {
+            return *index;
+        }
+        let index = self.strings.len() as u64;
+        self.indexes.insert(string.to_string(), index);
+        self.strings.push(string.to_string());
+        index
+    }
+}
+
+/// A ValueType message, used for both the sample type and the period type.
+fn space_in_bytes(strings: &mut StringTable) -> Vec<u8> {
+    let mut value_type = vec![];
+    write_uint(&mut value_type, 1, strings.index("space"));
+    write_uint(&mut value_type, 2, strings.index("bytes"));
+    value_type
+}
+
+/// Write a Profile message with one "space" sample, in bytes, per callstack.
+/// Callstacks are given outermost frame first, as in collapsed stacks.
+pub fn write_profile<W: Write>(
+    out: &mut W,
+    samples: &[(Vec<Frame>, usize)],
+) -> std::io::Result<()> {
+    let mut strings = StringTable::new();
+    // Ids must be non-zero, so they're all 1 + their index:
+    let mut function_ids: HashMap<(&str, &str), u64> = HashMap::default();
+    let mut functions = vec![];
+    let mut location_ids: HashMap<(u64, u16), u64> = HashMap::default();
+    let mut locations = vec![];
+    let mut profile = vec![];
+
+    let sample_type = space_in_bytes(&mut strings);
+    write_bytes(&mut profile, 1, &sample_type);
+
+    for (frames, size) in samples {
+        let mut sample_location_ids = vec![];
+        // pprof wants the innermost frame first:
+        for frame in frames.iter().rev() {
+            let function_id = match function_ids.get(&(&frame.function, &frame.filename)) {
+                Some(function_id) => *function_id,
+                None => {
+                    let function_id = functions.len() as u64 + 1;
+                    let mut function = vec![];
+                    write_uint(&mut function, 1, function_id);
+                    write_uint(&mut function, 2, strings.index(&frame.function));
+                    write_uint(&mut function, 4, strings.index(&frame.filename));
+                    functions.push(function);
+                    function_ids.insert((&frame.function, &frame.filename), function_id);
+                    function_id
+                }
+            };
+            let location_id = *location_ids
+                .entry((function_id, frame.line))
+                .or_insert_with(|| {
+                    let location_id = locations.len() as u64 + 1;
+                    let mut line = vec![];
+                    write_uint(&mut line, 1, function_id);
+                    write_uint(&mut line, 2, frame.line as u64);
+                    let mut location = vec![];
+                    write_uint(&mut location, 1, location_id);
+                    write_bytes(&mut location, 4, &line);
+                    locations.push(location);
+                    location_id
+                });
+            sample_location_ids.push(location_id);
+        }
+        let mut sample = vec![];
+        write_packed(&mut sample, 1, &sample_location_ids);
+        write_packed(&mut sample, 2, &[*size as u64]);
+        write_bytes(&mut profile, 2, &sample);
+    }
+    for location in locations {
+        write_bytes(&mut profile, 4, &location);
+    }
+    for function in functions {
+        write_bytes(&mut profile, 5, &function);
+    }
+    let period_type = space_in_bytes(&mut strings);
+    for string in &strings.strings {
+        write_bytes(&mut profile, 6, string.as_bytes());
+    }
+    write_bytes(&mut profile, 11, &period_type);
+    write_uint(&mut profile, 12, 1);
+
+    out.write_all(&profile)?;
+    out.flush()
+}
+
+/// Just enough protobuf decoding to check what we wrote.
+#[cfg(test)]
+pub mod decode {
+    #[derive(Debug, PartialEq)]
+    pub enum Value<'a> {
+        Varint(u64),
+        Bytes(&'a [u8]),
+    }
+
+    fn read_varint(data: &[u8], position: &mut usize) -> u64 {
+        let mut result = 0;
+        let mut shift = 0;
+        loop {
+            let byte = data[*position];
+            *position += 1;
+            result |= ((byte & 0x7f) as u64) << shift;
+            if byte < 0x80 {
+                return result;
+            }
+            shift += 7;
+        }
+    }
+
+    /// The (field number, value) pairs of a message.
+    pub fn fields(data: &[u8]) -> Vec<(u64, Value<'_>)> {
+        let mut result = vec![];
+        let mut position = 0;
+        while position < data.len() {
+            let key = read_varint(data, &mut position);
+            let value = match key & 7 {
+                0 => Value::Varint(read_varint(data, &mut position)),
+                2 => {
+                    let length = read_varint(data, &mut position) as usize;
+                    position += length;
+                    Value::Bytes(&data[position - length..position])
+                }
+                wire_type => panic!("Unexpected wire type {}", wire_type),
+            };
+            result.push((key >> 3, value));
+        }
+        result
+    }
+
+    /// Decode a packed repeated varint field.
+    pub fn packed(data: &[u8]) -> Vec<u64> {
+        let mut result = vec![];
+        let mut position = 0;
+        while position < data.len() {
+            result.push(read_varint(data, &mut position));
+        }
+        result
+    }
+
+    /// The given field's byte values in a message.
+    pub fn bytes_fields(data: &[u8], field: u64) -> Vec<&[u8]> {
+        fields(data)
+            .into_iter()
+            .filter_map(|(number, value)| match value {
+                Value::Bytes(bytes) if number == field => Some(bytes),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The sum of all sample values in a Profile.
+    pub fn sample_total(profile: &[u8]) -> u64 {
+        bytes_fields(profile, 2)
+            .into_iter()
+            .flat_map(|sample| bytes_fields(sample, 2))
+            .flat_map(packed)
+            .sum()
+    }
+
+    /// The string table of a Profile.
+    pub fn strings(profile: &[u8]) -> Vec<String> {
+        bytes_fields(profile, 6)
+            .into_iter()
+            .map(|bytes| String::from_utf8(bytes.to_vec()).unwrap())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode::{bytes_fields, packed, sample_total, strings};
+    use super::{write_profile, write_varint, Frame};
+
+    #[test]
+    fn varints() {
+        let encode = |value| {
+            let mut buf = vec![];
+            write_varint(&mut buf, value);
+            buf
+        };
+        assert_eq!(encode(1), vec![1]);
+        assert_eq!(encode(300), vec![0xac, 0x02]);
+        assert_eq!(packed(&encode(u64::MAX)), vec![u64::MAX]);
+    }
+
+    #[test]
+    fn profile_round_trips() {
+        let frame = |function: &str, filename: &str, line| Frame {
+            function: function.to_string(),
+            filename: filename.to_string(),
+            line,
+        };
+        let main = frame("main", "a.py", 1);
+        let f = frame("f", "b.py", 7);
+        let samples = vec![
+            (vec![main.clone(), f.clone()], 1000),
+            (vec![main.clone()], 234),
+            (vec![main, f], 5),
+        ];
+        let mut out = vec![];
+        write_profile(&mut out, &samples).unwrap();
+
+        assert_eq!(sample_total(&out), 1239);
+        let strings = strings(&out);
+        assert_eq!(strings[0], "");
+        for expected in &["space", "bytes", "main", "a.py", "f", "b.py"] {
+            assert_eq!(strings.iter().filter(|s| s == expected).count(), 1);
+        }
+        // Two functions, and two locations since each is on a single line:
+        assert_eq!(bytes_fields(&out, 5).len(), 2);
+        assert_eq!(bytes_fields(&out, 4).len(), 2);
+        // Innermost location first, so f is location 1 and main location 2:
+        let location_ids: Vec<Vec<u64>> = bytes_fields(&out, 2)
+            .into_iter()
+            .map(|sample| packed(bytes_fields(sample, 1)[0]))
+            .collect();
+        assert_eq!(location_ids, vec![vec![1, 2], vec![2], vec![1, 2]]);
+    }
+}
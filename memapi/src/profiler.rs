@@ -0,0 +1,107 @@
+//! A Rust-native interface to the profiler, for programs that want Fil's
+//! memory accounting without going through the C API.
+use super::memorytracking::{self, FunctionId};
+use std::collections::HashMap;
+
+/// Records Python-style calls and allocations, and dumps out peak memory
+/// usage.
+///
+/// Tracked allocations are global, as with the C API, so all `Profiler`s
+/// share them; each thread has its own callstack.
+///
+/// ```
+/// use pymemprofile_api::Profiler;
+///
+/// let mut profiler = Profiler::new();
+/// profiler.start_call("example.py", "main", 0, 3);
+/// profiler.record_allocation(0x1000, 1024, 4);
+/// profiler.record_allocation(0x2000, 2048, 5);
+/// profiler.record_free(0x1000);
+/// profiler.finish_call();
+/// assert_eq!(profiler.current_allocated_bytes(), 2048);
+/// assert_eq!(profiler.peak_allocated_bytes(), 3072);
+/// ```
+pub struct Profiler {
+    // Functions are interned, so repeated calls don't use more memory:
+    functions: HashMap<(String, String), FunctionId>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            functions: HashMap::default(),
+        }
+    }
+
+    /// A function was called from the given line of its caller, and is now at
+    /// the given line.
+    pub fn start_call(
+        &mut self,
+        filename: &str,
+        function_name: &str,
+        parent_line_number: u16,
+        line_number: u16,
+    ) {
+        let function = *self
+            .functions
+            .entry((filename.to_string(), function_name.to_string()))
+            .or_insert_with(|| FunctionId::leak_from_strings(filename, function_name));
+        memorytracking::start_call(function, parent_line_number, line_number);
+    }
+
+    /// The current function returned.
+    pub fn finish_call(&self) {
+        memorytracking::finish_call();
+    }
+
+    /// The current function moved on to a new line.
+    pub fn new_line_number(&self, line_number: u16) {
+        memorytracking::new_line_number(line_number);
+    }
+
+    /// Memory was allocated at the given line of the current function, or
+    /// the current line if it's 0.
+    pub fn record_allocation(&self, address: usize, size: usize, line_number: u16) {
+        memorytracking::add_allocation(address, size, line_number, false);
+    }
+
+    /// Memory was freed. Returns the size of the allocation, or None if it
+    /// wasn't being tracked.
+    pub fn record_free(&self, address: usize) -> Option<usize> {
+        memorytracking::free_allocation(address)
+    }
+
+    /// How many bytes are currently allocated.
+    pub fn current_allocated_bytes(&self) -> usize {
+        memorytracking::get_current_allocated_bytes()
+    }
+
+    /// The largest number of bytes that were allocated at any one time.
+    pub fn peak_allocated_bytes(&self) -> usize {
+        memorytracking::get_peak_allocated_bytes()
+    }
+
+    /// Write peak memory usage to the given directory, as flamegraph SVGs and
+    /// an index.html.
+    ///
+    /// ```
+    /// use pymemprofile_api::Profiler;
+    ///
+    /// let directory = tempfile::tempdir().unwrap();
+    /// let mut profiler = Profiler::new();
+    /// profiler.start_call("example.py", "main", 0, 3);
+    /// profiler.record_allocation(0x1000, 1024, 0);
+    /// profiler.dump_peak(directory.path().to_str().unwrap()).unwrap();
+    /// assert!(directory.path().join("peak-memory.svg").exists());
+    /// assert!(directory.path().join("index.html").exists());
+    /// ```
+    pub fn dump_peak(&self, path: &str) -> std::io::Result<()> {
+        memorytracking::dump_peak_report(path)
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
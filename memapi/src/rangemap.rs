@@ -1,4 +1,3 @@
-use libc;
 use std::cmp::{max, min};
 use std::collections::HashMap;
 
@@ -50,16 +49,23 @@ impl<V: Clone> RangeMap<V> {
         RangeMap { ranges: vec![] }
     }
 
-    pub fn add(&mut self, start: usize, length: libc::size_t, value: V) {
-        if length <= 0 {
-            return;
+    /// Add a range. Any existing ranges it overlaps lose the overlapping
+    /// bytes, the way mmap(MAP_FIXED) replaces existing mappings, so the newer
+    /// value wins.
+    ///
+    /// Return how many bytes of existing ranges were replaced.
+    pub fn add(&mut self, start: usize, length: libc::size_t, value: V) -> Vec<(V, usize)> {
+        if length == 0 {
+            return vec![];
         }
+        let replaced = self.remove(start, length);
         self.ranges.push((Range::new(start, length), value));
+        replaced
     }
 
     /// Return how many bytes were removed.
     pub fn remove(&mut self, start: usize, length: libc::size_t) -> Vec<(V, usize)> {
-        if length <= 0 {
+        if length == 0 {
             return vec![];
         }
         let mut new_ranges = vec![];
@@ -117,6 +123,95 @@ impl<V: Clone> RangeMap<V> {
         removed
     }
 
+    /// Resize the range at old_start, possibly moving it to new_start, the way
+    /// mremap() does. Existing bytes that survive the resize keep their
+    /// values, and growth gets the given value. Nothing happens if no part of
+    /// the old range is in the map.
+    ///
+    /// Returns the bytes removed, including any other ranges the new location
+    /// replaced, and the bytes added.
+    #[allow(clippy::type_complexity)]
+    pub fn resize(
+        &mut self,
+        old_start: usize,
+        old_length: libc::size_t,
+        new_start: usize,
+        new_length: libc::size_t,
+        value: V,
+    ) -> (Vec<(V, usize)>, Vec<(V, usize)>) {
+        if old_length == 0 {
+            return (vec![], vec![]);
+        }
+        let old = Range::new(old_start, old_length);
+        // Surviving chunks, as offsets relative to the start of the range:
+        let chunks: Vec<(Range, V)> = self
+            .ranges
+            .iter()
+            .filter_map(|(range, value)| {
+                range.intersection(&old).map(|i| {
+                    (
+                        Range {
+                            start: i.start - old_start,
+                            end: i.end - old_start,
+                        },
+                        value.clone(),
+                    )
+                })
+            })
+            .collect();
+        if chunks.is_empty() {
+            return (vec![], vec![]);
+        }
+        let mut removed = self.remove(old_start, old_length);
+        let mut added = vec![];
+        for (chunk, value) in chunks {
+            if chunk.start >= new_length {
+                continue;
+            }
+            let length = min(chunk.end, new_length) - chunk.start;
+            removed.extend(self.add(new_start + chunk.start, length, value.clone()));
+            added.push((value, length));
+        }
+        if new_length > old_length {
+            removed.extend(self.add(
+                new_start + old_length,
+                new_length - old_length,
+                value.clone(),
+            ));
+            added.push((value, new_length - old_length));
+        }
+        (removed, added)
+    }
+
+    /// The value of the range containing the given address, if any.
+    pub fn get(&self, address: usize) -> Option<&V> {
+        self.ranges
+            .iter()
+            .find(|(range, _)| range.start <= address && address < range.end)
+            .map(|(_, value)| value)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.ranges.iter().map(|(_, value)| value)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.ranges.iter_mut().map(|(_, value)| value)
+    }
+
+    /// The stored ranges, as start address, length and value.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize, &V)> {
+        self.ranges
+            .iter()
+            .map(|(range, value)| (range.start, range.size(), value))
+    }
+
+    /// How many ranges are stored.
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    #[cfg(test)]
     pub fn size(&self) -> usize {
         self.ranges.iter().map(|(r, _)| r.size()).sum()
     }
@@ -149,11 +244,15 @@ mod tests {
             }
         }
 
-        fn add(&mut self, start: usize, length: libc::size_t, value: V) {
+        fn add(&mut self, start: usize, length: libc::size_t, value: V) -> Vec<(V, usize)> {
             assert!(length > 0);
+            let mut replaced = HashMap::new();
             for i in start..(start + length) {
-                self.items.insert(i, value.clone());
+                if let Some(old) = self.items.insert(i, value) {
+                    *replaced.entry(old).or_insert(0) += 1;
+                }
             }
+            replaced.iter().map(|(k, v)| (*k, *v)).collect()
         }
 
         fn remove(&mut self, start: usize, length: libc::size_t) -> Vec<(V, usize)> {
@@ -167,6 +266,33 @@ mod tests {
             removed.iter().map(|(k, v)| (*k, *v)).collect()
         }
 
+        fn resize(
+            &mut self,
+            old_start: usize,
+            old_length: libc::size_t,
+            new_start: usize,
+            new_length: libc::size_t,
+            value: V,
+        ) {
+            let mut chunks = vec![];
+            for offset in 0..old_length {
+                if let Some(v) = self.items.remove(&(old_start + offset)) {
+                    chunks.push((offset, v));
+                }
+            }
+            if chunks.is_empty() {
+                return;
+            }
+            for (offset, v) in chunks {
+                if offset < new_length {
+                    self.items.insert(new_start + offset, v);
+                }
+            }
+            for offset in old_length..new_length {
+                self.items.insert(new_start + offset, value);
+            }
+        }
+
         pub fn size(&self) -> usize {
             self.items.len()
         }
@@ -198,11 +324,34 @@ mod tests {
         }
     }
 
+    /// Sum removed bytes by value, since the real RangeMap may report a value
+    /// more than once.
+    fn by_value(removed: Vec<(usize, usize)>) -> HashMap<usize, usize> {
+        let mut result = HashMap::new();
+        for (k, v) in removed {
+            *result.entry(k).or_insert(0) += v;
+        }
+        result
+    }
+
+    /// Address by address contents of a RangeMap, for comparing with a
+    /// StupidRangeMap when adjacent ranges may share a value.
+    fn items(rangemap: &RangeMap<usize>) -> BTreeMap<usize, usize> {
+        let mut result = BTreeMap::new();
+        for (start, length, value) in rangemap.iter() {
+            for address in start..start + length {
+                // Ranges never overlap each other:
+                assert!(result.insert(address, *value).is_none());
+            }
+        }
+        result
+    }
+
     fn ranges() -> impl Strategy<Value = Vec<(usize, usize)>> {
         proptest::collection::vec((1..20usize, 1..20usize), 1..20)
             .prop_map(|vec| {
                 let mut result: Vec<(usize, usize)> = Vec::new();
-                let mut previous_start = 0 as usize;
+                let mut previous_start = 0usize;
                 for (shift_start, length) in vec.iter() {
                     previous_start += shift_start;
                     result.push((previous_start, *length));
@@ -222,23 +371,144 @@ mod tests {
             let mut real_rangemap : RangeMap<usize> = RangeMap::new();
             let mut stupid_rangemap: StupidRangeMap<usize> = StupidRangeMap::new();
             for (start, length) in add_ranges {
-                real_rangemap.add(start, length, start * (length as usize));
-                stupid_rangemap.add(start, length, start * (length as usize));
+                let replaced1 = real_rangemap.add(start, length, start * length);
+                let replaced2 = stupid_rangemap.add(start, length, start * length);
+                prop_assert!(replaced1.is_empty());
+                prop_assert!(replaced2.is_empty());
                 prop_assert_eq!(real_rangemap.size(), stupid_rangemap.size());
                 prop_assert_eq!(real_rangemap.as_hashmap(), stupid_rangemap.as_hashmap());
             }
             for (start, length) in remove_ranges {
                 let removed1 = real_rangemap.remove(start, length * 2);
                 let removed2 = stupid_rangemap.remove(start, length * 2);
-                let mut removed1_map = HashMap::new();
-                for (k, v) in removed1 {
-                    *removed1_map.entry(k).or_insert(0) += v;
-                }
-                let removed2_map : HashMap<usize, usize> = removed2.iter().map(|v| *v).collect();
-                prop_assert_eq!(removed1_map, removed2_map);
+                prop_assert_eq!(by_value(removed1), by_value(removed2));
                 prop_assert_eq!(real_rangemap.size(), stupid_rangemap.size());
                 prop_assert_eq!(real_rangemap.as_hashmap(), stupid_rangemap.as_hashmap());
             }
         }
+
+        /// Adding ranges that overlap existing ones, e.g. mmap(MAP_FIXED) over
+        /// a reserved region, replaces the overlapping bytes, and removing
+        /// arbitrary sub-ranges splits what's left. The real and stupid range
+        /// maps agree throughout.
+        #[test]
+        fn overlapping_adds_and_partial_removes(
+            operations in proptest::collection::vec((any::<bool>(), 0..200usize, 1..60usize), 1..40)
+        ) {
+            let mut real_rangemap : RangeMap<usize> = RangeMap::new();
+            let mut stupid_rangemap: StupidRangeMap<usize> = StupidRangeMap::new();
+            for (i, (is_add, start, length)) in operations.into_iter().enumerate() {
+                let size_before = real_rangemap.size();
+                if is_add {
+                    let replaced1 = by_value(real_rangemap.add(start, length, i));
+                    let replaced2 = by_value(stupid_rangemap.add(start, length, i));
+                    prop_assert_eq!(&replaced1, &replaced2);
+                    let replaced: usize = replaced1.values().sum();
+                    prop_assert_eq!(size_before + length - replaced, real_rangemap.size());
+                } else {
+                    let removed1 = by_value(real_rangemap.remove(start, length));
+                    let removed2 = by_value(stupid_rangemap.remove(start, length));
+                    prop_assert_eq!(&removed1, &removed2);
+                    let removed: usize = removed1.values().sum();
+                    prop_assert_eq!(size_before - removed, real_rangemap.size());
+                }
+                prop_assert_eq!(real_rangemap.size(), stupid_rangemap.size());
+                prop_assert_eq!(&items(&real_rangemap), &stupid_rangemap.items);
+            }
+        }
+
+        /// Resizing ranges gives the same result in the real and stupid range
+        /// maps, and the bytes removed and added net out to the change in size.
+        #[test]
+        fn resizing_ranges(
+            add_ranges in ranges(),
+            resizes in proptest::collection::vec((0..400usize, 1..40usize, 0..400usize, 1..40usize), 1..10)
+        ) {
+            let mut real_rangemap : RangeMap<usize> = RangeMap::new();
+            let mut stupid_rangemap: StupidRangeMap<usize> = StupidRangeMap::new();
+            for (start, length) in add_ranges {
+                real_rangemap.add(start, length, start * length);
+                stupid_rangemap.add(start, length, start * length);
+            }
+            for (old_start, old_length, new_start, new_length) in resizes {
+                // mremap() doesn't allow overlapping moves, and we don't
+                // want to overlap other ranges:
+                if new_start != old_start {
+                    let moved_to = new_start..new_start + new_length;
+                    let moved_from = old_start..old_start + old_length;
+                    if moved_to.start < moved_from.end && moved_from.start < moved_to.end {
+                        continue;
+                    }
+                    real_rangemap.remove(new_start, new_length);
+                    stupid_rangemap.remove(new_start, new_length);
+                } else if new_length > old_length {
+                    real_rangemap.remove(old_start + old_length, new_length - old_length);
+                    stupid_rangemap.remove(old_start + old_length, new_length - old_length);
+                }
+                let size_before = real_rangemap.size();
+                let (removed, added) = real_rangemap.resize(old_start, old_length, new_start, new_length, 1);
+                stupid_rangemap.resize(old_start, old_length, new_start, new_length, 1);
+                let removed: usize = removed.iter().map(|(_, size)| size).sum();
+                let added: usize = added.iter().map(|(_, size)| size).sum();
+                prop_assert_eq!(size_before + added - removed, real_rangemap.size());
+                prop_assert_eq!(real_rangemap.size(), stupid_rangemap.size());
+                // Resizing can leave adjacent ranges with the same value, so
+                // compare address by address:
+                prop_assert_eq!(&items(&real_rangemap), &stupid_rangemap.items);
+            }
+        }
+    }
+
+    #[test]
+    fn add_over_existing_ranges() {
+        let mut rangemap: RangeMap<usize> = RangeMap::new();
+        rangemap.add(100, 100, 1);
+        rangemap.add(300, 10, 2);
+        // Nested inside an existing range, splitting it:
+        assert_eq!(rangemap.add(120, 10, 3), vec![(1, 10)]);
+        // Covering parts of several ranges:
+        let mut replaced = rangemap.add(190, 115, 4);
+        replaced.sort_unstable();
+        assert_eq!(replaced, vec![(1, 10), (2, 5)]);
+        let mut expected = HashMap::new();
+        expected.insert(100, (20, &1));
+        expected.insert(120, (10, &3));
+        expected.insert(130, (60, &1));
+        expected.insert(190, (115, &4));
+        expected.insert(305, (5, &2));
+        assert_eq!(rangemap.as_hashmap(), expected);
+        assert_eq!(rangemap.get(125), Some(&3));
+        assert_eq!(rangemap.size(), 210);
+    }
+
+    #[test]
+    fn resize_in_place_and_move() {
+        let mut rangemap: RangeMap<usize> = RangeMap::new();
+        rangemap.add(100, 50, 1);
+
+        // Grow in place; growth gets the new value:
+        let (removed, added) = rangemap.resize(100, 50, 100, 80, 2);
+        assert_eq!(removed, vec![(1, 50)]);
+        assert_eq!(added, vec![(1, 50), (2, 30)]);
+        let mut expected = HashMap::new();
+        expected.insert(100, (50, &1));
+        expected.insert(150, (30, &2));
+        assert_eq!(rangemap.as_hashmap(), expected);
+
+        // Shrink in place:
+        let (removed, added) = rangemap.resize(100, 80, 100, 20, 3);
+        assert_eq!(removed, vec![(1, 50), (2, 30)]);
+        assert_eq!(added, vec![(1, 20)]);
+        assert_eq!(rangemap.size(), 20);
+
+        // Move elsewhere:
+        rangemap.resize(100, 20, 1000, 20, 4);
+        let mut expected = HashMap::new();
+        expected.insert(1000, (20, &1));
+        assert_eq!(rangemap.as_hashmap(), expected);
+
+        // Resizing something we don't know about does nothing:
+        assert_eq!(rangemap.resize(5000, 10, 5000, 100, 5), (vec![], vec![]));
+        assert_eq!(rangemap.size(), 20);
     }
 }
@@ -0,0 +1,67 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+/// Bounded set that retains only the most recently inserted N items.
+///
+/// Inserting an item that's already in the set refreshes it, so it's retained
+/// until N newer insertions have happened.
+pub struct RecentSet<T: Copy + Eq + Hash> {
+    // Insertion order, oldest first, including repeated insertions:
+    order: VecDeque<T>,
+    // How many times each item appears in order:
+    counts: HashMap<T, usize>,
+    capacity: usize,
+}
+
+impl<T: Copy + Eq + Hash> RecentSet<T> {
+    pub fn new(capacity: usize) -> Self {
+        RecentSet {
+            order: VecDeque::with_capacity(capacity),
+            counts: HashMap::default(),
+            capacity,
+        }
+    }
+
+    /// Add an item, forgetting the oldest insertion if we're full.
+    pub fn insert(&mut self, item: T) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.order.len() == self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+            let count = self.counts.get_mut(&oldest).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                self.counts.remove(&oldest);
+            }
+        }
+        self.order.push_back(item);
+        *self.counts.entry(item).or_insert(0) += 1;
+    }
+
+    pub fn contains(&self, item: &T) -> bool {
+        self.counts.contains_key(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RecentSet;
+    use proptest::prelude::*;
+
+    proptest! {
+        /// An item is in the set if and only if it was one of the last
+        /// `capacity` insertions.
+        #[test]
+        fn retains_most_recent(capacity in 0..20usize, items in prop::collection::vec(0..30u32, 0..100)) {
+            let mut set = RecentSet::new(capacity);
+            for item in &items {
+                set.insert(*item);
+            }
+            let recent = &items[items.len().saturating_sub(capacity)..];
+            for item in 0..30 {
+                prop_assert_eq!(set.contains(&item), recent.contains(&item));
+            }
+        }
+    }
+}
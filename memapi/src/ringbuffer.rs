@@ -0,0 +1,87 @@
+/// Fixed-capacity ring buffer that retains only the most recent N items.
+///
+/// All storage is allocated up front, so pushing never allocates. That matters
+/// because we push while tracking allocations, and we want to be able to read
+/// it back after something has gone badly wrong.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RingBuffer<T: Copy> {
+    items: Vec<T>,
+    capacity: usize,
+    // Index of the slot the next item will be written to:
+    next: usize,
+}
+
+impl<T: Copy> RingBuffer<T> {
+    pub fn new(capacity: usize) -> Self {
+        RingBuffer {
+            items: Vec::with_capacity(capacity),
+            capacity,
+            next: 0,
+        }
+    }
+
+    /// Add an item, overwriting the oldest one if we're full.
+    pub fn push(&mut self, item: T) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.items.len() < self.capacity {
+            self.items.push(item);
+        } else {
+            self.items[self.next] = item;
+        }
+        self.next = (self.next + 1) % self.capacity;
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Iterate over the retained items, in no particular order.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.items.iter_mut()
+    }
+
+    /// Iterate from the oldest retained item to the newest.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        // Once we've wrapped around, the oldest item is the one we'll overwrite
+        // next:
+        let split = if self.items.len() < self.capacity {
+            0
+        } else {
+            self.next
+        };
+        let (newer, older) = self.items.split_at(split);
+        older.iter().chain(newer.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RingBuffer;
+    use proptest::prelude::*;
+
+    proptest! {
+        /// Only the most recent `capacity` items are retained, oldest first.
+        #[test]
+        fn retains_most_recent(capacity in 1..50usize, count in 0..200usize) {
+            let mut ring = RingBuffer::new(capacity);
+            for i in 0..count {
+                ring.push(i);
+            }
+            let expected: Vec<usize> = (count.saturating_sub(capacity)..count).collect();
+            let result: Vec<usize> = ring.iter().copied().collect();
+            prop_assert_eq!(ring.len(), expected.len());
+            prop_assert_eq!(result, expected);
+        }
+    }
+
+    #[test]
+    fn zero_capacity_stores_nothing() {
+        let mut ring = RingBuffer::new(0);
+        ring.push(1);
+        assert_eq!(ring.len(), 0);
+        assert_eq!(ring.iter().count(), 0);
+    }
+}
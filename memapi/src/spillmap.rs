@@ -0,0 +1,469 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::mem::{size_of, MaybeUninit};
+use std::ops::Index;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Values that can be spilled to disk. Lower priorities get spilled first.
+pub trait Spillable: Copy {
+    fn spill_priority(&self) -> u64;
+}
+
+/// A map from addresses to values which, above a configurable number of
+/// entries, moves the lowest-priority entries out of the heap into a
+/// memory-mapped file in the temporary directory. The kernel can then write
+/// those pages out to disk and drop them from memory, which it can't do
+/// with the heap when there's no swap.
+///
+/// With no limit set (the default) this is just a HashMap, and the only
+/// extra cost is a branch or two per operation.
+pub struct SpillMap<V: Spillable> {
+    memory: HashMap<usize, V>,
+    disk: Option<DiskTable<V>>,
+    // 0 means no limit:
+    max_in_memory: usize,
+}
+
+impl<V: Spillable> SpillMap<V> {
+    pub fn new() -> Self {
+        SpillMap {
+            memory: HashMap::default(),
+            disk: None,
+            max_in_memory: 0,
+        }
+    }
+
+    /// Keep at most this many entries in memory, spilling the rest; 0 means
+    /// no limit. Spilling happens in batches, down to half the limit, so its
+    /// cost is amortized across insertions.
+    pub fn set_max_in_memory(&mut self, max_in_memory: usize) {
+        self.max_in_memory = max_in_memory;
+        self.spill_if_needed();
+    }
+
+    pub fn insert(&mut self, key: usize, value: V) -> Option<V> {
+        let mut previous = self.memory.insert(key, value);
+        if previous.is_none() {
+            if let Some(disk) = &mut self.disk {
+                previous = disk.remove(key);
+            }
+        }
+        self.spill_if_needed();
+        previous
+    }
+
+    pub fn remove(&mut self, key: &usize) -> Option<V> {
+        match self.memory.remove(key) {
+            Some(value) => Some(value),
+            None => self.disk.as_mut().and_then(|disk| disk.remove(*key)),
+        }
+    }
+
+    pub fn get(&self, key: &usize) -> Option<&V> {
+        match self.memory.get(key) {
+            Some(value) => Some(value),
+            None => self.disk.as_ref().and_then(|disk| disk.get(*key)),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn contains_key(&self, key: &usize) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.memory.len() + self.disk.as_ref().map_or(0, |disk| disk.len)
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many entries are in memory, as opposed to spilled to disk.
+    #[cfg(test)]
+    pub fn in_memory_len(&self) -> usize {
+        self.memory.len()
+    }
+
+    /// Remove all entries, keeping the limit.
+    pub fn clear(&mut self) {
+        self.memory.clear();
+        self.disk = None;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&usize, &V)> {
+        self.memory
+            .iter()
+            .chain(self.disk.iter().flat_map(|disk| disk.iter()))
+    }
+
+    #[cfg(test)]
+    pub fn keys(&self) -> impl Iterator<Item = &usize> {
+        self.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.memory
+            .values_mut()
+            .chain(self.disk.iter_mut().flat_map(|disk| disk.values_mut()))
+    }
+
+    fn spill_if_needed(&mut self) {
+        if self.max_in_memory == 0 || self.memory.len() <= self.max_in_memory {
+            return;
+        }
+        if self.disk.is_none() {
+            match DiskTable::new(self.max_in_memory * 2) {
+                Ok(disk) => self.disk = Some(disk),
+                Err(e) => {
+                    eprintln!(
+                        "=fil-profile= Warning: couldn't spill allocations to disk, keeping them in memory: {}",
+                        e
+                    );
+                    self.max_in_memory = 0;
+                    return;
+                }
+            }
+        }
+        let disk = self.disk.as_mut().unwrap();
+        let keep = self.max_in_memory / 2;
+        let mut by_priority: Vec<(u64, usize)> = self
+            .memory
+            .iter()
+            .map(|(key, value)| (value.spill_priority(), *key))
+            .collect();
+        let spill_count = by_priority.len() - keep;
+        if spill_count < by_priority.len() {
+            by_priority.select_nth_unstable(spill_count);
+        }
+        for (_, key) in &by_priority[..spill_count] {
+            let value = self.memory.remove(key).unwrap();
+            if let Err(e) = disk.insert(*key, value) {
+                // Growing the file failed, so keep what's left in memory:
+                eprintln!(
+                    "=fil-profile= Warning: couldn't spill allocations to disk, keeping them in memory: {}",
+                    e
+                );
+                self.memory.insert(*key, value);
+                self.max_in_memory = 0;
+                return;
+            }
+        }
+    }
+}
+
+impl<V: Spillable> Default for SpillMap<V> {
+    fn default() -> Self {
+        SpillMap::new()
+    }
+}
+
+impl<V: Spillable> Index<&usize> for SpillMap<V> {
+    type Output = V;
+
+    fn index(&self, key: &usize) -> &V {
+        self.get(key).expect("No entry for key")
+    }
+}
+
+impl<V: Spillable + PartialEq> PartialEq for SpillMap<V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+impl<V: Spillable + fmt::Debug> fmt::Debug for SpillMap<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+const EMPTY: u8 = 0;
+const OCCUPIED: u8 = 1;
+const DELETED: u8 = 2;
+
+/// A slot in the on-disk table. The file starts out zeroed, i.e. all slots
+/// are EMPTY, and the value is only initialized when OCCUPIED.
+#[repr(C)]
+struct Slot<V> {
+    key: usize,
+    value: MaybeUninit<V>,
+    state: u8,
+}
+
+/// An open-addressing hash table with linear probing, stored in an unlinked
+/// memory-mapped temporary file.
+struct DiskTable<V> {
+    // Keeps the mapping's file open; it's already unlinked:
+    _file: fs::File,
+    slots: *mut Slot<V>,
+    // Always a power of two:
+    capacity: usize,
+    // OCCUPIED slots:
+    len: usize,
+    // OCCUPIED or DELETED slots:
+    used: usize,
+}
+
+// The mapping is only reachable through the table, so it can move between
+// threads like any other owned memory:
+unsafe impl<V: Send> Send for DiskTable<V> {}
+
+impl<V: Copy> DiskTable<V> {
+    fn new(capacity: usize) -> io::Result<Self> {
+        let capacity = capacity.max(16).next_power_of_two();
+        let file = spill_file()?;
+        file.set_len((capacity * size_of::<Slot<V>>()) as u64)?;
+        let slots = map_file(&file, capacity * size_of::<Slot<V>>())? as *mut Slot<V>;
+        Ok(DiskTable {
+            _file: file,
+            slots,
+            capacity,
+            len: 0,
+            used: 0,
+        })
+    }
+
+    fn slots(&self) -> &[Slot<V>] {
+        unsafe { std::slice::from_raw_parts(self.slots, self.capacity) }
+    }
+
+    fn slots_mut(&mut self) -> &mut [Slot<V>] {
+        unsafe { std::slice::from_raw_parts_mut(self.slots, self.capacity) }
+    }
+
+    fn start_index(&self, key: usize) -> usize {
+        // Fibonacci hashing; addresses' low bits are mostly zero, so the high
+        // bits of the product are used:
+        let hash = (key as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        (hash >> (64 - self.capacity.trailing_zeros())) as usize
+    }
+
+    /// The index of the OCCUPIED slot for the key, if any.
+    fn find(&self, key: usize) -> Option<usize> {
+        let slots = self.slots();
+        let mut index = self.start_index(key);
+        loop {
+            let slot = &slots[index];
+            match slot.state {
+                EMPTY => return None,
+                OCCUPIED if slot.key == key => return Some(index),
+                _ => {}
+            }
+            index = (index + 1) & (self.capacity - 1);
+        }
+    }
+
+    fn get(&self, key: usize) -> Option<&V> {
+        self.find(key)
+            .map(|index| unsafe { &*self.slots()[index].value.as_ptr() })
+    }
+
+    fn remove(&mut self, key: usize) -> Option<V> {
+        let index = self.find(key)?;
+        self.len -= 1;
+        let slot = &mut self.slots_mut()[index];
+        slot.state = DELETED;
+        Some(unsafe { slot.value.assume_init() })
+    }
+
+    /// Insert a key that isn't already in the table.
+    fn insert(&mut self, key: usize, value: V) -> io::Result<()> {
+        // Keep the load factor, including DELETED slots, under 3/4 so
+        // probing stays short and there's always an EMPTY slot:
+        if (self.used + 1) * 4 > self.capacity * 3 {
+            self.rebuild()?;
+        }
+        let mut index = self.start_index(key);
+        let capacity = self.capacity;
+        let slots = self.slots_mut();
+        while slots[index].state == OCCUPIED {
+            index = (index + 1) & (capacity - 1);
+        }
+        let slot = &mut slots[index];
+        let was_empty = slot.state == EMPTY;
+        slot.key = key;
+        slot.value = MaybeUninit::new(value);
+        slot.state = OCCUPIED;
+        if was_empty {
+            self.used += 1;
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Move everything into a new table, twice as big unless most of the
+    /// used slots are DELETED.
+    fn rebuild(&mut self) -> io::Result<()> {
+        let capacity = if self.len * 2 > self.used {
+            self.capacity * 2
+        } else {
+            self.capacity
+        };
+        let mut new_table = DiskTable::new(capacity)?;
+        for (key, value) in self.iter() {
+            new_table.insert(*key, *value)?;
+        }
+        *self = new_table;
+        Ok(())
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&usize, &V)> {
+        self.slots()
+            .iter()
+            .filter(|slot| slot.state == OCCUPIED)
+            .map(|slot| (&slot.key, unsafe { &*slot.value.as_ptr() }))
+    }
+
+    fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.slots_mut()
+            .iter_mut()
+            .filter(|slot| slot.state == OCCUPIED)
+            .map(|slot| unsafe { &mut *slot.value.as_mut_ptr() })
+    }
+}
+
+impl<V> Drop for DiskTable<V> {
+    fn drop(&mut self) {
+        unmap(self.slots as *mut u8, self.capacity * size_of::<Slot<V>>());
+    }
+}
+
+/// Create a new file in the temporary directory that will go away once it's
+/// closed.
+fn spill_file() -> io::Result<fs::File> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "fil-spill-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    fs::remove_file(&path)?;
+    Ok(file)
+}
+
+#[cfg(unix)]
+fn map_file(file: &fs::File, length: usize) -> io::Result<*mut u8> {
+    use std::os::unix::io::AsRawFd;
+    let address = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            length,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    if address == libc::MAP_FAILED {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(address as *mut u8)
+    }
+}
+
+#[cfg(not(unix))]
+fn map_file(_file: &fs::File, _length: usize) -> io::Result<*mut u8> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "spilling to disk isn't supported on this platform",
+    ))
+}
+
+#[cfg(unix)]
+fn unmap(address: *mut u8, length: usize) {
+    unsafe {
+        libc::munmap(address as *mut libc::c_void, length);
+    }
+}
+
+#[cfg(not(unix))]
+fn unmap(_address: *mut u8, _length: usize) {}
+
+#[cfg(test)]
+mod tests {
+    use super::{SpillMap, Spillable};
+    use proptest::prelude::*;
+    use std::collections::HashMap;
+
+    impl Spillable for u32 {
+        fn spill_priority(&self) -> u64 {
+            *self as u64
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    enum Operation {
+        Insert(usize, u32),
+        Remove(usize),
+    }
+
+    fn operation() -> impl Strategy<Value = Operation> {
+        prop_oneof![
+            (0..200usize, any::<u32>()).prop_map(|(key, value)| Operation::Insert(key, value)),
+            (0..200usize).prop_map(Operation::Remove),
+        ]
+    }
+
+    #[test]
+    fn spills_lowest_priority() {
+        let mut map = SpillMap::new();
+        map.set_max_in_memory(10);
+        for i in 0..11 {
+            map.insert(i, i as u32);
+        }
+        assert_eq!(map.len(), 11);
+        assert_eq!(map.in_memory_len(), 5);
+        for i in 0..6 {
+            assert!(!map.memory.contains_key(&i));
+            assert_eq!(map[&i], i as u32);
+        }
+        assert_eq!(map.remove(&2), Some(2));
+        assert!(!map.contains_key(&2));
+        assert!(map.contains_key(&3));
+        assert_eq!(map.len(), 10);
+        map.clear();
+        assert!(map.is_empty());
+    }
+
+    proptest! {
+        /// Whatever the limit, a SpillMap acts just like a HashMap.
+        #[test]
+        fn acts_like_hashmap(max_in_memory in 0..20usize, operations in prop::collection::vec(operation(), 0..500)) {
+            let mut map = SpillMap::new();
+            map.set_max_in_memory(max_in_memory);
+            let mut expected = HashMap::new();
+            for operation in operations {
+                match operation {
+                    Operation::Insert(key, value) => {
+                        prop_assert_eq!(map.insert(key, value), expected.insert(key, value));
+                    }
+                    Operation::Remove(key) => {
+                        prop_assert_eq!(map.remove(&key), expected.remove(&key));
+                    }
+                }
+                if max_in_memory > 0 {
+                    prop_assert!(map.in_memory_len() <= max_in_memory);
+                }
+            }
+            prop_assert_eq!(map.len(), expected.len());
+            let contents: HashMap<usize, u32> = map.iter().map(|(k, v)| (*k, *v)).collect();
+            prop_assert_eq!(contents, expected);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use super::ringbuffer::RingBuffer;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// A sample of total memory usage at some point in time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Sample {
+    // Time since the timeline started:
+    elapsed: Duration,
+    bytes: usize,
+    // Whether this sample is a new peak:
+    is_peak: bool,
+}
+
+/// Memory usage over time. A sample is taken whenever memory usage has changed
+/// by at least `delta` bytes since the previous sample, and whenever a new peak
+/// is reached. Only the most recent samples are kept.
+pub struct Timeline {
+    start: Instant,
+    delta: usize,
+    last_bytes: usize,
+    samples: RingBuffer<Sample>,
+}
+
+impl Timeline {
+    pub fn new(delta: usize, capacity: usize) -> Self {
+        Timeline {
+            start: Instant::now(),
+            delta,
+            last_bytes: 0,
+            samples: RingBuffer::new(capacity),
+        }
+    }
+
+    /// Memory usage changed to the given number of bytes.
+    pub fn memory_changed(&mut self, bytes: usize) {
+        if bytes.abs_diff(self.last_bytes) >= self.delta {
+            self.push(self.start.elapsed(), bytes, false);
+        }
+    }
+
+    /// A new peak was reached.
+    pub fn new_peak(&mut self, bytes: usize) {
+        self.push(self.start.elapsed(), bytes, true);
+    }
+
+    fn push(&mut self, elapsed: Duration, bytes: usize, is_peak: bool) {
+        self.last_bytes = bytes;
+        self.samples.push(Sample {
+            elapsed,
+            bytes,
+            is_peak,
+        });
+    }
+
+    /// Write the samples as CSV, oldest first. The last peak row is the
+    /// high-water mark.
+    pub fn write_csv<W: Write>(&self, out: &mut W) -> std::io::Result<()> {
+        writeln!(out, "seconds,bytes,is_peak")?;
+        for sample in self.samples.iter() {
+            writeln!(
+                out,
+                "{:.6},{},{}",
+                sample.elapsed.as_secs_f64(),
+                sample.bytes,
+                sample.is_peak as u8
+            )?;
+        }
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Timeline;
+    use std::time::Duration;
+
+    #[test]
+    fn samples_when_delta_is_crossed() {
+        let mut timeline = Timeline::new(100, 10);
+        for bytes in &[50, 99, 100, 150, 250, 160, 140] {
+            timeline.memory_changed(*bytes);
+        }
+        timeline.new_peak(250);
+        let bytes: Vec<(usize, bool)> = timeline
+            .samples
+            .iter()
+            .map(|sample| (sample.bytes, sample.is_peak))
+            .collect();
+        assert_eq!(
+            bytes,
+            vec![(100, false), (250, false), (140, false), (250, true)]
+        );
+    }
+
+    #[test]
+    fn csv_output() {
+        let mut timeline = Timeline::new(1, 10);
+        timeline.push(Duration::from_millis(1500), 1000, false);
+        timeline.push(Duration::from_millis(2000), 3000, true);
+        let mut out = vec![];
+        timeline.write_csv(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "seconds,bytes,is_peak\n1.500000,1000,0\n2.000000,3000,1\n"
+        );
+    }
+}
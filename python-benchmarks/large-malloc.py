@@ -0,0 +1,5 @@
+"""A malloc() large enough that the underlying malloc() uses mmap() for it."""
+from pymalloc import pymalloc
+
+# glibc's malloc() always uses mmap() for allocations of more than 32MB:
+result = pymalloc(50 * 1024 * 1024)
@@ -7,6 +7,8 @@ from pymalloc import (
     pyaligned_alloc,
     pyallocation_api,
     pyposix_memalign,
+    pyvalloc,
+    pymemalign,
 )
 import ctypes
 
@@ -24,6 +26,9 @@ def main():
     pyaligned_alloc()
     pyallocation_api()
     pyposix_memalign()
+    pyvalloc()
+    if sys.platform == "linux":
+        pymemalign()
     result = pymalloc(size * MB)
     result = pyrealloc(result, (size + 10) * MB)  # <-- peak
     result = pyrealloc(result, (size - 5) * MB)
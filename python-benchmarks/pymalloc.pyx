@@ -4,6 +4,18 @@ from libc.stdint cimport uint64_t
 cdef extern from "stdlib.h":
     void* aligned_alloc(size_t alignment, size_t size)
     int posix_memalign(void **memptr, size_t alignment, size_t size)
+    void* valloc(size_t size)
+
+cdef extern from *:
+    """
+    #ifdef __linux__
+    #include <malloc.h>
+    #else
+    // memalign() is glibc-only, so pymemalign() is only called on Linux.
+    static void *memalign(size_t alignment, size_t size) { return NULL; }
+    #endif
+    """
+    void* memalign(size_t alignment, size_t size)
 
 cdef extern from "Python.h":
     void* PyMem_Malloc(size_t n)
@@ -34,3 +46,9 @@ def pyallocation_api():
 def pyposix_memalign():
     cdef void *result;
     return posix_memalign(&result, 64, 1024 * 1024 * 15)
+
+def pyvalloc():
+    return <uint64_t>valloc(1024 * 1024 * 25)
+
+def pymemalign():
+    return <uint64_t>memalign(64, 1024 * 1024 * 35)
@@ -97,27 +97,36 @@ def test_malloc_in_c_extension():
     script = str(script)
 
     # The realloc() in the scripts adds 10 to the 70:
-    path = ((script, "<module>", 32), (script, "main", 28))
+    path = ((script, "<module>", 37), (script, "main", 33))
     assert match(allocations, {path: big}, as_mb) == pytest.approx(70 + 10, 0.1)
 
     # The C++ new allocation:
-    path = ((script, "<module>", 32), (script, "main", 23))
+    path = ((script, "<module>", 37), (script, "main", 25))
     assert match(allocations, {path: big}, as_mb) == pytest.approx(40, 0.1)
 
     # C++ aligned_alloc(); not available on Conda, where it's just a macro
     # redirecting to posix_memalign.
     if not os.environ.get("CONDA_PREFIX"):
-        path = ((script, "<module>", 32), (script, "main", 24))
+        path = ((script, "<module>", 37), (script, "main", 26))
         assert match(allocations, {path: big}, as_mb) == pytest.approx(90, 0.1)
 
     # Py*_*Malloc APIs:
-    path = ((script, "<module>", 32), (script, "main", 25))
+    path = ((script, "<module>", 37), (script, "main", 27))
     assert match(allocations, {path: big}, as_mb) == pytest.approx(30, 0.1)
 
     # posix_memalign():
-    path = ((script, "<module>", 32), (script, "main", 26))
+    path = ((script, "<module>", 37), (script, "main", 28))
     assert match(allocations, {path: big}, as_mb) == pytest.approx(15, 0.1)
 
+    # valloc():
+    path = ((script, "<module>", 37), (script, "main", 29))
+    assert match(allocations, {path: big}, as_mb) == pytest.approx(25, 0.1)
+
+    # memalign(), which is glibc-only:
+    if sys.platform == "linux":
+        path = ((script, "<module>", 37), (script, "main", 31))
+        assert match(allocations, {path: big}, as_mb) == pytest.approx(35, 0.1)
+
 
 def test_anonymous_mmap():
     """
@@ -135,6 +144,23 @@ def test_anonymous_mmap():
     assert match(allocations, {path: big}, as_mb) == pytest.approx(60, 0.1)
 
 
+def test_mmap_inside_malloc_is_recorded_once():
+    """
+    When the underlying malloc() uses mmap(), the memory is only recorded once,
+    for the malloc().
+    """
+    script = Path("python-benchmarks") / "large-malloc.py"
+    output_dir = profile(script)
+    allocations = get_allocations(output_dir)
+
+    script = str(script)
+    path = ((script, "<module>", 5),)
+
+    assert match(allocations, {path: big}, as_mb) == pytest.approx(50, 0.1)
+    # The mmap() wasn't recorded separately, e.g. without a Python stack:
+    assert sum(allocations.values()) / 1024 == pytest.approx(50, 0.1)
+
+
 def test_python_objects():
     """
     Python objects gets detected and tracked.
@@ -163,7 +189,7 @@ def test_minus_m():
     allocations = get_allocations(output_dir)
     stripped_allocations = {k[3:]: v for (k, v) in allocations.items()}
     script = str(script)
-    path = ((script, "<module>", 32), (script, "main", 28))
+    path = ((script, "<module>", 37), (script, "main", 33))
 
     assert match(stripped_allocations, {path: big}, as_mb) == pytest.approx(
         50 + 10, 0.1
@@ -200,7 +226,12 @@ def test_out_of_memory():
     time.sleep(10)  # wait for child process to finish
     allocations = get_allocations(
         output_dir,
-        ["out-of-memory.svg", "out-of-memory-reversed.svg", "out-of-memory.prof",],
+        [
+            "out-of-memory.svg",
+            "out-of-memory-reversed.svg",
+            "out-of-memory.prof",
+            "index.html",
+        ],
         "out-of-memory.prof",
     )
 